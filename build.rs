@@ -7,26 +7,33 @@ fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("template.rs");
 
-    // Read the lexer.rs template file
+    // Read the lexer.rs template file and the lexer_bytes.rs template of byte-level lexers
     let template_content =
         fs::read_to_string("src/lexer.rs").expect("Failed to read src/lexer.rs template file");
+    let bytes_template_content = fs::read_to_string("src/lexer_bytes.rs")
+        .expect("Failed to read src/lexer_bytes.rs template file");
 
-    // Generate template.rs with the embedded template
-    let escaped_content = template_content.replace("\\", "\\\\").replace("\"", "\\\"");
+    // Generate template.rs with the embedded templates
+    let escape = |content: &str| content.replace("\\", "\\\\").replace("\"", "\\\"");
     let template_code = format!(
         r#"// This file is auto-generated by build.rs
 // Do not edit manually
 
 pub const LEXER_TEMPLATE: &str = "{}";
+
+pub const LEXER_BYTES_TEMPLATE: &str = "{}";
 "#,
-        escaped_content
+        escape(&template_content),
+        escape(&bytes_template_content)
     );
 
     fs::write(&dest_path, template_code).expect("Failed to write template.rs");
 
     println!("cargo:rerun-if-changed=src/lexer.rs");
+    println!("cargo:rerun-if-changed=src/lexer_bytes.rs");
     println!("cargo:rerun-if-changed=src/parser.rs");
     println!("cargo:rerun-if-changed=src/generator.rs");
+    println!("cargo:rerun-if-changed=src/pretty.rs");
     println!("cargo:rerun-if-changed=src/token.rs");
 
     // Register all .klex files to trigger rebuild when they change
@@ -48,7 +55,7 @@ fn register_klex_files() {
     if let Ok(entries) = fs::read_dir(tests_dir) {
         for entry in entries.filter_map(|e| e.ok()) {
             let path = entry.path();
-            if path.extension().map_or(false, |ext| ext == "klex") {
+            if path.extension().is_some_and(|ext| ext == "klex") {
                 println!("cargo:rerun-if-changed={}", path.display());
             }
         }
@@ -68,7 +75,7 @@ fn generate_test_lexers() {
         Ok(entries) => entries
             .filter_map(|entry| entry.ok())
             .map(|entry| entry.path())
-            .filter(|path| path.extension().map_or(false, |ext| ext == "klex"))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "klex"))
             .collect(),
         Err(_) => return,
     };
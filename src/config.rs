@@ -0,0 +1,368 @@
+//! Project configuration files (`klex.toml`).
+//!
+//! A project with several lexers lists them in a `klex.toml` next to its `Cargo.toml`,
+//! with their output files and generator options, and `klex build` generates them all.
+//! Only the part of TOML the configuration needs is read: `[table]` and `[[array]]`
+//! headers, and `key = value` lines whose values are strings or booleans.
+//!
+//! ```toml
+//! [options]                 # generator options of every lexer
+//! backend = "dfa"
+//! visibility = "pub(crate)"
+//!
+//! [definitions]             # named definitions shared by every spec
+//! DIGIT = "[0-9]"
+//!
+//! [[lexer]]
+//! spec = "specs/calc.klex"
+//! output = "src/calc_lexer.rs"
+//! lexer-name = "CalcLexer"  # options of this lexer only
+//! ```
+
+use crate::generator::{Backend, GeneratorOptions, Visibility};
+use crate::parser::ParseError;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A lexer listed in a project configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexerConfig {
+    /// The specification file
+    pub spec: PathBuf,
+    /// The generated lexer (default: `NAME_lexer.rs` next to `NAME.klex`)
+    pub output: PathBuf,
+    /// C header of the extern "C" functions, also written when given (implies `ffi`)
+    pub c_header: Option<PathBuf>,
+    /// The parsed spec as JSON, also written when given
+    pub json: Option<PathBuf>,
+    /// Generator options: the `[options]` of the project with those of the lexer on top
+    pub options: GeneratorOptions,
+}
+
+/// A project configuration (`klex.toml`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProjectConfig {
+    /// `(NAME, pattern)` definitions shared by the specs, in order (`[definitions]`)
+    pub definitions: Vec<(String, String)>,
+    /// The lexers of the project (`[[lexer]]`), in order
+    pub lexers: Vec<LexerConfig>,
+}
+
+/// A value of the configuration.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Bool(bool),
+}
+
+/// A `key = value` line of the configuration with its line number.
+struct Entry {
+    key: String,
+    value: Value,
+    line: usize,
+}
+
+/// A table of the configuration: the header name, whether it was an `[[array]]` entry,
+/// and its entries.
+struct Table {
+    name: String,
+    array: bool,
+    entries: Vec<Entry>,
+    line: usize,
+}
+
+/// Returns an error located at a line of the configuration.
+fn config_error(message: String, line: usize, text: &str) -> ParseError {
+    let mut error = ParseError::new(message);
+    if line > 0 {
+        let source_line = text.lines().nth(line - 1).unwrap_or_default();
+        error.line = Some(line);
+        error.column = Some(source_line.chars().take_while(|c| c.is_whitespace()).count() + 1);
+        error.source_line = Some(source_line.to_string());
+    }
+    error
+}
+
+/// Removes a `#` comment from the end of a line, outside of strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+/// Reads a key: bare (`lexer-name`) or quoted (`"DIGIT"`).
+fn parse_key(key: &str) -> Option<String> {
+    let key = key.trim();
+    if key.starts_with(['"', '\'']) {
+        return match parse_value(key)? {
+            Value::String(key) => Some(key),
+            Value::Bool(_) => None,
+        };
+    }
+    let is_bare = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    is_bare.then(|| key.to_string())
+}
+
+/// Reads a value: a basic string (`"..."` with escapes), a literal string (`'...'`) or a boolean.
+fn parse_value(value: &str) -> Option<Value> {
+    let value = value.trim();
+    match value {
+        "true" => return Some(Value::Bool(true)),
+        "false" => return Some(Value::Bool(false)),
+        _ => {}
+    }
+    if let Some(literal) = value.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) {
+        return (!literal.contains('\'')).then(|| Value::String(literal.to_string()));
+    }
+    let body = value.strip_prefix('"')?.strip_suffix('"')?;
+    let mut text = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return None,
+            '\\' => text.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '\\' => '\\',
+                '"' => '"',
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                _ => return None,
+            }),
+            c => text.push(c),
+        }
+    }
+    Some(Value::String(text))
+}
+
+/// Splits the configuration into tables; entries before the first header form a table
+/// with an empty name.
+fn parse_tables(text: &str) -> Result<Vec<Table>, ParseError> {
+    let mut tables = vec![Table { name: String::new(), array: false, entries: Vec::new(), line: 0 }];
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("[[").and_then(|rest| rest.strip_suffix("]]")) {
+            tables.push(Table { name: name.trim().to_string(), array: true, entries: Vec::new(), line: line_number });
+        } else if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            let name = name.trim().to_string();
+            if tables.iter().any(|table| table.name == name && !table.array) {
+                return Err(config_error(format!("Table `[{}]` is defined twice", name), line_number, text));
+            }
+            tables.push(Table { name, array: false, entries: Vec::new(), line: line_number });
+        } else {
+            let (key, value) = line
+                .split_once('=')
+                .and_then(|(key, value)| Some((parse_key(key)?, parse_value(value)?)))
+                .ok_or_else(|| {
+                    config_error("Expected `key = value` with a string or boolean value".to_string(), line_number, text)
+                })?;
+            let table = tables.last_mut().expect("there is always a table");
+            if table.entries.iter().any(|entry| entry.key == key) {
+                return Err(config_error(format!("Key `{}` is defined twice", key), line_number, text));
+            }
+            table.entries.push(Entry { key, value, line: line_number });
+        }
+    }
+    Ok(tables)
+}
+
+/// Applies a generator option of `[options]` or `[[lexer]]` to `options`. Returns false
+/// if `key` is not a generator option.
+fn apply_option(options: &mut GeneratorOptions, entry: &Entry, text: &str) -> Result<bool, ParseError> {
+    let error = |expected: &str| config_error(format!("`{}` must be {}", entry.key, expected), entry.line, text);
+    let flag = match entry.key.replace('_', "-").as_str() {
+        "no-std" => &mut options.no_std,
+        "bytes" => &mut options.bytes,
+        "intern" => &mut options.intern,
+        "ffi" => &mut options.ffi,
+        "wasm" => &mut options.wasm,
+        "pyo3" => &mut options.pyo3,
+        key => {
+            let Value::String(value) = &entry.value else {
+                return match key {
+                    "backend" | "visibility" | "lexer-name" | "token-name" | "token-kind-name" => Err(error("a string")),
+                    _ => Ok(false),
+                };
+            };
+            match key {
+                "backend" => {
+                    options.backend = match value.as_str() {
+                        "regex" => Backend::Regex,
+                        "dfa" => Backend::Dfa,
+                        "precompiled-dfa" => Backend::PrecompiledDfa,
+                        "regex-set" => Backend::RegexSet,
+                        _ => return Err(error("regex, dfa, precompiled-dfa or regex-set")),
+                    }
+                }
+                "visibility" => {
+                    options.visibility = match value.as_str() {
+                        "pub" => Visibility::Public,
+                        "pub(crate)" | "crate" => Visibility::Crate,
+                        "private" => Visibility::Private,
+                        _ => return Err(error("pub, pub(crate) or private")),
+                    }
+                }
+                "lexer-name" | "token-name" | "token-kind-name" => {
+                    let mut chars = value.chars();
+                    let is_identifier = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+                        && chars.all(|c| c.is_alphanumeric() || c == '_');
+                    if !is_identifier {
+                        return Err(error("a Rust identifier"));
+                    }
+                    let name = match key {
+                        "lexer-name" => &mut options.lexer_name,
+                        "token-name" => &mut options.token_name,
+                        _ => &mut options.token_kind_name,
+                    };
+                    *name = Some(value.clone());
+                }
+                _ => return Ok(false),
+            }
+            return Ok(true);
+        }
+    };
+    match entry.value {
+        Value::Bool(value) => *flag = value,
+        Value::String(_) => return Err(error("true or false")),
+    }
+    Ok(true)
+}
+
+impl ProjectConfig {
+    /// Reads a project configuration from the text of a `klex.toml`.
+    ///
+    /// The paths of the lexers are relative to `base_dir`, the directory of the file.
+    /// Unknown tables and keys are errors, so typos don't go unnoticed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use klex::{Backend, ProjectConfig};
+    /// use std::path::Path;
+    ///
+    /// let config = ProjectConfig::parse(r#"
+    /// [options]
+    /// backend = "dfa"
+    ///
+    /// [definitions]
+    /// DIGIT = "[0-9]"
+    ///
+    /// [[lexer]]
+    /// spec = "specs/calc.klex"
+    /// lexer-name = "CalcLexer"
+    /// "#, Path::new("project")).unwrap();
+    /// assert_eq!(config.definitions, [("DIGIT".to_string(), "[0-9]".to_string())]);
+    /// let lexer = &config.lexers[0];
+    /// assert_eq!(lexer.output, Path::new("project/specs/calc_lexer.rs"));
+    /// assert_eq!(lexer.options.backend, Backend::Dfa);
+    /// assert_eq!(lexer.options.lexer_name.as_deref(), Some("CalcLexer"));
+    ///
+    /// let error = ProjectConfig::parse("[[lexer]]\nspec = \"a.klex\"\nbakend = \"dfa\"\n", Path::new("")).unwrap_err();
+    /// assert_eq!(error.line, Some(3));
+    /// ```
+    pub fn parse(text: &str, base_dir: &Path) -> Result<ProjectConfig, ParseError> {
+        let tables = parse_tables(text)?;
+        let mut config = ProjectConfig::default();
+
+        // [options] apply to every lexer, wherever the table is
+        let mut options = GeneratorOptions::default();
+        for table in tables.iter().filter(|table| table.name == "options") {
+            for entry in &table.entries {
+                if !apply_option(&mut options, entry, text)? {
+                    return Err(config_error(format!("Unknown option `{}`", entry.key), entry.line, text));
+                }
+            }
+        }
+
+        for table in &tables {
+            match (table.name.as_str(), table.array) {
+                ("options", false) => {}
+                ("definitions", false) => {
+                    for entry in &table.entries {
+                        let Value::String(pattern) = &entry.value else {
+                            let message = format!("Definition `{}` must be a pattern string", entry.key);
+                            return Err(config_error(message, entry.line, text));
+                        };
+                        config.definitions.push((entry.key.clone(), pattern.clone()));
+                    }
+                }
+                ("lexer", true) => config.lexers.push(Self::parse_lexer(table, &options, base_dir, text)?),
+                ("", false) if table.entries.is_empty() => {}
+                ("", false) => {
+                    let entry = &table.entries[0];
+                    let message = format!("Key `{}` must be in a table such as [options] or [[lexer]]", entry.key);
+                    return Err(config_error(message, entry.line, text));
+                }
+                (name, array) => {
+                    let header = if array { format!("[[{}]]", name) } else { format!("[{}]", name) };
+                    let message = format!("Unknown table `{}`: expected [options], [definitions] or [[lexer]]", header);
+                    return Err(config_error(message, table.line, text));
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Reads the project configuration file at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ProjectConfig, ParseError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| ParseError::new(format!("Cannot read {}: {}", path.display(), e)))?;
+        ProjectConfig::parse(&text, path.parent().unwrap_or(Path::new("")))
+    }
+
+    /// Reads a `[[lexer]]` table.
+    fn parse_lexer(
+        table: &Table,
+        options: &GeneratorOptions,
+        base_dir: &Path,
+        text: &str,
+    ) -> Result<LexerConfig, ParseError> {
+        let mut options = options.clone();
+        let (mut spec, mut output, mut c_header, mut json) = (None, None, None, None);
+        for entry in &table.entries {
+            let path = match entry.key.replace('_', "-").as_str() {
+                "spec" => &mut spec,
+                "output" => &mut output,
+                "c-header" => &mut c_header,
+                "json" => &mut json,
+                _ if apply_option(&mut options, entry, text)? => continue,
+                _ => return Err(config_error(format!("Unknown key `{}` in [[lexer]]", entry.key), entry.line, text)),
+            };
+            match &entry.value {
+                Value::String(value) => *path = Some(base_dir.join(value)),
+                Value::Bool(_) => {
+                    return Err(config_error(format!("`{}` must be a path string", entry.key), entry.line, text));
+                }
+            }
+        }
+        let spec: PathBuf =
+            spec.ok_or_else(|| config_error("A [[lexer]] needs a `spec` file".to_string(), table.line, text))?;
+        let output = output.unwrap_or_else(|| {
+            let stem = spec.file_stem().unwrap_or_default().to_string_lossy();
+            spec.with_file_name(format!("{}_lexer.rs", stem))
+        });
+        if c_header.is_some() {
+            options.ffi = true;
+        }
+        Ok(LexerConfig { spec, output, c_header, json, options })
+    }
+}
@@ -0,0 +1,340 @@
+//! DFA code generation backends.
+//!
+//! Compiles the patterns of several rules into one deterministic finite automaton
+//! with `regex-automata` and emits it into the generated lexer, either as tables
+//! plus a small scanner (`Backend::Dfa`) or as the serialized dense DFA searched
+//! with `regex-automata` at runtime (`Backend::PrecompiledDfa`).
+
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::{Anchored, MatchKind};
+use std::collections::HashMap;
+
+/// Bytes before a match that select the start state: none (start of input),
+/// `\n`, `\r`, a word byte and any other byte.
+const LOOK_BEHIND: [Option<u8>; 5] = [None, Some(b'\n'), Some(b'\r'), Some(b'a'), Some(b' ')];
+
+/// Number of table entries per line of generated code.
+const ENTRIES_PER_LINE: usize = 24;
+
+/// A DFA compiled from rule patterns, renumbered into compact tables.
+pub(crate) struct DfaTables {
+    /// Byte class of every byte value
+    pub(crate) classes: Vec<usize>,
+    /// Number of byte classes
+    pub(crate) class_count: usize,
+    /// Next state for every state and byte class; state 0 is the dead state
+    pub(crate) transitions: Vec<usize>,
+    /// Next state at the end of input for every state
+    pub(crate) eoi: Vec<usize>,
+    /// Rule indexes whose match ends one byte before entering each state
+    pub(crate) matches: Vec<Vec<usize>>,
+    /// Start state for each entry of `LOOK_BEHIND`
+    pub(crate) starts: Vec<usize>,
+}
+
+/// Builds a DFA reporting every match of every pattern, anchored at the start position.
+fn build_dfa(patterns: &[&str]) -> Option<dense::DFA<Vec<u32>>> {
+    dense::Builder::new()
+        .configure(dense::Config::new().match_kind(MatchKind::All).start_kind(StartKind::Anchored))
+        .build_many(patterns)
+        .ok()
+}
+
+/// Walks the states reachable from the start states and renumbers them.
+///
+/// `rule_ids` maps the pattern indexes of the DFA to rule indexes.
+fn collect_tables(dfa: &dense::DFA<Vec<u32>>, rule_ids: &[usize]) -> Option<DfaTables> {
+    let mut ids: HashMap<StateID, usize> = HashMap::new();
+    let mut queue: Vec<StateID> = Vec::new();
+    // Renumbers a state; dead and quit states become state 0
+    let number = |sid: StateID, ids: &mut HashMap<StateID, usize>, queue: &mut Vec<StateID>| {
+        if dfa.is_dead_state(sid) || dfa.is_quit_state(sid) {
+            return 0;
+        }
+        *ids.entry(sid).or_insert_with(|| {
+            queue.push(sid);
+            queue.len()
+        })
+    };
+
+    let mut starts = Vec::new();
+    for look_behind in LOOK_BEHIND {
+        let config = start::Config::new().anchored(Anchored::Yes).look_behind(look_behind);
+        let sid = dfa.start_state(&config).ok()?;
+        starts.push(number(sid, &mut ids, &mut queue));
+    }
+
+    // Row 0 is the dead state
+    let mut rows: Vec<Vec<usize>> = vec![vec![0; 256]];
+    let mut eoi = vec![0];
+    let mut matches = vec![Vec::new()];
+    let mut next = 0;
+    while next < queue.len() {
+        let sid = queue[next];
+        next += 1;
+        let row = (0..=255u8)
+            .map(|byte| number(dfa.next_state(sid, byte), &mut ids, &mut queue))
+            .collect();
+        rows.push(row);
+        eoi.push(number(dfa.next_eoi_state(sid), &mut ids, &mut queue));
+        let mut state_matches = Vec::new();
+        if dfa.is_match_state(sid) {
+            for i in 0..dfa.match_len(sid) {
+                state_matches.push(rule_ids[dfa.match_pattern(sid, i).as_usize()]);
+            }
+        }
+        matches.push(state_matches);
+    }
+
+    // Bytes with the same transitions in every state share a class
+    let mut classes = vec![0; 256];
+    let mut class_bytes: Vec<usize> = Vec::new();
+    for byte in 0..256 {
+        let same_class = class_bytes
+            .iter()
+            .position(|&other| rows.iter().all(|row| row[other] == row[byte]));
+        classes[byte] = match same_class {
+            Some(class) => class,
+            None => {
+                class_bytes.push(byte);
+                class_bytes.len() - 1
+            }
+        };
+    }
+    let transitions = rows
+        .iter()
+        .flat_map(|row| class_bytes.iter().map(move |&byte| row[byte]))
+        .collect();
+
+    Some(DfaTables {
+        classes,
+        class_count: class_bytes.len(),
+        transitions,
+        eoi,
+        matches,
+        starts,
+    })
+}
+
+/// Compiles the patterns the DFA can express into one DFA.
+///
+/// Returns the rule indexes of the compiled patterns in pattern order.
+fn compile(patterns: &[(usize, String)]) -> Option<(Vec<usize>, dense::DFA<Vec<u32>>)> {
+    let compiled: Vec<&(usize, String)> = patterns
+        .iter()
+        .filter(|(_, pattern)| build_dfa(&[pattern.as_str()]).is_some())
+        .collect();
+    if compiled.is_empty() {
+        return None;
+    }
+    let rule_ids: Vec<usize> = compiled.iter().map(|(rule_id, _)| *rule_id).collect();
+    let regexes: Vec<&str> = compiled.iter().map(|(_, pattern)| pattern.as_str()).collect();
+    Some((rule_ids, build_dfa(&regexes)?))
+}
+
+/// Compiles rule patterns into a DFA and returns its tables, as `generate_dfa_code`
+/// emits them, or None if no pattern could be compiled.
+pub(crate) fn compile_tables(patterns: &[(usize, String)]) -> Option<DfaTables> {
+    let (rule_ids, dfa) = compile(patterns)?;
+    collect_tables(&dfa, &rule_ids)
+}
+
+/// Formats a list of numbers as the body of a generated array.
+fn format_entries<T: ToString>(values: &[T]) -> String {
+    values
+        .chunks(ENTRIES_PER_LINE)
+        .map(|chunk| {
+            let line: Vec<String> = chunk.iter().map(|v| v.to_string()).collect();
+            format!("\t\t{},\n", line.join(", "))
+        })
+        .collect()
+}
+
+/// Compiles rule patterns into a DFA and generates its tables and scanner.
+///
+/// `patterns` holds `(rule index, regex)` pairs and `rule_count` is the number of
+/// rules in the spec. Patterns the DFA cannot express (e.g. Unicode word
+/// boundaries) are left out. Returns the indexes of the compiled rules and the
+/// generated code, or None if no pattern could be compiled.
+pub(crate) fn generate_dfa_code(patterns: &[(usize, String)], rule_count: usize) -> Option<(Vec<usize>, String)> {
+    let (rule_ids, dfa) = compile(patterns)?;
+    let tables = collect_tables(&dfa, &rule_ids)?;
+
+    let state_type = if tables.eoi.len() <= u16::MAX as usize { "u16" } else { "u32" };
+    let class_type = if tables.class_count <= 256 { "u8" } else { "u16" };
+    let matches: Vec<String> = tables
+        .matches
+        .iter()
+        .map(|rules| {
+            let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+            format!("\t\t&[{}],\n", rules.join(", "))
+        })
+        .collect();
+
+    let mut code = String::new();
+    code.push_str("\t/// Byte class of every byte value in the DFA (bytes with the same transitions share a class)\n");
+    code.push_str(&format!(
+        "\tconst DFA_CLASSES: [{}; 256] = [\n{}\t];\n\n",
+        class_type,
+        format_entries(&tables.classes)
+    ));
+    code.push_str("\t/// Transitions of the DFA indexed by `state * DFA_CLASS_COUNT + class`; state 0 is the dead state\n");
+    code.push_str(&format!("\tconst DFA_CLASS_COUNT: usize = {};\n", tables.class_count));
+    code.push_str(&format!(
+        "\tconst DFA_TRANSITIONS: [{}; {}] = [\n{}\t];\n\n",
+        state_type,
+        tables.transitions.len(),
+        format_entries(&tables.transitions)
+    ));
+    code.push_str("\t/// State of the DFA after the end of input\n");
+    code.push_str(&format!(
+        "\tconst DFA_EOI: [{}; {}] = [\n{}\t];\n\n",
+        state_type,
+        tables.eoi.len(),
+        format_entries(&tables.eoi)
+    ));
+    code.push_str("\t/// Rules whose match ends one byte before entering each DFA state\n");
+    code.push_str(&format!(
+        "\tconst DFA_MATCHES: [&'static [usize]; {}] = [\n{}\t];\n\n",
+        tables.matches.len(),
+        matches.concat()
+    ));
+    code.push_str("\t/// Start states of the DFA by the byte before the match: none, \\n, \\r, word byte, other byte\n");
+    code.push_str(&format!(
+        "\tconst DFA_START: [{}; 5] = [{}];\n\n",
+        state_type,
+        tables.starts.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", ")
+    ));
+    code.push_str(&format!(
+        r#"	/// Returns the length of the longest match at `pos` of every rule compiled into the DFA
+	fn dfa_match_lengths(input: &str, pos: usize) -> [Option<usize>; {rule_count}] {{
+		let bytes = input.as_bytes();
+		let mut lengths = [None; {rule_count}];
+		let start = match pos.checked_sub(1).map(|i| bytes[i]) {{
+			None => 0,
+			Some(b'\n') => 1,
+			Some(b'\r') => 2,
+			Some(b) if b.is_ascii_alphanumeric() || b == b'_' => 3,
+			Some(_) => 4,
+		}};
+		let mut state = Self::DFA_START[start] as usize;
+		let mut i = pos;
+		loop {{
+			// Matches are reported one byte after their end
+			state = match bytes.get(i) {{
+				Some(&byte) => Self::DFA_TRANSITIONS[state * Self::DFA_CLASS_COUNT + Self::DFA_CLASSES[byte as usize] as usize] as usize,
+				None => Self::DFA_EOI[state] as usize,
+			}};
+			if state == 0 {{
+				break;
+			}}
+			for &rule_id in Self::DFA_MATCHES[state] {{
+				lengths[rule_id] = Some(i - pos);
+			}}
+			if i >= bytes.len() {{
+				break;
+			}}
+			i += 1;
+		}}
+		lengths
+	}}
+
+"#,
+        rule_count = rule_count
+    ));
+    Some((rule_ids, code))
+}
+
+/// Compiles rule patterns into a DFA and generates code embedding its serialized bytes.
+///
+/// The generated lexer deserializes the DFA on first use without compiling any
+/// pattern, and searches it with `regex-automata`. Takes the same arguments and
+/// returns the same values as `generate_dfa_code`.
+pub(crate) fn generate_precompiled_code(
+    patterns: &[(usize, String)],
+    rule_count: usize,
+) -> Option<(Vec<usize>, String)> {
+    let (rule_ids, dfa) = compile(patterns)?;
+    let (little_endian, little_padding) = dfa.to_bytes_little_endian();
+    let (big_endian, big_padding) = dfa.to_bytes_big_endian();
+
+    let mut code = String::new();
+    code.push_str("\t/// Rule index of every pattern in the precompiled DFA\n");
+    code.push_str(&format!(
+        "\tconst DFA_RULES: [usize; {}] = [{}];\n\n",
+        rule_ids.len(),
+        rule_ids.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", ")
+    ));
+    code.push_str(&format!(
+        r#"	/// Returns the DFA compiled at generation time, deserialized on first use
+	fn precompiled_dfa() -> &'static regex_automata::dfa::dense::DFA<&'static [u32]> {{
+		// Serialized DFAs must be aligned to 4 bytes
+		#[repr(C)]
+		struct Aligned<B: ?Sized> {{
+			_align: [u32; 0],
+			bytes: B,
+		}}
+		#[cfg(target_endian = "little")]
+		static BYTES: &Aligned<[u8]> = &Aligned {{
+			_align: [],
+			bytes: [
+{}			],
+		}};
+		#[cfg(target_endian = "big")]
+		static BYTES: &Aligned<[u8]> = &Aligned {{
+			_align: [],
+			bytes: [
+{}			],
+		}};
+		static DFA: std::sync::OnceLock<regex_automata::dfa::dense::DFA<&'static [u32]>> = std::sync::OnceLock::new();
+		DFA.get_or_init(|| {{
+			regex_automata::dfa::dense::DFA::from_bytes(&BYTES.bytes)
+				.expect("invalid precompiled DFA")
+				.0
+		}})
+	}}
+
+	/// Returns the length of the longest match at `pos` of every rule compiled into the DFA
+	fn dfa_match_lengths(input: &str, pos: usize) -> [Option<usize>; {rule_count}] {{
+		use regex_automata::dfa::Automaton;
+		let dfa = Self::precompiled_dfa();
+		let bytes = input.as_bytes();
+		let mut lengths = [None; {rule_count}];
+		let config = regex_automata::util::start::Config::new()
+			.anchored(regex_automata::Anchored::Yes)
+			.look_behind(pos.checked_sub(1).map(|i| bytes[i]));
+		let Ok(mut state) = dfa.start_state(&config) else {{
+			return lengths;
+		}};
+		for i in pos..=bytes.len() {{
+			// Matches are reported one byte after their end
+			state = match bytes.get(i) {{
+				Some(&byte) => dfa.next_state(state, byte),
+				None => dfa.next_eoi_state(state),
+			}};
+			if dfa.is_dead_state(state) || dfa.is_quit_state(state) {{
+				break;
+			}}
+			if dfa.is_match_state(state) {{
+				for k in 0..dfa.match_len(state) {{
+					lengths[Self::DFA_RULES[dfa.match_pattern(state, k).as_usize()]] = Some(i - pos);
+				}}
+			}}
+		}}
+		lengths
+	}}
+
+"#,
+        indent_entries(&format_entries(&little_endian[little_padding..])),
+        indent_entries(&format_entries(&big_endian[big_padding..])),
+        rule_count = rule_count
+    ));
+    Some((rule_ids, code))
+}
+
+/// Indents formatted array entries by two more tabs.
+fn indent_entries(entries: &str) -> String {
+    entries.lines().map(|line| format!("\t\t{}\n", line)).collect()
+}
@@ -0,0 +1,213 @@
+//! Markdown reference of the token kinds of a spec.
+//!
+//! `generate_token_docs` lists every token kind with the patterns of its rules, its
+//! description (`-> NAME "..."` or the comments above the rule) and example matches.
+//! Examples are the texts of the kind in the `%test` inputs of the spec; kinds that no
+//! test covers get the shortest text their pattern matches, found by walking the DFA
+//! of the pattern with the most readable byte first.
+
+use crate::dfa::compile_tables;
+use crate::generator::{extract_action_kind, rule_literal, rule_to_regex};
+use crate::parser::{LexerRule, LexerSpec, RulePattern};
+use std::collections::HashSet;
+
+/// Examples shown per token kind at most.
+const MAX_EXAMPLES: usize = 3;
+
+/// Bytes tried when building an example match, most readable first.
+const EXAMPLE_BYTES: &[u8] =
+    b"abcdefghijklmnopqrstuvwxyz0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ_-+*/=.,;:!?#$%&@^~|<>()[]{}'\"`\\ \t\n";
+
+/// A row of the token table.
+struct KindDoc {
+    name: String,
+    patterns: Vec<String>,
+    description: Option<String>,
+    examples: Vec<String>,
+}
+
+/// Returns the shortest text of readable ASCII characters `regex` matches, if any.
+fn example_match(regex: &str) -> Option<String> {
+    let tables = compile_tables(&[(0, regex.to_string())])?;
+    let is_match = |state: usize| tables.matches[state].contains(&0);
+
+    // One byte of every class, so equivalent bytes are not tried twice
+    let mut seen_classes = vec![false; tables.class_count];
+    let bytes: Vec<u8> = EXAMPLE_BYTES
+        .iter()
+        .copied()
+        .filter(|&byte| !std::mem::replace(&mut seen_classes[tables.classes[byte as usize]], true))
+        .collect();
+
+    // Breadth-first, so the first match found is the shortest one
+    let mut visited = HashSet::from([tables.starts[0]]);
+    let mut queue = vec![(tables.starts[0], Vec::new())];
+    let mut next = 0;
+    while next < queue.len() {
+        let (state, path) = queue[next].clone();
+        next += 1;
+        if state == 0 {
+            continue;
+        }
+        // A match state is entered one byte after the end of the match
+        if !path.is_empty() && is_match(tables.eoi[state]) {
+            return String::from_utf8(path).ok();
+        }
+        for &byte in &bytes {
+            let target = tables.transitions[state * tables.class_count + tables.classes[byte as usize]];
+            if !path.is_empty() && is_match(target) {
+                return String::from_utf8(path).ok();
+            }
+            if visited.insert(target) {
+                let mut longer = path.clone();
+                longer.push(byte);
+                queue.push((target, longer));
+            }
+        }
+    }
+    None
+}
+
+/// Describes the pattern of a rule for the table: literals quoted, regexes as they
+/// are matched, with the start conditions and `^` of the rule.
+fn rule_pattern(rule: &LexerRule) -> String {
+    let pattern = match (&rule.pattern, rule_literal(rule)) {
+        (_, Some(literal)) => format!("{:?}", literal),
+        (RulePattern::Eof, _) => "<<EOF>>".to_string(),
+        (RulePattern::Balanced(open, close), _) => format!("%balanced {:?} {:?}", open, close),
+        (RulePattern::Heredoc(opening), _) => format!("%heredoc /{}/", opening),
+        // Control characters of the pattern are shown escaped
+        _ => rule_to_regex(rule)
+            .chars()
+            .map(|c| if c.is_control() { c.escape_default().to_string() } else { c.to_string() })
+            .collect(),
+    };
+    let states = if rule.states.is_empty() { String::new() } else { format!("<{}> ", rule.states.join(",")) };
+    let channel = rule.channel.as_ref().map(|channel| format!(" @{}", channel)).unwrap_or_default();
+    format!("{}{}{}{}", states, if rule.line_start { "^" } else { "" }, pattern, channel)
+}
+
+/// Returns an example match of a rule built from its pattern.
+fn rule_example(rule: &LexerRule) -> Option<String> {
+    match &rule.pattern {
+        _ if rule.trailing_context.is_some() => None,
+        RulePattern::Eof | RulePattern::Heredoc(_) => None,
+        RulePattern::Balanced(open, close) => Some(format!("{}{}", open, close)),
+        _ => rule_literal(rule).or_else(|| example_match(&rule_to_regex(rule))),
+    }
+}
+
+/// Returns the kind of the tokens a rule produces: its name, or for action code the
+/// first kind the code names. `%skip` rules produce none.
+fn rule_kind(rule: &LexerRule) -> Option<String> {
+    match &rule.action_code {
+        _ if rule.skip => None,
+        Some(action_code) => extract_action_kind(action_code),
+        None => Some(rule.name.clone()),
+    }
+}
+
+/// Returns the index of the row of a token kind, adding the row if it is missing.
+fn kind_index(kinds: &mut Vec<KindDoc>, name: &str) -> usize {
+    match kinds.iter().position(|kind| kind.name == name) {
+        Some(index) => index,
+        None => {
+            kinds.push(KindDoc { name: name.to_string(), patterns: Vec::new(), description: None, examples: Vec::new() });
+            kinds.len() - 1
+        }
+    }
+}
+
+/// Formats text as a code span of a Markdown table cell.
+fn code_cell(text: &str) -> String {
+    // Control characters such as line breaks are shown escaped
+    let text: String = text
+        .chars()
+        .map(|c| match c {
+            '|' => "\\|".to_string(),
+            c if c.is_control() => c.escape_default().to_string(),
+            c => c.to_string(),
+        })
+        .collect();
+    // A longer run of backticks delimits text containing backticks
+    let ticks = if text.contains('`') { "``" } else { "`" };
+    let pad = if text.starts_with('`') || text.ends_with('`') { " " } else { "" };
+    format!("{}{}{}{}{}", ticks, pad, text, pad, ticks)
+}
+
+/// Generates a Markdown table of the token kinds of a spec, titled `title`.
+///
+/// Every kind produced by a rule, a `%keyword` or a `%token` gets a row with the
+/// patterns of its rules, its description and up to three example matches. `%skip`
+/// rules produce no token and are left out.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::{generate_token_docs, parse_spec};
+///
+/// let spec = parse_spec("// Integer literal\n[0-9]+ -> NUMBER\n'+' -> PLUS\n%test \"1+23\" => NUMBER PLUS NUMBER\n").unwrap();
+/// let docs = generate_token_docs(&spec, "calc");
+/// assert!(docs.starts_with("# Tokens of calc\n"));
+/// assert!(docs.contains("| `NUMBER` | `[0-9]+` | Integer literal | `1`, `23` |"));
+/// assert!(docs.contains(r#"| `PLUS` | `"+"` |  | `+` |"#));
+/// ```
+pub fn generate_token_docs(spec: &LexerSpec, title: &str) -> String {
+    let mut kinds: Vec<KindDoc> = Vec::new();
+
+    for rule in &spec.rules {
+        let Some(name) = rule_kind(rule) else {
+            continue;
+        };
+        let index = kind_index(&mut kinds, &name);
+        let kind = &mut kinds[index];
+        kind.patterns.push(rule_pattern(rule));
+        if kind.description.is_none() {
+            kind.description = rule.doc.as_ref().map(|doc| doc.split_whitespace().collect::<Vec<_>>().join(" "));
+        }
+    }
+    for (word, name) in &spec.keywords {
+        let index = kind_index(&mut kinds, name);
+        kinds[index].patterns.push(format!("%keyword {}", word));
+        kinds[index].examples.push(word.clone());
+    }
+    for name in &spec.custom_tokens {
+        kind_index(&mut kinds, name);
+    }
+
+    // Examples from the %test inputs, then from the patterns
+    for (input, _) in &spec.tests {
+        for token in spec.interpret(input).unwrap_or_default() {
+            if let Some(kind) = kinds.iter_mut().find(|kind| kind.name == token.kind) {
+                if !token.text.is_empty() && !kind.examples.contains(&token.text) && kind.examples.len() < MAX_EXAMPLES {
+                    kind.examples.push(token.text);
+                }
+            }
+        }
+    }
+    for rule in &spec.rules {
+        let Some(name) = rule_kind(rule) else {
+            continue;
+        };
+        if let Some(kind) = kinds.iter_mut().find(|kind| kind.name == name && kind.examples.is_empty()) {
+            kind.examples.extend(rule_example(rule));
+        }
+    }
+
+    let mut out = format!("# Tokens of {}\n\n", title);
+    out.push_str("| Kind | Pattern | Description | Examples |\n");
+    out.push_str("|------|---------|-------------|----------|\n");
+    for kind in &kinds {
+        let patterns: Vec<String> = kind.patterns.iter().map(|pattern| code_cell(pattern)).collect();
+        let examples: Vec<String> = kind.examples.iter().map(|example| code_cell(example)).collect();
+        let description = kind.description.as_deref().unwrap_or("").replace('|', "\\|");
+        out.push_str(&format!(
+            "| {} | {} | {} | {} |\n",
+            code_cell(&kind.name),
+            patterns.join("<br>"),
+            description,
+            examples.join(", ")
+        ));
+    }
+    out
+}
@@ -3,16 +3,119 @@
 //! This module contains the functionality to generate Rust lexer code
 //! from a parsed lexer specification.
 
-use crate::parser::{LexerSpec, RulePattern};
+use crate::parser::{is_initial_state, LexerRule, LexerSpec, RulePattern, SourceSpan};
 use std::collections::HashSet;
 
 // Include the auto-generated template
 include!(concat!(env!("OUT_DIR"), "/template.rs"));
 
+/// How the generated lexer matches rules that need a regular expression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Compile the patterns with the `regex` crate when the lexer is created
+    #[default]
+    Regex,
+    /// Compile the patterns into DFA tables at generation time
+    Dfa,
+    /// Compile the patterns with `regex-automata` at generation time and embed the
+    /// serialized DFA, searched with `regex-automata` at runtime
+    PrecompiledDfa,
+    /// Check all regex rules with one anchored `RegexSet` per token and only run
+    /// the regexes of the rules that match
+    RegexSet,
+}
+
+/// Visibility of the items of the generated lexer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Visibility {
+    /// `pub`
+    #[default]
+    Public,
+    /// `pub(crate)`
+    Crate,
+    /// Private to the module the code is included in
+    Private,
+}
+
+impl Visibility {
+    /// Returns the keyword written before item declarations, with a trailing space.
+    fn keyword(self) -> &'static str {
+        match self {
+            Visibility::Public => "pub ",
+            Visibility::Crate => "pub(crate) ",
+            Visibility::Private => "",
+        }
+    }
+}
+
+/// Options controlling code generation.
+///
+/// The names and the visibility of the generated items can be changed so the lexer
+/// fits into an existing module. An explicit name takes precedence over `%prefix`.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::{parse_spec, generate_lexer_with_options, GeneratorOptions, Visibility};
+///
+/// let spec = parse_spec("[0-9]+ -> NUMBER\n").unwrap();
+/// let options = GeneratorOptions {
+///     lexer_name: Some("Scanner".to_string()),
+///     token_kind_name: Some("Kind".to_string()),
+///     visibility: Visibility::Crate,
+///     ..Default::default()
+/// };
+/// let code = generate_lexer_with_options(&spec, "example.klex", &options);
+/// assert!(code.contains("pub(crate) struct Scanner {"));
+/// assert!(code.contains("pub(crate) enum Kind {"));
+/// assert!(code.contains("pub(crate) struct Token {"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeneratorOptions {
+    /// Matching backend for regex rules
+    pub backend: Backend,
+    /// Generate code that only needs `core` and `alloc` (implies `Backend::Dfa`)
+    pub no_std: bool,
+    /// Generate a lexer over `&[u8]` whose character classes match single bytes
+    pub bytes: bool,
+    /// Share the texts of tokens with the same text through an `Interner`
+    pub intern: bool,
+    /// Also generate `extern "C"` functions over the lexer (see `generate_c_header`)
+    pub ffi: bool,
+    /// Also generate `wasm_bindgen` wrappers of the lexer for JavaScript
+    pub wasm: bool,
+    /// Also generate PyO3 wrappers of the lexer for Python
+    pub pyo3: bool,
+    /// Name of the lexer struct (`Lexer` when None)
+    pub lexer_name: Option<String>,
+    /// Name of the token struct (`Token` when None)
+    pub token_name: Option<String>,
+    /// Name of the token kind enum (`TokenKind` when None)
+    pub token_kind_name: Option<String>,
+    /// Visibility of the generated structs and enums
+    pub visibility: Visibility,
+}
+
+/// Returns the token kinds that are declared or defined by a rule, which action code is expected to refer to.
+pub(crate) fn defined_token_names(spec: &LexerSpec) -> HashSet<String> {
+    let mut names: HashSet<String> = ["Unknown", "Eof"].iter().map(|name| name.to_string()).collect();
+    names.extend(spec.rules.iter().filter(|rule| rule.action_code.is_none()).map(|rule| rule.name.clone()));
+    names.extend(spec.custom_tokens.iter().cloned());
+    names.extend(spec.keywords.iter().map(|(_, name)| name.clone()));
+    names.extend(spec.error_token.iter().cloned());
+    if spec.options.indentation_tokens {
+        names.extend(["Indent".to_string(), "Dedent".to_string()]);
+    }
+    if spec.options.shebang_token {
+        names.insert("Shebang".to_string());
+    }
+    names
+}
+
 /// Extracts custom token names from action code.
-/// Finds all occurrences of `TokenKind::Name` in the action code.
-fn extract_custom_tokens(action_code: &str) -> HashSet<String> {
-    let mut tokens = HashSet::new();
+/// Finds all occurrences of `TokenKind::Name` in the action code, in the order they first appear.
+pub(crate) fn extract_custom_tokens(action_code: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
     let pattern = "TokenKind::";
     
     for (i, _) in action_code.match_indices(pattern) {
@@ -28,8 +131,8 @@ fn extract_custom_tokens(action_code: &str) -> HashSet<String> {
         if end > 0 {
             let token_name = &remaining[..end];
             // Skip common enum variants that are always present
-            if token_name != "Unknown" && token_name != "Eof" {
-                tokens.insert(token_name.to_string());
+            if token_name != "Unknown" && token_name != "Eof" && !tokens.iter().any(|t| t == token_name) {
+                tokens.push(token_name.to_string());
             }
         }
     }
@@ -37,8 +140,19 @@ fn extract_custom_tokens(action_code: &str) -> HashSet<String> {
     tokens
 }
 
+/// Returns the first token kind named in action code (`TokenKind::Name`), which is the
+/// kind an action usually returns.
+pub(crate) fn extract_action_kind(action_code: &str) -> Option<String> {
+    let pattern = "TokenKind::";
+    action_code.match_indices(pattern).find_map(|(i, _)| {
+        let remaining = &action_code[i + pattern.len()..];
+        let end = remaining.chars().take_while(|c| c.is_alphanumeric() || *c == '_').count();
+        (end > 0).then(|| remaining[..end].to_string())
+    })
+}
+
 /// Converts a RulePattern to a regular expression string.
-fn pattern_to_regex(pattern: &RulePattern) -> String {
+pub(crate) fn pattern_to_regex(pattern: &RulePattern) -> String {
     match pattern {
         RulePattern::CharLiteral(ch) => {
             // Escape special regex characters
@@ -64,9 +178,23 @@ fn pattern_to_regex(pattern: &RulePattern) -> String {
             // Zero or more character range: [start-end]*
             format!("[{}-{}]*", start, end)
         }
+        RulePattern::CharRangeRepeat(start, end, min, max) => {
+            // Bounded repetition of a character range: [start-end]{min,max}
+            match max {
+                Some(max) if max == min => format!("[{}-{}]{{{}}}", start, end, min),
+                Some(max) => format!("[{}-{}]{{{},{}}}", start, end, min, max),
+                None => format!("[{}-{}]{{{},}}", start, end, min),
+            }
+        }
+        RulePattern::NegatedCharSet1(ranges) => {
+            format!("[^{}]+", char_class_to_regex(ranges))
+        }
+        RulePattern::NegatedCharSet0(ranges) => {
+            format!("[^{}]*", char_class_to_regex(ranges))
+        }
         RulePattern::Choice(patterns) => {
             // Create alternation: (pattern1|pattern2|...)
-            let alternatives: Vec<String> = patterns.iter().map(|p| pattern_to_regex(p)).collect();
+            let alternatives: Vec<String> = patterns.iter().map(pattern_to_regex).collect();
             format!("({})", alternatives.join("|"))
         }
         RulePattern::EscapedChar(ch) => {
@@ -81,40 +209,309 @@ fn pattern_to_regex(pattern: &RulePattern) -> String {
             // Match one or more of any character (except newline)
             ".+".to_string()
         }
+        RulePattern::CaseInsensitive(inner) => {
+            // Case-insensitive group
+            format!("(?i:{})", pattern_to_regex(inner))
+        }
+        RulePattern::Eof => {
+            // End of input
+            "$".to_string()
+        }
+        RulePattern::Balanced(open, close) => {
+            // Nesting cannot be expressed as a regex; this only matches the innermost level
+            format!("(?s){}.*?{}", regex::escape(open), regex::escape(close))
+        }
+        RulePattern::Heredoc(opening) => {
+            // Only the opening is a regex; the body is scanned for the captured terminator
+            opening.clone()
+        }
+        RulePattern::Sequence(patterns) => {
+            // Concatenation; raw regex parts are grouped to keep their alternatives inside
+            patterns
+                .iter()
+                .map(|p| match p {
+                    RulePattern::Regex(_) => format!("(?:{})", pattern_to_regex(p)),
+                    _ => pattern_to_regex(p),
+                })
+                .collect()
+        }
+    }
+}
+
+/// Converts character ranges to the body of a regex character class.
+fn char_class_to_regex(ranges: &[(char, char)]) -> String {
+    let escape = |ch: char| match ch {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\\' | ']' | '[' | '^' | '-' | '&' | '~' => format!("\\{}", ch),
+        c if c.is_control() => format!("\\x{{{:X}}}", c as u32),
+        c => c.to_string(),
+    };
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                escape(start)
+            } else {
+                format!("{}-{}", escape(start), escape(end))
+            }
+        })
+        .collect()
+}
+
+/// Converts character ranges to a Rust `matches!` pattern: `'"' | '\n' | 'a'..='z'`.
+fn char_class_to_match_arms(ranges: &[(char, char)]) -> String {
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                format!("{:?}", start)
+            } else {
+                format!("{:?}..={:?}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// Returns the `LexerState` variant name for a start condition.
+pub(crate) fn state_variant(state: &str) -> &str {
+    if is_initial_state(state) {
+        "Initial"
+    } else {
+        state
+    }
+}
+
+/// Returns the conditions (start condition, line anchor) under which a rule is tried.
+fn rule_conditions(spec: &LexerSpec, rule: &LexerRule) -> Vec<String> {
+    let mut conditions: Vec<String> = state_condition(spec, rule).into_iter().collect();
+    if rule.line_start {
+        conditions.push("self.col == 1".to_string());
     }
+    conditions
+}
+
+/// Code emitting `Indent`/`Dedent` tokens at line starts (%option indentation-tokens).
+///
+/// Blank lines do not change the indentation. Remaining blocks are closed at the end of input.
+const INDENTATION_CODE: &str = r#"
+		// Emit Indent/Dedent tokens when the indentation changes (%option indentation-tokens)
+		if self.pos >= self.input.len() {
+			if self.indent_stack.pop().is_some() {
+				return Some(Token::new(TokenKind::Dedent, String::new(), self.pos, self.row, self.col, 0, 0));
+			}
+		} else if self.row != self.indent_row {
+			let line_rest = self.input[self.pos..].split('\n').next().unwrap_or("");
+			if !line_rest.trim().is_empty() {
+				self.indent_row = self.row;
+				let width = self.calculate_line_indent();
+				if width > self.indent_stack.last().copied().unwrap_or(0) {
+					self.indent_stack.push(width);
+					return Some(Token::new(TokenKind::Indent, String::new(), self.pos, self.row, self.col, 0, width));
+				}
+				while self.indent_stack.last().is_some_and(|&level| level > width) {
+					self.indent_stack.pop();
+					self.pending_dedents += 1;
+				}
+			}
+		}
+		if self.pending_dedents > 0 {
+			self.pending_dedents -= 1;
+			return Some(Token::new(TokenKind::Dedent, String::new(), self.pos, self.row, self.col, 0, self.calculate_line_indent()));
+		}"#;
+
+/// Imports of the alloc types used by the generated code (%option no-std).
+const NO_STD_IMPORTS: &str = "extern crate alloc;
+#[allow(unused_imports)]
+use alloc::{boxed::Box, format, string::{String, ToString}, vec, vec::Vec};
+";
+
+/// Imports of the interner (%option intern).
+const INTERN_IMPORTS: &str = "use std::collections::HashSet;
+use std::sync::Arc;
+";
+
+/// Changes of the template for tokens sharing their text through an `Interner`
+/// (%option intern).
+const INTERN_REPLACEMENTS: [(&str, &str); 7] = [
+    (
+        "\t/// Actual string value of the token\n\tpub text: String,\n",
+        "\t/// Actual string value of the token, shared by all tokens with the same text\n\tpub text: Arc<str>,\n",
+    ),
+    (
+        "pub fn new(kind: TokenKind, text: String,",
+        "pub fn new(kind: TokenKind, text: impl Into<Arc<str>>,",
+    ),
+    (
+        "\t\tlet value = Token::parse_value(&kind, &text);\n",
+        "\t\tlet text: Arc<str> = text.into();\n\t\tlet value = Token::parse_value(&kind, &text);\n",
+    ),
+    (
+        "/// Token structure that represents a lexical token\n",
+        r#"/// Set of the distinct token texts, shared by the tokens of a lexer (%option intern)
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+	texts: HashSet<Arc<str>>,
+}
+
+impl Interner {
+	/// Returns the shared copy of a text, adding it on first use
+	pub fn intern(&mut self, text: &str) -> Arc<str> {
+		if let Some(shared) = self.texts.get(text) {
+			return shared.clone();
+		}
+		let shared: Arc<str> = Arc::from(text);
+		self.texts.insert(shared.clone());
+		shared
+	}
+
+	/// Returns the number of distinct texts
+	pub fn len(&self) -> usize {
+		self.texts.len()
+	}
+
+	/// Returns true if no text has been interned
+	pub fn is_empty(&self) -> bool {
+		self.texts.is_empty()
+	}
+}
+
+/// Token structure that represents a lexical token
+"#,
+    ),
+    (
+        "\tpub peeked: Vec<Token>,\n",
+        "\tpub peeked: Vec<Token>,\n\t/// Distinct token texts shared by the tokens (%option intern)\n\tpub interner: Interner,\n",
+    ),
+    ("\t\t\tpeeked: Vec::new(),\n", "\t\t\tpeeked: Vec::new(),\n\t\t\tinterner: Interner::default(),\n"),
+    (
+        "\t\tSome(token)\n\t}\n\n\t/// Reads the next token on any channel from the input\n",
+        "\t\t// Share the text with the tokens read before with the same text\n\t\tlet text = self.interner.intern(&token.text);\n\t\tSome(Token { text, ..token })\n\t}\n\n\t/// Reads the next token on any channel from the input\n",
+    ),
+];
+
+/// Changes of the template for a lexer borrowing its input as a `Cow<'a, str>`
+/// (%option borrow-input).
+const BORROW_INPUT_REPLACEMENTS: [(&str, &str); 14] = [
+    ("pub struct Lexer {", "pub struct Lexer<'a> {"),
+    ("impl Iterator<Item = Token> + '_ {", "impl Iterator<Item = Token> + use<'_, 'a> {"),
+    ("\tpub input: String,\n", "\tpub input: Cow<'a, str>,\n"),
+    ("impl Lexer {", "impl<'a> Lexer<'a> {"),
+    ("impl Iterator for Lexer {", "impl Iterator for Lexer<'_> {"),
+    (
+        "pub fn new(input: String) -> Self {\n",
+        "pub fn new(input: impl Into<Cow<'a, str>>) -> Self {\n\t\tlet input = input.into();\n",
+    ),
+    (
+        "pub fn set_input(&mut self, input: String) {\n",
+        "pub fn set_input(&mut self, input: impl Into<Cow<'a, str>>) {\n\t\tlet input = input.into();\n",
+    ),
+    ("pub fn new_with_source(input: String,", "pub fn new_with_source(input: impl Into<Cow<'a, str>>,"),
+    ("pub fn with_offset(input: String,", "pub fn with_offset(input: impl Into<Cow<'a, str>>,"),
+    (
+        "\t/// This is a convenience method that converts &str to String\n",
+        "\t/// The lexer borrows the input instead of copying it\n",
+    ),
+    ("pub fn from_str(input: &str) -> Self {\n\t\tSelf::new(input.to_string())", "pub fn from_str(input: &'a str) -> Self {\n\t\tSelf::new(input)"),
+    ("pub fn from_bytes(input: &[u8])", "pub fn from_bytes(input: &'a [u8])"),
+    ("pub fn tokenize_str(input: &str)", "pub fn tokenize_str(input: &'a str)"),
+    ("self.input.replace_range(", "self.input.to_mut().replace_range("),
+];
+
+/// Changes of the template for tokens holding byte offsets instead of their text
+/// (%option token-offsets).
+const TOKEN_OFFSETS_REPLACEMENTS: [(&str, &str); 9] = [
+    (
+        "\t/// Actual string value of the token\n\tpub text: String,\n",
+        "\t/// Byte offset just past the end of the token in the input\n\tpub end: usize,\n",
+    ),
+    (
+        "pub fn new(kind: TokenKind, text: String,",
+        "pub fn new(kind: TokenKind, text: impl AsRef<str>,",
+    ),
+    (
+        "\t\tlet value = Token::parse_value(&kind, &text);\n",
+        "\t\tlet text = text.as_ref();\n\t\tlet value = Token::parse_value(&kind, text);\n",
+    ),
+    ("\t\t\ttext,\n\t\t\tindex,\n", "\t\t\tend: index + text.len(),\n\t\t\tindex,\n"),
+    ("Token::new(kind, matched.clone(),", "Token::new(kind, &matched,"),
+    ("t.text.len() == token.text.len()", "t.end - t.index == token.end - token.index"),
+    (
+        "\t\t\ttoken.index += self.start_index;\n",
+        "\t\t\ttoken.index += self.start_index;\n\t\t\ttoken.end += self.start_index;\n",
+    ),
+    (
+        "\t\t\t\t\t\told_token.index = shifted(old_token.index);\n",
+        "\t\t\t\t\t\told_token.index = shifted(old_token.index);\n\t\t\t\t\t\told_token.end = shifted(old_token.end);\n",
+    ),
+    (
+        "//----<TOKEN_METHODS>----",
+        "\t/// Returns the text of the token in the input it was read from\n\tpub fn text<'a>(&self, input: &'a str) -> &'a str {\n\t\t&input[self.index..self.end]\n\t}\n//----<TOKEN_METHODS>----",
+    ),
+];
+
+/// Code skipping a `#!` line at the start of input (%option skip-shebang).
+const SHEBANG_CODE: &str = r##"// Skip a #! line at the start of input (%option skip-shebang)
+		if self.pos == 0 && self.input.starts_with("#!") {
+			let shebang = self.input.lines().next().unwrap_or("").to_string();
+			self.advance(&shebang);
+		}
+		"##;
+
+/// Code returning a `#!` line at the start of input as a `Shebang` token (%option shebang-token).
+const SHEBANG_TOKEN_CODE: &str = r##"// Return a #! line at the start of input as a Shebang token (%option shebang-token)
+		if self.pos == 0 && self.input.starts_with("#!") {
+			let shebang = self.input.lines().next().unwrap_or("").to_string();
+			self.advance(&shebang);
+			let token = Token::new(TokenKind::Shebang, shebang.clone(), 0, 1, 1, Token::text_length(&shebang), 0);
+			self.set_last_token(&token);
+			return Some(token);
+		}
+		"##;
+
+/// Returns the start condition check for a rule, if it needs one.
+///
+/// Rules without a state prefix are active in every state except the
+/// exclusive ones.
+fn state_condition(spec: &LexerSpec, rule: &LexerRule) -> Option<String> {
+    let (negate, states) = if !rule.states.is_empty() {
+        (false, &rule.states)
+    } else if !spec.exclusive_states.is_empty() {
+        (true, &spec.exclusive_states)
+    } else {
+        return None;
+    };
+    let states: Vec<String> = states
+        .iter()
+        .map(|s| format!("LexerState::{}", state_variant(s)))
+        .collect();
+    Some(format!(
+        "{}matches!(self.state, {})",
+        if negate { "!" } else { "" },
+        states.join(" | ")
+    ))
 }
 
 /// Generates optimized pattern matching code for a RulePattern.
 /// This generates direct character/string comparison code instead of using regex when possible.
-fn generate_pattern_match_code(pattern: &RulePattern, rule_name: &str) -> (String, bool) {
+/// Patterns that need regex are looked up in the regex cache by `rule_id`.
+fn generate_pattern_match_code(pattern: &RulePattern, rule_id: usize) -> (String, bool) {
     match pattern {
         RulePattern::CharLiteral(ch) => {
             // Direct character comparison (most efficient)
-            let escaped_ch = match ch {
-                '\n' => "\\n".to_string(),
-                '\t' => "\\t".to_string(),
-                '\r' => "\\r".to_string(),
-                '\\' => "\\\\".to_string(),
-                '\'' => "\\'".to_string(),
-                _ => ch.to_string(),
-            };
             let code = format!(
-                "if remaining.starts_with('{}') {{\n            Some(remaining.chars().next().unwrap().to_string())\n        }} else {{\n            None\n        }}",
-                escaped_ch
+                "if remaining.starts_with({:?}) {{\n            Some(remaining.chars().next().unwrap().to_string())\n        }} else {{\n            None\n        }}",
+                ch
             );
             (code, false) // false = doesn't need regex
         }
         RulePattern::StringLiteral(s) => {
             // Direct string comparison (very efficient)
-            let escaped_s = s
-                .replace("\\", "\\\\")
-                .replace("\"", "\\\"")
-                .replace("\n", "\\n")
-                .replace("\t", "\\t")
-                .replace("\r", "\\r");
             let code = format!(
-                "if remaining.starts_with(\"{}\") {{\n            Some(\"{}\".to_string())\n        }} else {{\n            None\n        }}",
-                escaped_s, escaped_s
+                "if remaining.starts_with({:?}) {{\n            Some({:?}.to_string())\n        }} else {{\n            None\n        }}",
+                s, s
             );
             (code, false) // false = doesn't need regex
         }
@@ -141,16 +538,16 @@ fn generate_pattern_match_code(pattern: &RulePattern, rule_name: &str) -> (Strin
         }
         RulePattern::AnyCharPlus => {
             // Match one or more characters (except newline) - needs regex for simplicity
-            (format!("self.match_cached_pattern(remaining, TokenKind::{})", rule_name), true)
+            (format!("self.match_cached_pattern(remaining, {})", rule_id), true)
         }
         RulePattern::CharRangeMatch1(start, end) => {
             // Character range with one or more matches - optimized direct matching
             let code = format!(
                 "{{
             let mut matched = String::new();
-            let mut chars = remaining.chars();
-            while let Some(ch) = chars.next() {{
-                if ch >= '{}' && ch <= '{}' {{
+            let range = '{}'..='{}';
+            for ch in remaining.chars() {{
+                if range.contains(&ch) {{
                     matched.push(ch);
                 }} else {{
                     break;
@@ -166,294 +563,2633 @@ fn generate_pattern_match_code(pattern: &RulePattern, rule_name: &str) -> (Strin
             );
             (code, false) // false = doesn't need regex
         }
+        RulePattern::CharRangeRepeat(start, end, min, max) => {
+            // Bounded repetition of a character range - optimized direct matching
+            let limit = max.map(|max| format!(".take({})", max)).unwrap_or_default();
+            let code = format!(
+                "{{
+            let range = '{}'..='{}';
+            let count = remaining.chars(){}.take_while(|ch| range.contains(ch)).count();
+            if count >= {} {{
+                Some(remaining.chars().take(count).collect::<String>())
+            }} else {{
+                None
+            }}
+        }}",
+                start, end, limit, min
+            );
+            (code, false)
+        }
+        RulePattern::NegatedCharSet1(ranges) => {
+            // Negated character class with one or more matches - optimized direct matching
+            let code = format!(
+                "{{
+            let matched: String = remaining.chars().take_while(|&ch| !matches!(ch, {})).collect();
+            if !matched.is_empty() {{
+                Some(matched)
+            }} else {{
+                None
+            }}
+        }}",
+                char_class_to_match_arms(ranges)
+            );
+            (code, false)
+        }
+        RulePattern::NegatedCharSet0(ranges) => {
+            // Negated character class with zero or more matches - optimized direct matching
+            let code = format!(
+                "Some(remaining.chars().take_while(|&ch| !matches!(ch, {})).collect::<String>())",
+                char_class_to_match_arms(ranges)
+            );
+            (code, false)
+        }
         RulePattern::CharRangeMatch0(_start, _end) => {
             // Character range with zero or more matches - needs regex for proper implementation
-            (format!("self.match_cached_pattern(remaining, TokenKind::{})", rule_name), true)
+            (format!("self.match_cached_pattern(remaining, {})", rule_id), true)
+        }
+        RulePattern::CaseInsensitive(inner) => {
+            // ASCII literals are compared directly, everything else needs regex
+            let literal = match inner.as_ref() {
+                RulePattern::StringLiteral(s) => Some(s.clone()),
+                RulePattern::CharLiteral(ch) => Some(ch.to_string()),
+                _ => None,
+            };
+            match literal {
+                Some(literal) if literal.is_ascii() && !literal.is_empty() => {
+                    let code = format!(
+                        "remaining.get(..{}).filter(|m| m.eq_ignore_ascii_case({:?})).map(|m| m.to_string())",
+                        literal.len(),
+                        literal
+                    );
+                    (code, false)
+                }
+                _ => (format!("self.match_cached_pattern(remaining, {})", rule_id), true),
+            }
+        }
+        RulePattern::Choice(patterns) => {
+            // Alternatives that can all be matched directly are tried in order
+            let alternatives: Vec<(String, bool)> = patterns
+                .iter()
+                .map(|p| generate_pattern_match_code(p, rule_id))
+                .collect();
+            if alternatives.iter().any(|(_, needs_regex)| *needs_regex) {
+                return (format!("self.match_cached_pattern(remaining, {})", rule_id), true);
+            }
+            let mut code = format!("{{\n            let mut choice = {{{}}};", alternatives[0].0);
+            for (alternative, _) in &alternatives[1..] {
+                code.push_str(&format!(
+                    "\n            if choice.is_none() {{\n                choice = {};\n            }}",
+                    alternative
+                ));
+            }
+            code.push_str("\n            choice\n        }");
+            (code, false)
+        }
+        RulePattern::Eof => {
+            // End of input - only matches when nothing is left
+            ("if remaining.is_empty() { Some(String::new()) } else { None }".to_string(), false)
+        }
+        RulePattern::Balanced(open, close) => {
+            // Depth-counting scan for nested delimiters
+            (format!("Self::match_balanced(remaining, {:?}, {:?})", open, close), false)
         }
-        RulePattern::Regex(_) | RulePattern::CharSet(_) | RulePattern::Choice(_) => {
+        RulePattern::Heredoc(_) => {
+            // Two-phase scan: the opening regex, then the captured terminator
+            (format!("self.match_heredoc(remaining, {})", rule_id), true)
+        }
+        RulePattern::Sequence(patterns) => {
+            // Sub-patterns are matched one after another when no backtracking is needed,
+            // that is when every sub-pattern but the last has a fixed length
+            let (last, init) = patterns.split_last().expect("sequence has sub-patterns");
+            let parts: Vec<(String, bool)> = patterns
+                .iter()
+                .map(|p| generate_pattern_match_code(p, rule_id))
+                .collect();
+            let is_direct = init.iter().all(is_fixed_length)
+                && parts.iter().all(|(_, needs_regex)| !needs_regex)
+                && !matches!(last, RulePattern::Eof);
+            if !is_direct {
+                return (format!("self.match_cached_pattern(remaining, {})", rule_id), true);
+            }
+            let mut code = "{\n            let input = remaining;\n            let mut len = Some(0);".to_string();
+            for (part, _) in &parts {
+                code.push_str(&format!(
+                    "\n            if let Some(l) = len {{\n                let remaining = &input[l..];\n                len = {{{}}}.map(|m| l + m.len());\n            }}",
+                    part
+                ));
+            }
+            code.push_str("\n            len.map(|l| input[..l].to_string())\n        }");
+            (code, false)
+        }
+        RulePattern::Regex(_) | RulePattern::CharSet(_) => {
             // Complex patterns need regex
-            (format!("self.match_cached_pattern(remaining, TokenKind::{})", rule_name), true)
+            (format!("self.match_cached_pattern(remaining, {})", rule_id), true)
         }
     }
 }
 
-/// Generates Rust code for the lexer (optimized version with regex caching).
-///
-/// This function takes a parsed lexer specification and generates complete
-/// Rust source code that includes:
-/// - Token kind constants
-/// - A Lexer struct with caching for compiled regex patterns
-/// - Token generation logic
-/// - User-defined prefix and suffix code
-///
-/// # Arguments
-///
-/// * `spec` - The parsed lexer specification containing rules and code sections
-/// * `source_file` - The name of the source file (used for comments)
-///
-/// # Returns
-///
-/// A String containing the complete generated Rust code for the lexer.
-///
-/// # Example
-///
-/// ```rust
-/// use klex::{parse_spec, generate_lexer};
-///
-/// let input = r#"
-/// use std::collections::HashMap;
-/// %%
-/// [0-9]+ -> NUMBER
-/// [a-zA-Z_][a-zA-Z0-9_]* -> IDENTIFIER
-/// %%
-/// fn main() { println!("Generated lexer"); }
-/// "#;
-///
-/// let spec = parse_spec(input).unwrap();
-/// let code = generate_lexer(&spec, "example.klex");
-/// // code now contains complete Rust lexer implementation
-/// ```
-pub fn generate_lexer(spec: &LexerSpec, source_file: &str) -> String {
-    // Use the embedded template
-    let template = LEXER_TEMPLATE;
-
-    let mut output = template.to_string();
+/// Returns true if a pattern always matches text of the same length.
+fn is_fixed_length(pattern: &RulePattern) -> bool {
+    match pattern {
+        RulePattern::CharLiteral(_)
+        | RulePattern::StringLiteral(_)
+        | RulePattern::EscapedChar(_)
+        | RulePattern::AnyChar => true,
+        RulePattern::CharRangeRepeat(_, _, min, max) => *max == Some(*min),
+        RulePattern::CaseInsensitive(inner) => {
+            matches!(inner.as_ref(), RulePattern::CharLiteral(c) if c.is_ascii())
+                || matches!(inner.as_ref(), RulePattern::StringLiteral(s) if s.is_ascii())
+        }
+        RulePattern::Sequence(patterns) => patterns.iter().all(is_fixed_length),
+        _ => false,
+    }
+}
 
-    // Add prefix code at the beginning
-    if !spec.prefix_code.is_empty() {
-        let prefix_with_newlines = format!("{}\n\n", spec.prefix_code);
-        output = output.replace(
-            "// This file is auto-generated.",
-            &format!("// This file is auto-generated.\n{}", prefix_with_newlines),
-        );
+/// Returns the regular expression compiled into the regex cache for a rule.
+///
+/// Rules with trailing context capture the matched text in group 1 and
+/// check the context after it without consuming it.
+pub(crate) fn rule_to_regex(rule: &LexerRule) -> String {
+    match &rule.trailing_context {
+        Some(context) => format!(
+            "({})(?:{})",
+            pattern_to_regex(&rule.pattern),
+            pattern_to_regex(context)
+        ),
+        None => pattern_to_regex(&rule.pattern),
     }
+}
 
-    // Generate TokenKind enum variants
-    let mut token_kind_variants = String::new();
-    let mut all_token_names = HashSet::new();
-    
-    // Collect token names from rules
-    for rule in &spec.rules {
-        if rule.action_code.is_none() && !rule.name.is_empty() {
-            // Skip Unknown and Eof as they are always added automatically
-            if rule.name != "Unknown" && rule.name != "Eof" {
-                all_token_names.insert(rule.name.clone());
+/// Rules matched by one combined matcher for all of them instead of one by one.
+#[derive(Default)]
+struct CombinedRules {
+    /// Backend of the combined matcher
+    backend: Backend,
+    /// Rule indexes in the order of the patterns of the matcher
+    rule_ids: Vec<usize>,
+}
+
+impl CombinedRules {
+    /// Returns the statement running the combined matcher on `remaining`, which starts at
+    /// `pos` of `input`, or an empty string if no rule is combined.
+    fn setup_code(&self, input: &str, pos: &str) -> String {
+        if self.rule_ids.is_empty() {
+            return String::new();
+        }
+        match self.backend {
+            Backend::Regex => String::new(),
+            Backend::Dfa | Backend::PrecompiledDfa => {
+                format!("let dfa_lengths = Self::dfa_match_lengths({}, {});", input, pos)
             }
+            Backend::RegexSet => "let regex_matches = Self::regex_set().matches(remaining);".to_string(),
         }
     }
-    
-    // Add explicitly declared custom tokens from %token directive
-    for token_name in &spec.custom_tokens {
-        if token_name != "Unknown" && token_name != "Eof" {
-            all_token_names.insert(token_name.clone());
-        }
+}
+
+/// Builds the combined matcher of the effective backend and returns the rules it matches
+/// together with its code.
+fn build_combined_rules(spec: &LexerSpec, options: &GeneratorOptions) -> (CombinedRules, String) {
+    let backend = effective_backend(spec, options);
+    let (rule_ids, matcher_code) = match backend {
+        Backend::Regex => None,
+        Backend::Dfa => crate::dfa::generate_dfa_code(&dfa_candidates(spec), spec.rules.len()),
+        Backend::PrecompiledDfa => crate::dfa::generate_precompiled_code(&dfa_candidates(spec), spec.rules.len()),
+        Backend::RegexSet => generate_regex_set_code(spec),
     }
-    
-    // Collect custom token names from action code
-    for rule in &spec.rules {
-        if let Some(action_code) = &rule.action_code {
-            let custom_tokens = extract_custom_tokens(action_code);
-            all_token_names.extend(custom_tokens);
+    .unwrap_or_default();
+    (CombinedRules { backend, rule_ids }, matcher_code)
+}
+
+/// Generates the matching code for a rule, taking its trailing context into account.
+///
+/// Rules in `combined` are matched with the combined matcher of their backend.
+fn generate_rule_match_code(rule: &LexerRule, rule_id: usize, combined: &CombinedRules) -> (String, bool) {
+    let position = combined.rule_ids.iter().position(|&id| id == rule_id);
+    match (combined.backend, position) {
+        (Backend::Dfa | Backend::PrecompiledDfa, Some(_)) => {
+            (format!("dfa_lengths[{}].map(|len| remaining[..len].to_string())", rule_id), false)
         }
-    }
-    
-    // Generate variants for all collected tokens
-    for token_name in &all_token_names {
-        // Find the rule that defines this token to get pattern description
-        if let Some(rule) = spec.rules.iter().find(|r| &r.name == token_name) {
-            let pattern_desc = pattern_to_regex(&rule.pattern)
-                .replace('\n', "\\n")
-                .replace('\t', "\\t")
-                .replace('\r', "\\r");
-            token_kind_variants.push_str(&format!("\t{}, // {}\n", token_name, pattern_desc));
-        } else {
-            // Custom token without a pattern (used only in action code or %token directive)
-            token_kind_variants.push_str(&format!("\t{}, // Custom token\n", token_name));
+        (Backend::RegexSet, Some(index)) => {
+            // Only run the regex of the rule when the set matched it
+            let (match_code, needs_regex) = generate_rule_match_code(rule, rule_id, &CombinedRules::default());
+            (
+                format!("if regex_matches.matched({}) {{ {} }} else {{ None }}", index, match_code),
+                needs_regex,
+            )
+        }
+        _ if rule.trailing_context.is_some() => {
+            (format!("self.match_cached_lookahead(remaining, {})", rule_id), true)
         }
+        _ => generate_pattern_match_code(&rule.pattern, rule_id),
     }
+}
 
-    // Generate regex cache code (only for patterns that need regex)
-    let mut regex_code = String::new();
-    regex_code.push_str("        // Pre-compile patterns that require regex\n");
-    for rule in &spec.rules {
-        let (_match_code, needs_regex) = generate_pattern_match_code(&rule.pattern, &rule.name);
-        if needs_regex {
-            // Convert pattern to regex and escape for string literal
-            let regex_pattern = pattern_to_regex(&rule.pattern);
-            let escaped_pattern = regex_pattern.replace("\\", "\\\\").replace("\"", "\\\"");
-            regex_code.push_str(&format!(
-                "        regex_cache.insert(TokenKind::{} as u32, Regex::new(\"^{}\").unwrap());\n",
-                rule.name, escaped_pattern
-            ));
-        }
+/// Returns true if the action code refers to the capture groups (`caps`) of its rule.
+fn uses_captures(action_code: &str) -> bool {
+    action_code
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .any(|word| word == "caps")
+}
+
+/// Returns true if the action code uses the control methods working on the token of the
+/// match, such as `self.token`, `self.set_tag` and `self.push_back`.
+fn uses_action_token(action_code: &str) -> bool {
+    ["self.token(", "self.error(", "self.set_tag(", "self.push_back(", "self.append_next("]
+        .iter()
+        .any(|call| action_code.contains(call))
+}
+
+/// Returns the statement making `test_t` the token of the control methods, if the action uses them.
+fn action_token_code(action_code: &str) -> &'static str {
+    if uses_action_token(action_code) {
+        "\n\t\t\tself.action_token = Some(test_t.clone());"
+    } else {
+        ""
     }
-    regex_code.push_str("        ");
+}
 
-    // Generate rule matching code
-    let mut rule_match_code = String::new();
+/// Returns true if a rule is matched with the regex cache instead of direct comparisons.
+pub(crate) fn uses_regex(rule: &LexerRule) -> bool {
+    generate_rule_match_code(rule, 0, &CombinedRules::default()).1
+}
 
-    // First, generate context-dependent rules (higher priority)
-    for rule in &spec.rules {
-        if let Some(context_token) = &rule.context_token {
-            // Find the context token name
-            let context_token_name = spec
-                .rules
-                .iter()
-                .find(|r| r.name == *context_token)
-                .map(|r| r.name.clone())
-                .unwrap_or_else(|| panic!("Context token '{}' not found", context_token));
-
-            let (match_code, _needs_regex) = generate_pattern_match_code(&rule.pattern, &rule.name);
-            let pattern_desc = pattern_to_regex(&rule.pattern)
-                .replace('\n', "\\n")
-                .replace('\t', "\\t")
-                .replace('\r', "\\r");
-            rule_match_code.push_str(&format!(
-                r#"        // Context-dependent rule: {} -> {} (after {})
-        if self.last_token_kind == Some(TokenKind::{}) {{
-            let matched_opt = {{{}}};
-            if let Some(matched) = matched_opt {{
-                let token = Token::new(
-                    TokenKind::{},
-                    matched.clone(),
-                    self.pos,
-                    start_row,
-                    start_col,
-                    matched.len(),
-                    indent,
-                );
-                self.advance(&matched);
-                self.last_token_kind = Some(token.kind.clone());
-                return Some(token);
-            }}
-        }}
+/// Returns the `(rule index, regex)` pairs of the rules the DFA backend can match.
+///
+/// Only rules that would otherwise use the regex cache are compiled into the DFA.
+/// Trailing context, `%heredoc` and actions using `caps` keep the regex crate.
+pub(crate) fn dfa_candidates(spec: &LexerSpec) -> Vec<(usize, String)> {
+    spec.rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| {
+            !matches!(rule.pattern, RulePattern::Eof | RulePattern::Heredoc(_))
+                && rule.trailing_context.is_none()
+                && !rule.action_code.as_deref().is_some_and(uses_captures)
+                && uses_regex(rule)
+        })
+        .map(|(rule_id, rule)| (rule_id, pattern_to_regex(&rule.pattern)))
+        .collect()
+}
+
+/// Generates the `RegexSet` of all regex rules (`Backend::RegexSet`).
+///
+/// Returns the indexes of the rules in the set and the generated code, or None if
+/// no rule needs a regex.
+fn generate_regex_set_code(spec: &LexerSpec) -> Option<(Vec<usize>, String)> {
+    let rules: Vec<(usize, &LexerRule)> = spec
+        .rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| !matches!(rule.pattern, RulePattern::Eof) && uses_regex(rule))
+        .collect();
+    if rules.is_empty() {
+        return None;
+    }
+    let patterns: String = rules
+        .iter()
+        .map(|(_, rule)| format!("\t\t\t\t{:?},\n", format!("^(?:{})", rule_to_regex(rule))))
+        .collect();
+    let code = format!(
+        r#"	/// Returns the anchored regexes of all regex rules as one set, compiled on first use
+	fn regex_set() -> &'static regex::RegexSet {{
+		static REGEX_SET: std::sync::OnceLock<regex::RegexSet> = std::sync::OnceLock::new();
+		REGEX_SET.get_or_init(|| {{
+			regex::RegexSet::new([
+{}			])
+			.unwrap()
+		}})
+	}}
 
 "#,
-                pattern_desc, rule.name, context_token, context_token_name, match_code, rule.name
-            ));
+        patterns
+    );
+    Some((rules.iter().map(|(rule_id, _)| *rule_id).collect(), code))
+}
+
+/// Removes the template lines between `//----<{name}_BEGIN>----` and `//----<{name}_END>----`,
+/// such as the `REGEX` regions, when `keep` is false, and only the marker lines otherwise.
+fn strip_regions(code: &str, name: &str, keep: bool) -> String {
+    let begin = format!("//----<{}_BEGIN>----", name);
+    let end = format!("//----<{}_END>----", name);
+    let mut output = String::new();
+    let mut inside = false;
+    for line in code.split_inclusive('\n') {
+        match line.trim() {
+            marker if marker == begin => inside = true,
+            marker if marker == end => inside = false,
+            _ if keep || !inside => output.push_str(line),
+            _ => {}
         }
     }
+    output
+}
 
-    // Second, generate action rules (higher priority than regular token rules)
+/// Returns the `#[cfg(...)]` attribute for a cfg predicate, or an empty string.
+fn cfg_attribute(cfg: Option<&str>) -> String {
+    cfg.map(|predicate| format!("#[cfg({})] ", predicate)).unwrap_or_default()
+}
+
+/// Returns the cfg predicate a token kind is compiled under (%cfg).
+///
+/// A token is only conditional when every rule that produces it is conditional;
+/// tokens declared by directives always exist.
+fn token_cfg(spec: &LexerSpec, token_name: &str) -> Option<String> {
+    let declared = spec.custom_tokens.iter().any(|t| t == token_name)
+        || spec.keywords.iter().any(|(_, t)| t == token_name)
+        || spec.error_token.as_deref() == Some(token_name)
+        || (spec.options.indentation_tokens && (token_name == "Indent" || token_name == "Dedent"))
+        || (spec.options.shebang_token && token_name == "Shebang");
+    if declared {
+        return None;
+    }
+    let mut predicates: Vec<&str> = Vec::new();
     for rule in &spec.rules {
-        if rule.context_token.is_none() && rule.action_code.is_some() {
-            let action_code = rule.action_code.as_ref().unwrap();
-            let (match_code, _needs_regex) = generate_pattern_match_code(&rule.pattern, &rule.name);
-            let pattern_desc = pattern_to_regex(&rule.pattern)
-                .replace('\n', "\\n")
-                .replace('\t', "\\t")
-                .replace('\r', "\\r");
-            rule_match_code.push_str(&format!(
-                r#"        // Action rule: {} -> {{ {} }}
-        {{
-            let matched_opt = {{{}}};
-            if let Some(matched) = matched_opt {{
-                let matched_str = matched.clone();
-                // Create token for action code to use
-                let test_t = Token::new(
+        let produces = match &rule.action_code {
+            Some(action_code) => extract_custom_tokens(action_code).iter().any(|t| t == token_name),
+            None => rule.name == token_name,
+        };
+        if produces {
+            let predicate = rule.cfg.as_deref()?;
+            if !predicates.contains(&predicate) {
+                predicates.push(predicate);
+            }
+        }
+    }
+    match predicates.len() {
+        0 => None,
+        1 => Some(predicates[0].to_string()),
+        _ => Some(format!("any({})", predicates.join(", "))),
+    }
+}
+
+/// Returns the snake case of a Rust name for C: `JsonLexer` -> `json_lexer`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    let mut previous_lower = false;
+    for c in name.chars() {
+        if c.is_uppercase() && previous_lower {
+            snake.push('_');
+        }
+        previous_lower = c.is_lowercase() || c.is_ascii_digit();
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+/// Returns the enum variant name for a channel or type name: `hidden` -> `Hidden`.
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Item names of the generated lexer that `%prefix` renames.
+const PREFIXED_ITEMS: [&str; 14] = [
+    "Token", "TokenKind", "TokenChannel", "TokenValue", "Lexer", "LexerState",
+    "LexError", "Edit", "Span", "Interner", "UserState", "LimitError", "CToken", "TokenInfo",
+];
+
+/// Renames the generated items used in `code` to the names returned by `rename`,
+/// such as `Lexer` -> `JsonLexer` for `%prefix Json`.
+///
+/// Only whole identifiers are renamed; string literals and comments are left untouched.
+fn rename_items(code: &str, rename: impl Fn(&str) -> Option<String>) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let mut result = String::with_capacity(code.len() + 256);
+    let mut i = 0;
+    // Copies chars[i..end] verbatim
+    let copy = |result: &mut String, from: usize, to: usize| result.extend(&chars[from..to.min(chars.len())]);
+    let find = |from: usize, needle: &str| -> usize {
+        let needle: Vec<char> = needle.chars().collect();
+        (from..chars.len())
+            .find(|&j| chars[j..].starts_with(&needle))
+            .map_or(chars.len(), |j| j + needle.len())
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let end = if c == '/' && next == Some('/') {
+            find(i, "\n")
+        } else if c == '/' && next == Some('*') {
+            find(i + 2, "*/")
+        } else if c == '"' {
+            let mut j = i + 1;
+            while j < chars.len() && chars[j] != '"' {
+                j += if chars[j] == '\\' { 2 } else { 1 };
+            }
+            j + 1
+        } else if c == '\'' && next == Some('\\') {
+            find(i + 3, "'")
+        } else if c == '\'' && chars.get(i + 2) == Some(&'\'') {
+            i + 3
+        } else if c.is_alphabetic() || c == '_' {
+            let mut j = i;
+            while j < chars.len() && (chars[j].is_alphanumeric() || chars[j] == '_') {
+                j += 1;
+            }
+            let word: String = chars[i..j].iter().collect();
+            if (word == "r" || word == "br") && matches!(chars.get(j), Some('"') | Some('#')) {
+                // Raw string literal: r"..." or r#"..."#
+                let hashes = chars[j..].iter().take_while(|&&ch| ch == '#').count();
+                find(j + hashes + 1, &format!("\"{}", "#".repeat(hashes)))
+            } else {
+                match rename(&word) {
+                    Some(name) => result.push_str(&name),
+                    None => result.push_str(&word),
+                }
+                i = j;
+                continue;
+            }
+        } else {
+            i + 1
+        };
+        copy(&mut result, i, end);
+        i = end.max(i + 1);
+    }
+    result
+}
+
+/// Returns the `TokenValue` variant name for a %type type: `i64` -> `I64`.
+fn value_variant(value_type: &str) -> String {
+    let last_segment = value_type.rsplit("::").next().unwrap_or(value_type);
+    capitalize(last_segment)
+}
+
+/// Returns true if %keyword lookups apply to the tokens of a rule.
+pub(crate) fn is_identifier_rule(spec: &LexerSpec, rule: &LexerRule) -> bool {
+    !spec.keywords.is_empty()
+        && matches!(rule.name.as_str(), "IDENTIFIER" | "Identifier" | "IDENT" | "Ident")
+}
+
+/// Returns the text argument of `Token::new` for the matched text in `variable`.
+///
+/// With %option token-offsets the token only stores offsets, so the text is borrowed.
+fn token_text_code(spec: &LexerSpec, variable: &str) -> String {
+    if spec.options.token_offsets {
+        format!("&{}", variable)
+    } else {
+        format!("{}.clone()", variable)
+    }
+}
+
+/// Generates the code creating the token of a rule as `token`, on the rule's channel.
+///
+/// Identifier tokens that are %keyword words get the keyword's kind.
+fn new_token_code(spec: &LexerSpec, rule: &LexerRule) -> String {
+    let kind_code = if is_identifier_rule(spec, rule) {
+        format!("Lexer::keyword_kind(&matched).unwrap_or(TokenKind::{})", rule.name)
+    } else {
+        format!("TokenKind::{}", rule.name)
+    };
+    let token_code = format!(
+        r#"Token::new(
+                    {},
+                    {},
+                    self.pos,
+                    start_row,
+                    start_col,
+                    Token::text_length(&matched),
+                    indent,
+                )"#,
+        kind_code,
+        token_text_code(spec, "matched")
+    );
+    match &rule.channel {
+        Some(channel) => format!(
+            "let mut token = {};\n                token.channel = TokenChannel::{};",
+            token_code,
+            capitalize(channel)
+        ),
+        None => format!("let token = {};", token_code),
+    }
+}
+
+/// Returns true if the tokens of a rule update the context of context rules.
+///
+/// Whitespace tokens and tokens on other channels don't update the context.
+pub(crate) fn updates_context(rule: &LexerRule) -> bool {
+    let is_whitespace = rule.name == "WHITESPACE" || rule.name == "Whitespace" || rule.name == "NEWLINE" || rule.name == "Newline";
+    !is_whitespace && rule.channel.is_none()
+}
+
+/// Generates the statement updating the context after a token has been created.
+fn update_context_code(rule: &LexerRule) -> &'static str {
+    if updates_context(rule) {
+        "self.set_last_token(&token)"
+    } else {
+        "// Whitespace tokens don't update context"
+    }
+}
+
+/// Generates the conditions checking the previous tokens of a context rule.
+///
+/// The last position of the context is the last token, and earlier positions are
+/// looked up in `earlier_token_kinds`. Token text is only checked on the last token.
+fn context_conditions(rule: &LexerRule) -> Vec<String> {
+    rule.context
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(age, position)| {
+            let previous = if age == 0 {
+                "self.last_token_kind".to_string()
+            } else {
+                format!("self.earlier_token_kinds[{}]", age - 1)
+            };
+            let kind_condition = match &position.kinds[..] {
+                [kind] => format!("{} == Some(TokenKind::{})", previous, kind),
+                kinds => {
+                    let kinds: Vec<String> = kinds.iter().map(|kind| format!("TokenKind::{}", kind)).collect();
+                    format!("matches!({}, Some({}))", previous, kinds.join(" | "))
+                }
+            };
+            match &position.text {
+                Some(text) => format!("{} && self.last_token_text == {:?}", kind_condition, text),
+                None => kind_condition,
+            }
+        })
+        .collect()
+}
+
+/// Describes the context of a context rule for generated comments: `IDENT`, `(A|B)`, `LET IDENT`
+/// or `IDENT("r")`.
+fn describe_context(rule: &LexerRule) -> String {
+    let positions: Vec<String> = rule
+        .context
+        .iter()
+        .map(|position| {
+            let kinds = match &position.kinds[..] {
+                [kind] => kind.clone(),
+                kinds => format!("({})", kinds.join("|")),
+            };
+            match &position.text {
+                Some(text) => format!("{}({:?})", kinds, text),
+                None => kinds,
+            }
+        })
+        .collect();
+    positions.join(" ")
+}
+
+/// Returns how many tokens before the last one the context rules look at.
+fn token_history_len(spec: &LexerSpec) -> usize {
+    spec.rules.iter().map(|rule| rule.context.len().saturating_sub(1)).max().unwrap_or(0)
+}
+
+/// Generated pieces of code for a single rule.
+struct RuleCode {
+    /// Index of the rule in the spec
+    rule_id: usize,
+    /// Comment describing the rule
+    comment: String,
+    /// Conditions (start condition, context) that must hold for the rule to be tried
+    conditions: Vec<String>,
+    /// Expression evaluating to `Option<String>` with the matched text
+    match_code: String,
+    /// Statements run with the matched text bound to `matched`
+    accept_code: String,
+    /// `#[cfg(...)]` attribute of the rule (%cfg), or an empty string
+    cfg_attribute: String,
+    /// Text matched by a fixed-string rule, which can be looked up together with
+    /// the neighbouring fixed-string rules
+    literal: Option<String>,
+}
+
+/// Returns the text a rule always matches, for token and skip rules with a fixed-string pattern.
+pub(crate) fn rule_literal(rule: &LexerRule) -> Option<String> {
+    if rule.trailing_context.is_some() || rule.cfg.is_some() {
+        return None;
+    }
+    let literal = match &rule.pattern {
+        RulePattern::CharLiteral(ch) | RulePattern::EscapedChar(ch) => ch.to_string(),
+        RulePattern::StringLiteral(s) => s.clone(),
+        _ => return None,
+    };
+    (!literal.is_empty()).then_some(literal)
+}
+
+/// Splits rule codes into runs matched one after another: runs of consecutive
+/// fixed-string rules with the same conditions, and single rules.
+///
+/// Every run is looked up at once, however short, so the longest literal wins
+/// whether or not other literal rules follow.
+fn group_rule_codes(rule_codes: &[RuleCode]) -> Vec<&[RuleCode]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    while start < rule_codes.len() {
+        let mut end = start + 1;
+        if rule_codes[start].literal.is_some() {
+            while end < rule_codes.len()
+                && rule_codes[end].literal.is_some()
+                && rule_codes[end].conditions == rule_codes[start].conditions
+            {
+                end += 1;
+            }
+        }
+        groups.push(&rule_codes[start..end]);
+        start = end;
+    }
+    groups
+}
+
+/// Generates an expression evaluating to `Option<(usize, String)>`, the rule index and
+/// the text of the longest literal of `group` that `remaining` starts with. With
+/// `bytes`, `remaining` is a byte slice and the expression evaluates to
+/// `Option<(usize, usize)>`, the rule index and the length of the literal.
+///
+/// Literals are compared by length, longest first, with one `match` per length.
+/// Of identical literals, the first rule wins.
+fn generate_literal_lookup_code(group: &[RuleCode], bytes: bool) -> String {
+    let mut literals: Vec<(&str, usize)> = Vec::new();
+    for rule in group {
+        let literal = rule.literal.as_deref().expect("grouped rules are literals");
+        if !literals.iter().any(|(l, _)| *l == literal) {
+            literals.push((literal, rule.rule_id));
+        }
+    }
+    let mut lengths: Vec<usize> = literals.iter().map(|(l, _)| l.len()).collect();
+    lengths.sort_unstable_by(|a, b| b.cmp(a));
+    lengths.dedup();
+
+    let mut code = String::new();
+    for (i, len) in lengths.iter().enumerate() {
+        if i > 0 {
+            code.push_str("\n.or_else(|| ");
+        }
+        code.push_str(&format!("match remaining.get(..{}) {{\n", len));
+        for (literal, rule_id) in literals.iter().filter(|(l, _)| l.len() == *len) {
+            let pattern = if bytes { format!("b\"{}\"", byte_string_literal(literal)) } else { format!("{:?}", literal) };
+            code.push_str(&format!("Some({}) => Some(({}, {})),\n", pattern, rule_id, len));
+        }
+        code.push_str("_ => None,\n}");
+        if i > 0 {
+            code.push(')');
+        }
+    }
+    if !bytes {
+        code.push_str("\n.map(|(rule_id, len)| (rule_id, remaining[..len].to_string()))");
+    }
+    code
+}
+
+/// Returns the comment and the opening line of the block trying a group of rules.
+fn group_opening(group: &[RuleCode]) -> (String, String) {
+    let first = &group[0];
+    let comment = if group.len() == 1 {
+        first.comment.clone()
+    } else {
+        let comments: Vec<&str> = group.iter().map(|rule| rule.comment.as_str()).collect();
+        format!("// Fixed-string rules, looked up by length (the longest literal wins)\n{}", comments.join("\n"))
+    };
+    let opening = if first.conditions.is_empty() {
+        format!("{}{{", first.cfg_attribute)
+    } else {
+        format!("{}if {} {{", first.cfg_attribute, first.conditions.join(" && "))
+    };
+    (comment, opening)
+}
+
+/// Returns a one-line description of a rule pattern for generated comments.
+pub(crate) fn describe_pattern(pattern: &RulePattern) -> String {
+    pattern_to_regex(pattern)
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+        .replace('\r', "\\r")
+}
+
+/// Collects the generated code of every rule in matching priority order:
+/// context-dependent rules first, then action rules, then regular rules.
+fn collect_rule_codes(spec: &LexerSpec, combined: &CombinedRules) -> Vec<RuleCode> {
+    let mut rule_codes = Vec::new();
+
+    // First, context-dependent rules (higher priority)
+    for (rule_id, rule) in spec.rules.iter().enumerate() {
+        if !rule.context.is_empty() {
+            let (match_code, _needs_regex) = generate_rule_match_code(rule, rule_id, combined);
+            let mut conditions = rule_conditions(spec, rule);
+            conditions.extend(context_conditions(rule));
+            rule_codes.push(RuleCode {
+                literal: None,
+                rule_id,
+                cfg_attribute: cfg_attribute(rule.cfg.as_deref()),
+                comment: format!(
+                    "// Context-dependent rule: {} -> {} (after {})",
+                    describe_pattern(&rule.pattern),
+                    rule.name,
+                    describe_context(rule)
+                ),
+                conditions,
+                match_code,
+                accept_code: format!(
+                    r#"{}
+                self.advance(&matched);
+                {};
+                return Some(token);"#,
+                    new_token_code(spec, rule),
+                    update_context_code(rule)
+                ),
+            });
+        }
+    }
+
+    // Second, action rules (higher priority than regular token rules)
+    for (rule_id, rule) in spec.rules.iter().enumerate() {
+        if matches!(rule.pattern, RulePattern::Eof) {
+            continue;
+        }
+        if let (true, Some(action_code)) = (rule.context.is_empty(), &rule.action_code) {
+            let (match_code, needs_regex) = generate_rule_match_code(rule, rule_id, combined);
+            // Regex rules expose their capture groups to the action as `caps`
+            let captures_code = if needs_regex && rule.trailing_context.is_none() {
+                format!(
+                    r#"
+                // Capture groups of the rule's regex (caps[0] is the whole match)
+                #[allow(unused_variables)]
+                let caps: Vec<&str> = self
+                    .regex_cache
+                    .get(&{})
+                    .and_then(|regex| regex.captures(&matched_str))
+                    .map(|c| c.iter().map(|m| m.map_or("", |m| m.as_str())).collect())
+                    .unwrap_or_default();"#,
+                    rule_id
+                )
+            } else {
+                String::new()
+            };
+            // Multi-line actions are shown on one line in the rule comment
+            let action_summary = action_code
+                .lines()
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join(" ");
+            rule_codes.push(RuleCode {
+                literal: None,
+                rule_id,
+                cfg_attribute: cfg_attribute(rule.cfg.as_deref()),
+                comment: format!(
+                    "// Action rule: {} -> {{ {} }}",
+                    describe_pattern(&rule.pattern),
+                    action_summary
+                ),
+                conditions: rule_conditions(spec, rule),
+                match_code,
+                accept_code: format!(
+                    r#"let matched_str = matched.clone();
+                // Create token for action code to use
+                #[allow(unused_variables)]
+                let test_t = Token::new(
                     TokenKind::Unknown,
-                    matched_str.clone(),
+                    {},
                     self.pos,
                     start_row,
                     start_col,
-                    matched_str.len(),
+                    Token::text_length(&matched_str),
                     indent,
-                );
+                );{}{}
                 self.advance(&matched_str);
                 // Execute action code with available variables
                 let action_result: Option<Token> = {{
                     {}
                 }};
-                if let Some(token) = action_result {{
-                    self.last_token_kind = Some(token.kind.clone());
+                if let Some(mut token) = action_result {{
+                    // The action may have changed the kind of the token
+                    if token.value.is_none() {{
+                        token.value = Token::parse_value(&token.kind, {});
+                    }}
+                    self.set_last_token(&token);
                     return Some(token);
                 }} else {{
-                    // Continue to next iteration if no token was returned from action
-                    return self.next_token();
-                }}
+                    // No token was returned from the action, continue with the next match
+                    continue;
+                }}"#,
+                    token_text_code(spec, "matched_str"),
+                    action_token_code(action_code),
+                    captures_code,
+                    action_code,
+                    if spec.options.token_offsets { "token.text(&self.input)" } else { "&token.text" }
+                ),
+            });
+        }
+    }
+
+    // Finally, regular token rules and skip rules
+    for (rule_id, rule) in spec.rules.iter().enumerate() {
+        if !rule.context.is_empty() || rule.action_code.is_some() || matches!(rule.pattern, RulePattern::Eof) {
+            continue;
+        }
+        let (match_code, _needs_regex) = generate_rule_match_code(rule, rule_id, combined);
+        let conditions = rule_conditions(spec, rule);
+        if rule.skip {
+            // Skip rule: consume the match without emitting a token
+            rule_codes.push(RuleCode {
+                literal: rule_literal(rule),
+                rule_id,
+                cfg_attribute: cfg_attribute(rule.cfg.as_deref()),
+                comment: format!("// Skip rule: {} -> %skip", describe_pattern(&rule.pattern)),
+                conditions,
+                match_code,
+                accept_code: "self.advance(&matched);\n                continue;"
+                    .to_string(),
+            });
+            continue;
+        }
+
+        rule_codes.push(RuleCode {
+            literal: rule_literal(rule),
+            rule_id,
+            cfg_attribute: cfg_attribute(rule.cfg.as_deref()),
+            comment: format!(
+                "// Rule: {} -> {}{}",
+                describe_pattern(&rule.pattern),
+                rule.name,
+                rule.channel.as_ref().map(|c| format!(" @{}", c)).unwrap_or_default()
+            ),
+            conditions,
+            match_code,
+            accept_code: format!(
+                r#"{}
+                self.advance(&matched);
+                {};
+                return Some(token);"#,
+                new_token_code(spec, rule),
+                update_context_code(rule)
+            ),
+        });
+    }
+
+    rule_codes
+}
+
+/// Generates the code of the `<<EOF>>` rules, run once at the end of input.
+///
+/// The first rule whose start condition holds and that produces a token wins.
+fn generate_eof_code(spec: &LexerSpec) -> String {
+    let mut code = String::new();
+    for rule in spec.rules.iter().filter(|r| matches!(r.pattern, RulePattern::Eof)) {
+        let mut conditions = vec!["eof_token.is_none()".to_string()];
+        conditions.extend(state_condition(spec, rule));
+        let attribute = cfg_attribute(rule.cfg.as_deref());
+        let body = match &rule.action_code {
+            Some(action_code) => format!(
+                r#"// EOF rule: <<EOF>> -> {{ {} }}
+		{}if {} {{
+			#[allow(unused_variables)]
+			let test_t = Token::new(TokenKind::Unknown, String::new(), self.pos, start_row, start_col, 0, indent);{}
+			eof_token = {{
+				{}
+			}};
+		}}
+"#,
+                action_code.lines().map(str::trim).collect::<Vec<_>>().join(" "),
+                attribute,
+                conditions.join(" && "),
+                action_token_code(action_code),
+                action_code
+            ),
+            None => format!(
+                r#"// EOF rule: <<EOF>> -> {}
+		{}if {} {{
+			eof_token = Some(Token::new(TokenKind::{}, String::new(), self.pos, start_row, start_col, 0, indent));
+		}}
+"#,
+                rule.name,
+                attribute,
+                conditions.join(" && "),
+                rule.name
+            ),
+        };
+        code.push_str(&body);
+        code.push_str("\t\t");
+    }
+    code
+}
+
+/// Generates the keyword lookup used for identifier tokens (%keyword).
+fn generate_keyword_method(spec: &LexerSpec) -> String {
+    if spec.keywords.is_empty() {
+        return String::new();
+    }
+    let mut code = String::new();
+    code.push_str("\t/// Returns the token kind of a word declared with %keyword\n");
+    code.push_str("\tpub fn keyword_kind(word: &str) -> Option<TokenKind> {\n");
+    code.push_str("\t\tmatch word {\n");
+    for (word, token_name) in &spec.keywords {
+        code.push_str(&format!("\t\t\t{:?} => Some(TokenKind::{}),\n", word, token_name));
+    }
+    code.push_str("\t\t\t_ => None,\n");
+    code.push_str("\t\t}\n");
+    code.push_str("\t}\n\n");
+    code
+}
+
+/// Generates the body of `any_rule_matches`, which checks every rule without running it.
+fn generate_any_rule_matches_code(rule_codes: &[RuleCode]) -> String {
+    let mut code = String::new();
+    for rule in rule_codes {
+        let mut conditions = rule.conditions.clone();
+        conditions.push(format!("matches!({}, Some(m) if !m.is_empty())", rule.match_code));
+        code.push_str(&format!(
+            "{}if {} {{\n\t\t\treturn true;\n\t\t}}\n\t\t",
+            rule.cfg_attribute,
+            conditions.join(" && ")
+        ));
+    }
+    code
+}
+
+/// Generates matching code where the first rule (in priority order) that matches wins.
+///
+/// Runs of fixed-string rules are looked up at once, and the longest literal wins.
+fn generate_first_match_code(rule_codes: &[RuleCode]) -> String {
+    let mut code = String::new();
+    for group in group_rule_codes(rule_codes) {
+        let (comment, opening) = group_opening(group);
+        if let [rule] = group {
+            code.push_str(&format!(
+                r#"        {}
+        {}
+            let matched_opt = {};
+            if let Some(matched) = matched_opt {{
+                {}
             }}
         }}
 
 "#,
-                pattern_desc, action_code, match_code, action_code
+                comment, opening, rule.match_code, rule.accept_code
             ));
+            continue;
         }
-    }
+        let arms: Vec<String> = group
+            .iter()
+            .map(|rule| format!("{} => {{\n{}\n}}\n", rule.rule_id, rule.accept_code))
+            .collect();
+        code.push_str(&format!(
+            r#"        {}
+        {}
+            let literal_match = {};
+            if let Some((rule_id, matched)) = literal_match {{
+                match rule_id {{
+                    {}_ => unreachable!(),
+                }}
+            }}
+        }}
 
-    // Finally, generate regular token rules
-    for rule in &spec.rules {
-        if rule.context_token.is_none() && rule.action_code.is_none() {
-            let update_context = if rule.name == "WHITESPACE" || rule.name == "Whitespace" || rule.name == "NEWLINE" || rule.name == "Newline" {
-                "// Whitespace tokens don't update context"
-            } else {
-                "self.last_token_kind = Some(token.kind.clone())"
-            };
+"#,
+            comment,
+            opening,
+            generate_literal_lookup_code(group, false),
+            arms.concat()
+        ));
+    }
+    code
+}
 
-            let (match_code, _needs_regex) = generate_pattern_match_code(&rule.pattern, &rule.name);
-            let pattern_desc = pattern_to_regex(&rule.pattern)
-                .replace('\n', "\\n")
-                .replace('\t', "\\t")
-                .replace('\r', "\\r");
-            rule_match_code.push_str(&format!(
-                r#"        // Rule: {} -> {}
-        {{
-            let matched_opt = {{{}}};
-            if let Some(matched) = matched_opt {{
-                let token = Token::new(
-                    TokenKind::{},
-                    matched.clone(),
-                    self.pos,
-                    start_row,
-                    start_col,
-                    matched.len(),
-                    indent,
-                );
-                self.advance(&matched);
-                {};
-                return Some(token);
+/// Generates matching code where the rule with the longest match wins
+/// (`%option longest-match`). Ties are resolved by rule priority.
+fn generate_longest_match_code(rule_codes: &[RuleCode]) -> String {
+    let mut code = String::new();
+    code.push_str("        let mut best_match: Option<(usize, String)> = None;\n\n");
+    for group in group_rule_codes(rule_codes) {
+        let (comment, opening) = group_opening(group);
+        let candidate_code = match group {
+            [rule] => format!("({}).map(|matched| ({}, matched))", rule.match_code, rule.rule_id),
+            _ => generate_literal_lookup_code(group, false),
+        };
+        code.push_str(&format!(
+            r#"        {}
+        {}
+            if let Some((rule_id, matched)) = {} {{
+                let is_longer = match &best_match {{
+                    Some((_, best)) => matched.len() > best.len(),
+                    None => true,
+                }};
+                if is_longer {{
+                    best_match = Some((rule_id, matched));
+                }}
             }}
         }}
 
 "#,
-                pattern_desc, rule.name, match_code, rule.name, update_context
-            ));
+            comment, opening, candidate_code
+        ));
+    }
+    code.push_str("        if let Some((rule_id, matched)) = best_match {\n");
+    code.push_str("            match rule_id {\n");
+    for rule in rule_codes {
+        code.push_str(&format!(
+            "            {}{} => {{\n                {}\n            }}\n",
+            rule.cfg_attribute, rule.rule_id, rule.accept_code
+        ));
+    }
+    code.push_str("            _ => unreachable!(),\n");
+    code.push_str("            }\n");
+    code.push_str("        }\n");
+    code
+}
+
+/// Generates the TokenKind variants of all token names of the spec, with the match
+/// arms of their names for Display and FromStr.
+fn generate_token_kind_code(spec: &LexerSpec) -> (String, String, String, String) {
+    let mut token_kind_variants = String::new();
+    let mut kind_from_u16_code = String::new();
+    let token_kinds = ordered_token_kinds(spec);
+
+    // Generate variants for all collected tokens, numbered in the order of their declarations
+    for (code, token_name) in token_kinds.iter().enumerate().skip(2) {
+        let attribute = cfg_attribute(token_cfg(spec, token_name).as_deref());
+        if let Some(doc) = token_doc(spec, token_name) {
+            for line in doc.lines() {
+                token_kind_variants.push_str(&format!("\t///{}{}\n", if line.is_empty() { "" } else { " " }, line));
+            }
         }
+        if let Some(pattern_desc) = token_pattern_description(spec, token_name) {
+            let pattern_desc = pattern_desc.replace('\n', "\\n").replace('\t', "\\t").replace('\r', "\\r");
+            token_kind_variants.push_str(&format!("\t{}{} = {}, // {}\n", attribute, token_name, code, pattern_desc));
+        } else {
+            // Custom token without a pattern (used only in action code or %token directive)
+            token_kind_variants.push_str(&format!("\t{}{} = {}, // Custom token\n", attribute, token_name, code));
+        }
+        kind_from_u16_code.push_str(&format!("\t\t\t{}{} => Some(TokenKind::{}),\n", attribute, code, token_name));
+    }
+
+    // Generate the names of the token kinds for Display and FromStr
+    let mut kind_names_code = String::new();
+    let mut kind_from_name_code = String::new();
+    for token_name in token_kinds.iter().skip(2) {
+        let attribute = cfg_attribute(token_cfg(spec, token_name).as_deref());
+        kind_names_code.push_str(&format!(
+            "\t\t\t{}TokenKind::{} => \"{}\",\n",
+            attribute, token_name, token_name
+        ));
+        kind_from_name_code.push_str(&format!(
+            "\t\t\t{}\"{}\" => Ok(TokenKind::{}),\n",
+            attribute, token_name, token_name
+        ));
+    }
+
+    (token_kind_variants, kind_names_code, kind_from_name_code, kind_from_u16_code)
+}
+
+/// Returns the pattern of the first rule producing a token kind, or None for kinds
+/// without a rule (declared with `%token` or only used in action code).
+fn token_pattern_description(spec: &LexerSpec, token_name: &str) -> Option<String> {
+    let rule = spec.rules.iter().find(|r| r.name == token_name)?;
+    Some(pattern_to_regex(&rule.pattern))
+}
+
+/// Returns the description of a token kind given by the first rule that has one.
+fn token_doc<'a>(spec: &'a LexerSpec, token_name: &str) -> Option<&'a str> {
+    spec.rules.iter().filter(|r| r.name == token_name).find_map(|r| r.doc.as_deref())
+}
+
+/// Generates the doc comment lines of `Lexer` listing the described token kinds.
+fn generate_lexer_doc(spec: &LexerSpec) -> String {
+    let described: Vec<String> = ordered_token_kinds(spec)
+        .iter()
+        .filter_map(|name| token_doc(spec, name).map(|doc| format!("/// - `{}`: {}\n", name, doc.replace('\n', " "))))
+        .collect();
+    if described.is_empty() {
+        return String::new();
+    }
+    format!("///\n/// Token kinds:\n{}", described.concat())
+}
+
+/// Generates the entries of the `TOKENS` table after those of `Unknown` and `Eof`.
+fn generate_token_infos(spec: &LexerSpec) -> String {
+    let mut code = String::new();
+    for (id, token_name) in ordered_token_kinds(spec).iter().enumerate().skip(2) {
+        let attribute = cfg_attribute(token_cfg(spec, token_name).as_deref());
+        let pattern = token_pattern_description(spec, token_name).unwrap_or_default();
+        code.push_str(&format!(
+            "\t{}TokenInfo {{ kind: TokenKind::{}, name: {:?}, id: {}, pattern: {:?}, is_trivia: {} }},\n",
+            attribute,
+            token_name,
+            token_name,
+            id,
+            pattern,
+            spec.trivia_kinds.contains(token_name)
+        ));
     }
+    code
+}
 
-    // Generate to_string method
-    let mut to_string_method = String::new();
-    to_string_method.push_str("\t/// Returns a string representation of the token kind for debugging purposes.\n");
-    to_string_method.push_str("\t///\n");
-    to_string_method.push_str("\t/// # Returns\n");
-    to_string_method.push_str("\t///\n");
-    to_string_method.push_str("\t/// A human-readable string representation of the token kind\n");
-    to_string_method.push_str("\tpub fn to_string(&self) -> String {\n");
-    to_string_method.push_str("\t\tmatch self.kind {\n");
+/// Collects the names of the token kinds besides `Unknown` and `Eof`.
+fn collect_token_names(spec: &LexerSpec) -> HashSet<String> {
+    let mut all_token_names = HashSet::new();
+    
+    // Collect token names from rules
+    for rule in &spec.rules {
+        if rule.action_code.is_none() && !rule.name.is_empty() {
+            // Skip Unknown and Eof as they are always added automatically
+            if rule.name != "Unknown" && rule.name != "Eof" {
+                all_token_names.insert(rule.name.clone());
+            }
+        }
+    }
     
-    // Add cases for all collected tokens (including custom tokens)
-    for token_name in &all_token_names {
-        to_string_method.push_str(&format!("\t\t\tTokenKind::{} => \"{}\".to_string(),\n", token_name, token_name));
+    // Add explicitly declared custom tokens from %token directive
+    for token_name in &spec.custom_tokens {
+        if token_name != "Unknown" && token_name != "Eof" {
+            all_token_names.insert(token_name.clone());
+        }
     }
     
-    // Add case for Unknown
-    to_string_method.push_str("\t\t\tTokenKind::Unknown => \"UNKNOWN\".to_string(),\n");
-    to_string_method.push_str("\t\t}\n");
-    to_string_method.push_str("\t}");
+    // Add the tokens of %option indentation-tokens
+    if spec.options.indentation_tokens {
+        all_token_names.insert("Indent".to_string());
+        all_token_names.insert("Dedent".to_string());
+    }
 
-    // Replace markers with generated code
+    // Add the token of %option shebang-token
+    if spec.options.shebang_token {
+        all_token_names.insert("Shebang".to_string());
+    }
+
+    // Add the %keyword tokens
+    for (_, token_name) in &spec.keywords {
+        all_token_names.insert(token_name.clone());
+    }
+
+    // Add the %error token
+    if let Some(error_token) = &spec.error_token {
+        all_token_names.insert(error_token.clone());
+    }
+
+    // Collect custom token names from action code
+    for rule in &spec.rules {
+        if let Some(action_code) = &rule.action_code {
+            let custom_tokens = extract_custom_tokens(action_code);
+            all_token_names.extend(custom_tokens);
+        }
+    }
+
+    all_token_names
+}
+
+/// Returns the first feature of the spec that byte-level lexers (`%option bytes`) do not
+/// support, described for the `compile_error!` of the generated code.
+fn bytes_unsupported_feature(spec: &LexerSpec, options: &GeneratorOptions) -> Option<String> {
+    for rule in &spec.rules {
+        let feature = if matches!(rule.pattern, RulePattern::Eof | RulePattern::Balanced(..) | RulePattern::Heredoc(_)) {
+            "<<EOF>>, %balanced and %heredoc rules"
+        } else if rule.action_code.is_some() {
+            "action code"
+        } else if !rule.context.is_empty() {
+            "context-dependent rules"
+        } else if !rule.states.is_empty() {
+            "start conditions"
+        } else if rule.channel.is_some() {
+            "channels"
+        } else if rule.line_start {
+            "rules anchored at the start of a line"
+        } else if rule.cfg.is_some() {
+            "%cfg rules"
+        } else {
+            continue;
+        };
+        let pattern = match rule.pattern {
+            RulePattern::Eof => "<<EOF>>".to_string(),
+            _ => describe_pattern(&rule.pattern),
+        };
+        return Some(format!("{} (rule `{}`)", feature, pattern));
+    }
+    let spec_options = &spec.options;
+    [
+        (!spec.keywords.is_empty(), "%keyword"),
+        (!spec.token_types.is_empty(), "%type"),
+        (!spec.state_fields.is_empty(), "%state_struct"),
+        (spec_options.indentation_tokens, "%option indentation-tokens"),
+        (spec_options.skip_shebang || spec_options.shebang_token, "the shebang options"),
+        (spec_options.token_offsets, "%option token-offsets"),
+        (spec_options.utf16_columns, "%option utf16-columns"),
+        (spec_options.trivia, "%option trivia"),
+        (spec_options.result_errors, "%option result-errors"),
+        (spec_options.length_chars || spec_options.token_lengths, "the token length options"),
+        (spec_options.max_token_length.is_some(), "%option max-token-length"),
+        (spec_options.max_tokens.is_some(), "%option max-tokens"),
+        (spec_options.intern || options.intern, "%option intern (tokens already borrow their text)"),
+        (spec_options.ffi || options.ffi, "%option ffi"),
+        (spec_options.wasm || options.wasm, "%option wasm"),
+        (spec_options.pyo3 || options.pyo3, "%option pyo3"),
+        (options.no_std || spec_options.no_std, "no_std"),
+        (
+            spec_options.dfa || spec_options.precompiled_dfa || spec_options.regex_set || options.backend != Backend::Regex,
+            "backends other than the regex crate",
+        ),
+    ]
+    .into_iter()
+    .find(|(used, _)| *used)
+    .map(|(_, feature)| feature.to_string())
+}
+
+/// Writes text as the body of a Rust byte string literal, escaping bytes outside printable ASCII.
+fn byte_string_literal(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'"' => "\\\"".to_string(),
+            b'\\' => "\\\\".to_string(),
+            b' '..=b'~' => (byte as char).to_string(),
+            _ => format!("\\x{:02x}", byte),
+        })
+        .collect()
+}
+
+/// Generates the expression returning the match length of a rule in a byte-level lexer,
+/// and the regex compiled for it if it is not a plain literal.
+///
+/// Regexes are compiled without Unicode mode, so `.` and character classes match single
+/// bytes; `(?u:...)` turns Unicode mode back on for a part of a pattern.
+fn bytes_rule_match_code(rule: &LexerRule, rule_id: usize) -> (String, Option<String>) {
+    if let Some(literal) = rule_literal(rule) {
+        return (format!("Self::match_literal(remaining, b\"{}\")", byte_string_literal(&literal)), None);
+    }
+    let regex = format!("(?-u)^(?:{})", rule_to_regex(rule));
+    let method = if rule.trailing_context.is_some() { "match_cached_lookahead" } else { "match_cached_pattern" };
+    (format!("self.{}(remaining, {})", method, rule_id), Some(regex))
+}
+
+/// Generates a lexer over `&[u8]` from the byte-level template (`%option bytes`).
+///
+/// Only rules emitting a token or skipping input are supported; the first feature
+/// outside of those is reported with `compile_error!`.
+fn generate_bytes_lexer(spec: &LexerSpec, source_file: &str, options: &GeneratorOptions) -> String {
+    let mut output = LEXER_BYTES_TEMPLATE.to_string();
+    let (token_kind_variants, kind_names_code, kind_from_name_code, kind_from_u16_code) =
+        generate_token_kind_code(spec);
+
+    let mut imports_code = String::new();
+    if let Some(feature) = bytes_unsupported_feature(spec, options) {
+        imports_code.push_str(&format!(
+            "compile_error!({:?});\n",
+            format!("byte-level lexers do not support {}", feature)
+        ));
+    }
+
+    // Generate the regexes and the matching code of the rules in priority order
+    let mut regex_code = String::new();
+    let mut rule_codes = Vec::new();
+    for (rule_id, rule) in spec.rules.iter().enumerate() {
+        if rule.action_code.is_some() || !rule.context.is_empty() || matches!(rule.pattern, RulePattern::Eof) {
+            continue;
+        }
+        let (match_code, regex) = bytes_rule_match_code(rule, rule_id);
+        if let Some(regex) = regex {
+            regex_code.push_str(&format!("\t\t\t({}, Regex::new({:?}).unwrap()),\n", rule_id, regex));
+        }
+        let (comment, accept_code) = if rule.skip {
+            (
+                format!("// Skip rule: {} -> %skip", describe_pattern(&rule.pattern)),
+                "self.advance(length);\n\t\t\t\tcontinue;".to_string(),
+            )
+        } else {
+            (
+                format!("// Rule: {} -> {}", describe_pattern(&rule.pattern), rule.name),
+                format!("return Some(self.accept(TokenKind::{}, length));", rule.name),
+            )
+        };
+        rule_codes.push(RuleCode {
+            rule_id,
+            comment,
+            conditions: Vec::new(),
+            match_code,
+            accept_code,
+            cfg_attribute: String::new(),
+            literal: rule_literal(rule),
+        });
+    }
+
+    // Runs of fixed-string rules are looked up by length like in the other lexers
+    let mut rule_match_code = String::new();
+    if spec.options.longest_match {
+        rule_match_code.push_str("// Find the rule with the longest match (the first rule wins ties)\n\t\t\tlet mut longest: Option<(usize, usize)> = None;\n");
+        for group in group_rule_codes(&rule_codes) {
+            let (comment, _) = group_opening(group);
+            let candidate_code = match group {
+                [rule] => format!("({}).map(|length| ({}, length))", rule.match_code, rule.rule_id),
+                _ => generate_literal_lookup_code(group, true),
+            };
+            rule_match_code.push_str(&format!(
+                "\t\t\t{}\n\t\t\tif let Some((rule_id, length)) = {} {{\n\t\t\t\tif length > longest.map_or(0, |(_, longest_length)| longest_length) {{\n\t\t\t\t\tlongest = Some((rule_id, length));\n\t\t\t\t}}\n\t\t\t}}\n",
+                comment, candidate_code
+            ));
+        }
+        rule_match_code.push_str("\t\t\tmatch longest {\n");
+        for rule in &rule_codes {
+            rule_match_code.push_str(&format!("\t\t\t\tSome(({}, length)) => {{\n{}\n}}\n", rule.rule_id, rule.accept_code));
+        }
+        rule_match_code.push_str("\t\t\t\t_ => {}\n\t\t\t}\n");
+    } else {
+        for group in group_rule_codes(&rule_codes) {
+            let (comment, _) = group_opening(group);
+            if let [rule] = group {
+                rule_match_code.push_str(&format!(
+                    "\t\t\t{}\n\t\t\tif let Some(length) = {} {{\n\t\t\t\t{}\n\t\t\t}}\n\n",
+                    comment, rule.match_code, rule.accept_code
+                ));
+                continue;
+            }
+            let arms: String = group
+                .iter()
+                .map(|rule| format!("\t\t\t\t\t{} => {{\n{}\n}}\n", rule.rule_id, rule.accept_code))
+                .collect();
+            rule_match_code.push_str(&format!(
+                "\t\t\t{}\n\t\t\tif let Some((rule_id, length)) = {} {{\n\t\t\t\tmatch rule_id {{\n{}\t\t\t\t\t_ => unreachable!(),\n\t\t\t\t}}\n\t\t\t}}\n\n",
+                comment,
+                generate_literal_lookup_code(group, true),
+                arms
+            ));
+        }
+    }
+
+    let any_rule_matches_code: String = rule_codes
+        .iter()
+        .map(|rule| format!("if {}.is_some() {{\n\t\t\treturn true;\n\t\t}}\n\t\t", rule.match_code))
+        .collect();
+
+    let new_input_code = if spec.options.strip_bom {
+        "// Remove a byte order mark (%option strip-bom)\n\t\tlet input = input.strip_prefix(b\"\\xef\\xbb\\xbf\").unwrap_or(input);"
+    } else {
+        ""
+    };
+    let before_token_code = if spec.options.skip_whitespace {
+        "// Skip whitespace between tokens (%option skip-whitespace)\n\t\t\tlet skipped = self.input[self.pos..].iter().take_while(|byte| byte.is_ascii_whitespace()).count();\n\t\t\tself.advance(skipped);"
+    } else {
+        ""
+    };
+
+    output = output.replace("//----<IMPORTS>----\n", &imports_code);
     output = output.replace(
         "//----<GENERATED_BY>----",
         &format!("// Generated from: {}", source_file),
     );
     output = output.replace("//----<TOKEN_KIND>----", &token_kind_variants);
-    output = output.replace("//----<REG_EX_CODE>----", &regex_code);
+    output = output.replace("//----<TOKEN_KIND_NAMES>----\n", &kind_names_code);
+    output = output.replace("//----<TOKEN_KIND_FROM_NAME>----\n", &kind_from_name_code);
+    output = output.replace("//----<TOKEN_KIND_FROM_U16>----\n", &kind_from_u16_code);
+    output = output.replace("//----<TOKEN_INFOS>----\n", &generate_token_infos(spec));
+    output = output.replace("//----<LEXER_DOC>----\n", &generate_lexer_doc(spec));
+    output = output.replace("//----<TRIVIA_KINDS>----\n\t\tfalse", &generate_trivia_code(spec));
+    output = output.replace("//----<REG_EX_CODE>----\n", &regex_code);
+    output = output.replace("//----<NEW_INPUT_CODE>----", new_input_code);
+    output = output.replace("//----<BEFORE_TOKEN_CODE>----", before_token_code);
     output = output.replace("//----<RULE_MATCH_CODE>----", &rule_match_code);
-    output = output.replace("//----<TO_STRING_METHOD>----", &to_string_method);
+    output = output.replace("//----<ANY_RULE_MATCHES>----", &any_rule_matches_code);
+    // Lexers of literals only drop the regex cache and its imports
+    output = strip_regions(&output, "REGEX", !regex_code.is_empty());
+    finish_lexer(spec, options, output)
+}
 
-    // Add suffix code
-    if !spec.suffix_code.is_empty() {
-        output.push_str(&format!("\n{}\n", spec.suffix_code));
+/// Generates the body of `TokenKind::is_trivia` matching the `%trivia` kinds.
+fn generate_trivia_code(spec: &LexerSpec) -> String {
+    if spec.trivia_kinds.is_empty() {
+        return "false".to_string();
     }
+    let kinds: Vec<String> = spec.trivia_kinds.iter().map(|name| format!("TokenKind::{}", name)).collect();
+    format!("matches!(self, {})", kinds.join(" | "))
+}
 
-    output
+/// Generates the `klex_tests` module checking the token kinds of the `%test` inputs.
+fn generate_spec_tests(spec: &LexerSpec) -> String {
+    if spec.tests.is_empty() {
+        return String::new();
+    }
+    let mut code = String::from("\n#[cfg(test)]\nmod klex_tests {\n\tuse super::*;\n");
+    for (i, (input, expected)) in spec.tests.iter().enumerate() {
+        let names: Vec<String> = expected.iter().map(|name| format!("{:?}", name)).collect();
+        code.push_str(&format!(
+            r#"
+	// %test {input:?} => {summary}
+	#[test]
+	fn spec_test_{number}() {{
+		let tokens = Lexer::from_str({input:?}).tokenize();
+		let kinds: Vec<String> = tokens.iter().map(|t| t.kind.to_string()).collect();
+		let expected: [&str; {count}] = [{names}];
+		assert_eq!(kinds, expected, "%test {{:?}}", {input:?});
+	}}
+"#,
+            input = input,
+            summary = expected.join(" "),
+            number = i + 1,
+            count = expected.len(),
+            names = names.join(", ")
+        ));
+    }
+    code.push_str("}\n");
+    code
+}
+
+/// Returns the backend a lexer is generated with: the `%option`s of the spec
+/// take precedence over the generator options, and no_std lexers use the DFA.
+pub(crate) fn effective_backend(spec: &LexerSpec, options: &GeneratorOptions) -> Backend {
+    if options.no_std || spec.options.no_std || spec.options.dfa {
+        Backend::Dfa
+    } else if spec.options.precompiled_dfa {
+        Backend::PrecompiledDfa
+    } else if spec.options.regex_set {
+        Backend::RegexSet
+    } else {
+        options.backend
+    }
+}
+
+/// Generates a criterion benchmark that lexes each sample input with the generated lexer.
+///
+/// The benchmark includes the lexer from `lexer_path`, relative to the directory of
+/// `Cargo.toml` unless it is absolute, and measures the throughput of `tokenize` for every `(name, input)`
+/// sample. The benchmark group is named after the spec and the backend, so runs with
+/// different backends can be compared. Without samples, the inputs of the `%test`
+/// directives of the spec are used.
+///
+/// The benchmark needs `criterion` as a dev-dependency and a `[[bench]]` entry with
+/// `harness = false`.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::{parse_spec, generate_bench, GeneratorOptions};
+///
+/// let spec = parse_spec("[0-9]+ -> NUMBER\n\"+\" -> PLUS\n").unwrap();
+/// let samples = vec![("sum".to_string(), "1+2+3".to_string())];
+/// let code = generate_bench(&spec, "calc.klex", "src/calc_lexer.rs", &samples, &GeneratorOptions::default());
+/// assert!(code.contains("criterion_main!(benches);"));
+/// assert!(code.contains("(\"sum\", \"1+2+3\")"));
+/// ```
+pub fn generate_bench(
+    spec: &LexerSpec,
+    source_file: &str,
+    lexer_path: &str,
+    samples: &[(String, String)],
+    options: &GeneratorOptions,
+) -> String {
+    let samples: Vec<(String, String)> = if samples.is_empty() {
+        spec.tests
+            .iter()
+            .enumerate()
+            .map(|(i, (input, _))| (format!("test_{}", i + 1), input.clone()))
+            .collect()
+    } else {
+        samples.to_vec()
+    };
+    let sample_entries: Vec<String> = samples
+        .iter()
+        .map(|(name, input)| format!("\t({:?}, {:?}),\n", name, input))
+        .collect();
+    let lexer_name = generated_lexer_name(spec, options);
+    let include_path = if lexer_path.starts_with('/') {
+        format!("{:?}", lexer_path)
+    } else {
+        format!("concat!(env!(\"CARGO_MANIFEST_DIR\"), {:?})", format!("/{}", lexer_path))
+    };
+    let group_name = format!(
+        "{}/{:?}",
+        std::path::Path::new(source_file).file_stem().and_then(|stem| stem.to_str()).unwrap_or("lexer"),
+        effective_backend(spec, options)
+    );
+    let code = format!(
+        r#"// Benchmark generated by klex
+// --------------------------------------------------------
+// Generated from: {source_file}
+// Needs criterion as a dev-dependency and a [[bench]] entry with harness = false
+
+// The benchmark only uses part of the lexer, and the #[test] functions of its
+// test modules are left out of benchmarks built without the test harness
+#![allow(dead_code, unused_imports)]
+
+use criterion::{{criterion_group, criterion_main, Criterion, Throughput}};
+use std::hint::black_box;
+
+include!({include_path});
+
+/// Sample inputs (name, text)
+const SAMPLES: [(&str, &str); {count}] = [
+{sample_entries}];
+
+/// Measures the throughput of tokenizing each sample
+fn bench_lexer(c: &mut Criterion) {{
+let mut group = c.benchmark_group({group_name:?});
+for (name, input) in SAMPLES {{
+group.throughput(Throughput::Bytes(input.len() as u64));
+group.bench_function(name, |b| b.iter(|| {lexer_name}::from_str(black_box(input)).tokenize()));
+}}
+group.finish();
+}}
+
+criterion_group!(benches, bench_lexer);
+criterion_main!(benches);
+"#,
+        source_file = source_file,
+        include_path = include_path,
+        count = samples.len(),
+        sample_entries = sample_entries.concat(),
+        group_name = group_name,
+        lexer_name = lexer_name
+    );
+    crate::pretty::pretty_print(&code)
+}
+
+/// Returns the name of the generated lexer struct: `Lexer`, `{prefix}Lexer` or the configured name.
+fn generated_lexer_name(spec: &LexerSpec, options: &GeneratorOptions) -> String {
+    match (&options.lexer_name, &spec.name_prefix) {
+        (Some(name), _) => name.clone(),
+        (None, Some(prefix)) => format!("{}Lexer", prefix),
+        (None, None) => "Lexer".to_string(),
+    }
+}
+
+/// Returns the token kinds in the order of their codes, also the codes of the C API
+/// (%option ffi): `Unknown` and `Eof`, the kinds of options (`Indent`, `Dedent` and
+/// `Shebang`), then the other kinds in the order the spec first declares them, by a
+/// directive, a rule or action code. A kind added after the others keeps their codes.
+fn ordered_token_kinds(spec: &LexerSpec) -> Vec<String> {
+    let names = collect_token_names(spec);
+    let mut kinds: Vec<String> = vec!["Unknown".to_string(), "Eof".to_string()];
+    let mut add = |name: &str| {
+        if names.contains(name) && !kinds.iter().any(|kind| kind == name) {
+            kinds.push(name.to_string());
+        }
+    };
+    if spec.options.indentation_tokens {
+        add("Indent");
+        add("Dedent");
+    }
+    if spec.options.shebang_token {
+        add("Shebang");
+    }
+    let mut declarations = spec.token_declarations.iter().peekable();
+    for (index, rule) in spec.rules.iter().enumerate() {
+        while let Some((_, name)) = declarations.next_if(|(rules_before, _)| *rules_before <= index) {
+            add(name);
+        }
+        match &rule.action_code {
+            Some(action_code) => extract_custom_tokens(action_code).iter().for_each(|name| add(name)),
+            None => add(&rule.name),
+        }
+    }
+    for (_, name) in declarations {
+        add(name);
+    }
+    // Specs built without the parser may not list their declarations
+    let mut rest: Vec<&String> = names.iter().filter(|name| !kinds.contains(name)).collect();
+    rest.sort();
+    kinds.extend(rest.into_iter().cloned());
+    kinds
+}
+
+/// Generates the `extern "C"` functions over the lexer (%option ffi).
+///
+/// The lexer is passed to C as an opaque pointer, and tokens are copied into a `#[repr(C)]`
+/// `CToken` holding the byte range of the text instead of the text. The functions use
+/// `#[unsafe(no_mangle)]` (Rust 1.82, the crate's `rust-version`), as the 2024 edition
+/// rejects a plain `#[no_mangle]`.
+fn generate_ffi_code(spec: &LexerSpec, options: &GeneratorOptions) -> String {
+    let function_prefix = snake_case(&generated_lexer_name(spec, options));
+    let constant_prefix = function_prefix.to_uppercase();
+    format!(
+        r#"
+// C API of the lexer (%option ffi); klex --emit-c-header writes the matching header
+
+/// Token passed to C by `{f}_next_token`
+/// The text is the `len` bytes at `start` in the input given to `{f}_new`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CToken {{
+	/// Code of the token kind, one of the `{c}_KIND_` constants of the header
+	pub kind: u32,
+	/// Byte offset of the token in the input
+	pub start: usize,
+	/// Length of the token in bytes
+	pub len: usize,
+	/// Row number where the token appears (1-based)
+	pub row: usize,
+	/// Column number where the token appears (1-based)
+	pub col: usize,
+	/// User-defined tag
+	pub tag: isize,
+}}
+
+impl CToken {{
+	/// Returns the code of a token kind in the C API
+	{v}fn kind_code(kind: &TokenKind) -> u32 {{
+		u32::from(kind.as_u16())
+	}}
+}}
+
+/// Creates a lexer over a copy of the `len` bytes of UTF-8 text at `input`
+/// Returns null when the text is not valid UTF-8; the lexer is freed by `{f}_free`
+///
+/// # Safety
+///
+/// `input` must point to `len` readable bytes, or may be null when `len` is 0
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn {f}_new(input: *const u8, len: usize) -> *mut core::ffi::c_void {{
+	let bytes = if len == 0 {{ &[][..] }} else {{ unsafe {{ core::slice::from_raw_parts(input, len) }} }};
+	match core::str::from_utf8(bytes) {{
+		Ok(input) => Box::into_raw(Box::new(Lexer::new(input.to_string()))).cast(),
+		Err(_) => core::ptr::null_mut(),
+	}}
+}}
+
+/// Frees a lexer created by `{f}_new`; null is ignored
+///
+/// # Safety
+///
+/// `lexer` must come from `{f}_new` and must not be used afterwards
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn {f}_free(lexer: *mut core::ffi::c_void) {{
+	if !lexer.is_null() {{
+		drop(unsafe {{ Box::from_raw(lexer.cast::<Lexer>()) }});
+	}}
+}}
+
+/// Reads the next token on the default channel into `token`
+/// Returns 1 when a token was read, and 0 at the end of input or when a pointer is null
+///
+/// # Safety
+///
+/// `lexer` must come from `{f}_new`, and `token` must point to a writable `CToken`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn {f}_next_token(lexer: *mut core::ffi::c_void, token: *mut CToken) -> i32 {{
+	let (Some(lexer), Some(out)) = (unsafe {{ lexer.cast::<Lexer>().as_mut() }}, unsafe {{ token.as_mut() }}) else {{
+		return 0;
+	}};
+	match lexer.next_token() {{
+		Some(next) => {{
+			*out = CToken {{
+				kind: CToken::kind_code(&next.kind),
+				start: next.span.start,
+				len: next.span.len(),
+				row: next.row,
+				col: next.col,
+				tag: next.tag,
+			}};
+			1
+		}}
+		None => 0,
+	}}
+}}
+"#,
+        f = function_prefix,
+        c = constant_prefix,
+        v = options.visibility.keyword(),
+    )
+}
+
+/// Generates the `wasm_bindgen` wrappers of the lexer for JavaScript (%option wasm).
+///
+/// The wrappers live in a `wasm` module, so their `wasm_bindgen` import does not clash with
+/// the code of the spec. JavaScript sees them as the lexer and token classes.
+fn generate_wasm_code(spec: &LexerSpec) -> String {
+    // Tokens with offsets hold no text, so it is sliced from the input
+    let text_code = if spec.options.token_offsets {
+        "self.lexer.input[token.span.range()].to_string()"
+    } else {
+        "token.text.to_string()"
+    };
+    // The wrappers own their lexer, so a borrowing lexer owns its input there
+    let lexer_type = if spec.options.borrow_input { "Lexer<'static>" } else { "Lexer" };
+    format!(
+        r#"
+// JavaScript API of the lexer (%option wasm); needs the wasm-bindgen crate
+pub mod wasm {{
+	use super::*;
+	use wasm_bindgen::prelude::*;
+
+	/// Lexer exported to JavaScript: `new Lexer(input)` and `nextToken()`
+	#[wasm_bindgen(js_name = Lexer)]
+	pub struct WasmLexer {{
+		lexer: {lexer_type},
+	}}
+
+	#[wasm_bindgen(js_class = Lexer)]
+	impl WasmLexer {{
+		/// Creates a lexer over the input
+		#[wasm_bindgen(constructor)]
+		pub fn new(input: &str) -> WasmLexer {{
+			WasmLexer {{ lexer: Lexer::new(input.to_string()) }}
+		}}
+
+		/// Returns the next token on the default channel, or undefined at the end of input
+		#[wasm_bindgen(js_name = nextToken)]
+		pub fn next_token(&mut self) -> Option<WasmToken> {{
+			let token = self.lexer.next_token()?;
+			Some(WasmToken {{
+				kind: token.kind.to_string(),
+				text: {text_code},
+				start: token.span.start,
+				end: token.span.end,
+				row: token.row,
+				col: token.col,
+				tag: token.tag,
+				error: token.error,
+			}})
+		}}
+	}}
+
+	/// Token passed to JavaScript, with the name of its kind instead of the enum
+	#[wasm_bindgen(js_name = Token, getter_with_clone)]
+	pub struct WasmToken {{
+		/// Name of the token kind, as written by Display
+		pub kind: String,
+		/// Text of the token
+		pub text: String,
+		/// Byte offset of the first byte of the token in the input
+		pub start: usize,
+		/// Byte offset just past the last byte of the token in the input
+		pub end: usize,
+		/// Row number where the token appears (1-based)
+		pub row: usize,
+		/// Column number where the token appears (1-based)
+		pub col: usize,
+		/// User-defined tag
+		pub tag: isize,
+		/// Error message of tokens produced for unmatched input
+		pub error: Option<String>,
+	}}
+}}
+"#,
+        text_code = text_code,
+        lexer_type = lexer_type
+    )
+}
+
+/// Generates the PyO3 wrappers of the lexer for Python (%option pyo3).
+///
+/// The wrappers live in a `python` module with a `#[pymodule]` function named after the
+/// lexer (`lexer`, or `json_lexer` for `%prefix Json`), which is the name Python imports.
+fn generate_pyo3_code(spec: &LexerSpec, options: &GeneratorOptions) -> String {
+    let lexer_name = generated_lexer_name(spec, options);
+    let token_name = match (&options.token_name, &spec.name_prefix) {
+        (Some(name), _) => name.clone(),
+        (None, prefix) => format!("{}Token", prefix.as_deref().unwrap_or("")),
+    };
+    // Tokens with offsets hold no text, so it is sliced from the input
+    let text_code = if spec.options.token_offsets {
+        "self.lexer.input[token.span.range()].to_string()"
+    } else {
+        "token.text.to_string()"
+    };
+    // The wrappers own their lexer, so a borrowing lexer owns its input there
+    let lexer_type = if spec.options.borrow_input { "Lexer<'static>" } else { "Lexer" };
+    format!(
+        r#"
+// Python API of the lexer (%option pyo3); needs the pyo3 crate
+pub mod python {{
+	use super::*;
+	use pyo3::prelude::*;
+
+	/// Lexer exported to Python: `{l}(input)`, `next_token()`, `tokenize()` and iteration
+	#[pyclass(name = "{l}")]
+	pub struct PyLexer {{
+		lexer: {lexer_type},
+	}}
+
+	#[pymethods]
+	impl PyLexer {{
+		/// Creates a lexer over the input
+		#[new]
+		pub fn new(input: &str) -> Self {{
+			PyLexer {{ lexer: Lexer::new(input.to_string()) }}
+		}}
+
+		/// Returns the next token on the default channel, or None at the end of input
+		pub fn next_token(&mut self) -> Option<PyToken> {{
+			let token = self.lexer.next_token()?;
+			Some(PyToken {{
+				kind: token.kind.to_string(),
+				text: {text_code},
+				start: token.span.start,
+				end: token.span.end,
+				row: token.row,
+				col: token.col,
+				tag: token.tag,
+				error: token.error,
+			}})
+		}}
+
+		/// Returns the remaining tokens as a list
+		pub fn tokenize(&mut self) -> Vec<PyToken> {{
+			core::iter::from_fn(|| self.next_token()).collect()
+		}}
+
+		fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {{
+			slf
+		}}
+
+		fn __next__(&mut self) -> Option<PyToken> {{
+			self.next_token()
+		}}
+	}}
+
+	/// Token passed to Python, with the name of its kind instead of the enum
+	#[pyclass(name = "{t}", get_all)]
+	#[derive(Debug, Clone, PartialEq)]
+	pub struct PyToken {{
+		/// Name of the token kind, as written by Display
+		pub kind: String,
+		/// Text of the token
+		pub text: String,
+		/// Byte offset of the first byte of the token in the input
+		pub start: usize,
+		/// Byte offset just past the last byte of the token in the input
+		pub end: usize,
+		/// Row number where the token appears (1-based)
+		pub row: usize,
+		/// Column number where the token appears (1-based)
+		pub col: usize,
+		/// User-defined tag
+		pub tag: isize,
+		/// Error message of tokens produced for unmatched input
+		pub error: Option<String>,
+	}}
+
+	#[pymethods]
+	impl PyToken {{
+		fn __repr__(&self) -> String {{
+			format!("{t}({{}}, {{:?}}, {{}}:{{}})", self.kind, self.text, self.row, self.col)
+		}}
+	}}
+
+	/// Python module with the lexer and token classes
+	#[pymodule]
+	pub fn {m}(module: &Bound<'_, PyModule>) -> PyResult<()> {{
+		module.add_class::<PyLexer>()?;
+		module.add_class::<PyToken>()?;
+		Ok(())
+	}}
+}}
+"#,
+        l = lexer_name,
+        t = token_name,
+        m = snake_case(&lexer_name),
+        text_code = text_code,
+        lexer_type = lexer_type
+    )
+}
+
+/// Generates a C header declaring the functions of a lexer generated with `%option ffi`
+/// (or `GeneratorOptions::ffi`).
+///
+/// The header declares the lexer as an opaque type, the `CToken` struct, a constant for
+/// the code of each token kind, and the functions to create a lexer, read tokens and
+/// free the lexer. Their names are derived from the lexer name, such as `json_lexer_new`
+/// for `%prefix Json`.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::{parse_spec, generate_c_header, GeneratorOptions};
+///
+/// let spec = parse_spec("[0-9]+ -> NUMBER\n\"+\" -> PLUS\n").unwrap();
+/// let header = generate_c_header(&spec, "calc.klex", &GeneratorOptions::default());
+/// assert!(header.contains("#define LEXER_KIND_NUMBER 2"));
+/// assert!(header.contains("Lexer *lexer_new(const uint8_t *input, size_t len);"));
+/// ```
+pub fn generate_c_header(spec: &LexerSpec, source_file: &str, options: &GeneratorOptions) -> String {
+    let lexer_name = generated_lexer_name(spec, options);
+    let token_name = format!("{}CToken", spec.name_prefix.as_deref().unwrap_or(""));
+    let function_prefix = snake_case(&lexer_name);
+    let constant_prefix = function_prefix.to_uppercase();
+    let kind_constants: String = ordered_token_kinds(spec)
+        .iter()
+        .enumerate()
+        .map(|(code, token_name)| {
+            format!("#define {}_KIND_{} {}\n", constant_prefix, snake_case(token_name).to_uppercase(), code)
+        })
+        .collect();
+    format!(
+        r#"/* C header generated by klex */
+/* Generated from: {source_file} */
+#ifndef {c}_H
+#define {c}_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {{
+#endif
+
+/* Codes of the token kinds */
+{kind_constants}
+/* Lexer created by {f}_new and freed by {f}_free */
+typedef struct {l} {l};
+
+/* Token read by {f}_next_token; the text is the len bytes at start in the input */
+typedef struct {t} {{
+    uint32_t kind;
+    size_t start;
+    size_t len;
+    size_t row;
+    size_t col;
+    intptr_t tag;
+}} {t};
+
+/* Creates a lexer over a copy of the input; returns NULL if it is not valid UTF-8 */
+{l} *{f}_new(const uint8_t *input, size_t len);
+
+/* Frees a lexer; NULL is ignored */
+void {f}_free({l} *lexer);
+
+/* Reads the next token into token; returns 1 when a token was read, 0 at the end of input */
+int32_t {f}_next_token({l} *lexer, {t} *token);
+
+#ifdef __cplusplus
+}}
+#endif
+
+#endif /* {c}_H */
+"#,
+        source_file = source_file,
+        c = constant_prefix,
+        f = function_prefix,
+        l = lexer_name,
+        t = token_name,
+        kind_constants = kind_constants
+    )
+}
+
+/// Result of `generate`: the code of the lexer and a summary of what was generated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GenerationResult {
+    /// Rust code of the lexer, as returned by `generate_lexer_with_options`
+    pub code: String,
+    /// Names of the token kinds in the order of their codes: `Unknown` and `Eof`, then the
+    /// other kinds in the order the spec declares them
+    pub token_names: Vec<String>,
+    /// Indexes of the rules (in `LexerSpec::rules`) matched with the regex crate at runtime
+    pub rules_needing_regex: Vec<usize>,
+    /// Messages of the problems of the spec, such as rules that can never match or
+    /// features whose `compile_error!` keeps the generated code from compiling
+    pub warnings: Vec<String>,
+    /// The problems with their severities, codes and the rules they are about, for editors
+    /// and CI tools
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl GenerationResult {
+    /// Returns true if a diagnostic is an error, so the generated lexer should not be used.
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+}
+
+/// Codes of the diagnostics that are errors: the generated lexer does not compile,
+/// panics when it is created or has a rule that can never match.
+const ERROR_CODES: [&str; 4] = ["unreachable-rule", "invalid-regex", "unknown-trivia-kind", "unsupported-feature"];
+
+/// How serious a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The generated lexer does not work as written; the command line tool does not
+    /// write it and `--check` fails
+    Error,
+    /// A likely mistake that leaves the generated lexer working
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// A problem of a spec found while generating its lexer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Whether the problem breaks the generated lexer
+    pub severity: Severity,
+    /// Kind of the problem, such as `unreachable-rule` or `invalid-regex`
+    pub code: &'static str,
+    /// Description of the problem, as in `GenerationResult::warnings`
+    pub message: String,
+    /// Where the rule the problem is about is in the spec (None for problems of the whole
+    /// spec and for rules not parsed from a specification)
+    pub span: Option<SourceSpan>,
+}
+
+impl Diagnostic {
+    pub(crate) fn new(code: &'static str, message: String) -> Self {
+        let severity = if ERROR_CODES.contains(&code) { Severity::Error } else { Severity::Warning };
+        Diagnostic { severity, code, message, span: None }
+    }
+
+    /// Locates the diagnostic at a rule.
+    pub(crate) fn at(mut self, rule: &LexerRule) -> Self {
+        self.span = (rule.span.line > 0).then_some(rule.span);
+        self
+    }
+}
+
+/// Generates the lexer like `generate_lexer_with_options` and reports what was generated.
+///
+/// Build scripts can turn the warnings into `cargo:warning` lines, and tools can inspect
+/// the token names and the rules that need the regex crate without reading the code.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::{parse_spec, generate, GeneratorOptions, Severity};
+///
+/// let spec = parse_spec("[0-9]+ -> NUMBER\n/[a-z]+(_[a-z]+)*/ -> WORD\n[0-9]+ -> INT\n").unwrap();
+/// let result = generate(&spec, "calc.klex", &GeneratorOptions::default());
+/// // Kinds are numbered in the order the spec declares them
+/// assert_eq!(result.token_names, ["Unknown", "Eof", "NUMBER", "WORD", "INT"]);
+/// // Simple character classes are matched without the regex crate
+/// assert_eq!(result.rules_needing_regex, [1]);
+/// assert_eq!(result.warnings, ["rule `[0-9]+ -> INT` can never match: rule `[0-9]+ -> NUMBER` matches the same input first"]);
+/// for warning in &result.warnings {
+///     println!("cargo:warning={}", warning);
+/// }
+///
+/// let spec = parse_spec("%token PLUS MINUS\n'+' -> PLUS\n").unwrap();
+/// let result = generate(&spec, "calc.klex", &GeneratorOptions::default());
+/// assert_eq!(result.warnings, ["%token `MINUS` is never produced by a rule or action"]);
+/// assert!(!result.has_errors());
+///
+/// // Kinds only named in action code are declared automatically, with a warning
+/// let spec = parse_spec("\"@\" -> { self.token(TokenKind::At) }").unwrap();
+/// let result = generate(&spec, "at.klex", &GeneratorOptions::default());
+/// assert!(result.code.contains("At,"));
+/// assert_eq!(result.diagnostics[0].code, "undeclared-token");
+///
+/// let spec = parse_spec("/[a-/ -> WORD\n").unwrap();
+/// let result = generate(&spec, "calc.klex", &GeneratorOptions::default());
+/// assert_eq!(result.warnings, ["rule `[a- -> WORD` has an invalid regex: unclosed character class"]);
+/// assert_eq!(result.diagnostics[0].code, "invalid-regex");
+/// assert_eq!(result.diagnostics[0].span.map(|span| span.line), Some(1));
+/// // The lexer would panic when it is created
+/// assert_eq!(result.diagnostics[0].severity, Severity::Error);
+/// assert!(result.has_errors());
+/// ```
+pub fn generate(spec: &LexerSpec, source_file: &str, options: &GeneratorOptions) -> GenerationResult {
+    let diagnostics = generation_warnings(spec, options);
+    GenerationResult {
+        code: generate_lexer_with_options(spec, source_file, options),
+        token_names: ordered_token_kinds(spec),
+        rules_needing_regex: rules_needing_regex(spec, options),
+        warnings: diagnostics.iter().map(|diagnostic| diagnostic.message.clone()).collect(),
+        diagnostics,
+    }
+}
+
+/// Returns the indexes of the rules that the generated lexer matches with the regex crate.
+fn rules_needing_regex(spec: &LexerSpec, options: &GeneratorOptions) -> Vec<usize> {
+    let rules = spec.rules.iter().enumerate();
+    if options.bytes || spec.options.bytes {
+        return rules
+            .filter(|(_, rule)| {
+                rule.action_code.is_none() && rule.context.is_empty() && !matches!(rule.pattern, RulePattern::Eof)
+            })
+            .filter(|(rule_id, rule)| bytes_rule_match_code(rule, *rule_id).1.is_some())
+            .map(|(rule_id, _)| rule_id)
+            .collect();
+    }
+    let (combined, _) = build_combined_rules(spec, options);
+    rules
+        .filter(|(rule_id, rule)| generate_rule_match_code(rule, *rule_id, &combined).1)
+        .map(|(rule_id, _)| rule_id)
+        .collect()
+}
+
+/// Returns the problems of the spec that do not stop generation.
+pub(crate) fn generation_warnings(spec: &LexerSpec, options: &GeneratorOptions) -> Vec<Diagnostic> {
+    let mut warnings = Vec::new();
+
+    // Rules after a rule with the same pattern and conditions never win
+    for (i, rule) in spec.rules.iter().enumerate() {
+        if matches!(rule.pattern, RulePattern::Eof | RulePattern::Balanced(..) | RulePattern::Heredoc(_)) {
+            continue;
+        }
+        let same_input = |earlier: &&LexerRule| {
+            !matches!(earlier.pattern, RulePattern::Eof | RulePattern::Balanced(..) | RulePattern::Heredoc(_))
+                && rule_to_regex(earlier) == rule_to_regex(rule)
+                && earlier.states == rule.states
+                && earlier.context == rule.context
+                && earlier.line_start == rule.line_start
+                && earlier.cfg == rule.cfg
+        };
+        if let Some(earlier) = spec.rules[..i].iter().find(same_input) {
+            let message = format!(
+                "rule `{} -> {}` can never match: rule `{} -> {}` matches the same input first",
+                describe_pattern(&rule.pattern),
+                rule.name,
+                describe_pattern(&earlier.pattern),
+                earlier.name
+            );
+            warnings.push(Diagnostic::new("unreachable-rule", message).at(rule));
+        }
+    }
+
+    // Regexes the regex crate rejects make the generated lexer panic when it is created
+    let bytes = options.bytes || spec.options.bytes;
+    for (rule_id, rule) in spec.rules.iter().enumerate() {
+        if matches!(rule.pattern, RulePattern::Eof | RulePattern::Balanced(..) | RulePattern::Heredoc(_)) {
+            continue;
+        }
+        let error = if bytes {
+            bytes_rule_match_code(rule, rule_id).1.and_then(|regex| regex::bytes::Regex::new(&regex).err())
+        } else {
+            regex::Regex::new(&rule_to_regex(rule)).err()
+        };
+        if let Some(error) = error {
+            // The last line of the message names the problem
+            let error = error.to_string();
+            let message = format!(
+                "rule `{} -> {}` has an invalid regex: {}",
+                describe_pattern(&rule.pattern),
+                rule.name,
+                error.lines().last().unwrap_or_default().trim().trim_start_matches("error: ")
+            );
+            warnings.push(Diagnostic::new("invalid-regex", message).at(rule));
+        }
+    }
+
+    // %trivia names that are not token kinds do not compile
+    let token_names = collect_token_names(spec);
+    for name in &spec.trivia_kinds {
+        if !token_names.contains(name) {
+            let message = format!("%trivia names `{}`, which is not a token kind", name);
+            warnings.push(Diagnostic::new("unknown-trivia-kind", message));
+        }
+    }
+
+    // Kinds only named in action code are declared automatically, but are often typos
+    let defined = defined_token_names(spec);
+    for rule in &spec.rules {
+        let Some(action_code) = &rule.action_code else {
+            continue;
+        };
+        let mut names: Vec<String> = extract_custom_tokens(action_code).into_iter().collect();
+        names.sort();
+        for name in names.into_iter().filter(|name| !defined.contains(name)) {
+            let message = format!(
+                "action code refers to TokenKind::{}, which is neither declared with %token nor defined by a rule; it is declared automatically",
+                name
+            );
+            warnings.push(Diagnostic::new("undeclared-token", message).at(rule));
+        }
+    }
+
+    // %token kinds that no rule or action produces are usually leftovers or typos
+    for name in &spec.custom_tokens {
+        let produced = spec.rules.iter().any(|rule| match &rule.action_code {
+            Some(action_code) => extract_custom_tokens(action_code).contains(name),
+            None => rule.name == *name,
+        });
+        if !produced && !spec.keywords.iter().any(|(_, keyword)| keyword == name) && spec.error_token.as_ref() != Some(name) {
+            let message = format!("%token `{}` is never produced by a rule or action", name);
+            warnings.push(Diagnostic::new("unused-token", message));
+        }
+    }
+
+    let no_std = options.no_std || spec.options.no_std;
+    let intern = options.intern || spec.options.intern;
+    if options.bytes || spec.options.bytes {
+        if let Some(feature) = bytes_unsupported_feature(spec, options) {
+            let message = format!("byte-level lexers do not support {}; the generated code does not compile", feature);
+            warnings.push(Diagnostic::new("unsupported-feature", message));
+        }
+    } else if no_std {
+        for rule_id in rules_needing_regex(spec, options) {
+            let rule = &spec.rules[rule_id];
+            let message = format!(
+                "no_std lexers cannot match the rule `{} -> {}` without the regex crate; the generated code does not compile",
+                describe_pattern(&rule.pattern),
+                rule.name
+            );
+            warnings.push(Diagnostic::new("unsupported-feature", message).at(rule));
+        }
+        if intern && !spec.options.token_offsets {
+            let message = "no_std lexers cannot intern token texts; the generated code does not compile".to_string();
+            warnings.push(Diagnostic::new("unsupported-feature", message));
+        }
+    }
+    if intern && spec.options.token_offsets {
+        let message = "%option intern has no effect with token-offsets, whose tokens hold no text".to_string();
+        warnings.push(Diagnostic::new("ineffective-option", message));
+    }
+    warnings
+}
+
+/// Generates Rust code for the lexer (optimized version with regex caching).
+///
+/// This function takes a parsed lexer specification and generates complete
+/// Rust source code that includes:
+/// - Token kind constants
+/// - A Lexer struct with caching for compiled regex patterns
+/// - Token generation logic
+/// - User-defined prefix and suffix code
+///
+/// # Arguments
+///
+/// * `spec` - The parsed lexer specification containing rules and code sections
+/// * `source_file` - The name of the source file (used for comments)
+///
+/// # Returns
+///
+/// A String containing the complete generated Rust code for the lexer.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::{parse_spec, generate_lexer};
+///
+/// let input = r#"
+/// use std::collections::HashMap;
+/// %%
+/// [0-9]+ -> NUMBER
+/// [a-zA-Z_][a-zA-Z0-9_]* -> IDENTIFIER
+/// %%
+/// fn main() { println!("Generated lexer"); }
+/// "#;
+///
+/// let spec = parse_spec(input).unwrap();
+/// let code = generate_lexer(&spec, "example.klex");
+/// // code now contains complete Rust lexer implementation
+/// ```
+pub fn generate_lexer(spec: &LexerSpec, source_file: &str) -> String {
+    generate_lexer_with_options(spec, source_file, &GeneratorOptions::default())
+}
+
+/// Generates Rust code for the lexer with the given generator options.
+///
+/// With `Backend::Dfa` (or `%option dfa` in the spec) the regex rules are compiled
+/// into DFA tables, and the generated lexer only depends on the `regex` crate if
+/// some rule still needs it (trailing context, `%heredoc` or actions using `caps`).
+/// With `no_std` (`%option no-std`) the DFA backend is used and the generated code
+/// only needs `core` and `alloc`; rules that still need the regex crate are reported
+/// with `compile_error!`.
+/// `Backend::PrecompiledDfa` (`%option precompiled-dfa`) embeds the serialized DFA
+/// instead, so the generated lexer depends on `regex-automata`. `Backend::RegexSet`
+/// (`%option regex-set`) checks all regex rules with one `RegexSet` per token.
+/// With `bytes` (`%option bytes`) the lexer reads `&[u8]` and its regexes match bytes.
+/// With `wasm` (`%option wasm`) a `wasm` module wraps the lexer in `wasm_bindgen` classes
+/// for JavaScript; it needs the `wasm-bindgen` crate. With `pyo3` (`%option pyo3`) a `python`
+/// module wraps it in PyO3 classes and a `#[pymodule]` for Python; it needs the `pyo3` crate.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::{parse_spec, generate_lexer_with_options, Backend, GeneratorOptions};
+///
+/// let spec = parse_spec("/[0-9]+(\\.[0-9]+)?/ -> NUMBER\n/[a-z][a-z0-9]*/ -> WORD\n").unwrap();
+/// let options = GeneratorOptions { backend: Backend::Dfa, ..Default::default() };
+/// let code = generate_lexer_with_options(&spec, "example.klex", &options);
+/// assert!(code.contains("fn dfa_match_lengths"));
+/// assert!(!code.contains("use regex::Regex;"));
+///
+/// let options = GeneratorOptions { wasm: true, ..Default::default() };
+/// let code = generate_lexer_with_options(&spec, "example.klex", &options);
+/// assert!(code.contains("#[wasm_bindgen(js_name = nextToken)]"));
+///
+/// let options = GeneratorOptions { pyo3: true, ..Default::default() };
+/// let code = generate_lexer_with_options(&spec, "example.klex", &options);
+/// assert!(code.contains("#[pyclass(name = \"Lexer\")]"));
+/// ```
+pub fn generate_lexer_with_options(spec: &LexerSpec, source_file: &str, options: &GeneratorOptions) -> String {
+    if options.bytes || spec.options.bytes {
+        return generate_bytes_lexer(spec, source_file, options);
+    }
+
+    // Use the embedded template
+    let template = LEXER_TEMPLATE;
+
+    let mut output = template.to_string();
+
+    // Add prefix code at the beginning
+    if !spec.prefix_code.is_empty() {
+        let prefix_with_newlines = format!("{}\n\n", spec.prefix_code);
+        output = output.replace(
+            "// This file is auto-generated.",
+            &format!("// This file is auto-generated.\n{}", prefix_with_newlines),
+        );
+    }
+
+    // Generate TokenKind enum variants, their names for Display and FromStr and their codes
+    let (token_kind_variants, kind_names_code, kind_from_name_code, kind_from_u16_code) =
+        generate_token_kind_code(spec);
+
+    // Generate LexerState enum variants for declared start conditions
+    let mut state_variants = String::new();
+    for state in &spec.states {
+        state_variants.push_str(&format!("\t{},\n", state));
+    }
+
+    // Generate TokenChannel enum variants for the channels used by rules
+    let mut channel_variants = String::new();
+    let mut declared_channels = Vec::new();
+    for channel in &spec.channels {
+        let variant = capitalize(channel);
+        if !declared_channels.contains(&variant) {
+            channel_variants.push_str(&format!("\t{},\n", variant));
+            declared_channels.push(variant);
+        }
+    }
+
+    // Generate the UserState fields declared with %state_struct
+    let user_state_fields: String = spec
+        .state_fields
+        .iter()
+        .map(|(name, field_type)| format!("\tpub {}: {},\n", name, field_type))
+        .collect();
+
+    // Generate TokenValue variants and parsing code for %type declarations
+    let mut value_variants = String::new();
+    let mut parse_value_code = String::new();
+    let mut declared_values = Vec::new();
+    for (token_name, value_type) in &spec.token_types {
+        let variant = value_variant(value_type);
+        if !declared_values.contains(&variant) {
+            value_variants.push_str(&format!("\t{}({}),\n", variant, value_type));
+            declared_values.push(variant.clone());
+        }
+        let value_code = if value_type == "String" {
+            format!("Some(TokenValue::{}(text.to_string()))", variant)
+        } else {
+            format!("text.parse::<{}>().ok().map(TokenValue::{})", value_type, variant)
+        };
+        parse_value_code.push_str(&format!(
+            "{}if *kind == TokenKind::{} {{\n\t\t\treturn {};\n\t\t}}\n\t\t",
+            cfg_attribute(token_cfg(spec, token_name).as_deref()),
+            token_name,
+            value_code
+        ));
+    }
+
+    // Build the combined matcher of the backend
+    let no_std = options.no_std || spec.options.no_std;
+    let (combined, matcher_code) = build_combined_rules(spec, options);
+    let backend = combined.backend;
+
+    // Generate regex cache code (only for patterns that need regex)
+    let mut needs_regex_crate = false;
+    let mut regex_code = String::new();
+    regex_code.push_str("        // Pre-compile patterns that require regex\n");
+    for (rule_id, rule) in spec.rules.iter().enumerate() {
+        let (_match_code, needs_regex) = generate_rule_match_code(rule, rule_id, &combined);
+        if needs_regex {
+            needs_regex_crate = true;
+            // Convert pattern to regex and escape for string literal
+            let regex_pattern = rule_to_regex(rule);
+            let escaped_pattern = regex_pattern.replace("\\", "\\\\").replace("\"", "\\\"");
+            regex_code.push_str(&format!(
+                "        regex_cache.insert({}, Regex::new(\"^{}\").unwrap());\n",
+                rule_id, escaped_pattern
+            ));
+        }
+    }
+    regex_code.push_str("        ");
+
+    // Generate rule matching code
+    let rule_codes = collect_rule_codes(spec, &combined);
+    let mut rule_match_code = String::new();
+    let setup_code = combined.setup_code("&self.input[..self.pos + remaining.len()]", "self.pos");
+    if !setup_code.is_empty() {
+        rule_match_code.push_str(&format!(
+            "        // Run the combined matcher once for all of its rules\n        {}\n\n",
+            setup_code
+        ));
+    }
+    if spec.options.longest_match {
+        rule_match_code.push_str(&generate_longest_match_code(&rule_codes));
+    } else {
+        rule_match_code.push_str(&generate_first_match_code(&rule_codes));
+    }
+
+    // Generate code that prepares the input in Lexer::new
+    let new_input_code = if spec.options.strip_bom && spec.options.borrow_input {
+        "// Remove a byte order mark (%option strip-bom)\n\t\tlet input = match input {\n\t\t\tCow::Borrowed(text) => Cow::Borrowed(text.strip_prefix('\\u{FEFF}').unwrap_or(text)),\n\t\t\tCow::Owned(text) => Cow::Owned(text.strip_prefix('\\u{FEFF}').map(str::to_string).unwrap_or(text)),\n\t\t};"
+    } else if spec.options.strip_bom {
+        "// Remove a byte order mark (%option strip-bom)\n\t\tlet input = input.strip_prefix('\\u{FEFF}').map(str::to_string).unwrap_or(input);"
+    } else {
+        ""
+    };
+
+    // Generate code that runs before each token is matched
+    let mut before_token_code = String::new();
+    if spec.options.shebang_token {
+        before_token_code.push_str(SHEBANG_TOKEN_CODE);
+    } else if spec.options.skip_shebang {
+        before_token_code.push_str(SHEBANG_CODE);
+    }
+    if spec.options.skip_whitespace {
+        before_token_code.push_str(
+            "// Skip whitespace between tokens (%option skip-whitespace)\n\t\tlet skipped: String = self.input[self.pos..].chars().take_while(|c| c.is_whitespace()).collect();\n\t\tself.advance(&skipped);",
+        );
+    }
+    if spec.options.indentation_tokens {
+        before_token_code.push_str(INDENTATION_CODE);
+    }
+
+    // Generate the check used to find the end of unmatched input
+    let mut any_rule_matches_code = String::new();
+    let setup_code = combined.setup_code("&self.input", "self.input.len() - remaining.len()");
+    if !setup_code.is_empty() {
+        any_rule_matches_code.push_str(&format!("{}\n\t\t", setup_code));
+    }
+    any_rule_matches_code.push_str(&generate_any_rule_matches_code(&rule_codes));
+
+    // Generate end of input code
+    let eof_code = generate_eof_code(spec);
+
+    // Store byte offsets instead of the text in tokens (%option token-offsets)
+    if spec.options.token_offsets {
+        for (owned, offsets) in TOKEN_OFFSETS_REPLACEMENTS {
+            output = output.replace(owned, offsets);
+        }
+    }
+
+    if spec.options.borrow_input {
+        for (owned, borrowed) in BORROW_INPUT_REPLACEMENTS {
+            output = output.replace(owned, borrowed);
+        }
+    }
+
+    // Share the texts of the tokens (%option intern); tokens with offsets have no text
+    let intern = (options.intern || spec.options.intern) && !spec.options.token_offsets;
+    if intern {
+        for (owned, interned) in INTERN_REPLACEMENTS {
+            output = output.replace(owned, interned);
+        }
+    }
+
+    // Import the alloc types for no_std lexers
+    let mut imports_code = String::new();
+    if intern && !no_std {
+        imports_code.push_str(INTERN_IMPORTS);
+    }
+    if spec.options.borrow_input {
+        imports_code.push_str(if no_std { "use alloc::borrow::Cow;\n" } else { "use std::borrow::Cow;\n" });
+    }
+    if no_std {
+        imports_code.push_str(NO_STD_IMPORTS);
+        if let Some((_, rule)) = spec.rules.iter().enumerate().find(|(rule_id, rule)| {
+            generate_rule_match_code(rule, *rule_id, &combined).1
+        }) {
+            imports_code.push_str(&format!(
+                "compile_error!({:?});\n",
+                format!(
+                    "no_std lexers cannot match the rule `{} -> {}` without the regex crate",
+                    describe_pattern(&rule.pattern),
+                    rule.name
+                )
+            ));
+        }
+        if intern {
+            imports_code.push_str("compile_error!(\"no_std lexers cannot intern token texts without std::collections::HashSet\");\n");
+        }
+    }
+
+    // Replace markers with generated code
+    output = output.replace("//----<IMPORTS>----\n", &imports_code);
+    output = output.replace(
+        "//----<GENERATED_BY>----",
+        &format!("// Generated from: {}", source_file),
+    );
+    output = output.replace("//----<TOKEN_KIND>----", &token_kind_variants);
+    output = output.replace("//----<LEXER_STATE>----", &state_variants);
+    output = output.replace("//----<TOKEN_CHANNEL>----", &channel_variants);
+    output = output.replace("//----<TOKEN_VALUE>----", &value_variants);
+    output = output.replace("//----<USER_STATE>----", &user_state_fields);
+    output = output.replace("//----<PARSE_VALUE>----", &parse_value_code);
+    output = output.replace("//----<REG_EX_CODE>----", &regex_code);
+    output = output.replace("//----<NEW_INPUT_CODE>----", new_input_code);
+    output = output.replace("//----<RULE_MATCH_CODE>----", &rule_match_code);
+    output = output.replace("//----<BEFORE_TOKEN_CODE>----", &before_token_code);
+    output = output.replace("//----<EOF_CODE>----", &eof_code);
+    output = output.replace("//----<ANY_RULE_MATCHES>----", &any_rule_matches_code);
+    output = output.replace("//----<KEYWORD_METHOD>----\n", &generate_keyword_method(spec));
+    output = output.replace("//----<MATCHER_CODE>----\n", &matcher_code);
+    // Without regex rules the DFA backends drop the regex cache and its imports
+    output = strip_regions(&output, "REGEX", needs_regex_crate || matches!(backend, Backend::Regex | Backend::RegexSet));
+    // Parts that need std, such as the deadline, are left out of no_std lexers
+    output = strip_regions(&output, "STD", !no_std);
+    let history_len = token_history_len(spec);
+    let checks_token_text = spec.rules.iter().flat_map(|rule| &rule.context).any(|position| position.text.is_some());
+    output = strip_regions(&output, "LAST_TOKEN_TEXT", checks_token_text);
+    if checks_token_text && spec.options.token_offsets {
+        output = output.replace("self.last_token_text.push_str(&token.text);", "self.last_token_text.push_str(token.text(&self.input));");
+    }
+    if history_len > 0 {
+        output = output.replace("[Option<TokenKind>; 0]", &format!("[Option<TokenKind>; {}]", history_len));
+        output = output.replace("earlier_token_kinds: [None; 0]", &format!("earlier_token_kinds: [None; {}]", history_len));
+    }
+    if !spec.states.is_empty() || !spec.state_fields.is_empty() || spec.options.indentation_tokens || history_len > 0 || checks_token_text {
+        // Lexer::relex cannot tell whether the lexer is in the same state at an old token with these
+        output = output.replace("const RELEX_REUSES_TOKENS: bool = true;", "const RELEX_REUSES_TOKENS: bool = false;");
+    }
+    output = output.replace("//----<TOKEN_KIND_NAMES>----\n", &kind_names_code);
+    output = output.replace("//----<TOKEN_KIND_FROM_NAME>----\n", &kind_from_name_code);
+    output = output.replace("//----<TOKEN_KIND_FROM_U16>----\n", &kind_from_u16_code);
+    output = output.replace("//----<TOKEN_INFOS>----\n", &generate_token_infos(spec));
+    output = output.replace("//----<LEXER_DOC>----\n", &generate_lexer_doc(spec));
+    output = output.replace("//----<TRIVIA_KINDS>----\n\t\tfalse", &generate_trivia_code(spec));
+    output = output.replace("//----<TOKEN_METHODS>----\n", "");
+    finish_lexer(spec, options, output)
+}
+
+/// Applies the runtime options, the visibility, the code sections and the names
+/// shared by all templates to a lexer whose markers have been replaced, and lays it out.
+fn finish_lexer(spec: &LexerSpec, options: &GeneratorOptions, mut output: String) -> String {
+    if let Some(error_token) = &spec.error_token {
+        // %error turns unmatched input into error tokens
+        output = output.replace(
+            "error_kind: None,",
+            &format!("error_kind: Some(TokenKind::{}),", error_token),
+        );
+    }
+    if spec.options.recover || spec.error_token.is_some() {
+        // Unmatched input is coalesced up to the next position where a rule matches
+        output = output.replace("recover: false,", "recover: true,");
+    }
+    if spec.options.emit_eof {
+        // %option emit-eof turns on the final Eof token
+        output = output.replace("emit_eof: false,", "emit_eof: true,");
+    }
+    if spec.options.trivia {
+        // Tokens on other channels become trivia of the tokens around them (%option trivia)
+        output = output.replace("attach_trivia: false,", "attach_trivia: true,");
+    }
+    if spec.options.utf16_columns {
+        // Columns count UTF-16 code units (%option utf16-columns)
+        output = output.replace("utf16_columns: false,", "utf16_columns: true,");
+    }
+    if let Some(tab_width) = spec.options.tab_width {
+        // %option tab-width moves columns after a tab to the next tab stop
+        output = output.replace("tab_width: 1,", &format!("tab_width: {},", tab_width));
+    }
+    if let Some(max) = spec.options.max_token_length {
+        // Longer tokens stop the lexer with a LimitError (%option max-token-length)
+        output = output.replace("max_token_length: None,", &format!("max_token_length: Some({}),", max));
+    }
+    if let Some(max) = spec.options.max_tokens {
+        // Further tokens stop the lexer with a LimitError (%option max-tokens)
+        output = output.replace("max_tokens: None,", &format!("max_tokens: Some({}),", max));
+    }
+    if spec.options.length_chars {
+        // Token lengths count characters (%option length-chars)
+        output = output.replace(
+            "pub fn text_length(text: &str) -> usize {\n\t\ttext.len()",
+            "pub fn text_length(text: &str) -> usize {\n\t\ttext.chars().count()",
+        );
+    }
+    // Tokens carry both lengths with %option token-lengths
+    output = strip_regions(&output, "TOKEN_LENGTHS", spec.options.token_lengths);
+    // Lexers with %option result-errors iterate over Result values
+    output = strip_regions(&output, "RESULT_ERRORS", spec.options.result_errors);
+    if options.visibility != Visibility::Public {
+        // Only the top-level declarations of the template; the code sections are the user's.
+        // Unused parts of a lexer that is not public would otherwise be reported as dead code
+        let keyword = options.visibility.keyword();
+        output = output.replace("\npub struct ", &format!("\n#[allow(dead_code)]\n{}struct ", keyword));
+        output = output.replace("\npub enum ", &format!("\n#[allow(dead_code)]\n{}enum ", keyword));
+        output = output.replace("\npub const ", &format!("\n#[allow(dead_code)]\n{}const ", keyword));
+        output = output.replace("\nimpl ", "\n#[allow(dead_code)]\nimpl ");
+        output = output.replace("\nimpl<", "\n#[allow(dead_code)]\nimpl<");
+    }
+    if (spec.options.ffi || options.ffi) && !(spec.options.bytes || options.bytes) {
+        // C functions over the lexer (%option ffi), declared by generate_c_header
+        output.push_str(&generate_ffi_code(spec, options));
+    }
+    if (spec.options.wasm || options.wasm) && !(spec.options.bytes || options.bytes) {
+        // JavaScript classes wrapping the lexer (%option wasm)
+        output.push_str(&generate_wasm_code(spec));
+    }
+    if (spec.options.pyo3 || options.pyo3) && !(spec.options.bytes || options.bytes) {
+        // Python classes wrapping the lexer (%option pyo3)
+        output.push_str(&generate_pyo3_code(spec, options));
+    }
+
+    // Add suffix code
+    if !spec.suffix_code.is_empty() {
+        output.push_str(&format!("\n{}\n", spec.suffix_code));
+    }
+    output.push_str(&generate_spec_tests(spec));
+
+    // Rename the generated items for %prefix and the configured names
+    let custom_names = [
+        ("Lexer", &options.lexer_name),
+        ("Token", &options.token_name),
+        ("TokenKind", &options.token_kind_name),
+    ];
+    if spec.name_prefix.is_some() || custom_names.iter().any(|(_, name)| name.is_some()) {
+        let prefix = spec.name_prefix.as_deref().unwrap_or("");
+        output = rename_items(&output, |word| {
+            if let Some((_, Some(name))) = custom_names.iter().find(|(item, _)| *item == word) {
+                return Some(name.clone());
+            }
+            if word == "TOKENS" {
+                // The table is a constant, so it gets the prefix in upper snake case
+                return (!prefix.is_empty()).then(|| format!("{}_TOKENS", snake_case(prefix).to_uppercase()));
+            }
+            PREFIXED_ITEMS.contains(&word).then(|| format!("{}{}", prefix, word))
+        });
+    }
+
+    crate::pretty::pretty_print(&output)
 }
@@ -0,0 +1,278 @@
+//! Graphviz export of the structure of a lexer.
+//!
+//! `generate_graph` draws the start conditions of a spec with the rules active in each
+//! of them, numbered in the order the generated lexer tries them, so it shows which rule
+//! wins when several match. Context rules are linked to the previous token kinds they
+//! depend on, and action code switching start conditions (`LexerState::X`) is drawn as
+//! an edge to that state, so unreachable states stand out. With the DFA backends the
+//! automaton the regex rules are compiled into is drawn as well.
+
+use crate::dfa::{compile_tables, DfaTables};
+use crate::generator::{dfa_candidates, effective_backend, extract_action_kind, rule_to_regex, Backend, GeneratorOptions};
+use crate::parser::{is_initial_state, LexerRule, LexerSpec, RulePattern};
+
+/// Byte labels of the DFA edges beyond which the rest is elided.
+const MAX_EDGE_RANGES: usize = 6;
+
+/// Names of the DFA start states by the byte before the match, as in `LOOK_BEHIND`.
+const START_NAMES: [&str; 5] = ["start", "after \\n", "after \\r", "after word byte", "after other byte"];
+
+/// Quotes a string as a DOT identifier or label.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns the node id of a start condition; `INITIAL` and `Initial` are the same node.
+fn state_node(state: &str) -> String {
+    if is_initial_state(state) {
+        quote("state:INITIAL")
+    } else {
+        quote(&format!("state:{}", state))
+    }
+}
+
+/// Returns what a rule produces: its token kind, `%skip`, or `{ KIND }` for action code.
+fn rule_target(rule: &LexerRule) -> String {
+    match &rule.action_code {
+        _ if rule.skip => "%skip".to_string(),
+        Some(action_code) => format!("{{ {} }}", extract_action_kind(action_code).as_deref().unwrap_or("...")),
+        None => rule.name.clone(),
+    }
+}
+
+/// Describes a rule for its node: `[0-9]+ -> NUMBER`, `/\* -> { COMMENT }` or `\s+ -> %skip`.
+fn rule_label(rule: &LexerRule) -> String {
+    let pattern = match &rule.pattern {
+        RulePattern::Eof => "<<EOF>>".to_string(),
+        RulePattern::Balanced(open, close) => format!("%balanced {} {}", open, close),
+        RulePattern::Heredoc(opening) => format!("%heredoc {}", opening),
+        _ => rule_to_regex(rule),
+    };
+    // Control characters of the pattern are shown escaped
+    let pattern: String = pattern
+        .chars()
+        .map(|c| if c.is_control() { c.escape_default().to_string() } else { c.to_string() })
+        .collect();
+    let mut label = format!("{}{} -> {}", if rule.line_start { "^" } else { "" }, pattern, rule_target(rule));
+    if let Some(channel) = &rule.channel {
+        label.push_str(&format!(" @{}", channel));
+    }
+    if let Some(cfg) = &rule.cfg {
+        label.push_str(&format!("\n%cfg({})", cfg));
+    }
+    label
+}
+
+/// Returns the start conditions named in action code (`LexerState::X`).
+fn action_states(action_code: &str) -> Vec<String> {
+    let pattern = "LexerState::";
+    let mut states: Vec<String> = Vec::new();
+    for (i, _) in action_code.match_indices(pattern) {
+        let remaining = &action_code[i + pattern.len()..];
+        let end = remaining.chars().take_while(|c| c.is_alphanumeric() || *c == '_').count();
+        let state = remaining[..end].to_string();
+        if end > 0 && !states.contains(&state) {
+            states.push(state);
+        }
+    }
+    states
+}
+
+/// Formats a byte for a DFA edge label.
+fn byte_label(byte: usize) -> String {
+    match byte {
+        0x21..=0x7e => (byte as u8 as char).to_string(),
+        _ => format!("\\x{:02X}", byte),
+    }
+}
+
+/// Writes the automaton of the DFA backends as a cluster.
+fn write_dfa(out: &mut String, spec: &LexerSpec, tables: &DfaTables) {
+    out.push_str("\n    subgraph cluster_dfa {\n        label=\"DFA\";\n");
+    out.push_str("        \"dfa:start\" [shape=point];\n");
+    let state_count = tables.eoi.len();
+    for state in 1..state_count {
+        let names: Vec<String> = tables.matches[state].iter().map(|&rule_id| rule_target(&spec.rules[rule_id])).collect();
+        if names.is_empty() {
+            out.push_str(&format!("        \"dfa:{}\" [shape=circle, label=\"{}\"];\n", state, state));
+        } else {
+            // The match of a DFA state ends one byte before entering it
+            let label = format!("{}\nmatched: {}", state, names.join(", "));
+            out.push_str(&format!("        \"dfa:{}\" [shape=doublecircle, label={}];\n", state, quote(&label)));
+        }
+    }
+
+    let mut starts: Vec<(usize, Vec<&str>)> = Vec::new();
+    for (&state, name) in tables.starts.iter().zip(START_NAMES) {
+        match starts.iter_mut().find(|(start, _)| *start == state) {
+            Some((_, names)) => names.push(name),
+            None => starts.push((state, vec![name])),
+        }
+    }
+    for (state, names) in starts.iter().filter(|(state, _)| *state != 0) {
+        out.push_str(&format!("        \"dfa:start\" -> \"dfa:{}\" [label={}];\n", state, quote(&names.join(", "))));
+    }
+
+    for state in 1..state_count {
+        let next = |byte: usize| tables.transitions[state * tables.class_count + tables.classes[byte]];
+        // Targets in the order they are first reached, with their byte ranges
+        let mut edges: Vec<(usize, Vec<(usize, usize)>)> = Vec::new();
+        for byte in 0..256 {
+            let target = next(byte);
+            if target == 0 {
+                continue;
+            }
+            let ranges = match edges.iter_mut().find(|(other, _)| *other == target) {
+                Some((_, ranges)) => ranges,
+                None => {
+                    edges.push((target, Vec::new()));
+                    &mut edges.last_mut().expect("edge was pushed").1
+                }
+            };
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == byte => *end = byte,
+                _ => ranges.push((byte, byte)),
+            }
+        }
+        for (target, ranges) in &edges {
+            let mut labels: Vec<String> = ranges
+                .iter()
+                .take(MAX_EDGE_RANGES)
+                .map(|&(start, end)| match end - start {
+                    0 => byte_label(start),
+                    1 => format!("{} {}", byte_label(start), byte_label(end)),
+                    _ => format!("{}-{}", byte_label(start), byte_label(end)),
+                })
+                .collect();
+            if ranges.len() > MAX_EDGE_RANGES {
+                labels.push("...".to_string());
+            }
+            out.push_str(&format!(
+                "        \"dfa:{}\" -> \"dfa:{}\" [label={}];\n",
+                state,
+                target,
+                quote(&labels.join(" "))
+            ));
+        }
+        if tables.eoi[state] != 0 {
+            out.push_str(&format!(
+                "        \"dfa:{}\" -> \"dfa:{}\" [label=\"EOI\", style=dashed];\n",
+                state, tables.eoi[state]
+            ));
+        }
+    }
+    out.push_str("    }\n");
+}
+
+/// Generates a Graphviz (DOT) diagram of the structure of the lexer of a spec.
+///
+/// Start conditions are ellipses linked to the rules active in them, numbered in the
+/// order the lexer tries them. Context rules get dashed edges from the previous token
+/// kinds they check, and action code naming a start condition gets a dotted edge to it.
+/// When the lexer is generated with the DFA backends (`options.backend`, or the
+/// `%option`s of the spec), the states and transitions of the DFA are drawn too.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::{generate_graph, parse_spec, GeneratorOptions};
+///
+/// let spec = parse_spec("%xstate STR\n'\"' -> { self.begin(LexerState::STR); None }\n<STR> [^\"]+ -> TEXT\n").unwrap();
+/// let dot = generate_graph(&spec, &GeneratorOptions::default());
+/// assert!(dot.starts_with("digraph lexer {"));
+/// assert!(dot.contains(r#""state:INITIAL" -> "rule:0" [label="1"];"#));
+/// assert!(dot.contains(r#""rule:0" -> "state:STR" [style=dotted];"#));
+/// ```
+pub fn generate_graph(spec: &LexerSpec, options: &GeneratorOptions) -> String {
+    let mut out = String::from("digraph lexer {\n    rankdir=LR;\n    node [fontname=\"monospace\"];\n\n");
+
+    // Start conditions
+    out.push_str("    \"state:INITIAL\" [shape=ellipse, style=bold, label=\"INITIAL\"];\n");
+    for state in &spec.states {
+        let label = if spec.exclusive_states.contains(state) { format!("{} (exclusive)", state) } else { state.clone() };
+        out.push_str(&format!("    {} [shape=ellipse, label={}];\n", state_node(state), quote(&label)));
+    }
+
+    // Rules, in the order they are tried: context rules, then action rules, then the others
+    let is_eof = |rule: &LexerRule| matches!(rule.pattern, RulePattern::Eof);
+    let rules = spec.rules.iter().enumerate();
+    let order = rules
+        .clone()
+        .filter(|(_, rule)| !is_eof(rule) && !rule.context.is_empty())
+        .chain(rules.clone().filter(|(_, rule)| !is_eof(rule) && rule.context.is_empty() && rule.action_code.is_some()))
+        .chain(rules.clone().filter(|(_, rule)| !is_eof(rule) && rule.context.is_empty() && rule.action_code.is_none()))
+        .chain(rules.filter(|(_, rule)| is_eof(rule)));
+    let initial = "INITIAL".to_string();
+    let all_states: Vec<&String> = std::iter::once(&initial).chain(&spec.states).collect();
+    let mut tried = vec![0; all_states.len()];
+    let mut kinds: Vec<&str> = Vec::new();
+    let mut rule_code = String::new();
+    let mut edge_code = String::new();
+    for (rule_id, rule) in order {
+        let node = quote(&format!("rule:{}", rule_id));
+        let style = if rule.skip { ", style=dashed" } else { "" };
+        rule_code.push_str(&format!("    {} [shape=box{}, label={}];\n", node, style, quote(&rule_label(rule))));
+        for (i, state) in all_states.iter().enumerate() {
+            let active = if rule.states.is_empty() {
+                !spec.exclusive_states.contains(state)
+            } else {
+                rule.states.iter().any(|name| name == *state || (is_initial_state(name) && i == 0))
+            };
+            if active {
+                tried[i] += 1;
+                edge_code.push_str(&format!("    {} -> {} [label=\"{}\"];\n", state_node(state), node, tried[i]));
+            }
+        }
+        for (age, position) in rule.context.iter().rev().enumerate() {
+            let label = match (&position.text, age) {
+                (Some(text), _) => format!("previous {:?}", text),
+                (None, 0) => "previous".to_string(),
+                (None, age) => format!("previous -{}", age),
+            };
+            for kind in &position.kinds {
+                if !kinds.contains(&kind.as_str()) {
+                    kinds.push(kind);
+                }
+                edge_code.push_str(&format!(
+                    "    {} -> {} [style=dashed, label={}];\n",
+                    quote(&format!("kind:{}", kind)),
+                    node,
+                    quote(&label)
+                ));
+            }
+        }
+        for state in rule.action_code.as_deref().map(action_states).unwrap_or_default() {
+            if is_initial_state(&state) || spec.states.contains(&state) {
+                edge_code.push_str(&format!("    {} -> {} [style=dotted];\n", node, state_node(&state)));
+            }
+        }
+    }
+    out.push('\n');
+    out.push_str(&rule_code);
+    if !kinds.is_empty() {
+        out.push('\n');
+        for kind in &kinds {
+            out.push_str(&format!("    {} [shape=note, label={}];\n", quote(&format!("kind:{}", kind)), quote(kind)));
+        }
+    }
+    out.push('\n');
+    out.push_str(&edge_code);
+
+    if matches!(effective_backend(spec, options), Backend::Dfa | Backend::PrecompiledDfa) {
+        if let Some(tables) = compile_tables(&dfa_candidates(spec)) {
+            write_dfa(&mut out, spec, &tables);
+        }
+    }
+    out.push_str("}\n");
+    out
+}
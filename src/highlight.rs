@@ -0,0 +1,119 @@
+//! Terminal syntax highlighting with the rules of a spec.
+//!
+//! `LexerSpec::highlight` tokenizes a sample input with [`LexerSpec::interpret`] and
+//! wraps every token in the ANSI escape codes of its kind, so grammar authors see at a
+//! glance how their rules segment real input. The styles of a kind are given with
+//! `%style KIND word...`; other kinds get colors of a fixed palette in the order the
+//! spec names them, trivia kinds are dimmed and unmatched input is shown in reverse red.
+
+use crate::generator::extract_action_kind;
+use crate::parser::{LexerSpec, ParseError};
+
+/// Colors given to the kinds without a `%style`, in turn.
+const PALETTE: [&str; 6] = ["cyan", "green", "yellow", "blue", "magenta", "bright-red"];
+
+/// Names of the ANSI colors, in the order of their codes (30-37).
+const COLORS: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+/// Returns the ANSI code of a style word: `bold`, `dim`, `italic`, `underline`,
+/// `reverse`, a color such as `red`, or a bright color such as `bright-red`.
+pub(crate) fn style_code(word: &str) -> Option<u8> {
+    let color = |name: &str| COLORS.iter().position(|color| *color == name).map(|i| i as u8);
+    match word {
+        "bold" => Some(1),
+        "dim" => Some(2),
+        "italic" => Some(3),
+        "underline" => Some(4),
+        "reverse" => Some(7),
+        _ => match word.strip_prefix("bright-") {
+            Some(name) => color(name).map(|i| 90 + i),
+            None => color(word).map(|i| 30 + i),
+        },
+    }
+}
+
+/// Returns the escape sequence setting the styles of a space-separated list of style words.
+fn escape_sequence(styles: &str) -> String {
+    let codes: Vec<String> = styles.split_whitespace().filter_map(style_code).map(|code| code.to_string()).collect();
+    format!("\x1b[{}m", codes.join(";"))
+}
+
+impl LexerSpec {
+    /// Returns the styles of a token kind: its `%style`, or the default for the kind.
+    fn kind_style(&self, kind: &str, palette_kinds: &mut Vec<String>) -> String {
+        if let Some((_, styles)) = self.styles.iter().find(|(name, _)| name == kind) {
+            return styles.clone();
+        }
+        if kind == "Unknown" || self.error_token.as_deref() == Some(kind) {
+            return "reverse red".to_string();
+        }
+        if self.trivia_kinds.iter().any(|name| name == kind) {
+            return "dim".to_string();
+        }
+        let index = match palette_kinds.iter().position(|name| name == kind) {
+            Some(index) => index,
+            None => {
+                palette_kinds.push(kind.to_string());
+                palette_kinds.len() - 1
+            }
+        };
+        PALETTE[index % PALETTE.len()].to_string()
+    }
+
+    /// Tokenizes `input` like [`LexerSpec::interpret`] and returns it with the text of
+    /// every token wrapped in the ANSI escape codes of its kind.
+    ///
+    /// Text between tokens, such as skipped whitespace, is left as it is.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use klex::parse_spec;
+    ///
+    /// let spec = parse_spec("%style NUMBER bold yellow\n[0-9]+ -> NUMBER\n'+' -> PLUS\n[ ]+ -> %skip\n").unwrap();
+    /// let highlighted = spec.highlight("1 + 2").unwrap();
+    /// assert_eq!(highlighted, "\x1b[1;33m1\x1b[0m \x1b[36m+\x1b[0m \x1b[1;33m2\x1b[0m");
+    /// ```
+    pub fn highlight(&self, input: &str) -> Result<String, ParseError> {
+        let tokens = self.interpret(input)?;
+        let input = if self.options.strip_bom { input.strip_prefix('\u{FEFF}').unwrap_or(input) } else { input };
+
+        // Kinds take the palette colors in the order the spec names them
+        let mut palette_kinds: Vec<String> = Vec::new();
+        for rule in &self.rules {
+            let kind = match &rule.action_code {
+                _ if rule.skip => None,
+                Some(action_code) => extract_action_kind(action_code),
+                None => Some(rule.name.clone()),
+            };
+            if let Some(kind) = kind {
+                self.kind_style(&kind, &mut palette_kinds);
+            }
+        }
+
+        let mut out = String::with_capacity(input.len() * 2);
+        let mut pos = 0;
+        for token in &tokens {
+            if token.index < pos || token.text.is_empty() {
+                continue;
+            }
+            out.push_str(&input[pos..token.index]);
+            let styles = self.kind_style(&token.kind, &mut palette_kinds);
+            // Styles are reset at line breaks so a multi-line token stays readable in pagers
+            let lines: Vec<&str> = token.text.split('\n').collect();
+            for (i, line) in lines.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                }
+                if !line.is_empty() {
+                    out.push_str(&escape_sequence(&styles));
+                    out.push_str(line);
+                    out.push_str("\x1b[0m");
+                }
+            }
+            pos = token.index + token.text.len();
+        }
+        out.push_str(&input[pos..]);
+        Ok(out)
+    }
+}
@@ -0,0 +1,482 @@
+//! In-process interpretation of lexer specifications.
+//!
+//! `LexerSpec::interpret` tokenizes a sample input with the rules of a spec directly,
+//! without generating and compiling the lexer, so grammar authors can see what their
+//! rules produce. Rules are tried in the order of the generated lexer: context rules,
+//! then action rules, then the other rules, and the first rule that matches wins (the
+//! longest match with `%option longest-match`). Runs of fixed-string rules are looked
+//! up by length like in the generated code, so the longest literal wins among them.
+//!
+//! Action code is not run. An action rule produces a token of the first kind its code
+//! names (`TokenKind::X`), or no token when it names none, and its calls of `begin`,
+//! `push_state` and `pop_state` change the start condition in the order they are written.
+//! `%cfg` rules are all enabled, and `%option indentation-tokens` is not interpreted.
+
+use crate::generator::{
+    extract_action_kind, is_identifier_rule, rule_literal, rule_to_regex, state_variant, updates_context,
+};
+use crate::lexer::Lexer;
+use crate::parser::{LexerRule, LexerSpec, ParseError, RulePattern};
+use regex::Regex;
+use std::str::FromStr;
+
+/// A token read by [`LexerSpec::interpret`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleToken {
+    /// Name of the token kind; `Unknown` (or the `%error` token) for unmatched input
+    pub kind: String,
+    /// Matched text
+    pub text: String,
+    /// Value parsed from the text, for tokens with a `%type` of a primitive type
+    pub value: Option<String>,
+    /// Byte offset of the token in the input
+    pub index: usize,
+    /// Line of the token (1-based)
+    pub row: usize,
+    /// Column of the token (1-based)
+    pub col: usize,
+    /// Channel of the token (None = default channel)
+    pub channel: Option<String>,
+}
+
+/// How a rule matches the start of the remaining input.
+enum Matcher {
+    /// Fixed text
+    Literal(String),
+    /// Anchored regex; with trailing context, the token is capture group 1
+    Regex(Regex, bool),
+    /// Nested delimiters (%balanced)
+    Balanced(String, String),
+    /// Opening regex whose first group (or whole match) is the terminator (%heredoc)
+    Heredoc(Regex),
+}
+
+impl Matcher {
+    fn new(rule: &LexerRule) -> Result<Matcher, ParseError> {
+        let anchored = |regex: &str| {
+            Regex::new(&format!("^(?:{})", regex)).map_err(|error| {
+                let error = error.to_string();
+                ParseError::new(format!(
+                    "Rule `-> {}` has an invalid regex: {}",
+                    rule.name,
+                    error.lines().last().unwrap_or_default().trim().trim_start_matches("error: ")
+                ))
+            })
+        };
+        Ok(match &rule.pattern {
+            RulePattern::Balanced(open, close) => Matcher::Balanced(open.clone(), close.clone()),
+            RulePattern::Heredoc(opening) => Matcher::Heredoc(anchored(opening)?),
+            _ => match rule_literal(rule) {
+                Some(literal) => Matcher::Literal(literal),
+                None => Matcher::Regex(anchored(&rule_to_regex(rule))?, rule.trailing_context.is_some()),
+            },
+        })
+    }
+
+    /// Returns the length of the match at the start of `remaining`.
+    fn match_len(&self, remaining: &str) -> Option<usize> {
+        let len = match self {
+            Matcher::Literal(literal) => remaining.starts_with(literal.as_str()).then_some(literal.len())?,
+            Matcher::Regex(regex, trailing) => {
+                let caps = regex.captures(remaining)?;
+                caps.get(if *trailing { 1 } else { 0 })?.end()
+            }
+            // The generated lexer's own matchers
+            Matcher::Balanced(open, close) => Lexer::match_balanced(remaining, open, close)?.len(),
+            Matcher::Heredoc(opening) => Lexer::match_heredoc_regex(opening, remaining)?.len(),
+        };
+        (len > 0).then_some(len)
+    }
+}
+
+/// Parses the value of a token with a `%type` of a primitive type, as the generated
+/// `Token::parse_value` does: `None` if the text is not a value of the type.
+fn parse_value(value_type: &str, text: &str) -> Option<String> {
+    fn parse<T: FromStr + ToString>(text: &str) -> Option<String> {
+        text.parse::<T>().ok().map(|value| value.to_string())
+    }
+    match value_type {
+        "String" | "&str" => Some(format!("{:?}", text)),
+        "i8" => parse::<i8>(text),
+        "i16" => parse::<i16>(text),
+        "i32" => parse::<i32>(text),
+        "i64" => parse::<i64>(text),
+        "i128" => parse::<i128>(text),
+        "isize" => parse::<isize>(text),
+        "u8" => parse::<u8>(text),
+        "u16" => parse::<u16>(text),
+        "u32" => parse::<u32>(text),
+        "u64" => parse::<u64>(text),
+        "u128" => parse::<u128>(text),
+        "usize" => parse::<usize>(text),
+        "f32" => parse::<f32>(text),
+        "f64" => parse::<f64>(text),
+        "bool" => parse::<bool>(text),
+        "char" => text.parse::<char>().ok().map(|c| format!("{:?}", c)),
+        _ => None,
+    }
+}
+
+/// A change of the start condition made by action code.
+enum StateChange {
+    Begin(String),
+    Push(String),
+    Pop,
+}
+
+/// Returns the calls of `begin(LexerState::X)`, `push_state(LexerState::X)` and
+/// `pop_state()` in action code, in the order they are written.
+fn state_changes(action_code: &str) -> Vec<StateChange> {
+    let mut changes = Vec::new();
+    let mut rest = action_code;
+    let calls = [".begin(", ".push_state(", ".pop_state("];
+    while let Some((at, call)) = calls.iter().filter_map(|call| rest.find(call).map(|at| (at, *call))).min() {
+        rest = &rest[at + call.len()..];
+        // The argument names the state as `LexerState::X` (or with the %prefix of the lexer)
+        let argument = &rest[..rest.find(')').unwrap_or(rest.len())];
+        let state = argument.rsplit_once("State::").map(|(_, name)| {
+            name.chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect::<String>()
+        });
+        match (call, state) {
+            (".pop_state(", _) => changes.push(StateChange::Pop),
+            (".begin(", Some(state)) => changes.push(StateChange::Begin(state)),
+            (".push_state(", Some(state)) => changes.push(StateChange::Push(state)),
+            _ => {}
+        }
+    }
+    changes
+}
+
+/// A rule with its matcher, in matching order.
+struct Candidate<'a> {
+    rule: &'a LexerRule,
+    matcher: Matcher,
+    /// Fixed text of a rule that can be looked up with other literals
+    literal: bool,
+}
+
+/// Splits the candidates into runs matched one after another, like the generated code:
+/// runs of consecutive literal rules under the same conditions, and single rules.
+fn group_candidates<'a, 'b>(candidates: &'b [Candidate<'a>]) -> Vec<&'b [Candidate<'a>]> {
+    let same_conditions = |a: &LexerRule, b: &LexerRule| a.states == b.states && a.line_start == b.line_start;
+    let mut groups = Vec::new();
+    let mut start = 0;
+    while start < candidates.len() {
+        let mut end = start + 1;
+        if candidates[start].literal {
+            while end < candidates.len()
+                && candidates[end].literal
+                && same_conditions(candidates[end].rule, candidates[start].rule)
+            {
+                end += 1;
+            }
+        }
+        groups.push(&candidates[start..end]);
+        start = end;
+    }
+    groups
+}
+
+/// State of an interpretation run.
+struct Interpreter<'a> {
+    spec: &'a LexerSpec,
+    input: &'a str,
+    pos: usize,
+    row: usize,
+    col: usize,
+    /// Kinds of the tokens that update the context, newest last
+    previous_kinds: Vec<String>,
+    last_text: String,
+    /// `LexerState` variant of the start condition, and the ones saved by `push_state`
+    state: String,
+    state_stack: Vec<String>,
+    tokens: Vec<SampleToken>,
+}
+
+impl<'a> Interpreter<'a> {
+    /// Copies the position and context, without the tokens read so far.
+    fn snapshot(&self) -> Interpreter<'a> {
+        Interpreter {
+            spec: self.spec,
+            input: self.input,
+            pos: self.pos,
+            row: self.row,
+            col: self.col,
+            previous_kinds: self.previous_kinds.clone(),
+            last_text: self.last_text.clone(),
+            state: self.state.clone(),
+            state_stack: self.state_stack.clone(),
+            tokens: Vec::new(),
+        }
+    }
+
+    /// Moves past `len` bytes, counting columns like the generated lexer.
+    fn advance(&mut self, len: usize) {
+        let tab_width = self.spec.options.tab_width.unwrap_or(1).max(1);
+        for ch in self.input[self.pos..self.pos + len].chars() {
+            if ch == '\n' {
+                self.row += 1;
+                self.col = 1;
+            } else if ch == '\t' {
+                self.col = (self.col - 1) / tab_width * tab_width + tab_width + 1;
+            } else if self.spec.options.utf16_columns {
+                self.col += ch.len_utf16();
+            } else {
+                self.col += 1;
+            }
+        }
+        self.pos += len;
+    }
+
+    /// Applies the start condition changes of a rule's action code.
+    fn change_state(&mut self, rule: &LexerRule) {
+        let Some(action_code) = &rule.action_code else {
+            return;
+        };
+        for change in state_changes(action_code) {
+            match change {
+                StateChange::Begin(state) => self.state = state_variant(&state).to_string(),
+                StateChange::Push(state) => {
+                    let state = state_variant(&state).to_string();
+                    self.state_stack.push(std::mem::replace(&mut self.state, state));
+                }
+                StateChange::Pop => self.state = self.state_stack.pop().unwrap_or_else(|| "Initial".to_string()),
+            }
+        }
+    }
+
+    /// Returns true if the rule may be tried at the current position.
+    fn applies(&self, rule: &LexerRule) -> bool {
+        // Rules without states are tried in every state except the exclusive ones
+        let in_state = |states: &[String]| states.iter().any(|state| state_variant(state) == self.state);
+        let active = if rule.states.is_empty() {
+            !in_state(&self.spec.exclusive_states)
+        } else {
+            in_state(&rule.states)
+        };
+        if !active || (rule.line_start && self.col != 1) {
+            return false;
+        }
+        rule.context.iter().rev().enumerate().all(|(age, position)| {
+            let Some(kind) = self.previous_kinds.iter().rev().nth(age) else {
+                return false;
+            };
+            position.kinds.contains(kind) && position.text.as_ref().is_none_or(|text| age == 0 && *text == self.last_text)
+        })
+    }
+
+    /// Consumes `len` bytes as a token of `kind` produced by `rule` (None for unmatched input).
+    fn push_token(&mut self, kind: String, len: usize, rule: Option<&LexerRule>) {
+        let text = self.input[self.pos..self.pos + len].to_string();
+        let value = self
+            .spec
+            .token_types
+            .iter()
+            .find(|(name, _)| *name == kind)
+            .and_then(|(_, value_type)| parse_value(value_type, &text));
+        if rule.is_none_or(updates_context) {
+            self.previous_kinds.push(kind.clone());
+            self.last_text = text.clone();
+        }
+        let token = SampleToken {
+            kind,
+            text,
+            value,
+            index: self.pos,
+            row: self.row,
+            col: self.col,
+            channel: rule.and_then(|rule| rule.channel.clone()),
+        };
+        self.advance(len);
+        self.tokens.push(token);
+    }
+
+    /// Returns the rule that matches at the current position with the length of its match.
+    fn find_match<'c>(&self, groups: &[&'c [Candidate<'c>]]) -> Option<(&'c LexerRule, usize)> {
+        let remaining = &self.input[self.pos..];
+        let mut best: Option<(&LexerRule, usize)> = None;
+        for group in groups {
+            let mut group_best: Option<(&LexerRule, usize)> = None;
+            for candidate in group.iter().filter(|candidate| self.applies(candidate.rule)) {
+                if let Some(len) = candidate.matcher.match_len(remaining) {
+                    if group_best.is_none_or(|(_, best_len)| len > best_len) {
+                        group_best = Some((candidate.rule, len));
+                    }
+                    if group.len() == 1 {
+                        break;
+                    }
+                }
+            }
+            let Some((rule, len)) = group_best else {
+                continue;
+            };
+            if !self.spec.options.longest_match {
+                return Some((rule, len));
+            }
+            if best.is_none_or(|(_, best_len)| len > best_len) {
+                best = Some((rule, len));
+            }
+        }
+        best
+    }
+}
+
+impl LexerSpec {
+    /// Tokenizes `input` with the rules of the spec, without generating the lexer.
+    ///
+    /// Action code is not run: an action rule produces a token of the first kind its
+    /// code names, or no token if it names none, and changes the start condition with
+    /// the `begin`, `push_state` and `pop_state` calls in its code. Returns an error if
+    /// a rule has a regex the regex crate rejects.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use klex::parse_spec;
+    ///
+    /// let spec = parse_spec("%type NUMBER i64\n[0-9]+ -> NUMBER\n'+' -> PLUS\n[ ]+ -> %skip\n").unwrap();
+    /// let tokens = spec.interpret("1 + 23").unwrap();
+    /// let kinds: Vec<&str> = tokens.iter().map(|t| t.kind.as_str()).collect();
+    /// assert_eq!(kinds, ["NUMBER", "PLUS", "NUMBER"]);
+    /// assert_eq!(tokens[2].value.as_deref(), Some("23"));
+    /// assert_eq!((tokens[2].row, tokens[2].col), (1, 5));
+    ///
+    /// // Values out of the range of the declared type are None, as in the generated lexer
+    /// let spec = parse_spec("%type BYTE u8\n[0-9]+ -> BYTE\n[ ]+ -> %skip\n").unwrap();
+    /// let values: Vec<Option<String>> = spec.interpret("255 300").unwrap().into_iter().map(|t| t.value).collect();
+    /// assert_eq!(values, [Some("255".to_string()), None]);
+    ///
+    /// // Start conditions follow the state changes of action code
+    /// let spec = parse_spec("%xstate STR\n'\"' -> { self.begin(LexerState::STR); None }\n<STR>[^\"]+ -> TEXT\n<STR>'\"' -> { self.begin(LexerState::Initial); None }\n[a-z]+ -> WORD\n").unwrap();
+    /// let kinds: Vec<String> = spec.interpret("a\"b c\"d").unwrap().into_iter().map(|t| t.kind).collect();
+    /// assert_eq!(kinds, ["WORD", "TEXT", "WORD"]);
+    /// ```
+    pub fn interpret(&self, input: &str) -> Result<Vec<SampleToken>, ParseError> {
+        let input = if self.options.strip_bom { input.strip_prefix('\u{FEFF}').unwrap_or(input) } else { input };
+
+        // Context rules first, then action rules, then the other rules
+        let rules = self.rules.iter().filter(|rule| !matches!(rule.pattern, RulePattern::Eof));
+        let context_rules = rules.clone().filter(|rule| !rule.context.is_empty());
+        let action_rules = rules.clone().filter(|rule| rule.context.is_empty() && rule.action_code.is_some());
+        let other_rules = rules.filter(|rule| rule.context.is_empty() && rule.action_code.is_none());
+        let mut candidates = Vec::new();
+        for rule in context_rules.chain(action_rules) {
+            candidates.push(Candidate { rule, matcher: Matcher::new(rule)?, literal: false });
+        }
+        for rule in other_rules {
+            candidates.push(Candidate { rule, matcher: Matcher::new(rule)?, literal: rule_literal(rule).is_some() });
+        }
+        let groups = group_candidates(&candidates);
+
+        let mut interpreter = Interpreter {
+            spec: self,
+            input,
+            pos: 0,
+            row: 1,
+            col: 1,
+            previous_kinds: Vec::new(),
+            last_text: String::new(),
+            state: "Initial".to_string(),
+            state_stack: Vec::new(),
+            tokens: Vec::new(),
+        };
+        if input.starts_with("#!") && (self.options.skip_shebang || self.options.shebang_token) {
+            let len = input.lines().next().unwrap_or("").len();
+            if self.options.shebang_token {
+                interpreter.push_token("Shebang".to_string(), len, None);
+            } else {
+                interpreter.advance(len);
+            }
+        }
+        loop {
+            if self.options.skip_whitespace {
+                let remaining = &input[interpreter.pos..];
+                let len = remaining.len() - remaining.trim_start().len();
+                interpreter.advance(len);
+            }
+            if interpreter.pos >= input.len() {
+                break;
+            }
+            match interpreter.find_match(&groups) {
+                Some((rule, len)) if rule.skip => interpreter.advance(len),
+                Some((rule, len)) => {
+                    let kind = match &rule.action_code {
+                        Some(action_code) => extract_action_kind(action_code),
+                        None if is_identifier_rule(self, rule) => {
+                            let text = &input[interpreter.pos..interpreter.pos + len];
+                            let keyword = self.keywords.iter().find(|(word, _)| word == text);
+                            Some(keyword.map_or(&rule.name, |(_, kind)| kind).clone())
+                        }
+                        None => Some(rule.name.clone()),
+                    };
+                    match kind {
+                        Some(kind) => interpreter.push_token(kind, len, Some(rule)),
+                        None => interpreter.advance(len),
+                    }
+                    interpreter.change_state(rule);
+                }
+                None => {
+                    // Unmatched input is one character, or the whole run when recovering
+                    let remaining = &input[interpreter.pos..];
+                    let mut len = remaining.chars().next().map_or(0, char::len_utf8);
+                    if self.options.recover {
+                        let at = |len: usize| Interpreter { pos: interpreter.pos + len, ..interpreter.snapshot() };
+                        while len < remaining.len() && at(len).find_match(&groups).is_none() {
+                            len += remaining[len..].chars().next().map_or(0, char::len_utf8);
+                        }
+                    }
+                    let kind = self.error_token.clone().unwrap_or_else(|| "Unknown".to_string());
+                    interpreter.push_token(kind, len, None);
+                }
+            }
+        }
+
+        // End of input: the first <<EOF>> rule, or the Eof token with emit-eof
+        let eof_rule = self
+            .rules
+            .iter()
+            .filter(|rule| matches!(rule.pattern, RulePattern::Eof))
+            .find(|rule| interpreter.applies(rule));
+        let eof_kind = match eof_rule {
+            Some(rule) => match &rule.action_code {
+                Some(action_code) => extract_action_kind(action_code),
+                None => Some(rule.name.clone()),
+            },
+            None => None,
+        };
+        match eof_kind {
+            Some(kind) => interpreter.push_token(kind, 0, eof_rule),
+            None if self.options.emit_eof => interpreter.push_token("Eof".to_string(), 0, None),
+            None => {}
+        }
+        Ok(interpreter.tokens)
+    }
+
+    /// Returns the features of the spec that [`LexerSpec::interpret`] does not reproduce,
+    /// so its tokens may differ from those of the generated lexer.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use klex::parse_spec;
+    ///
+    /// let spec = parse_spec("[0-9]+ -> NUMBER\n[ ]+ -> %skip\n").unwrap();
+    /// assert!(spec.interpret_warnings().is_empty());
+    ///
+    /// let spec = parse_spec("%option indentation-tokens\n[0-9]+ -> { self.count += 1; Some(self.token(TokenKind::NUMBER)) }\n").unwrap();
+    /// assert_eq!(spec.interpret_warnings().len(), 2);
+    /// ```
+    pub fn interpret_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.rules.iter().any(|rule| rule.action_code.is_some()) {
+            warnings.push(
+                "action code is not run: action rules produce the first token kind their code names, and only begin, push_state and pop_state change the start condition"
+                    .to_string(),
+            );
+        }
+        if self.options.indentation_tokens {
+            warnings.push("%option indentation-tokens is not interpreted: no Indent or Dedent tokens are produced".to_string());
+        }
+        warnings
+    }
+}
@@ -0,0 +1,864 @@
+//! JSON form of lexer specifications.
+//!
+//! `LexerSpec::to_json` writes a parsed spec as JSON and `LexerSpec::from_json` reads it
+//! back, so other tools can produce specs without the `%%` text format. Fields are named
+//! as in the Rust structs and tuples are arrays. A `RulePattern` is an object keyed by its
+//! variant name, e.g. `{"CharRangeMatch1": ["0", "9"]}`, or just the name for variants
+//! without data, e.g. `"AnyChar"`. Missing fields take their default values.
+//!
+//! Source spans and directives describe the text of a spec, so they are only part of the
+//! AST form written by `LexerSpec::to_ast_json`, for tools inspecting how a spec was parsed.
+
+use crate::parser::{
+    ContextToken, LexerOptions, LexerRule, LexerSpec, ParseError, RulePattern, SourceSpan, SpecDirective,
+};
+
+/// A JSON value. Numbers are the non-negative integers a spec uses.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn string(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    fn strings(items: &[String]) -> Json {
+        Json::Array(items.iter().map(|s| Json::string(s)).collect())
+    }
+
+    fn pairs(items: &[(String, String)]) -> Json {
+        Json::Array(items.iter().map(|(a, b)| Json::Array(vec![Json::string(a), Json::string(b)])).collect())
+    }
+
+    fn optional_string(s: &Option<String>) -> Json {
+        s.as_deref().map_or(Json::Null, Json::string)
+    }
+
+    fn optional_number(n: Option<usize>) -> Json {
+        n.map_or(Json::Null, |n| Json::Number(n as u64))
+    }
+
+    fn char(c: char) -> Json {
+        Json::String(c.to_string())
+    }
+
+    /// Whether the value fits on one line: a scalar, an array of scalars, or an object
+    /// with a single such entry.
+    fn is_flat(&self) -> bool {
+        match self {
+            Json::Array(items) => items.iter().all(|item| !matches!(item, Json::Array(_) | Json::Object(_))),
+            Json::Object(fields) => fields.len() == 1 && fields[0].1.is_flat(),
+            _ => true,
+        }
+    }
+
+    /// Writes the value with two spaces of indentation per level.
+    fn write(&self, out: &mut String, indent: usize) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Number(n) => out.push_str(&n.to_string()),
+            Json::String(s) => write_string(out, s),
+            Json::Array(items) if items.is_empty() => out.push_str("[]"),
+            Json::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Json::Array(items) if self.is_flat() => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    item.write(out, indent);
+                }
+                out.push(']');
+            }
+            Json::Object(fields) if self.is_flat() => {
+                out.push('{');
+                write_string(out, &fields[0].0);
+                out.push_str(": ");
+                fields[0].1.write(out, indent);
+                out.push('}');
+            }
+            Json::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(if i > 0 { ",\n" } else { "\n" });
+                    out.push_str(&"  ".repeat(indent + 1));
+                    item.write(out, indent + 1);
+                }
+                out.push('\n');
+                out.push_str(&"  ".repeat(indent));
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    out.push_str(if i > 0 { ",\n" } else { "\n" });
+                    out.push_str(&"  ".repeat(indent + 1));
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value.write(out, indent + 1);
+                }
+                out.push('\n');
+                out.push_str(&"  ".repeat(indent));
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Writes a JSON string literal.
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Quotes a string as a JSON string literal, escaping quotes, backslashes and
+/// control characters.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::json_string;
+///
+/// assert_eq!(json_string("say \"hi\"\n"), r#""say \"hi\"\n""#);
+/// ```
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    write_string(&mut out, s);
+    out
+}
+
+/// Reads JSON text, reporting syntax errors at their line and column.
+struct Reader<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self, message: &str) -> ParseError {
+        let line_start = self.text[..self.pos].rfind('\n').map_or(0, |i| i + 1);
+        let mut error = ParseError::new(format!("Invalid JSON spec: {}", message));
+        error.line = Some(self.text[..self.pos].matches('\n').count() + 1);
+        error.column = Some(self.text[line_start..self.pos].chars().count() + 1);
+        error.source_line = self.text[line_start..].lines().next().map(str::to_string);
+        error
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected '{}'", c)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn read_value(&mut self) -> Result<Json, ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() != Some('"') {
+                        return Err(self.error("expected a field name"));
+                    }
+                    let key = self.read_string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.read_value()?));
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some('}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.read_value()?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some('"') => Ok(Json::String(self.read_string()?)),
+            Some(c) if c.is_ascii_digit() => {
+                let digits = self.text[self.pos..].chars().take_while(|c| c.is_ascii_digit()).count();
+                let number = self.text[self.pos..self.pos + digits]
+                    .parse()
+                    .map_err(|_| self.error("number out of range"))?;
+                self.pos += digits;
+                if matches!(self.peek(), Some('.' | 'e' | 'E')) {
+                    return Err(self.error("expected a non-negative integer"));
+                }
+                Ok(Json::Number(number))
+            }
+            _ => {
+                for (word, value) in [("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))] {
+                    if self.text[self.pos..].starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+
+    fn read_string(&mut self) -> Result<String, ParseError> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += escape.len_utf8();
+                    match escape {
+                        '"' | '\\' | '/' => s.push(escape),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'u' => {
+                            let mut code = self.read_hex4()?;
+                            if (0xD800..0xDC00).contains(&code) && self.text[self.pos..].starts_with("\\u") {
+                                self.pos += 2;
+                                let low = self.read_hex4()?;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            s.push(char::from_u32(code).ok_or_else(|| self.error("invalid \\u escape"))?);
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn read_hex4(&mut self) -> Result<u32, ParseError> {
+        let hex = self.text.get(self.pos..self.pos + 4).ok_or_else(|| self.error("invalid \\u escape"))?;
+        let code = u32::from_str_radix(hex, 16).map_err(|_| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+/// Returns an error about the JSON value at `path`.
+fn invalid(path: &str, expected: &str) -> ParseError {
+    ParseError::new(format!("Invalid JSON spec: `{}` must be {}", path, expected))
+}
+
+/// Returns the fields of an object, rejecting fields other than `known`.
+fn object_fields<'a>(value: &'a Json, path: &str, known: &[&str]) -> Result<&'a [(String, Json)], ParseError> {
+    let Json::Object(fields) = value else {
+        return Err(invalid(path, "an object"));
+    };
+    if let Some((key, _)) = fields.iter().find(|(key, _)| !known.contains(&key.as_str())) {
+        return Err(ParseError::new(format!("Invalid JSON spec: unknown field `{}` in `{}`", key, path)));
+    }
+    Ok(fields)
+}
+
+/// Returns the value of a field, or `None` if it is missing or null.
+fn field<'a>(fields: &'a [(String, Json)], key: &str) -> Option<&'a Json> {
+    fields.iter().find(|(k, _)| k == key).map(|(_, value)| value).filter(|value| **value != Json::Null)
+}
+
+fn to_string(value: &Json, path: &str) -> Result<String, ParseError> {
+    match value {
+        Json::String(s) => Ok(s.clone()),
+        _ => Err(invalid(path, "a string")),
+    }
+}
+
+fn to_char(value: &Json, path: &str) -> Result<char, ParseError> {
+    let s = to_string(value, path)?;
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(invalid(path, "a string of one character")),
+    }
+}
+
+fn to_usize(value: &Json, path: &str) -> Result<usize, ParseError> {
+    match value {
+        Json::Number(n) => usize::try_from(*n).map_err(|_| invalid(path, "a smaller number")),
+        _ => Err(invalid(path, "a non-negative integer")),
+    }
+}
+
+fn to_bool(value: &Json, path: &str) -> Result<bool, ParseError> {
+    match value {
+        Json::Bool(b) => Ok(*b),
+        _ => Err(invalid(path, "true or false")),
+    }
+}
+
+fn to_array<'a>(value: &'a Json, path: &str) -> Result<&'a [Json], ParseError> {
+    match value {
+        Json::Array(items) => Ok(items),
+        _ => Err(invalid(path, "an array")),
+    }
+}
+
+/// Returns the items of an array of exactly `N` items.
+fn to_tuple<'a, const N: usize>(value: &'a Json, path: &str) -> Result<&'a [Json; N], ParseError> {
+    to_array(value, path)?.try_into().map_err(|_| invalid(path, &format!("an array of {} items", N)))
+}
+
+fn to_strings(value: &Json, path: &str) -> Result<Vec<String>, ParseError> {
+    let items = to_array(value, path)?;
+    items.iter().enumerate().map(|(i, item)| to_string(item, &format!("{}[{}]", path, i))).collect()
+}
+
+fn to_pairs(value: &Json, path: &str) -> Result<Vec<(String, String)>, ParseError> {
+    let items = to_array(value, path)?;
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let path = format!("{}[{}]", path, i);
+            let [a, b] = to_tuple(item, &path)?;
+            Ok((to_string(a, &path)?, to_string(b, &path)?))
+        })
+        .collect()
+}
+
+fn to_ranges(value: &Json, path: &str) -> Result<Vec<(char, char)>, ParseError> {
+    let items = to_array(value, path)?;
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let path = format!("{}[{}]", path, i);
+            let [from, to] = to_tuple(item, &path)?;
+            Ok((to_char(from, &path)?, to_char(to, &path)?))
+        })
+        .collect()
+}
+
+fn to_patterns(value: &Json, path: &str) -> Result<Vec<RulePattern>, ParseError> {
+    let items = to_array(value, path)?;
+    items.iter().enumerate().map(|(i, item)| pattern_from_json(item, &format!("{}[{}]", path, i))).collect()
+}
+
+/// The `bool` fields of `LexerOptions` by name.
+fn option_flags(options: &mut LexerOptions) -> [(&'static str, &mut bool); 26] {
+    [
+        ("longest_match", &mut options.longest_match),
+        ("skip_whitespace", &mut options.skip_whitespace),
+        ("emit_eof", &mut options.emit_eof),
+        ("indentation_tokens", &mut options.indentation_tokens),
+        ("skip_shebang", &mut options.skip_shebang),
+        ("shebang_token", &mut options.shebang_token),
+        ("strip_bom", &mut options.strip_bom),
+        ("dfa", &mut options.dfa),
+        ("precompiled_dfa", &mut options.precompiled_dfa),
+        ("regex_set", &mut options.regex_set),
+        ("token_offsets", &mut options.token_offsets),
+        ("no_std", &mut options.no_std),
+        ("recover", &mut options.recover),
+        ("bytes", &mut options.bytes),
+        ("intern", &mut options.intern),
+        ("ffi", &mut options.ffi),
+        ("wasm", &mut options.wasm),
+        ("pyo3", &mut options.pyo3),
+        ("length_chars", &mut options.length_chars),
+        ("token_lengths", &mut options.token_lengths),
+        ("borrow_input", &mut options.borrow_input),
+        ("result_errors", &mut options.result_errors),
+        ("trivia", &mut options.trivia),
+        ("utf16_columns", &mut options.utf16_columns),
+        ("unique_tokens", &mut options.unique_tokens),
+        ("merge_tokens", &mut options.merge_tokens),
+    ]
+}
+
+/// The `Option<usize>` fields of `LexerOptions` by name.
+fn option_values(options: &mut LexerOptions) -> [(&'static str, &mut Option<usize>); 3] {
+    [
+        ("tab_width", &mut options.tab_width),
+        ("max_token_length", &mut options.max_token_length),
+        ("max_tokens", &mut options.max_tokens),
+    ]
+}
+
+fn options_to_json(options: &LexerOptions) -> Json {
+    let mut options = options.clone();
+    let mut fields: Vec<(String, Json)> =
+        option_flags(&mut options).into_iter().map(|(name, flag)| (name.to_string(), Json::Bool(*flag))).collect();
+    fields.extend(
+        option_values(&mut options).into_iter().map(|(name, value)| (name.to_string(), Json::optional_number(*value))),
+    );
+    Json::Object(fields)
+}
+
+fn options_from_json(value: &Json, path: &str) -> Result<LexerOptions, ParseError> {
+    let mut options = LexerOptions::default();
+    let Json::Object(fields) = value else {
+        return Err(invalid(path, "an object"));
+    };
+    for (key, value) in fields {
+        let field_path = format!("{}.{}", path, key);
+        let flag = option_flags(&mut options).into_iter().find(|(name, _)| name == key);
+        if let Some((_, flag)) = flag {
+            *flag = to_bool(value, &field_path)?;
+            continue;
+        }
+        let option = option_values(&mut options).into_iter().find(|(name, _)| name == key);
+        let Some((_, option)) = option else {
+            return Err(ParseError::new(format!("Invalid JSON spec: unknown field `{}` in `{}`", key, path)));
+        };
+        *option = match value {
+            Json::Null => None,
+            value => Some(to_usize(value, &field_path)?),
+        };
+    }
+    Ok(options)
+}
+
+fn pattern_to_json(pattern: &RulePattern) -> Json {
+    let (name, value) = match pattern {
+        RulePattern::CharLiteral(c) => ("CharLiteral", Json::char(*c)),
+        RulePattern::StringLiteral(s) => ("StringLiteral", Json::string(s)),
+        RulePattern::Regex(s) => ("Regex", Json::string(s)),
+        RulePattern::CharSet(s) => ("CharSet", Json::string(s)),
+        RulePattern::CharRangeMatch1(from, to) => ("CharRangeMatch1", Json::Array(vec![Json::char(*from), Json::char(*to)])),
+        RulePattern::CharRangeMatch0(from, to) => ("CharRangeMatch0", Json::Array(vec![Json::char(*from), Json::char(*to)])),
+        RulePattern::CharRangeRepeat(from, to, min, max) => (
+            "CharRangeRepeat",
+            Json::Array(vec![Json::char(*from), Json::char(*to), Json::Number(*min as u64), Json::optional_number(*max)]),
+        ),
+        RulePattern::NegatedCharSet1(ranges) | RulePattern::NegatedCharSet0(ranges) => (
+            if matches!(pattern, RulePattern::NegatedCharSet1(_)) { "NegatedCharSet1" } else { "NegatedCharSet0" },
+            Json::Array(ranges.iter().map(|(from, to)| Json::Array(vec![Json::char(*from), Json::char(*to)])).collect()),
+        ),
+        RulePattern::Choice(patterns) => ("Choice", Json::Array(patterns.iter().map(pattern_to_json).collect())),
+        RulePattern::EscapedChar(c) => ("EscapedChar", Json::char(*c)),
+        RulePattern::AnyChar => return Json::string("AnyChar"),
+        RulePattern::AnyCharPlus => return Json::string("AnyCharPlus"),
+        RulePattern::CaseInsensitive(pattern) => ("CaseInsensitive", pattern_to_json(pattern)),
+        RulePattern::Eof => return Json::string("Eof"),
+        RulePattern::Balanced(open, close) => ("Balanced", Json::Array(vec![Json::string(open), Json::string(close)])),
+        RulePattern::Heredoc(opener) => ("Heredoc", Json::string(opener)),
+        RulePattern::Sequence(patterns) => ("Sequence", Json::Array(patterns.iter().map(pattern_to_json).collect())),
+    };
+    Json::Object(vec![(name.to_string(), value)])
+}
+
+fn pattern_from_json(value: &Json, path: &str) -> Result<RulePattern, ParseError> {
+    let (name, value) = match value {
+        Json::String(name) => (name.as_str(), &Json::Null),
+        Json::Object(fields) if fields.len() == 1 => (fields[0].0.as_str(), &fields[0].1),
+        _ => return Err(invalid(path, "a pattern variant name or an object with one pattern variant")),
+    };
+    let path = &format!("{}.{}", path, name);
+    Ok(match name {
+        "CharLiteral" => RulePattern::CharLiteral(to_char(value, path)?),
+        "StringLiteral" => RulePattern::StringLiteral(to_string(value, path)?),
+        "Regex" => RulePattern::Regex(to_string(value, path)?),
+        "CharSet" => RulePattern::CharSet(to_string(value, path)?),
+        "CharRangeMatch1" | "CharRangeMatch0" => {
+            let [from, to] = to_tuple(value, path)?;
+            let (from, to) = (to_char(from, path)?, to_char(to, path)?);
+            if name == "CharRangeMatch1" {
+                RulePattern::CharRangeMatch1(from, to)
+            } else {
+                RulePattern::CharRangeMatch0(from, to)
+            }
+        }
+        "CharRangeRepeat" => {
+            let [from, to, min, max] = to_tuple(value, path)?;
+            let max = match max {
+                Json::Null => None,
+                max => Some(to_usize(max, path)?),
+            };
+            RulePattern::CharRangeRepeat(to_char(from, path)?, to_char(to, path)?, to_usize(min, path)?, max)
+        }
+        "NegatedCharSet1" => RulePattern::NegatedCharSet1(to_ranges(value, path)?),
+        "NegatedCharSet0" => RulePattern::NegatedCharSet0(to_ranges(value, path)?),
+        "Choice" => RulePattern::Choice(to_patterns(value, path)?),
+        "EscapedChar" => RulePattern::EscapedChar(to_char(value, path)?),
+        "AnyChar" => RulePattern::AnyChar,
+        "AnyCharPlus" => RulePattern::AnyCharPlus,
+        "CaseInsensitive" => RulePattern::CaseInsensitive(Box::new(pattern_from_json(value, path)?)),
+        "Eof" => RulePattern::Eof,
+        "Balanced" => {
+            let [open, close] = to_tuple(value, path)?;
+            RulePattern::Balanced(to_string(open, path)?, to_string(close, path)?)
+        }
+        "Heredoc" => RulePattern::Heredoc(to_string(value, path)?),
+        "Sequence" => RulePattern::Sequence(to_patterns(value, path)?),
+        _ => return Err(ParseError::new(format!("Invalid JSON spec: unknown pattern variant `{}`", name))),
+    })
+}
+
+fn context_to_json(position: &ContextToken) -> Json {
+    Json::Object(vec![
+        ("kinds".to_string(), Json::strings(&position.kinds)),
+        ("text".to_string(), Json::optional_string(&position.text)),
+    ])
+}
+
+fn context_from_json(value: &Json, path: &str) -> Result<ContextToken, ParseError> {
+    let fields = object_fields(value, path, &["kinds", "text"])?;
+    let kinds = match field(fields, "kinds") {
+        Some(kinds) => to_strings(kinds, &format!("{}.kinds", path))?,
+        None => Vec::new(),
+    };
+    if kinds.is_empty() {
+        return Err(invalid(&format!("{}.kinds", path), "a non-empty array"));
+    }
+    let text = field(fields, "text").map(|text| to_string(text, &format!("{}.text", path))).transpose()?;
+    Ok(ContextToken { kinds, text })
+}
+
+fn span_to_json(span: &SourceSpan) -> Json {
+    Json::Object(vec![
+        ("start".to_string(), Json::Number(span.start as u64)),
+        ("end".to_string(), Json::Number(span.end as u64)),
+        ("line".to_string(), Json::Number(span.line as u64)),
+    ])
+}
+
+fn span_from_json(value: &Json, path: &str) -> Result<SourceSpan, ParseError> {
+    let fields = object_fields(value, path, &["start", "end", "line"])?;
+    let number = |key: &str| field(fields, key).map_or(Ok(0), |value| to_usize(value, &format!("{}.{}", path, key)));
+    Ok(SourceSpan { start: number("start")?, end: number("end")?, line: number("line")? })
+}
+
+fn directive_to_json(directive: &SpecDirective) -> Json {
+    Json::Object(vec![
+        ("name".to_string(), Json::string(&directive.name)),
+        ("span".to_string(), span_to_json(&directive.span)),
+    ])
+}
+
+fn directive_from_json(value: &Json, path: &str) -> Result<SpecDirective, ParseError> {
+    let fields = object_fields(value, path, &["name", "span"])?;
+    let name = field(fields, "name").ok_or_else(|| invalid(&format!("{}.name", path), "given"))?;
+    Ok(SpecDirective {
+        name: to_string(name, &format!("{}.name", path))?,
+        span: field(fields, "span").map_or(Ok(SourceSpan::default()), |span| span_from_json(span, &format!("{}.span", path)))?,
+    })
+}
+
+/// Writes a rule, with its source spans when `spans` is set.
+fn rule_to_json(rule: &LexerRule, spans: bool) -> Json {
+    let mut fields = vec![
+        ("pattern".to_string(), pattern_to_json(&rule.pattern)),
+        ("kind".to_string(), Json::Number(u64::from(rule.kind))),
+        ("name".to_string(), Json::string(&rule.name)),
+        ("context".to_string(), Json::Array(rule.context.iter().map(context_to_json).collect())),
+        ("action_code".to_string(), Json::optional_string(&rule.action_code)),
+        ("states".to_string(), Json::strings(&rule.states)),
+        ("skip".to_string(), Json::Bool(rule.skip)),
+        ("trailing_context".to_string(), rule.trailing_context.as_ref().map_or(Json::Null, pattern_to_json)),
+        ("channel".to_string(), Json::optional_string(&rule.channel)),
+        ("line_start".to_string(), Json::Bool(rule.line_start)),
+        ("cfg".to_string(), Json::optional_string(&rule.cfg)),
+        ("doc".to_string(), Json::optional_string(&rule.doc)),
+    ];
+    if spans {
+        fields.push(("span".to_string(), span_to_json(&rule.span)));
+        fields.push(("pattern_span".to_string(), span_to_json(&rule.pattern_span)));
+    }
+    Json::Object(fields)
+}
+
+/// Reads a rule; `kind` defaults to the position of the rule, as in the text format.
+fn rule_from_json(value: &Json, path: &str, index: usize) -> Result<LexerRule, ParseError> {
+    let fields = object_fields(
+        value,
+        path,
+        &[
+            "pattern", "kind", "name", "context", "action_code", "states", "skip", "trailing_context",
+            "channel", "line_start", "cfg", "doc", "span", "pattern_span",
+        ],
+    )?;
+    let optional_string = |key: &str| field(fields, key).map(|value| to_string(value, &format!("{}.{}", path, key))).transpose();
+    let pattern = field(fields, "pattern").ok_or_else(|| invalid(&format!("{}.pattern", path), "given"))?;
+    let mut rule = LexerRule::new(
+        pattern_from_json(pattern, &format!("{}.pattern", path))?,
+        index as u32,
+        optional_string("name")?.unwrap_or_default(),
+    );
+    if let Some(kind) = field(fields, "kind") {
+        let kind_path = format!("{}.kind", path);
+        rule.kind = u32::try_from(to_usize(kind, &kind_path)?).map_err(|_| invalid(&kind_path, "a smaller number"))?;
+    }
+    if let Some(context) = field(fields, "context") {
+        let context_path = format!("{}.context", path);
+        rule.context = to_array(context, &context_path)?
+            .iter()
+            .enumerate()
+            .map(|(i, position)| context_from_json(position, &format!("{}[{}]", context_path, i)))
+            .collect::<Result<_, _>>()?;
+    }
+    rule.action_code = optional_string("action_code")?;
+    if let Some(states) = field(fields, "states") {
+        rule.states = to_strings(states, &format!("{}.states", path))?;
+    }
+    if let Some(skip) = field(fields, "skip") {
+        rule.skip = to_bool(skip, &format!("{}.skip", path))?;
+    }
+    if let Some(trailing) = field(fields, "trailing_context") {
+        rule.trailing_context = Some(pattern_from_json(trailing, &format!("{}.trailing_context", path))?);
+    }
+    rule.channel = optional_string("channel")?;
+    if let Some(line_start) = field(fields, "line_start") {
+        rule.line_start = to_bool(line_start, &format!("{}.line_start", path))?;
+    }
+    rule.cfg = optional_string("cfg")?;
+    rule.doc = optional_string("doc")?;
+    if let Some(span) = field(fields, "span") {
+        rule.span = span_from_json(span, &format!("{}.span", path))?;
+    }
+    if let Some(pattern_span) = field(fields, "pattern_span") {
+        rule.pattern_span = span_from_json(pattern_span, &format!("{}.pattern_span", path))?;
+    }
+    Ok(rule)
+}
+
+impl LexerSpec {
+    /// Writes the spec as JSON, which `from_json` reads back.
+    ///
+    /// Fields are named as in the Rust structs, and each `RulePattern` is an object keyed
+    /// by its variant name. Source spans and directives are left out (see `to_ast_json`).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use klex::{generate_lexer, parse_spec, LexerSpec};
+    ///
+    /// let spec = parse_spec("[0-9]+ -> NUMBER\n'+' -> PLUS \"Addition\"\n").unwrap();
+    /// let json = spec.to_json();
+    /// assert!(json.contains(r#""pattern": {"CharRangeMatch1": ["0", "9"]}"#));
+    ///
+    /// // The JSON form round-trips and generates the same lexer
+    /// let read = LexerSpec::from_json(&json).unwrap();
+    /// assert_eq!(read.to_json(), json);
+    /// assert_eq!(generate_lexer(&read, "calc.klex"), generate_lexer(&spec, "calc.klex"));
+    /// ```
+    pub fn to_json(&self) -> String {
+        self.write_json(false)
+    }
+
+    /// Writes the spec as JSON with the source spans of its rules and its directives,
+    /// showing exactly how the spec was parsed. `from_json` reads it back as well.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use klex::{parse_spec, LexerSpec};
+    ///
+    /// let spec = parse_spec("%token EXTRA\n[0-9]+ -> NUMBER\n").unwrap();
+    /// let json = spec.to_ast_json();
+    /// assert!(json.contains(r#""name": "%token""#));
+    /// assert!(json.contains(r#""pattern_span": {"#));
+    ///
+    /// let read = LexerSpec::from_json(&json).unwrap();
+    /// assert_eq!(read.rules[0].span, spec.rules[0].span);
+    /// assert_eq!(read.directives, spec.directives);
+    /// ```
+    pub fn to_ast_json(&self) -> String {
+        self.write_json(true)
+    }
+
+    /// Writes the spec as JSON, with source spans and directives when `spans` is set.
+    fn write_json(&self, spans: bool) -> String {
+        let definitions = self
+            .definitions
+            .iter()
+            .map(|(name, pattern)| Json::Array(vec![Json::string(name), pattern_to_json(pattern)]))
+            .collect();
+        let tests = self
+            .tests
+            .iter()
+            .map(|(input, kinds)| Json::Array(vec![Json::string(input), Json::strings(kinds)]))
+            .collect();
+        let rules = self.rules.iter().map(|rule| rule_to_json(rule, spans)).collect();
+        let token_declarations = self
+            .token_declarations
+            .iter()
+            .map(|(rules_before, name)| Json::Array(vec![Json::Number(*rules_before as u64), Json::string(name)]))
+            .collect();
+        let mut fields = vec![
+            ("prefix_code".to_string(), Json::string(&self.prefix_code)),
+            ("rules".to_string(), Json::Array(rules)),
+            ("suffix_code".to_string(), Json::string(&self.suffix_code)),
+            ("custom_tokens".to_string(), Json::strings(&self.custom_tokens)),
+            ("states".to_string(), Json::strings(&self.states)),
+            ("exclusive_states".to_string(), Json::strings(&self.exclusive_states)),
+            ("definitions".to_string(), Json::Array(definitions)),
+            ("options".to_string(), options_to_json(&self.options)),
+            ("error_token".to_string(), Json::optional_string(&self.error_token)),
+            ("channels".to_string(), Json::strings(&self.channels)),
+            ("token_types".to_string(), Json::pairs(&self.token_types)),
+            ("keywords".to_string(), Json::pairs(&self.keywords)),
+            ("name_prefix".to_string(), Json::optional_string(&self.name_prefix)),
+            ("tests".to_string(), Json::Array(tests)),
+            ("trivia_kinds".to_string(), Json::strings(&self.trivia_kinds)),
+            ("state_fields".to_string(), Json::pairs(&self.state_fields)),
+            ("styles".to_string(), Json::pairs(&self.styles)),
+            ("token_declarations".to_string(), Json::Array(token_declarations)),
+        ];
+        if spans {
+            fields.push(("directives".to_string(), Json::Array(self.directives.iter().map(directive_to_json).collect())));
+        }
+        let json = Json::Object(fields);
+        let mut out = String::new();
+        json.write(&mut out, 0);
+        out.push('\n');
+        out
+    }
+
+    /// Reads a spec from the JSON written by `to_json` or `to_ast_json`.
+    ///
+    /// Missing fields take their default values, so a minimal spec only lists its rules.
+    /// Syntax errors carry the line and column of the JSON text.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use klex::LexerSpec;
+    ///
+    /// let spec = LexerSpec::from_json(r#"{"rules": [
+    ///     {"pattern": {"CharRangeMatch1": ["0", "9"]}, "name": "NUMBER"},
+    ///     {"pattern": "AnyChar", "action_code": "None"}
+    /// ]}"#).unwrap();
+    /// assert_eq!(spec.rules[0].name, "NUMBER");
+    /// assert_eq!(spec.rules[1].kind, 1);
+    ///
+    /// let error = LexerSpec::from_json("{\n  \"rules\": [,]\n}").unwrap_err();
+    /// assert_eq!((error.line, error.column), (Some(2), Some(13)));
+    /// assert!(LexerSpec::from_json(r#"{"rulez": []}"#).is_err());
+    /// ```
+    pub fn from_json(json: &str) -> Result<LexerSpec, ParseError> {
+        let mut reader = Reader { text: json, pos: 0 };
+        let value = reader.read_value()?;
+        reader.skip_whitespace();
+        if reader.pos < json.len() {
+            return Err(reader.error("unexpected text after the spec"));
+        }
+
+        let fields = object_fields(
+            &value,
+            "spec",
+            &[
+                "prefix_code", "rules", "suffix_code", "custom_tokens", "states", "exclusive_states", "definitions",
+                "options", "error_token", "channels", "token_types", "keywords", "name_prefix", "tests",
+                "trivia_kinds", "state_fields", "styles", "token_declarations", "directives",
+            ],
+        )?;
+        let mut spec = LexerSpec::new();
+        for (key, value) in fields.iter().filter(|(_, value)| *value != Json::Null) {
+            let path = key.as_str();
+            match path {
+                "prefix_code" => spec.prefix_code = to_string(value, path)?,
+                "suffix_code" => spec.suffix_code = to_string(value, path)?,
+                "rules" => {
+                    for (i, rule) in to_array(value, path)?.iter().enumerate() {
+                        spec.rules.push(rule_from_json(rule, &format!("rules[{}]", i), i)?);
+                    }
+                }
+                "custom_tokens" => spec.custom_tokens = to_strings(value, path)?,
+                "states" => spec.states = to_strings(value, path)?,
+                "exclusive_states" => spec.exclusive_states = to_strings(value, path)?,
+                "definitions" => {
+                    for (i, definition) in to_array(value, path)?.iter().enumerate() {
+                        let path = format!("definitions[{}]", i);
+                        let [name, pattern] = to_tuple(definition, &path)?;
+                        spec.definitions.push((to_string(name, &path)?, pattern_from_json(pattern, &path)?));
+                    }
+                }
+                "options" => spec.options = options_from_json(value, path)?,
+                "error_token" => spec.error_token = Some(to_string(value, path)?),
+                "channels" => spec.channels = to_strings(value, path)?,
+                "token_types" => spec.token_types = to_pairs(value, path)?,
+                "keywords" => spec.keywords = to_pairs(value, path)?,
+                "name_prefix" => spec.name_prefix = Some(to_string(value, path)?),
+                "tests" => {
+                    for (i, test) in to_array(value, path)?.iter().enumerate() {
+                        let path = format!("tests[{}]", i);
+                        let [input, kinds] = to_tuple(test, &path)?;
+                        spec.tests.push((to_string(input, &path)?, to_strings(kinds, &path)?));
+                    }
+                }
+                "trivia_kinds" => spec.trivia_kinds = to_strings(value, path)?,
+                "styles" => spec.styles = to_pairs(value, path)?,
+                "token_declarations" => {
+                    for (i, declaration) in to_array(value, path)?.iter().enumerate() {
+                        let path = format!("token_declarations[{}]", i);
+                        let [rules_before, name] = to_tuple(declaration, &path)?;
+                        spec.token_declarations.push((to_usize(rules_before, &path)?, to_string(name, &path)?));
+                    }
+                }
+                "directives" => {
+                    for (i, directive) in to_array(value, path)?.iter().enumerate() {
+                        spec.directives.push(directive_from_json(directive, &format!("directives[{}]", i))?);
+                    }
+                }
+                _ => spec.state_fields = to_pairs(value, path)?,
+            }
+        }
+        Ok(spec)
+    }
+}
@@ -2,16 +2,165 @@
 // Do not edit manually
 // --------------------------------------------------------
 //----<GENERATED_BY>----
+//----<IMPORTS>----
 
+//----<REGEX_BEGIN>----
 use regex::Regex;
 use std::collections::HashMap;
+//----<REGEX_END>----
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
 pub enum TokenKind {
-	Unknown,
+	/// Input that no rule matched
+	Unknown = 0,
+	/// End of input
+	Eof = 1,
 //----<TOKEN_KIND>----
 }
 
+impl TokenKind {
+	/// Returns the numeric code of the kind
+	/// Codes follow the order the spec declares the kinds in, after 0 for Unknown and 1 for Eof
+	pub fn as_u16(self) -> u16 {
+		self as u16
+	}
+
+	/// Returns the kind with the given code from as_u16
+	pub fn from_u16(code: u16) -> Option<Self> {
+		match code {
+			0 => Some(TokenKind::Unknown),
+			1 => Some(TokenKind::Eof),
+//----<TOKEN_KIND_FROM_U16>----
+			_ => None,
+		}
+	}
+
+	/// Returns true for the kinds declared with %trivia, such as whitespace and comments
+	pub fn is_trivia(&self) -> bool {
+		//----<TRIVIA_KINDS>----
+		false
+	}
+}
+
+/// Description of a token kind in TOKENS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenInfo {
+	/// The token kind
+	pub kind: TokenKind,
+	/// Name of the kind, as written by Display
+	pub name: &'static str,
+	/// Numeric code of the kind, as returned by TokenKind::as_u16
+	pub id: u16,
+	/// Pattern of the first rule producing the kind (empty for kinds without a rule)
+	pub pattern: &'static str,
+	/// True for the kinds declared with %trivia
+	pub is_trivia: bool,
+}
+
+/// Every token kind of the lexer, in the order of their codes
+pub const TOKENS: &[TokenInfo] = &[
+	TokenInfo { kind: TokenKind::Unknown, name: "UNKNOWN", id: 0, pattern: "", is_trivia: false },
+	TokenInfo { kind: TokenKind::Eof, name: "EOF", id: 1, pattern: "", is_trivia: false },
+//----<TOKEN_INFOS>----
+];
+
+impl core::fmt::Display for TokenKind {
+	/// Writes the name of the token kind (`UNKNOWN` and `EOF` for the built-in kinds)
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		let name = match self {
+			TokenKind::Unknown => "UNKNOWN",
+			TokenKind::Eof => "EOF",
+//----<TOKEN_KIND_NAMES>----
+		};
+		f.write_str(name)
+	}
+}
+
+impl core::str::FromStr for TokenKind {
+	type Err = String;
+
+	/// Parses a name written by Display back into a token kind
+	fn from_str(name: &str) -> Result<Self, Self::Err> {
+		match name {
+			"UNKNOWN" => Ok(TokenKind::Unknown),
+			"EOF" => Ok(TokenKind::Eof),
+//----<TOKEN_KIND_FROM_NAME>----
+			_ => Err(format!("Unknown token kind: {}", name)),
+		}
+	}
+}
+
+/// Start conditions of the lexer
+/// Rules prefixed with `<STATE>` only match while the lexer is in that state
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexerState {
+	Initial,
+//----<LEXER_STATE>----
+}
+
+/// Channels that tokens are emitted on
+/// Tokens on channels other than Default are only returned by next_token_any
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenChannel {
+	Default,
+//----<TOKEN_CHANNEL>----
+}
+
+/// Typed values of tokens whose kind has a type declared with %type
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenValue {
+//----<TOKEN_VALUE>----
+}
+
+/// State of the lexer declared with %state_struct
+/// Action code reads and writes its fields through `self.user_state`
+#[derive(Debug, Clone, Default)]
+pub struct UserState {
+//----<USER_STATE>----
+}
+
+/// Byte range of a token in the input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+	/// Byte offset of the first byte
+	pub start: usize,
+	/// Byte offset just past the last byte
+	pub end: usize,
+}
+
+impl Span {
+	/// Creates a span covering the bytes `start..end`
+	pub fn new(start: usize, end: usize) -> Self {
+		Span { start, end }
+	}
+
+	/// Returns the smallest span covering both spans, such as the span of a whole expression
+	pub fn join(self, other: Span) -> Span {
+		Span {
+			start: self.start.min(other.start),
+			end: self.end.max(other.end),
+		}
+	}
+
+	/// Returns the length of the span in bytes
+	pub fn len(&self) -> usize {
+		self.end - self.start
+	}
+
+	/// Returns true if the span covers no bytes
+	pub fn is_empty(&self) -> bool {
+		self.start == self.end
+	}
+
+	/// Returns the span as a range for slicing the input: `&input[span.range()]`
+	pub fn range(&self) -> core::ops::Range<usize> {
+		self.start..self.end
+	}
+}
+
 /// Token structure that represents a lexical token
 /// Holds information about each token generated by the lexer
 #[derive(Debug, Clone, PartialEq)]
@@ -24,37 +173,167 @@ pub struct Token {
 	pub index: usize,
 	/// Row number where the token appears (1-based)
 	pub row: usize,
-	/// Column number where the token appears (1-based)
+	/// Column number where the token appears (1-based, in UTF-16 code units with utf16_columns)
 	pub col: usize,
-	/// Length of the token in characters
+	/// Byte range of the token in the input
+	pub span: Span,
+	/// Length of the token in bytes (in characters with %option length-chars)
 	pub length: usize,
+	//----<TOKEN_LENGTHS_BEGIN>----
+	/// Length of the token in bytes (%option token-lengths)
+	pub len_bytes: usize,
+	/// Length of the token in characters (%option token-lengths)
+	pub len_chars: usize,
+	//----<TOKEN_LENGTHS_END>----
 	/// Indentation from the beginning of the line (number of spaces)
 	pub indent: usize,
 	/// User-defined tag (for additional information)
 	pub tag: isize,
+	/// Error message of tokens produced for unmatched input
+	pub error: Option<String>,
+	/// Channel the token is emitted on
+	pub channel: TokenChannel,
+	/// Value parsed from the text when the token kind has a type declared with %type
+	pub value: Option<TokenValue>,
+	/// Tokens on other channels before this token (%option trivia)
+	pub leading_trivia: Vec<Token>,
+	/// Tokens on other channels after this token up to the end of its line (%option trivia)
+	pub trailing_trivia: Vec<Token>,
+	/// Identifier of the source the token was read from (see `Lexer::new_with_source`)
+	pub source_id: usize,
 }
 
 impl Token {
 	/// Creates a new token with the specified parameters
-	/// The tag field is initialized to 0
+	/// The tag field is initialized to 0, the error field to None
+	/// and the channel to the default channel
+	/// The value is parsed from the text when the kind has a declared type
 	pub fn new(kind: TokenKind, text: String, index: usize, row: usize, col: usize, length: usize, indent: usize) -> Self {
+		let value = Token::parse_value(&kind, &text);
+		let span = Span::new(index, index + text.len());
+		//----<TOKEN_LENGTHS_BEGIN>----
+		let len_chars = text.chars().count();
+		//----<TOKEN_LENGTHS_END>----
 		Token {
 			kind,
 			text,
 			index,
 			row,
 			col,
+			span,
 			length,
+			//----<TOKEN_LENGTHS_BEGIN>----
+			len_bytes: span.len(),
+			len_chars,
+			//----<TOKEN_LENGTHS_END>----
 			indent,
 			tag: 0,
+			error: None,
+			channel: TokenChannel::Default,
+			value,
+			leading_trivia: Vec::new(),
+			trailing_trivia: Vec::new(),
+			source_id: 0,
+		}
+	}
+
+	/// Returns the length of a token text as stored in `length`
+	pub fn text_length(text: &str) -> usize {
+		text.len()
+	}
+
+	/// Parses the text of a token into the type declared with %type for its kind
+	/// Returns None for kinds without a declared type and for text that fails to parse
+	#[allow(unused_variables)]
+	pub fn parse_value(kind: &TokenKind, text: &str) -> Option<TokenValue> {
+		//----<PARSE_VALUE>----
+		None
+	}
+
+//----<TOKEN_METHODS>----
+}
+
+impl core::fmt::Display for Token {
+	/// Writes the name of the token kind
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		core::fmt::Display::fmt(&self.kind, f)
+	}
+}
+
+/// Error for input that no rule matched, collected by `Lexer::tokenize_str`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+	/// Description of the error
+	pub message: String,
+	/// 0-based start position of the unmatched input (in bytes)
+	pub index: usize,
+	/// Row number of the unmatched input (1-based)
+	pub row: usize,
+	/// Column number of the unmatched input (1-based)
+	pub col: usize,
+	/// Byte range of the unmatched input
+	pub span: Span,
+	/// The unmatched input
+	pub text: String,
+	/// Start condition of the lexer when the error was returned
+	pub state: LexerState,
+}
+
+impl core::fmt::Display for LexError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.write_str(&self.message)
+	}
+}
+
+/// Limit that stopped the lexer, set in `Lexer::limit_error`
+/// Positions are those of the token or input where the limit was hit
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitError {
+	/// A token was longer than `max_token_length` bytes (%option max-token-length)
+	/// Rules only match up to one character past the limit, which bounds `length`
+	TokenTooLong { index: usize, row: usize, col: usize, length: usize, max: usize },
+	/// More than `max_tokens` tokens were read (%option max-tokens)
+	TooManyTokens { index: usize, row: usize, col: usize, max: usize },
+	/// The deadline set with `Lexer::set_timeout` passed
+	DeadlineExceeded { index: usize, row: usize, col: usize },
+	/// The callback set with `Lexer::on_progress` returned false
+	Cancelled { index: usize, row: usize, col: usize },
+}
+
+impl core::fmt::Display for LimitError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			LimitError::TokenTooLong { row, col, length, max, .. } => {
+				write!(f, "Token of {} bytes at line {}, column {} exceeds the limit of {} bytes", length, row, col, max)
+			}
+			LimitError::TooManyTokens { row, col, max, .. } => {
+				write!(f, "Token at line {}, column {} exceeds the limit of {} tokens", row, col, max)
+			}
+			LimitError::DeadlineExceeded { row, col, .. } => {
+				write!(f, "Deadline exceeded at line {}, column {}", row, col)
+			}
+			LimitError::Cancelled { row, col, .. } => {
+				write!(f, "Lexing cancelled at line {}, column {}", row, col)
+			}
 		}
 	}
+}
 
-//----<TO_STRING_METHOD>----
+/// Text edit applied by `Lexer::relex`
+/// The bytes `start..old_end` of the old input are replaced by `text`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+	/// Start of the replaced range in the old input (in bytes)
+	pub start: usize,
+	/// End of the replaced range in the old input (in bytes)
+	pub old_end: usize,
+	/// Text inserted in place of the range
+	pub text: String,
 }
 
 /// Lexer structure for lexical analysis
 /// Parses input strings and generates tokens
+//----<LEXER_DOC>----
 pub struct Lexer {
 	/// Input string to be analyzed
 	pub input: String,
@@ -64,35 +343,216 @@ pub struct Lexer {
 	pub row: usize,
 	/// Current column number (1-based)
 	pub col: usize,
-	/// Regular expression cache (for performance optimization)
+	/// Identifier of the input, such as an index into a file table, stamped into every token
+	pub source_id: usize,
+	/// Position of the start of the input in an enclosing document (see `Lexer::with_offset`)
+	/// Token indices and spans are shifted by `start_index`
+	pub start_index: usize,
+	/// Row of the start of the input in an enclosing document (1-based)
+	pub start_row: usize,
+	/// Column of the start of the input in an enclosing document (1-based)
+	pub start_col: usize,
+	//----<REGEX_BEGIN>----
+	/// Regular expression cache keyed by rule index (for performance optimization)
 	pub regex_cache: HashMap<u32, Regex>,
+	//----<REGEX_END>----
 	/// Type of the last generated token
 	pub last_token_kind: Option<TokenKind>,
+	/// Types of the tokens before the last one, newest first (as many as %seq contexts need)
+	pub earlier_token_kinds: [Option<TokenKind>; 0],
+	//----<LAST_TOKEN_TEXT_BEGIN>----
+	/// Text of the last generated token (for context rules on token text)
+	pub last_token_text: String,
+	//----<LAST_TOKEN_TEXT_END>----
+	/// Current start condition
+	pub state: LexerState,
+	/// Start conditions saved by push_state
+	pub state_stack: Vec<LexerState>,
+	/// Fields declared with %state_struct
+	pub user_state: UserState,
+	/// Token of the match whose action is running, returned by `token` and `error`
+	pub action_token: Option<Token>,
+	/// Match kept by append_next, whose text starts the next token
+	pub append_start: Option<Token>,
+	/// Whether the end of input has already been handled
+	pub eof_reached: bool,
+	/// Whether a final Eof token is returned at the end of input
+	pub emit_eof: bool,
+	/// Token kind for unmatched input (%error); None emits Unknown tokens
+	pub error_kind: Option<TokenKind>,
+	/// Whether a run of unmatched input becomes a single token (%option recover or %error)
+	pub recover: bool,
+	/// Columns between tab stops, used for `col` and `indent` (%option tab-width)
+	/// The default of 1 counts a tab as one column
+	pub tab_width: usize,
+	/// Whether tokens on other channels are attached to the tokens on the default channel
+	/// as trivia instead of being dropped by next_token (%option trivia)
+	pub attach_trivia: bool,
+	/// Trivia read after the last token, attached to the next token as leading trivia
+	pub pending_trivia: Vec<Token>,
+	/// Token read while collecting trailing trivia, returned by the next read
+	pub pending_token: Option<Token>,
+	/// Whether columns count UTF-16 code units instead of characters, as the Language Server
+	/// Protocol expects (%option utf16-columns)
+	pub utf16_columns: bool,
+	/// Indentation widths of the enclosing blocks (%option indentation-tokens)
+	pub indent_stack: Vec<usize>,
+	/// Number of Dedent tokens still to be returned
+	pub pending_dedents: usize,
+	/// Last row whose indentation has been checked
+	pub indent_row: usize,
+	/// Tokens read ahead by peek, returned by next_token before reading more input
+	pub peeked: Vec<Token>,
+	/// Longest token in bytes; a longer token stops lexing (%option max-token-length)
+	pub max_token_length: Option<usize>,
+	/// Most tokens on any channel; the next token stops lexing (%option max-tokens)
+	pub max_tokens: Option<usize>,
+	//----<STD_BEGIN>----
+	/// Time after which lexing stops, checked before each token
+	pub deadline: Option<std::time::Instant>,
+	//----<STD_END>----
+	/// Callback set with on_progress, called with the bytes lexed so far and the input length
+	pub progress: Option<Box<dyn FnMut(usize, usize) -> bool + Send>>,
+	/// Bytes of input between calls of the progress callback
+	pub progress_interval: usize,
+	/// Position from which the progress callback is called next
+	pub next_progress: usize,
+	/// Number of tokens read on any channel
+	pub token_count: usize,
+	/// Limit that stopped the lexer; no more tokens are returned once it is set
+	pub limit_error: Option<LimitError>,
 }
 
 impl Lexer {
 	/// Creates a new lexer instance with the given input string
 	/// Initializes the position to the beginning and sets up regex cache
 	pub fn new(input: String) -> Self {
+		//----<NEW_INPUT_CODE>----
+		//----<REGEX_BEGIN>----
 		let mut regex_cache = HashMap::new();
 		regex_cache.insert(u32::MAX, Regex::new("__Unknown__").unwrap());
 		//----<REG_EX_CODE>----
+		//----<REGEX_END>----
 		Lexer {
 			input,
 			pos: 0,
 			row: 1,
 			col: 1,
+			source_id: 0,
+			start_index: 0,
+			start_row: 1,
+			start_col: 1,
+			//----<REGEX_BEGIN>----
 			regex_cache,
+			//----<REGEX_END>----
 			last_token_kind: None,
+			earlier_token_kinds: [None; 0],
+			//----<LAST_TOKEN_TEXT_BEGIN>----
+			last_token_text: String::new(),
+			//----<LAST_TOKEN_TEXT_END>----
+			state: LexerState::Initial,
+			state_stack: Vec::new(),
+			user_state: UserState::default(),
+			action_token: None,
+			append_start: None,
+			eof_reached: false,
+			emit_eof: false,
+			error_kind: None,
+			recover: false,
+			tab_width: 1,
+			attach_trivia: false,
+			pending_trivia: Vec::new(),
+			pending_token: None,
+			utf16_columns: false,
+			indent_stack: Vec::new(),
+			pending_dedents: 0,
+			indent_row: 0,
+			peeked: Vec::new(),
+			max_token_length: None,
+			max_tokens: None,
+			//----<STD_BEGIN>----
+			deadline: None,
+			//----<STD_END>----
+			progress: None,
+			progress_interval: 64 * 1024,
+			next_progress: 0,
+			token_count: 0,
+			limit_error: None,
 		}
 	}
 
+	/// Creates a new lexer instance whose tokens record `source_id`, so tokens of several
+	/// files can be told apart
+	pub fn new_with_source(input: String, source_id: usize) -> Self {
+		let mut lexer = Self::new(input);
+		lexer.source_id = source_id;
+		lexer
+	}
+
+	/// Creates a new lexer instance for text embedded in another document, such as a code
+	/// fence in markdown, which starts at `start_index` (in bytes), `start_row` and `start_col`
+	/// Positions of the tokens refer to the enclosing document
+	pub fn with_offset(input: String, start_index: usize, start_row: usize, start_col: usize) -> Self {
+		let mut lexer = Self::new(input);
+		lexer.start_index = start_index;
+		lexer.start_row = start_row;
+		lexer.start_col = start_col;
+		lexer.row = start_row;
+		lexer.col = start_col;
+		lexer
+	}
+
 	/// Creates a new lexer instance from a string slice
 	/// This is a convenience method that converts &str to String
+	#[allow(clippy::should_implement_trait)]
 	pub fn from_str(input: &str) -> Self {
 		Self::new(input.to_string())
 	}
 
+	/// Creates a new lexer instance from UTF-8 encoded bytes
+	/// Returns an error describing the first invalid byte sequence if the input is not valid UTF-8
+	pub fn from_bytes(input: &[u8]) -> Result<Self, core::str::Utf8Error> {
+		let input = core::str::from_utf8(input)?;
+		Ok(Self::from_str(input))
+	}
+
+	/// Rewinds the lexer to the start of its input, as if it had just been created
+	/// Settings such as `recover`, the limits, `source_id` and the start offsets are kept,
+	/// and so are the compiled regexes
+	pub fn reset(&mut self) {
+		self.pos = 0;
+		self.row = self.start_row;
+		self.col = self.start_col;
+		self.last_token_kind = None;
+		self.earlier_token_kinds.fill(None);
+		//----<LAST_TOKEN_TEXT_BEGIN>----
+		self.last_token_text.clear();
+		//----<LAST_TOKEN_TEXT_END>----
+		self.state = LexerState::Initial;
+		self.state_stack.clear();
+		self.user_state = UserState::default();
+		self.action_token = None;
+		self.append_start = None;
+		self.next_progress = 0;
+		self.token_count = 0;
+		self.limit_error = None;
+		self.eof_reached = false;
+		self.peeked.clear();
+		self.pending_trivia.clear();
+		self.pending_token = None;
+		self.indent_stack.clear();
+		self.pending_dedents = 0;
+		self.indent_row = 0;
+	}
+
+	/// Replaces the input and rewinds the lexer to its start (see `reset`)
+	/// Reusing a lexer for many inputs saves compiling the regexes for each of them
+	pub fn set_input(&mut self, input: String) {
+		//----<NEW_INPUT_CODE>----
+		self.input = input;
+		self.reset();
+	}
+
 	/// Tokenize input
 	pub fn tokenize(&mut self) -> Vec<Token> {
 		let mut tokens = vec![];
@@ -102,56 +562,529 @@ impl Lexer {
 		tokens
 	}
 
-	/// Returns the next token from the input string
+	/// Tokenizes the remaining input like `tokenize`, returning the limit that stopped the
+	/// lexer instead of the tokens read before it
+	pub fn try_tokenize(&mut self) -> Result<Vec<Token>, LimitError> {
+		let tokens = self.tokenize();
+		match self.limit_error.clone() {
+			Some(error) => Err(error),
+			None => Ok(tokens),
+		}
+	}
+
+	/// Returns an iterator over the remaining tokens
+	pub fn tokens(&mut self) -> impl Iterator<Item = Token> + '_ {
+		core::iter::from_fn(move || self.next_token())
+	}
+
+	/// Returns an iterator over the remaining tokens whose kind is not declared with %trivia
+	pub fn significant_tokens(&mut self) -> impl Iterator<Item = Token> + '_ {
+		self.tokens().filter(|token| !token.kind.is_trivia())
+	}
+
+	/// Returns an iterator over the remaining tokens of the given kind
+	pub fn tokens_of(&mut self, kind: TokenKind) -> impl Iterator<Item = Token> + '_ {
+		self.tokens().filter(move |token| token.kind == kind)
+	}
+
+	/// Tokenizes the whole input in one call
+	/// Unmatched input is returned in the list of errors instead of as tokens
+	pub fn tokenize_str(input: &str) -> (Vec<Token>, Vec<LexError>) {
+		let mut lexer = Self::from_str(input);
+		let mut tokens = Vec::new();
+		let mut errors = Vec::new();
+		while let Some(token) = lexer.next_token() {
+			match &token.error {
+				Some(message) => errors.push(lexer.lex_error(&token, message.clone())),
+				None => tokens.push(token),
+			}
+		}
+		(tokens, errors)
+	}
+
+	/// Builds the LexError of an error token returned by next_token
+	fn lex_error(&self, token: &Token, message: String) -> LexError {
+		let start = token.span.start - self.start_index;
+		let end = token.span.end - self.start_index;
+		LexError {
+			message,
+			index: token.index,
+			row: token.row,
+			col: token.col,
+			span: token.span,
+			text: self.input[start..end].to_string(),
+			state: self.state,
+		}
+	}
+
+	/// Applies a text edit to the input and re-tokenizes it, reusing the old tokens after the edit
+	/// `old_tokens` are the tokens of the input before the edit, as returned by `tokenize`
+	/// Lexing starts over, as the match of any token before the edit may have looked past
+	/// its start, and stops as soon as a new token lines up with an old token after the edit;
+	/// the remaining old tokens are shifted and reused
+	/// Returns the tokens of the edited input; the lexer is left at the end of input
+	pub fn relex(&mut self, edit: Edit, old_tokens: &[Token]) -> Vec<Token> {
+		// Start conditions and indentation are not recorded in tokens, so such lexers lex to the end
+		const RELEX_REUSES_TOKENS: bool = true;
+		self.input.replace_range(edit.start..edit.old_end, &edit.text);
+		let delta = edit.text.len() as isize - (edit.old_end - edit.start) as isize;
+		let shifted = |index: usize| (index as isize + delta) as usize;
+		// Token positions are shifted by start_index, the edit is in the input
+		let start_index = self.start_index;
+		let edit_start = start_index + edit.start;
+		let mut tokens = Vec::with_capacity(old_tokens.len());
+		self.reset();
+
+		// Error messages name the position of the error, so error tokens are never reused
+		let last_error = old_tokens.iter().rposition(|t| t.error.is_some());
+		let reusable = if RELEX_REUSES_TOKENS { old_tokens } else { &[] };
+		let mut old = reusable.iter().enumerate().filter(|(_, t)| t.index >= start_index + edit.old_end).peekable();
+		while let Some(token) = self.next_token() {
+			// Skip the old tokens the new tokens have passed
+			while old.peek().is_some_and(|(_, t)| shifted(t.index) < token.index) {
+				old.next();
+			}
+			if let Some(&(i, t)) = old.peek() {
+				let in_sync = token.index >= edit_start + edit.text.len()
+					&& shifted(t.index) == token.index
+					&& t.kind == token.kind
+					&& t.text.len() == token.text.len()
+					&& last_error.filter(|&e| e > i).is_none();
+				if in_sync {
+					// The rest of the old tokens only move
+					let row_delta = token.row as isize - t.row as isize;
+					let col_delta = token.col as isize - t.col as isize;
+					let (sync_row, indent) = (t.row, token.indent);
+					tokens.push(token);
+					// Shifts a reused token and the trivia attached to it
+					fn shift(token: &mut Token, delta: isize, sync_row: usize, row_delta: isize, col_delta: isize, indent: usize) {
+						let shifted = |index: usize| (index as isize + delta) as usize;
+						token.index = shifted(token.index);
+						token.span = Span::new(shifted(token.span.start), shifted(token.span.end));
+						if token.row == sync_row {
+							token.col = (token.col as isize + col_delta) as usize;
+							token.indent = indent;
+						}
+						token.row = (token.row as isize + row_delta) as usize;
+						for trivia in token.leading_trivia.iter_mut().chain(token.trailing_trivia.iter_mut()) {
+							shift(trivia, delta, sync_row, row_delta, col_delta, indent);
+						}
+					}
+					for old_token in &old_tokens[i + 1..] {
+						let mut old_token = old_token.clone();
+						shift(&mut old_token, delta, sync_row, row_delta, col_delta, indent);
+						tokens.push(old_token);
+					}
+					// Move to the end of input as if the rest had been lexed
+					for ch in self.input[self.pos..].chars() {
+						if ch == '\n' {
+							self.row += 1;
+							self.col = 1;
+						} else {
+							self.col = self.next_col(self.col, ch);
+						}
+					}
+					self.pos = self.input.len();
+					self.eof_reached = true;
+					return tokens;
+				}
+			}
+			tokens.push(token);
+		}
+		tokens
+	}
+
+	/// Returns the next token on the default channel
 	/// Returns None when the end of input is reached
 	pub fn next_token(&mut self) -> Option<Token> {
-		if self.pos >= self.input.len() {
+		if !self.peeked.is_empty() {
+			return Some(self.peeked.remove(0));
+		}
+		self.read_token()
+	}
+
+	/// Returns the next token on the default channel without consuming it
+	pub fn peek(&mut self) -> Option<&Token> {
+		self.peek_n(0)
+	}
+
+	/// Returns the token `n` positions ahead on the default channel (0 is the next token)
+	/// without consuming any token
+	/// Peeked tokens are buffered and returned by next_token; next_token_any does not see them
+	pub fn peek_n(&mut self, n: usize) -> Option<&Token> {
+		while self.peeked.len() <= n {
+			let token = self.read_token()?;
+			self.peeked.push(token);
+		}
+		self.peeked.get(n)
+	}
+
+	/// Reads the next token on the default channel from the input
+	fn read_token(&mut self) -> Option<Token> {
+		if self.attach_trivia {
+			return self.read_token_with_trivia();
+		}
+		loop {
+			let token = self.next_token_any()?;
+			if token.channel == TokenChannel::Default {
+				return Some(token);
+			}
+		}
+	}
+
+	/// Reads the next token on the default channel and attaches the tokens on other channels
+	/// around it: those up to the end of its line become its trailing trivia, the others
+	/// the leading trivia of the next token
+	/// Trivia at the end of input becomes the leading trivia of an Eof token
+	fn read_token_with_trivia(&mut self) -> Option<Token> {
+		let mut leading = core::mem::take(&mut self.pending_trivia);
+		let mut token = loop {
+			match self.pending_token.take().or_else(|| self.next_token_any()) {
+				Some(token) if token.channel == TokenChannel::Default => break token,
+				Some(trivia) => leading.push(trivia),
+				None if leading.is_empty() => return None,
+				None => {
+					let mut eof = Token::new(TokenKind::Eof, String::new(), self.start_index + self.pos, self.row, self.col, 0, 0);
+					eof.source_id = self.source_id;
+					break eof;
+				}
+			}
+		};
+		token.leading_trivia = leading;
+		let row = self.row;
+		while let Some(next) = self.next_token_any() {
+			if next.channel == TokenChannel::Default {
+				self.pending_token = Some(next);
+				break;
+			}
+			if next.row != row {
+				self.pending_trivia.push(next);
+				break;
+			}
+			// The trailing trivia ends with the line break
+			let ends_line = self.row != row;
+			token.trailing_trivia.push(next);
+			if ends_line {
+				break;
+			}
+		}
+		Some(token)
+	}
+
+	/// Returns the next token on any channel, including hidden tokens
+	/// Returns None when the end of input is reached or a limit stopped the lexer
+	pub fn next_token_any(&mut self) -> Option<Token> {
+		if self.limit_error.is_some() {
+			return None;
+		}
+		//----<STD_BEGIN>----
+		if self.deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+			self.limit_error = Some(LimitError::DeadlineExceeded { index: self.start_index + self.pos, row: self.row, col: self.col });
+			return None;
+		}
+		//----<STD_END>----
+		let total = self.input.len();
+		if let Some(progress) = self.progress.as_mut().filter(|_| self.pos >= self.next_progress) {
+			// Called every progress_interval bytes and once at the end of input
+			self.next_progress = if self.pos >= total { total + 1 } else { (self.pos + self.progress_interval).min(total) };
+			if !progress(self.pos, total) {
+				self.limit_error = Some(LimitError::Cancelled { index: self.start_index + self.pos, row: self.row, col: self.col });
+				return None;
+			}
+		}
+		let mut token = self.lex_token()?;
+		if let Some(start) = self.append_start.take() {
+			token = self.appended_token(start, token);
+		}
+		if self.start_index > 0 {
+			// Positions refer to the enclosing document
+			token.index += self.start_index;
+			token.span = Span::new(token.span.start + self.start_index, token.span.end + self.start_index);
+		}
+		if let Some(max) = self.max_token_length.filter(|&max| token.span.len() > max) {
+			self.limit_error = Some(LimitError::TokenTooLong {
+				index: token.index,
+				row: token.row,
+				col: token.col,
+				length: token.span.len(),
+				max,
+			});
 			return None;
 		}
+		self.token_count += 1;
+		if let Some(max) = self.max_tokens.filter(|&max| self.token_count > max) {
+			self.limit_error = Some(LimitError::TooManyTokens { index: token.index, row: token.row, col: token.col, max });
+			return None;
+		}
+		token.source_id = self.source_id;
+		Some(token)
+	}
 
-		let remaining = &self.input[self.pos..];
+	/// Reads the next token on any channel from the input
+	#[allow(clippy::never_loop)]
+	fn lex_token(&mut self) -> Option<Token> {
+		// Skipped matches continue with the next match instead of recursing, so long runs
+		// of skipped input cannot overflow the stack
+		loop {
+			//----<BEFORE_TOKEN_CODE>----
+			if self.pos >= self.input.len() {
+				return self.end_of_input();
+			}
+
+			// Rules see one character past max_token_length, so a token that is too long
+			// is found without matching the rest of the input (%option max-token-length)
+			let remaining = &self.input[self.pos..self.match_end()];
+			let start_row = self.row;
+			let start_col = self.col;
+
+			// Calculate indent (spaces at the start of current line)
+			let indent = self.calculate_line_indent();
+
+			//----<RULE_MATCH_CODE>----
+
+			// No pattern matched, consume one character (or the whole unmatched run when recovering)
+			let mut length = remaining.chars().next().unwrap().len_utf8();
+			if self.recover {
+				// Skip forward to where a rule matches again and return the run as one token
+				let remaining = &self.input[self.pos..];
+				while length < remaining.len() && !self.any_rule_matches(&remaining[length..]) {
+					length += remaining[length..].chars().next().unwrap().len_utf8();
+				}
+			}
+			let matched = self.input[self.pos..self.pos + length].to_string();
+			let current_pos = self.pos;
+			self.advance(&matched);
+			let kind = self.error_kind.unwrap_or(TokenKind::Unknown);
+			let mut token = Token::new(kind, matched.clone(), current_pos, start_row, start_col, Token::text_length(&matched), indent);
+			token.error = Some(format!("Unexpected input {:?} at line {}, column {}", matched, start_row, start_col));
+			self.set_last_token(&token);
+			return Some(token);
+		}
+	}
+
+	/// Returns the end of the input the rules may match at the current position: one
+	/// character past max_token_length bytes, or the end of input without a limit
+	fn match_end(&self) -> usize {
+		let Some(max) = self.max_token_length else {
+			return self.input.len();
+		};
+		let mut end = self.pos.saturating_add(max).saturating_add(1).min(self.input.len());
+		while !self.input.is_char_boundary(end) {
+			end += 1;
+		}
+		end
+	}
+
+	/// Returns true if any rule matches a non-empty prefix of the input in the current state
+	/// Used to find where unmatched input ends
+	#[allow(unused_variables)]
+	fn any_rule_matches(&self, remaining: &str) -> bool {
+		//----<ANY_RULE_MATCHES>----
+		false
+	}
+
+	/// Handles the end of input once: runs the <<EOF>> rules and returns
+	/// the Eof token when emit_eof is set. Returns None afterwards
+	fn end_of_input(&mut self) -> Option<Token> {
+		if self.eof_reached {
+			return None;
+		}
+		self.eof_reached = true;
 		let start_row = self.row;
 		let start_col = self.col;
-
-		// Calculate indent (spaces at the start of current line)
 		let indent = self.calculate_line_indent();
+		let mut eof_token: Option<Token> = None;
+		//----<EOF_CODE>----
+		if eof_token.is_none() && self.emit_eof {
+			eof_token = Some(Token::new(TokenKind::Eof, String::new(), self.pos, start_row, start_col, 0, indent));
+		}
+		if let Some(token) = &eof_token {
+			self.set_last_token(token);
+		}
+		eof_token
+	}
+
+	//----<STD_BEGIN>----
+	/// Stops lexing once `timeout` has passed from now, with `LimitError::DeadlineExceeded`
+	/// The deadline is checked between tokens, so a single match is never interrupted
+	pub fn set_timeout(&mut self, timeout: std::time::Duration) {
+		self.deadline = Some(std::time::Instant::now() + timeout);
+	}
+	//----<STD_END>----
+
+	/// Calls `callback` with the bytes lexed so far and the length of the input every
+	/// `progress_interval` bytes (64 KiB by default), and once at the end of input
+	/// Returning false from the callback stops lexing with `LimitError::Cancelled`
+	pub fn on_progress(&mut self, callback: impl FnMut(usize, usize) -> bool + Send + 'static) {
+		self.progress = Some(Box::new(callback));
+	}
 
-		//----<RULE_MATCH_CODE>----
+	/// Sets the columns between tab stops used for `col` and `indent` of the following tokens
+	pub fn set_tab_width(&mut self, tab_width: usize) {
+		self.tab_width = tab_width.max(1);
+	}
+
+	/// Returns the match whose action is running as a token of the given kind
+	/// For use as the result of action code: `-> { self.token(TokenKind::Number) }`
+	pub fn token(&mut self, kind: TokenKind) -> Option<Token> {
+		let mut token = self.action_token.take()?;
+		token.kind = kind;
+		Some(token)
+	}
 
-		// No pattern matched, consume one character
-		let ch = remaining.chars().next().unwrap();
-		let matched = ch.to_string();
-		let current_pos = self.pos;
-		self.advance(&matched);
-		let token = Token::new(TokenKind::Unknown, matched, current_pos, start_row, start_col, 1, indent);
-		self.last_token_kind = Some(token.kind.clone());
+	/// Returns the match whose action is running as an error token with the given message
+	/// The token has the %error kind, or Unknown without %error
+	pub fn error(&mut self, message: impl Into<String>) -> Option<Token> {
+		let mut token = self.action_token.take()?;
+		token.kind = self.error_kind.unwrap_or(TokenKind::Unknown);
+		token.error = Some(message.into());
 		Some(token)
 	}
 
+	/// Consumes the match whose action is running without returning a token
+	/// For use as the result of action code: `-> { self.skip() }`
+	pub fn skip(&mut self) -> Option<Token> {
+		self.action_token = None;
+		None
+	}
+
+	/// Sets the tag of the token returned by the running action through `token` or `error`
+	pub fn set_tag(&mut self, tag: isize) {
+		if let Some(token) = &mut self.action_token {
+			token.tag = tag;
+		}
+	}
+
+	/// Gives back the last `n` characters of the match whose action is running, so the
+	/// next rules match them again (yyless)
+	/// The token returned by `token` or `error` is shortened accordingly
+	pub fn push_back(&mut self, n: usize) {
+		let Some(token) = self.action_token.take() else {
+			return;
+		};
+		let text = &self.input[token.span.range()];
+		let keep = text.chars().count().saturating_sub(n);
+		let kept: String = text.chars().take(keep).collect();
+		let length = Token::text_length(&kept);
+		self.pos = token.index;
+		self.row = token.row;
+		self.col = token.col;
+		self.advance(&kept);
+		let shortened = Token::new(token.kind, kept, token.index, token.row, token.col, length, token.indent);
+		self.action_token = Some(Token {
+			tag: token.tag,
+			error: token.error,
+			channel: token.channel,
+			..shortened
+		});
+	}
+
+	/// Consumes the match whose action is running and makes its text the start of the
+	/// next token (yymore)
+	/// For use as the result of action code: `-> { self.append_next() }`
+	pub fn append_next(&mut self) -> Option<Token> {
+		if self.append_start.is_none() {
+			self.append_start = self.action_token.take();
+		}
+		self.action_token = None;
+		None
+	}
+
+	/// Extends a token to start at the match kept by append_next
+	fn appended_token(&self, start: Token, token: Token) -> Token {
+		if token.span.end < start.index {
+			return token;
+		}
+		let text = self.input[start.index..token.span.end].to_string();
+		let length = Token::text_length(&text);
+		let appended = Token::new(token.kind, text, start.index, start.row, start.col, length, start.indent);
+		Token {
+			tag: token.tag,
+			error: token.error,
+			channel: token.channel,
+			..appended
+		}
+	}
+
+	/// Switches the lexer to the given start condition
+	pub fn begin(&mut self, state: LexerState) {
+		self.state = state;
+	}
+
+	/// Enters the given start condition, saving the current one on the state stack
+	pub fn push_state(&mut self, state: LexerState) {
+		self.state_stack.push(self.state);
+		self.state = state;
+	}
+
+	/// Returns to the start condition saved by the last push_state
+	/// Falls back to the initial state when the state stack is empty
+	pub fn pop_state(&mut self) {
+		self.state = self.state_stack.pop().unwrap_or(LexerState::Initial);
+	}
+
+//----<KEYWORD_METHOD>----
+//----<MATCHER_CODE>----
 	/// Calculates the indentation level of the current line
-	/// Returns the number of spaces from the beginning of the line
+	/// Returns the width of the spaces and tabs at the beginning of the line,
+	/// with tabs advancing to the next tab stop
 	pub fn calculate_line_indent(&self) -> usize {
-		// Find the start of the current line
-		let mut line_start = 0;
-		let mut pos = 0;
-		
 		// Find the beginning of the current line
-		while pos < self.pos {
-			if self.input.chars().nth(pos) == Some('\n') {
-				line_start = pos + 1;
+		let line_start = self.input[..self.pos].rfind('\n').map_or(0, |newline| newline + 1);
+
+		// Measure the leading whitespace of the line
+		let mut col = 1;
+		for ch in self.input[line_start..].chars().take_while(|&c| c == ' ' || c == '\t') {
+			col = self.next_col(col, ch);
+		}
+		col - 1
+	}
+
+	/// Returns the column following a character at `col` on the same line
+	/// A tab moves to the next tab stop, every other character takes one column
+	/// (or its number of UTF-16 code units with utf16_columns)
+	fn next_col(&self, col: usize, ch: char) -> usize {
+		let tab_width = self.tab_width.max(1);
+		if ch == '\t' {
+			(col - 1) / tab_width * tab_width + tab_width + 1
+		} else if self.utf16_columns {
+			col + ch.len_utf16()
+		} else {
+			col + 1
+		}
+	}
+
+	/// Matches text enclosed in possibly nested delimiters (%balanced)
+	/// Returns None if the input does not start with `open` or the delimiters are unbalanced
+	pub fn match_balanced(input: &str, open: &str, close: &str) -> Option<String> {
+		if !input.starts_with(open) {
+			return None;
+		}
+		let mut depth = 0;
+		let mut pos = 0;
+		while pos < input.len() {
+			let rest = &input[pos..];
+			if depth > 0 && rest.starts_with(close) {
+				depth -= 1;
+				pos += close.len();
+				if depth == 0 {
+					return Some(input[..pos].to_string());
+				}
+			} else if rest.starts_with(open) {
+				depth += 1;
+				pos += open.len();
+			} else {
+				pos += rest.chars().next()?.len_utf8();
 			}
-			pos += 1;
 		}
-		
-		// Count spaces from the beginning of the line
-		let line_content = &self.input[line_start..];
-		line_content.chars().take_while(|&c| c == ' ').count()
+		None
 	}
 
-	/// Attempts to match a cached regex pattern against the input
+	//----<REGEX_BEGIN>----
+	/// Attempts to match the cached regex pattern of a rule against the input
 	/// Returns the matched string if found, None otherwise
-	pub fn match_cached_pattern(&self, input: &str, token_kind: TokenKind) -> Option<String> {
-		if let Some(regex) = self.regex_cache.get(&(token_kind as u32)) {
+	pub fn match_cached_pattern(&self, input: &str, rule_id: u32) -> Option<String> {
+		if let Some(regex) = self.regex_cache.get(&rule_id) {
 			if let Some(mat) = regex.find(input) {
 				return Some(mat.as_str().to_string());
 			}
@@ -159,6 +1092,41 @@ impl Lexer {
 		None
 	}
 
+	/// Matches an opening regex and the text up to its terminator (%heredoc)
+	/// The terminator is the first capture group of the opening (or the whole opening)
+	/// and must appear at the start of a line after the opening
+	pub fn match_heredoc(&self, input: &str, rule_id: u32) -> Option<String> {
+		Self::match_heredoc_regex(self.regex_cache.get(&rule_id)?, input)
+	}
+
+	/// Matches a heredoc with the given opening regex, as `match_heredoc` does with a cached one
+	pub fn match_heredoc_regex(opening: &Regex, input: &str) -> Option<String> {
+		let caps = opening.captures(input)?;
+		let body_start = caps.get(0)?.end();
+		let terminator = caps.get(1).or(caps.get(0))?.as_str();
+		if terminator.is_empty() {
+			return None;
+		}
+		let mut search = body_start;
+		while let Some(offset) = input[search..].find(terminator) {
+			let pos = search + offset;
+			if input[..pos].ends_with('\n') {
+				return Some(input[..pos + terminator.len()].to_string());
+			}
+			search = pos + terminator.len();
+		}
+		None
+	}
+
+	/// Attempts to match a cached trailing-context pattern against the input
+	/// Returns only the text before the trailing context, which is not consumed
+	pub fn match_cached_lookahead(&self, input: &str, rule_id: u32) -> Option<String> {
+		let regex = self.regex_cache.get(&rule_id)?;
+		let caps = regex.captures(input)?;
+		caps.get(1).map(|mat| mat.as_str().to_string())
+	}
+	//----<REGEX_END>----
+
 	/// Advances the lexer position based on the matched string
 	/// Updates position, row, and column counters appropriately
 	fn advance(&mut self, matched: &str) {
@@ -168,8 +1136,38 @@ impl Lexer {
 				self.row += 1;
 				self.col = 1;
 			} else {
-				self.col += 1;
+				self.col = self.next_col(self.col, ch);
+			}
+		}
+	}
+
+	/// Records a token as the context of the following rules
+	fn set_last_token(&mut self, token: &Token) {
+		if let Some(oldest) = self.earlier_token_kinds.len().checked_sub(1) {
+			self.earlier_token_kinds.copy_within(..oldest, 1);
+			if let Some(earlier) = self.earlier_token_kinds.first_mut() {
+				*earlier = self.last_token_kind;
 			}
 		}
+		self.last_token_kind = Some(token.kind);
+		//----<LAST_TOKEN_TEXT_BEGIN>----
+		self.last_token_text.clear();
+		self.last_token_text.push_str(&token.text);
+		//----<LAST_TOKEN_TEXT_END>----
+	}
+}
+
+//----<RESULT_ERRORS_BEGIN>----
+/// Iterates over the tokens, returning unmatched input as `Err` (%option result-errors)
+impl Iterator for Lexer {
+	type Item = Result<Token, LexError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let token = self.next_token()?;
+		match &token.error {
+			Some(message) => Some(Err(self.lex_error(&token, message.clone()))),
+			None => Some(Ok(token)),
+		}
 	}
 }
+//----<RESULT_ERRORS_END>----
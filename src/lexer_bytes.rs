@@ -0,0 +1,402 @@
+// This file is auto-generated by build.rs
+// Do not edit manually
+// --------------------------------------------------------
+//----<GENERATED_BY>----
+//----<IMPORTS>----
+
+//----<REGEX_BEGIN>----
+use regex::bytes::Regex;
+use std::collections::HashMap;
+//----<REGEX_END>----
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum TokenKind {
+	/// Input that no rule matched
+	Unknown = 0,
+	/// End of input
+	Eof = 1,
+//----<TOKEN_KIND>----
+}
+
+impl TokenKind {
+	/// Returns the numeric code of the kind
+	/// Codes follow the order the spec declares the kinds in, after 0 for Unknown and 1 for Eof
+	pub fn as_u16(self) -> u16 {
+		self as u16
+	}
+
+	/// Returns the kind with the given code from as_u16
+	pub fn from_u16(code: u16) -> Option<Self> {
+		match code {
+			0 => Some(TokenKind::Unknown),
+			1 => Some(TokenKind::Eof),
+//----<TOKEN_KIND_FROM_U16>----
+			_ => None,
+		}
+	}
+
+	/// Returns true for the kinds declared with %trivia, such as whitespace and comments
+	pub fn is_trivia(&self) -> bool {
+		//----<TRIVIA_KINDS>----
+		false
+	}
+}
+
+/// Description of a token kind in TOKENS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenInfo {
+	/// The token kind
+	pub kind: TokenKind,
+	/// Name of the kind, as written by Display
+	pub name: &'static str,
+	/// Numeric code of the kind, as returned by TokenKind::as_u16
+	pub id: u16,
+	/// Pattern of the first rule producing the kind (empty for kinds without a rule)
+	pub pattern: &'static str,
+	/// True for the kinds declared with %trivia
+	pub is_trivia: bool,
+}
+
+/// Every token kind of the lexer, in the order of their codes
+pub const TOKENS: &[TokenInfo] = &[
+	TokenInfo { kind: TokenKind::Unknown, name: "UNKNOWN", id: 0, pattern: "", is_trivia: false },
+	TokenInfo { kind: TokenKind::Eof, name: "EOF", id: 1, pattern: "", is_trivia: false },
+//----<TOKEN_INFOS>----
+];
+
+impl core::fmt::Display for TokenKind {
+	/// Writes the name of the token kind (`UNKNOWN` and `EOF` for the built-in kinds)
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		let name = match self {
+			TokenKind::Unknown => "UNKNOWN",
+			TokenKind::Eof => "EOF",
+//----<TOKEN_KIND_NAMES>----
+		};
+		f.write_str(name)
+	}
+}
+
+impl core::str::FromStr for TokenKind {
+	type Err = String;
+
+	/// Parses a name written by Display back into a token kind
+	fn from_str(name: &str) -> Result<Self, Self::Err> {
+		match name {
+			"UNKNOWN" => Ok(TokenKind::Unknown),
+			"EOF" => Ok(TokenKind::Eof),
+//----<TOKEN_KIND_FROM_NAME>----
+			_ => Err(format!("Unknown token kind: {}", name)),
+		}
+	}
+}
+
+/// Byte range of a token in the input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+	/// Byte offset of the first byte
+	pub start: usize,
+	/// Byte offset just past the last byte
+	pub end: usize,
+}
+
+impl Span {
+	/// Creates a span covering the bytes `start..end`
+	pub fn new(start: usize, end: usize) -> Self {
+		Span { start, end }
+	}
+
+	/// Returns the smallest span covering both spans, such as the span of a whole expression
+	pub fn join(self, other: Span) -> Span {
+		Span {
+			start: self.start.min(other.start),
+			end: self.end.max(other.end),
+		}
+	}
+
+	/// Returns the length of the span in bytes
+	pub fn len(&self) -> usize {
+		self.end - self.start
+	}
+
+	/// Returns true if the span covers no bytes
+	pub fn is_empty(&self) -> bool {
+		self.start == self.end
+	}
+
+	/// Returns the span as a range for slicing the input: `&input[span.range()]`
+	pub fn range(&self) -> core::ops::Range<usize> {
+		self.start..self.end
+	}
+}
+
+/// Token structure that represents a lexical token
+/// The text borrows the bytes of the input, which need not be UTF-8
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token<'a> {
+	/// Token type identifier
+	pub kind: TokenKind,
+	/// Bytes of the token
+	pub text: &'a [u8],
+	/// 0-based start position in the entire input (in bytes)
+	pub index: usize,
+	/// Row number where the token appears (1-based)
+	pub row: usize,
+	/// Column number where the token appears (1-based, in bytes)
+	pub col: usize,
+	/// Byte range of the token in the input
+	pub span: Span,
+	/// User-defined tag (for additional information)
+	pub tag: isize,
+	/// Error message of tokens produced for unmatched input
+	pub error: Option<String>,
+}
+
+impl<'a> Token<'a> {
+	/// Creates a new token with the specified parameters
+	/// The tag field is initialized to 0 and the error field to None
+	pub fn new(kind: TokenKind, text: &'a [u8], index: usize, row: usize, col: usize) -> Self {
+		Token {
+			kind,
+			text,
+			index,
+			row,
+			col,
+			span: Span::new(index, index + text.len()),
+			tag: 0,
+			error: None,
+		}
+	}
+
+	/// Returns the text as a string, replacing invalid UTF-8 with U+FFFD
+	pub fn text_lossy(&self) -> std::borrow::Cow<'a, str> {
+		String::from_utf8_lossy(self.text)
+	}
+}
+
+impl core::fmt::Display for Token<'_> {
+	/// Writes the name of the token kind
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		core::fmt::Display::fmt(&self.kind, f)
+	}
+}
+
+/// Lexer structure for lexical analysis of bytes
+/// Character classes of the rules match single bytes, so the input may be in any encoding
+//----<LEXER_DOC>----
+pub struct Lexer<'a> {
+	/// Input bytes to be analyzed
+	pub input: &'a [u8],
+	/// Current parsing position (in bytes)
+	pub pos: usize,
+	/// Current row number (1-based)
+	pub row: usize,
+	/// Current column number (1-based, in bytes)
+	pub col: usize,
+	//----<REGEX_BEGIN>----
+	/// Regular expression cache keyed by rule index (for performance optimization)
+	pub regex_cache: HashMap<u32, Regex>,
+	//----<REGEX_END>----
+	/// Type of the last generated token
+	pub last_token_kind: Option<TokenKind>,
+	/// Whether the end of input has already been handled
+	pub eof_reached: bool,
+	/// Whether a final Eof token is returned at the end of input
+	pub emit_eof: bool,
+	/// Token kind for unmatched input (%error); None emits Unknown tokens
+	pub error_kind: Option<TokenKind>,
+	/// Whether a run of unmatched input becomes a single token (%option recover or %error)
+	pub recover: bool,
+	/// Columns between tab stops, used for `col` (%option tab-width)
+	/// The default of 1 counts a tab as one column
+	pub tab_width: usize,
+}
+
+impl<'a> Lexer<'a> {
+	/// Creates a new lexer instance over the given bytes
+	/// Initializes the position to the beginning and sets up regex cache
+	pub fn new(input: &'a [u8]) -> Self {
+		//----<NEW_INPUT_CODE>----
+		//----<REGEX_BEGIN>----
+		let regex_cache: HashMap<u32, Regex> = HashMap::from([
+			//----<REG_EX_CODE>----
+		]);
+		//----<REGEX_END>----
+		Lexer {
+			input,
+			pos: 0,
+			row: 1,
+			col: 1,
+			//----<REGEX_BEGIN>----
+			regex_cache,
+			//----<REGEX_END>----
+			last_token_kind: None,
+			eof_reached: false,
+			emit_eof: false,
+			error_kind: None,
+			recover: false,
+			tab_width: 1,
+		}
+	}
+
+	/// Creates a new lexer instance over the bytes of a string slice
+	#[allow(clippy::should_implement_trait)]
+	pub fn from_str(input: &'a str) -> Self {
+		Self::new(input.as_bytes())
+	}
+
+	/// Sets the columns between tab stops used for `col` of the following tokens
+	pub fn set_tab_width(&mut self, tab_width: usize) {
+		self.tab_width = tab_width.max(1);
+	}
+
+	/// Rewinds the lexer to the start of its input, as if it had just been created
+	/// Settings such as `recover` are kept, and so are the compiled regexes
+	pub fn reset(&mut self) {
+		self.pos = 0;
+		self.row = 1;
+		self.col = 1;
+		self.last_token_kind = None;
+		self.eof_reached = false;
+	}
+
+	/// Replaces the input and rewinds the lexer to its start (see `reset`)
+	/// Reusing a lexer for many inputs saves compiling the regexes for each of them
+	pub fn set_input(&mut self, input: &'a [u8]) {
+		//----<NEW_INPUT_CODE>----
+		self.input = input;
+		self.reset();
+	}
+
+	/// Tokenize input
+	pub fn tokenize(&mut self) -> Vec<Token<'a>> {
+		let mut tokens = vec![];
+		while let Some(tok) = self.next_token() {
+			tokens.push(tok);
+		}
+		tokens
+	}
+
+	/// Returns an iterator over the remaining tokens
+	pub fn tokens(&mut self) -> impl Iterator<Item = Token<'a>> + '_ {
+		core::iter::from_fn(move || self.next_token())
+	}
+
+	/// Returns an iterator over the remaining tokens whose kind is not declared with %trivia
+	pub fn significant_tokens(&mut self) -> impl Iterator<Item = Token<'a>> + '_ {
+		self.tokens().filter(|token| !token.kind.is_trivia())
+	}
+
+	/// Returns an iterator over the remaining tokens of the given kind
+	pub fn tokens_of(&mut self, kind: TokenKind) -> impl Iterator<Item = Token<'a>> + '_ {
+		self.tokens().filter(move |token| token.kind == kind)
+	}
+
+	/// Returns the next token
+	/// Returns None when the end of input is reached
+	#[allow(clippy::never_loop)]
+	pub fn next_token(&mut self) -> Option<Token<'a>> {
+		// Skipped matches continue with the next match instead of recursing
+		loop {
+			//----<BEFORE_TOKEN_CODE>----
+			if self.pos >= self.input.len() {
+				return self.end_of_input();
+			}
+
+			// Borrow the input itself rather than the lexer, so tokens can outlive the call
+			let input = self.input;
+			let remaining = &input[self.pos..];
+
+			//----<RULE_MATCH_CODE>----
+
+			// No pattern matched, consume one byte (or the whole unmatched run when recovering)
+			let mut length = 1;
+			if self.recover {
+				// Skip forward to where a rule matches again and return the run as one token
+				while length < remaining.len() && !self.any_rule_matches(&remaining[length..]) {
+					length += 1;
+				}
+			}
+			let (row, col) = (self.row, self.col);
+			let kind = self.error_kind.unwrap_or(TokenKind::Unknown);
+			let mut token = self.accept(kind, length);
+			token.error = Some(format!(
+				"Unexpected input {:?} at line {}, column {}",
+				token.text_lossy(),
+				row,
+				col
+			));
+			return Some(token);
+		}
+	}
+
+	/// Returns true if any rule matches a non-empty prefix of the input
+	/// Used to find where unmatched input ends
+	#[allow(unused_variables)]
+	fn any_rule_matches(&self, remaining: &[u8]) -> bool {
+		//----<ANY_RULE_MATCHES>----
+		false
+	}
+
+	/// Handles the end of input once and returns the Eof token when emit_eof is set
+	/// Returns None afterwards
+	fn end_of_input(&mut self) -> Option<Token<'a>> {
+		if self.eof_reached || !self.emit_eof {
+			self.eof_reached = true;
+			return None;
+		}
+		self.eof_reached = true;
+		let input = self.input;
+		let token = Token::new(TokenKind::Eof, &input[self.pos..], self.pos, self.row, self.col);
+		self.last_token_kind = Some(token.kind);
+		Some(token)
+	}
+
+	/// Consumes `length` bytes and returns them as a token of the given kind
+	fn accept(&mut self, kind: TokenKind, length: usize) -> Token<'a> {
+		let input = self.input;
+		let token = Token::new(kind, &input[self.pos..self.pos + length], self.pos, self.row, self.col);
+		self.advance(length);
+		self.last_token_kind = Some(kind);
+		token
+	}
+
+	/// Returns the length of `literal` if the input starts with it
+	pub fn match_literal(input: &[u8], literal: &[u8]) -> Option<usize> {
+		(!literal.is_empty() && input.starts_with(literal)).then_some(literal.len())
+	}
+
+	//----<REGEX_BEGIN>----
+	/// Attempts to match the cached regex pattern of a rule against the input
+	/// Returns the length of a non-empty match
+	pub fn match_cached_pattern(&self, input: &[u8], rule_id: u32) -> Option<usize> {
+		let mat = self.regex_cache.get(&rule_id)?.find(input)?;
+		(mat.end() > 0).then_some(mat.end())
+	}
+
+	/// Attempts to match a cached trailing-context pattern against the input
+	/// Returns the length of the text before the trailing context, which is not consumed
+	pub fn match_cached_lookahead(&self, input: &[u8], rule_id: u32) -> Option<usize> {
+		let caps = self.regex_cache.get(&rule_id)?.captures(input)?;
+		caps.get(1).map(|mat| mat.end()).filter(|&length| length > 0)
+	}
+	//----<REGEX_END>----
+
+	/// Advances the lexer position by `length` bytes
+	/// Updates position, row, and column counters appropriately
+	fn advance(&mut self, length: usize) {
+		let tab_width = self.tab_width.max(1);
+		for &byte in &self.input[self.pos..self.pos + length] {
+			if byte == b'\n' {
+				self.row += 1;
+				self.col = 1;
+			} else if byte == b'\t' {
+				// Move to the next tab stop
+				self.col = (self.col - 1) / tab_width * tab_width + tab_width + 1;
+			} else {
+				self.col += 1;
+			}
+		}
+		self.pos += length;
+	}
+}
@@ -38,6 +38,9 @@
 //! (Rust code here – e.g. main function or tests)
 //! ```
 //!
+//! Only lines consisting of `%%` separate sections, so `%%` may appear in code and patterns.
+//! The last section may be omitted, and a file without a `%%` line contains rules only.
+//!
 //! ### Writing rules
 //!
 //! Write one rule per line in the following form:
@@ -58,7 +61,34 @@ pub mod parser;
 pub mod generator;
 pub mod token;
 pub mod lexer;
+pub mod lexer_bytes;
+mod config;
+mod dfa;
+mod docs;
+mod graph;
+mod highlight;
+mod interpreter;
+mod json;
+mod lint;
+mod pretty;
+mod repl;
+mod stats;
 
-pub use generator::generate_lexer;
-pub use parser::{parse_spec, LexerRule, LexerSpec, ParseError};
+pub use config::{LexerConfig, ProjectConfig};
+pub use generator::{
+    generate, generate_bench, generate_c_header, generate_lexer, generate_lexer_with_options, Backend, Diagnostic,
+    GenerationResult, GeneratorOptions, Severity, Visibility,
+};
+pub use docs::generate_token_docs;
+pub use graph::generate_graph;
+pub use parser::{
+    parse_spec, parse_spec_all, parse_spec_file, parse_spec_file_all, parse_spec_file_with_definitions,
+    spec_file_includes, supported_spec_version, supports_spec_version, ContextToken, LexerOptions, LexerRule,
+    LexerSpec, ParseError, SourceSpan, SpecDirective,
+};
+pub use interpreter::SampleToken;
+pub use json::json_string;
+pub use lint::{lint_spec, LINTS};
+pub use repl::run_repl;
+pub use stats::{spec_stats, SpecStats};
 pub use token::Token;
@@ -0,0 +1,165 @@
+//! Lints of lexer specifications.
+//!
+//! `lint_spec` reports the problems generation warns about together with patterns that
+//! are legal but likely mistakes, each under a named code so `klex lint` can deny or
+//! allow them one by one.
+
+use crate::generator::{describe_pattern, generation_warnings, rule_literal, rule_to_regex, Diagnostic, GeneratorOptions};
+use crate::parser::{is_initial_state, LexerRule, LexerSpec, RulePattern};
+use regex::Regex;
+
+/// The codes of the lints with what they report, including the generation warnings.
+pub const LINTS: &[(&str, &str)] = &[
+    ("unreachable-rule", "a rule after another rule with the same pattern and conditions"),
+    ("invalid-regex", "a pattern the regex crate rejects"),
+    ("unknown-trivia-kind", "a %trivia name that is not a token kind"),
+    ("undeclared-token", "a token kind only named in action code, declared automatically"),
+    ("unused-token", "a %token kind that no rule or action produces"),
+    ("unsupported-feature", "a feature the selected kind of lexer does not support"),
+    ("ineffective-option", "an option that has no effect with the other options"),
+    ("suspicious-dot", "a regex that reads like literal text but has an unescaped `.`"),
+    ("missing-whitespace-rule", "no rule matches a space and whitespace is not skipped"),
+    ("shadowed-keyword", "a keyword rule after an identifier rule matching the whole keyword"),
+    ("keyword-prefix", "a keyword rule before an identifier rule, splitting longer words at the keyword"),
+];
+
+/// Returns true if an unescaped `.` is the only regex syntax of a pattern of more than
+/// one character, like `1.0` or `/foo.bar/`, where `\.` was probably meant.
+fn is_suspicious_dot(regex: &str) -> bool {
+    let mut chars = regex.chars();
+    let mut dots = 0;
+    let mut len = 0;
+    while let Some(c) = chars.next() {
+        len += 1;
+        match c {
+            '.' => dots += 1,
+            // Escaped characters are literal text
+            '\\' if chars.next().is_some_and(|c| !c.is_alphanumeric()) => {}
+            '\\' | '[' | ']' | '(' | ')' | '{' | '}' | '*' | '+' | '?' | '|' | '^' | '$' => return false,
+            _ => {}
+        }
+    }
+    dots > 0 && len > 1
+}
+
+/// Returns the regex patterns of a pattern, looking into choices and sequences.
+fn regex_parts(pattern: &RulePattern) -> Vec<&str> {
+    match pattern {
+        RulePattern::Regex(regex) => vec![regex.as_str()],
+        RulePattern::Choice(patterns) | RulePattern::Sequence(patterns) => {
+            patterns.iter().flat_map(regex_parts).collect()
+        }
+        RulePattern::CaseInsensitive(inner) => regex_parts(inner),
+        _ => Vec::new(),
+    }
+}
+
+/// Returns true if a rule is tried in the initial start condition.
+fn in_initial_state(rule: &LexerRule) -> bool {
+    rule.states.is_empty() || rule.states.iter().any(|state| is_initial_state(state))
+}
+
+/// Returns true if two plain rules (no action, no context) are tried under the same conditions.
+fn same_conditions(a: &LexerRule, b: &LexerRule) -> bool {
+    let plain = |rule: &LexerRule| rule.action_code.is_none() && rule.context.is_empty() && rule.cfg.is_none();
+    plain(a) && plain(b) && a.states == b.states && a.line_start == b.line_start
+}
+
+/// Returns the regex matching exactly what a rule matches, for the rules it can be built for.
+fn whole_match_regex(rule: &LexerRule) -> Option<Regex> {
+    if matches!(rule.pattern, RulePattern::Eof | RulePattern::Balanced(..) | RulePattern::Heredoc(_))
+        || rule.trailing_context.is_some()
+    {
+        return None;
+    }
+    Regex::new(&format!("^(?:{})$", rule_to_regex(rule))).ok()
+}
+
+/// Describes a rule in messages: `[0-9]+ -> NUMBER`.
+fn describe_rule(rule: &LexerRule) -> String {
+    format!("{} -> {}", describe_pattern(&rule.pattern), if rule.skip { "%skip" } else { &rule.name })
+}
+
+/// Checks a spec for the problems generation warns about and for likely mistakes.
+///
+/// Every diagnostic carries one of the codes of [`LINTS`] and, for problems of a
+/// single rule, the span of that rule.
+///
+/// # Examples
+///
+/// ```rust
+/// use klex::{lint_spec, parse_spec, GeneratorOptions};
+///
+/// let spec = parse_spec("[a-z]+ -> IDENT\n\"if\" -> IF\n1.0 -> VERSION\n").unwrap();
+/// let lints = lint_spec(&spec, &GeneratorOptions::default());
+/// let codes: Vec<&str> = lints.iter().map(|lint| lint.code).collect();
+/// assert_eq!(codes, ["suspicious-dot", "missing-whitespace-rule", "shadowed-keyword"]);
+/// assert_eq!(lints[2].span.map(|span| span.line), Some(2));
+/// ```
+pub fn lint_spec(spec: &LexerSpec, options: &GeneratorOptions) -> Vec<Diagnostic> {
+    let mut lints = generation_warnings(spec, options);
+
+    for rule in &spec.rules {
+        if regex_parts(&rule.pattern).into_iter().any(is_suspicious_dot) {
+            let message = format!(
+                "rule `{}` matches any character at `.`; write `\\.` for a literal dot",
+                describe_rule(rule)
+            );
+            lints.push(Diagnostic::new("suspicious-dot", message).at(rule));
+        }
+    }
+
+    let matches_space = spec
+        .rules
+        .iter()
+        .filter(|rule| in_initial_state(rule) && rule.context.is_empty())
+        .filter_map(whole_match_regex)
+        .any(|regex| regex.is_match(" "));
+    if !spec.rules.is_empty() && !matches_space && !spec.options.skip_whitespace {
+        let message = "no rule matches a space, so spaces become Unknown tokens; add a rule such as `[ \\t\\n]+ -> %skip` or `%option skip-whitespace`".to_string();
+        lints.push(Diagnostic::new("missing-whitespace-rule", message));
+    }
+
+    // Keyword rules are literal words; identifier rules are regexes matching such words
+    let keywords = spec.rules.iter().enumerate().filter_map(|(i, rule)| {
+        let word = rule_literal(rule)?;
+        let is_word = word.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && word.chars().all(|c| c.is_alphanumeric() || c == '_');
+        is_word.then_some((i, rule, word))
+    });
+    for (i, keyword, word) in keywords {
+        for (j, other) in spec.rules.iter().enumerate() {
+            if i == j || rule_literal(other).is_some() || !same_conditions(keyword, other) {
+                continue;
+            }
+            let Some(regex) = whole_match_regex(other) else {
+                continue;
+            };
+            if !regex.is_match(&word) {
+                continue;
+            }
+            if j < i {
+                let message = format!(
+                    "keyword rule `{}` can never match: rule `{}` before it matches the whole word; move the keyword rule first",
+                    describe_rule(keyword),
+                    describe_rule(other)
+                );
+                lints.push(Diagnostic::new("shadowed-keyword", message).at(keyword));
+                break;
+            }
+            let longer = ["a", "z", "_", "0"].iter().map(|c| format!("{}{}", word, c)).find(|text| regex.is_match(text));
+            if let (Some(longer), false) = (longer, spec.options.longest_match) {
+                let message = format!(
+                    "keyword rule `{}` also matches the start of words of rule `{}`, so `{}` is split after `{}`; use `%option longest-match` or `%keyword`",
+                    describe_rule(keyword),
+                    describe_rule(other),
+                    longer,
+                    word
+                );
+                lints.push(Diagnostic::new("keyword-prefix", message).at(keyword));
+                break;
+            }
+        }
+    }
+    lints
+}
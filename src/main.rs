@@ -3,21 +3,332 @@
 //! This is the command-line interface for klex. It takes a lexer specification
 //! file and generates Rust code for a lexer.
 
-mod generator;
-mod parser;
-mod token;
-
+use klex::{
+    generate, generate_bench, generate_c_header, generate_graph, generate_token_docs, json_string, lint_spec, parse_spec_all,
+    parse_spec_file_with_definitions, run_repl, spec_file_includes, spec_stats, Backend, Diagnostic, GeneratorOptions, LexerSpec, ParseError, Severity,
+    ProjectConfig, Visibility, LINTS,
+};
+use std::path::{Path, PathBuf};
 use std::env;
 use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::iter;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often --watch looks at the modification times of the watched files.
+const WATCH_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Whether errors and warnings are printed as JSON lines (--message-format=json).
+static JSON_MESSAGES: AtomicBool = AtomicBool::new(false);
 
 /// Main entry point for the klex command-line tool.
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // --message-format=json prints errors and warnings as JSON lines for editors and CI
+    let message_format = match args.iter().position(|arg| arg.starts_with("--message-format=")) {
+        Some(index) => Some(args.remove(index)["--message-format=".len()..].to_string()),
+        None => take_value(&mut args, "--message-format"),
+    };
+    match message_format.as_deref() {
+        None | Some("human") => {}
+        Some("json") => JSON_MESSAGES.store(true, Ordering::Relaxed),
+        Some(format) => {
+            eprintln!("Invalid message format '{}': expected human or json", format);
+            process::exit(1);
+        }
+    }
+
+    // `klex tokenize SPEC SAMPLE` prints the tokens the rules of a spec read from a sample
+    if args.get(1).is_some_and(|arg| arg == "tokenize") {
+        if args.len() != 4 {
+            eprintln!("Usage: {} tokenize <input_file> <sample_file>", args[0]);
+            eprintln!("  Prints the tokens the rules of the specification read from the sample input");
+            process::exit(1);
+        }
+        if !tokenize_sample(&args[2], &args[3]) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // `klex highlight SPEC FILE` prints FILE with its tokens colored by kind
+    if args.get(1).is_some_and(|arg| arg == "highlight") {
+        if args.len() != 4 {
+            eprintln!("Usage: {} highlight <input_file> <sample_file>", args[0]);
+            eprintln!("  Prints the sample input with ANSI colors per token kind, set with %style KIND bold yellow");
+            process::exit(1);
+        }
+        if !highlight_sample(&args[2], &args[3]) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // `klex repl SPEC` tokenizes sample lines typed in interactively
+    if args.get(1).is_some_and(|arg| arg == "repl") {
+        if args.len() != 3 || args[2] == "-" {
+            eprintln!("Usage: {} repl <input_file>", args[0]);
+            eprintln!("  Prints the tokens of each line typed in; :help lists the commands to switch rules and try patterns");
+            process::exit(1);
+        }
+        if !repl(&args[2]) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // `klex build [CONFIG]` generates the lexers listed in a project configuration
+    if args.get(1).is_some_and(|arg| arg == "build") {
+        if args.len() > 3 {
+            eprintln!("Usage: {} build [config_file]", args[0]);
+            eprintln!("  Generates the lexers listed in the project configuration (default: klex.toml)");
+            process::exit(1);
+        }
+        if !build_project(args.get(2).map_or("klex.toml", String::as_str)) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // --dfa, --precompiled-dfa and --regex-set select the other backends
+    let mut options = GeneratorOptions::default();
+    if let Some(index) = args.iter().position(|arg| arg == "--dfa") {
+        args.remove(index);
+        options.backend = Backend::Dfa;
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--precompiled-dfa") {
+        args.remove(index);
+        options.backend = Backend::PrecompiledDfa;
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--regex-set") {
+        args.remove(index);
+        options.backend = Backend::RegexSet;
+    }
+
+    // --no-std generates code without std (using the DFA backend)
+    if let Some(index) = args.iter().position(|arg| arg == "--no-std") {
+        args.remove(index);
+        options.no_std = true;
+    }
+
+    // --intern shares the texts of tokens with the same text
+    if let Some(index) = args.iter().position(|arg| arg == "--intern") {
+        args.remove(index);
+        options.intern = true;
+    }
+
+    // --bytes generates a lexer over &[u8] that matches bytes instead of characters
+    if let Some(index) = args.iter().position(|arg| arg == "--bytes") {
+        args.remove(index);
+        options.bytes = true;
+    }
+
+    // --ffi adds extern "C" functions, and --emit-c-header also writes their C header
+    if let Some(index) = args.iter().position(|arg| arg == "--ffi") {
+        args.remove(index);
+        options.ffi = true;
+    }
+    let header_file = take_value(&mut args, "--emit-c-header");
+
+    // --wasm adds wasm_bindgen classes for JavaScript
+    if let Some(index) = args.iter().position(|arg| arg == "--wasm") {
+        args.remove(index);
+        options.wasm = true;
+    }
+
+    // --pyo3 adds PyO3 classes for Python
+    if let Some(index) = args.iter().position(|arg| arg == "--pyo3") {
+        args.remove(index);
+        options.pyo3 = true;
+    }
+    if header_file.is_some() {
+        options.ffi = true;
+    }
+
+    // --lexer-name, --token-name and --token-kind-name rename the generated items
+    options.lexer_name = take_name(&mut args, "--lexer-name");
+    options.token_name = take_name(&mut args, "--token-name");
+    options.token_kind_name = take_name(&mut args, "--token-kind-name");
+    if let Some(visibility) = take_value(&mut args, "--visibility") {
+        options.visibility = match visibility.as_str() {
+            "pub" => Visibility::Public,
+            "pub(crate)" | "crate" => Visibility::Crate,
+            "private" => Visibility::Private,
+            _ => {
+                eprintln!("Invalid visibility '{}': expected pub, pub(crate) or private", visibility);
+                process::exit(1);
+            }
+        };
+    }
+
+    // --emit-bench also writes a criterion benchmark over the --bench-sample files
+    let bench_file = take_value(&mut args, "--emit-bench");
+    let mut bench_samples = Vec::new();
+    while let Some(sample_file) = take_value(&mut args, "--bench-sample") {
+        match fs::read_to_string(&sample_file) {
+            Ok(input) => {
+                let name = Path::new(&sample_file)
+                    .file_name()
+                    .map_or(sample_file.clone(), |name| name.to_string_lossy().into_owned());
+                bench_samples.push((name, input));
+            }
+            Err(e) => {
+                eprintln!("Error reading sample input '{}': {}", sample_file, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // --emit-json also writes the parsed spec as JSON
+    let json_file = take_value(&mut args, "--emit-json");
+
+    // --emit json/ast prints the parsed spec as JSON instead of generating the lexer
+    let emit = take_value(&mut args, "--emit");
+    if emit.as_ref().is_some_and(|emit| emit != "json" && emit != "ast") {
+        eprintln!("Invalid value for --emit '{}': expected json or ast", emit.unwrap_or_default());
+        process::exit(1);
+    }
+
+    // --check only reports the problems of the specs, without writing any file
+    let check = match args.iter().position(|arg| arg == "--check") {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+
+    // --watch regenerates the output files whenever the spec or a file it includes changes
+    let watch = match args.iter().position(|arg| arg == "--watch") {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+
+    // -o names the output file, instead of the argument after the input file
+    let output_option = take_value(&mut args, "-o");
+
+    // --out-dir generates one lexer per input file into a directory
+    let out_dir = take_value(&mut args, "--out-dir");
+
+    // `klex graph SPEC --format dot` draws the rules, contexts and DFA of a spec
+    if args.get(1).is_some_and(|arg| arg == "graph") {
+        let format = take_value(&mut args, "--format").unwrap_or_else(|| "dot".to_string());
+        if format != "dot" {
+            eprintln!("Invalid graph format '{}': expected dot", format);
+            process::exit(1);
+        }
+        if args.len() != 3 {
+            eprintln!("Usage: {} graph [--dfa | --precompiled-dfa] [--format dot] [-o FILE] <input_file>", args[0]);
+            eprintln!("  Prints a Graphviz diagram of the start conditions, rules, contexts and DFA states of the lexer");
+            process::exit(1);
+        }
+        let output_file = output_option.unwrap_or_else(|| "-".to_string());
+        if !graph_spec(&args[2], &output_file, &options) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // `klex doc SPEC -o TOKENS.md` writes a Markdown reference of the token kinds
+    if args.get(1).is_some_and(|arg| arg == "doc") {
+        if args.len() != 3 {
+            eprintln!("Usage: {} doc [-o FILE] <input_file>", args[0]);
+            eprintln!("  Prints a Markdown table of the token kinds with their patterns, descriptions and example matches");
+            process::exit(1);
+        }
+        let output_file = output_option.unwrap_or_else(|| "-".to_string());
+        if !document_tokens(&args[2], &output_file) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // `klex stats SPEC` prints the rule counts and automaton size of a spec
+    if args.get(1).is_some_and(|arg| arg == "stats") {
+        if args.len() != 3 {
+            eprintln!("Usage: {} stats [--dfa | --precompiled-dfa | --regex-set] <input_file>", args[0]);
+            eprintln!("  Prints rule counts by pattern kind, regex and direct matching, the automaton size and the longest literal");
+            process::exit(1);
+        }
+        if !print_stats(&args[2], &options) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // `klex lint [--deny CODE] [--allow CODE] SPEC...` reports likely mistakes in specs
+    if args.get(1).is_some_and(|arg| arg == "lint") {
+        let (mut deny, mut allow) = (Vec::new(), Vec::new());
+        while let Some(code) = take_value(&mut args, "--deny") {
+            deny.push(code);
+        }
+        while let Some(code) = take_value(&mut args, "--allow") {
+            allow.push(code);
+        }
+        if let Some(code) = deny.iter().chain(&allow).find(|code| *code != "all" && !LINTS.iter().any(|(name, _)| name == code)) {
+            eprintln!("Unknown lint '{}'", code);
+            process::exit(1);
+        }
+        if args.len() < 3 {
+            eprintln!("Usage: {} lint [--deny CODE]... [--allow CODE]... <input_file>...", args[0]);
+            eprintln!("  Reports likely mistakes in specifications; CODE is one of these lints, or all:");
+            for (code, description) in LINTS {
+                eprintln!("  {:<24} {}", code, description);
+            }
+            process::exit(1);
+        }
+        if !lint_specs(&expand_globs(&args[2..]), &options, &deny, &allow) {
+            process::exit(1);
+        }
+        return;
+    }
 
     if args.len() < 2 {
-        eprintln!("Usage: {} <input_file> [output_file]", args[0]);
-        eprintln!("  Generates a Rust lexer from a specification file");
+        eprintln!("Usage: {} [--dfa | --precompiled-dfa | --regex-set] [--no-std] [--bytes] [--intern] [--ffi] [--wasm] [--pyo3] [NAME OPTIONS] [BENCH OPTIONS] [--emit-json FILE] <input_file> [output_file]", args[0]);
+        eprintln!("       {} --check [OPTIONS] <input_file>...", args[0]);
+        eprintln!("       {} --watch [OPTIONS] <input_file> [-o output_file]", args[0]);
+        eprintln!("       {} --out-dir DIR [OPTIONS] <input_file>...", args[0]);
+        eprintln!("       {} --emit json|ast <input_file>", args[0]);
+        eprintln!("       {} build [config_file]", args[0]);
+        eprintln!("       {} doc [-o FILE] <input_file>", args[0]);
+        eprintln!("       {} stats <input_file>", args[0]);
+        eprintln!("       {} lint [--deny CODE] [--allow CODE] <input_file>...", args[0]);
+        eprintln!("       {} graph [--dfa] [--format dot] [-o FILE] <input_file>", args[0]);
+        eprintln!("       {} tokenize <input_file> <sample_file>", args[0]);
+        eprintln!("       {} highlight <input_file> <sample_file>", args[0]);
+        eprintln!("       {} repl <input_file>", args[0]);
+        eprintln!("  Generates a Rust lexer from a specification file (.klex, or .json as written by --emit-json)");
+        eprintln!("  Use - as the input or output file to read from standard input or write to standard output");
+        eprintln!("  --emit json|ast        Print the parsed specification as JSON (ast: with source spans and directives)");
+        eprintln!("  --message-format=json  Print errors and warnings as JSON lines (file, line, column, severity, code, message)");
+        eprintln!("  --check                Report the problems of the specifications without writing any file");
+        eprintln!("  --watch                Regenerate whenever the specification or a file it %includes changes");
+        eprintln!("  -o FILE                Output file (default: lexer.rs)");
+        eprintln!("  --out-dir DIR          Generate DIR/NAME_lexer.rs for each input file NAME.klex (globs like *.klex work)");
+        eprintln!("  --dfa                  Compile regex rules into DFA tables instead of using the regex crate");
+        eprintln!("  --precompiled-dfa      Embed a DFA serialized with regex-automata (needs regex-automata)");
+        eprintln!("  --regex-set            Check all regex rules with one RegexSet per token");
+        eprintln!("  --no-std               Generate code needing only core and alloc (uses the DFA)");
+        eprintln!("  --bytes                Generate a lexer over &[u8] whose character classes match bytes");
+        eprintln!("  --intern               Share the texts of tokens with the same text (Arc<str>)");
+        eprintln!("  --ffi                  Also generate extern \"C\" functions over the lexer");
+        eprintln!("  --emit-c-header FILE   Also write the C header of the extern \"C\" functions to FILE (implies --ffi)");
+        eprintln!("  --wasm                 Also generate wasm_bindgen classes of the lexer for JavaScript");
+        eprintln!("  --pyo3                 Also generate PyO3 classes of the lexer for Python");
+        eprintln!("  --lexer-name NAME      Name of the lexer struct (default: Lexer)");
+        eprintln!("  --token-name NAME      Name of the token struct (default: Token)");
+        eprintln!("  --token-kind-name NAME Name of the token kind enum (default: TokenKind)");
+        eprintln!("  --visibility VIS       pub (default), pub(crate) or private");
+        eprintln!("  --emit-bench FILE      Also write a criterion benchmark of the lexer to FILE");
+        eprintln!("  --bench-sample FILE    Sample input for the benchmark (repeatable; default: %test inputs)");
+        eprintln!("  --emit-json FILE       Also write the parsed specification as JSON to FILE");
         eprintln!();
         eprintln!("Input file format:");
         eprintln!("  (Rust code)");
@@ -28,42 +339,746 @@ fn main() {
         process::exit(1);
     }
 
+    if let Some(emit) = emit {
+        if args.len() != 2 {
+            eprintln!("--emit prints a single specification");
+            process::exit(1);
+        }
+        if !emit_spec(&args[1], emit == "ast") {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if check {
+        let (mut errors, mut warnings) = (0, 0);
+        for input_file in expand_globs(&args[1..]) {
+            let (file_errors, file_warnings) = check_spec(&input_file, &options);
+            errors += file_errors;
+            warnings += file_warnings;
+        }
+        if errors + warnings > 0 {
+            let plural = |n: usize| if n == 1 { "" } else { "s" };
+            eprintln!("{} error{}, {} warning{}", errors, plural(errors), warnings, plural(warnings));
+        }
+        if errors > 0 {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(out_dir) = out_dir {
+        if watch || output_option.is_some() || header_file.is_some() || json_file.is_some() || bench_file.is_some() {
+            eprintln!("--out-dir cannot be combined with --watch, -o, --emit-c-header, --emit-json or --emit-bench");
+            process::exit(1);
+        }
+        if !generate_all(&expand_globs(&args[1..]), Path::new(&out_dir), &options) {
+            process::exit(1);
+        }
+        return;
+    }
+
     let input_file = &args[1];
-    let output_file = if args.len() >= 3 {
-        args[2].clone()
-    } else {
-        "lexer.rs".to_string()
+    let output_file = match (output_option, args.get(2)) {
+        (Some(output_file), _) => output_file,
+        (None, Some(output_file)) => output_file.clone(),
+        (None, None) => "lexer.rs".to_string(),
     };
+    let outputs = Outputs { output_file, header_file, json_file, bench_file, bench_samples };
 
-    // Read input file
-    let input = match fs::read_to_string(input_file) {
-        Ok(content) => content,
-        Err(e) => {
-            eprintln!("Error reading file '{}': {}", input_file, e);
+    if watch {
+        if input_file == "-" {
+            eprintln!("--watch needs a specification file, not standard input");
             process::exit(1);
         }
+        watch_spec(input_file, &outputs, &options);
+    }
+    if !generate_outputs(input_file, &outputs, &options, &[]) {
+        process::exit(1);
+    }
+}
+
+/// Generates `NAME_lexer.rs` in `out_dir` for each input file `NAME.klex` (--out-dir) and
+/// prints a summary. Returns false if any of them failed.
+fn generate_all(input_files: &[String], out_dir: &Path, options: &GeneratorOptions) -> bool {
+    let mut output_files: Vec<PathBuf> = Vec::new();
+    for input_file in input_files {
+        if input_file == "-" {
+            eprintln!("--out-dir needs specification files, not standard input");
+            return false;
+        }
+        let stem = Path::new(input_file).file_stem().unwrap_or_default().to_string_lossy();
+        let output_file = out_dir.join(format!("{}_lexer.rs", stem));
+        if output_files.contains(&output_file) {
+            eprintln!("Two input files would both be generated into {}", output_file.display());
+            return false;
+        }
+        output_files.push(output_file);
+    }
+    if let Err(e) = fs::create_dir_all(out_dir) {
+        eprintln!("Error creating output directory '{}': {}", out_dir.display(), e);
+        return false;
+    }
+
+    let mut failed = Vec::new();
+    for (input_file, output_file) in input_files.iter().zip(output_files) {
+        let outputs = Outputs {
+            output_file: output_file.to_string_lossy().into_owned(),
+            header_file: None,
+            json_file: None,
+            bench_file: None,
+            bench_samples: Vec::new(),
+        };
+        if !generate_outputs(input_file, &outputs, options, &[]) {
+            failed.push(input_file.as_str());
+        }
+    }
+    let generated = input_files.len() - failed.len();
+    let count = input_files.len();
+    progress(&format!("{} of {} lexer{} generated", generated, count, if count == 1 { "" } else { "s" }));
+    if !failed.is_empty() {
+        eprintln!("Failed: {}", failed.join(", "));
+    }
+    failed.is_empty()
+}
+
+/// Generates the lexers listed in a project configuration (`klex build`) and prints a
+/// summary. Returns false if the configuration or any of the lexers failed.
+fn build_project(config_file: &str) -> bool {
+    let config = match ProjectConfig::from_file(config_file) {
+        Ok(config) => config,
+        Err(e) if JSON_MESSAGES.load(Ordering::Relaxed) => {
+            print_json_message(config_file, e.line, e.column, "error", "config-error", &e.message, false);
+            return false;
+        }
+        Err(e) => {
+            eprintln!("Error reading configuration '{}': {}", config_file, e);
+            return false;
+        }
     };
+    if config.lexers.is_empty() {
+        eprintln!("No [[lexer]] listed in '{}'", config_file);
+        return false;
+    }
+
+    let mut failed = Vec::new();
+    for lexer in &config.lexers {
+        let input_file = lexer.spec.to_string_lossy().into_owned();
+        let path_name = |path: &Path| path.to_string_lossy().into_owned();
+        if let Some(dir) = lexer.output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if let Err(e) = fs::create_dir_all(dir) {
+                eprintln!("Error creating output directory '{}': {}", dir.display(), e);
+                failed.push(input_file);
+                continue;
+            }
+        }
+        let outputs = Outputs {
+            output_file: path_name(&lexer.output),
+            header_file: lexer.c_header.as_deref().map(path_name),
+            json_file: lexer.json.as_deref().map(path_name),
+            bench_file: None,
+            bench_samples: Vec::new(),
+        };
+        if !generate_outputs(&input_file, &outputs, &lexer.options, &config.definitions) {
+            failed.push(input_file);
+        }
+    }
+    let count = config.lexers.len();
+    progress(&format!("{} of {} lexer{} generated", count - failed.len(), count, if count == 1 { "" } else { "s" }));
+    if !failed.is_empty() {
+        eprintln!("Failed: {}", failed.join(", "));
+    }
+    failed.is_empty()
+}
+
+/// Expands arguments with `*` or `?` in their file name into the matching files, for
+/// shells that do not expand globs. Arguments matching nothing are kept as they are.
+fn expand_globs(args: &[String]) -> Vec<String> {
+    let mut files = Vec::new();
+    for arg in args {
+        let path = Path::new(arg);
+        let pattern = path.file_name().unwrap_or_default().to_string_lossy();
+        if !pattern.contains(['*', '?']) || path.exists() {
+            files.push(arg.clone());
+            continue;
+        }
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let mut matches: Vec<String> = fs::read_dir(dir.unwrap_or(Path::new(".")))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| glob_matches(&pattern, &entry.file_name().to_string_lossy()))
+            .map(|entry| match dir {
+                Some(dir) => dir.join(entry.file_name()).to_string_lossy().into_owned(),
+                None => entry.file_name().to_string_lossy().into_owned(),
+            })
+            .collect();
+        if matches.is_empty() {
+            files.push(arg.clone());
+        }
+        matches.sort();
+        files.extend(matches);
+    }
+    files
+}
+
+/// Returns true if `name` matches a glob `pattern` where `*` matches any run of characters
+/// and `?` any single character.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // matched[j]: the pattern read so far matches the first j characters of the name
+    let mut matched = vec![false; name.len() + 1];
+    matched[0] = true;
+    for p in pattern {
+        let mut next = vec![false; name.len() + 1];
+        for j in 0..=name.len() {
+            next[j] = match p {
+                '*' => matched[j] || (j > 0 && next[j - 1]),
+                '?' => j > 0 && matched[j - 1],
+                c => j > 0 && matched[j - 1] && name[j - 1] == c,
+            };
+        }
+        matched = next;
+    }
+    matched[name.len()]
+}
 
-    // Parse specification
-    let spec = match parser::parse_spec(&input) {
+/// The files generated from a specification.
+struct Outputs {
+    /// The lexer
+    output_file: String,
+    /// C header of the extern "C" functions (--emit-c-header)
+    header_file: Option<String>,
+    /// The parsed spec as JSON (--emit-json)
+    json_file: Option<String>,
+    /// Criterion benchmark of the lexer (--emit-bench)
+    bench_file: Option<String>,
+    /// Names and inputs of the benchmark samples (--bench-sample)
+    bench_samples: Vec<(String, String)>,
+}
+
+impl Outputs {
+    /// Returns true if one of the files is written to standard output.
+    fn writes_stdout(&self) -> bool {
+        let files = [Some(&self.output_file), self.header_file.as_ref(), self.json_file.as_ref(), self.bench_file.as_ref()];
+        files.into_iter().flatten().any(|file| file == "-")
+    }
+
+    /// Prints a progress message, on standard error when a file is written to standard output
+    fn report(&self, message: &str) {
+        if self.writes_stdout() {
+            eprintln!("{}", message);
+        } else {
+            progress(message);
+        }
+    }
+}
+
+/// Reads and parses a specification file, or standard input for `-`, with the shared
+/// `definitions` of a project configuration.
+///
+/// `%include` paths in a specification read from standard input are relative to the
+/// current directory.
+fn read_spec(input_file: &str, definitions: &[(String, String)]) -> Result<LexerSpec, Vec<ParseError>> {
+    if input_file != "-" {
+        return parse_spec_file_with_definitions(input_file, definitions);
+    }
+    let input = io::read_to_string(io::stdin())
+        .map_err(|e| vec![ParseError::new(format!("Cannot read standard input: {}", e))])?;
+    parse_spec_all(&input)
+}
+
+/// Writes an output file, or standard output for `-`.
+fn write_output(file: &str, contents: &str) -> io::Result<()> {
+    if file == "-" {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(contents.as_bytes())?;
+        stdout.flush()
+    } else {
+        fs::write(file, contents)
+    }
+}
+
+/// Name of the input in generated comments and messages.
+fn source_name(input_file: &str) -> &str {
+    if input_file == "-" {
+        "<stdin>"
+    } else {
+        input_file
+    }
+}
+
+/// Name of an output file in messages.
+fn output_name(file: &str) -> &str {
+    if file == "-" {
+        "<stdout>"
+    } else {
+        file
+    }
+}
+
+/// Parses the specification and writes the output files, printing the problems found.
+/// Returns false if the specification has errors or a file cannot be written.
+fn generate_outputs(
+    input_file: &str,
+    outputs: &Outputs,
+    options: &GeneratorOptions,
+    definitions: &[(String, String)],
+) -> bool {
+    // Read and parse specification
+    let spec = match read_spec(input_file, definitions) {
         Ok(spec) => spec,
-        Err(e) => {
-            eprintln!("Error parsing specification: {}", e);
-            process::exit(1);
+        Err(errors) => {
+            report_parse_errors(input_file, &errors, outputs.writes_stdout());
+            if errors.len() > 1 && !JSON_MESSAGES.load(Ordering::Relaxed) {
+                eprintln!("{} errors found", errors.len());
+            }
+            return false;
         }
     };
 
-    // Generate lexer code
-    let generated_code = generator::generate_lexer(&spec, input_file);
+    // Generate lexer code, reporting the problems of the spec; errors stop generation
+    let result = generate(&spec, source_name(input_file), options);
+    report_diagnostics(input_file, &result.diagnostics, outputs.writes_stdout(), false);
+    if result.has_errors() {
+        if !JSON_MESSAGES.load(Ordering::Relaxed) {
+            eprintln!("Lexer not generated: '{}' has errors", source_name(input_file));
+        }
+        return false;
+    }
 
     // Write output file
-    match fs::write(&output_file, generated_code) {
+    let output_file = &outputs.output_file;
+    match write_output(output_file, &result.code) {
         Ok(_) => {
-            println!("Lexer generated successfully: {}", output_file);
+            outputs.report(&format!("Lexer generated successfully: {}", output_name(output_file)));
         }
         Err(e) => {
             eprintln!("Error writing output file '{}': {}", output_file, e);
-            process::exit(1);
+            return false;
+        }
+    }
+
+    if let Some(header_file) = &outputs.header_file {
+        match write_output(header_file, &generate_c_header(&spec, source_name(input_file), options)) {
+            Ok(_) => {
+                outputs.report(&format!("C header generated successfully: {}", output_name(header_file)));
+            }
+            Err(e) => {
+                eprintln!("Error writing C header file '{}': {}", header_file, e);
+                return false;
+            }
+        }
+    }
+
+    if let Some(json_file) = &outputs.json_file {
+        match write_output(json_file, &spec.to_json()) {
+            Ok(_) => {
+                outputs.report(&format!("JSON spec written successfully: {}", output_name(json_file)));
+            }
+            Err(e) => {
+                eprintln!("Error writing JSON file '{}': {}", json_file, e);
+                return false;
+            }
+        }
+    }
+
+    if let Some(bench_file) = &outputs.bench_file {
+        if outputs.bench_samples.is_empty() && spec.tests.is_empty() {
+            eprintln!("No sample inputs for the benchmark: use --bench-sample or %test");
+            return false;
+        }
+        let bench_code = generate_bench(&spec, source_name(input_file), output_file, &outputs.bench_samples, options);
+        match write_output(bench_file, &bench_code) {
+            Ok(_) => {
+                outputs.report(&format!("Benchmark generated successfully: {}", output_name(bench_file)));
+            }
+            Err(e) => {
+                eprintln!("Error writing benchmark file '{}': {}", bench_file, e);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Regenerates the output files whenever the specification or a file it includes
+/// changes (--watch). Runs until the process is interrupted.
+fn watch_spec(input_file: &str, outputs: &Outputs, options: &GeneratorOptions) -> ! {
+    let mut last_seen = Vec::new();
+    loop {
+        // The includes are looked up again each time, as edits may add or remove them
+        let files = iter::once(PathBuf::from(input_file)).chain(spec_file_includes(input_file));
+        let seen: Vec<(PathBuf, Option<SystemTime>)> = files
+            .map(|file| {
+                let modified = fs::metadata(&file).and_then(|metadata| metadata.modified()).ok();
+                (file, modified)
+            })
+            .collect();
+        if seen != last_seen {
+            if !last_seen.is_empty() {
+                progress("");
+            }
+            generate_outputs(input_file, outputs, options, &[]);
+            progress(&format!("Watching {} file{} for changes (Ctrl-C to stop)", seen.len(), if seen.len() == 1 { "" } else { "s" }));
+            last_seen = seen;
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Parses a specification and checks it as generation and `klex lint` would, printing
+/// its problems (--check). Returns the numbers of errors and of warnings.
+fn check_spec(input_file: &str, options: &GeneratorOptions) -> (usize, usize) {
+    match read_spec(input_file, &[]) {
+        Ok(spec) => {
+            let diagnostics = lint_spec(&spec, options);
+            report_diagnostics(input_file, &diagnostics, false, true);
+            if diagnostics.is_empty() {
+                progress(&format!("{}: ok", source_name(input_file)));
+            }
+            let errors = diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Error).count();
+            (errors, diagnostics.len() - errors)
+        }
+        Err(errors) => {
+            report_parse_errors(input_file, &errors, false);
+            (errors.len(), 0)
+        }
+    }
+}
+
+/// Prints the parsed specification as JSON (--emit json), with its source spans and
+/// directives for `ast`. Returns false if the specification has errors.
+fn emit_spec(input_file: &str, ast: bool) -> bool {
+    match read_spec(input_file, &[]) {
+        Ok(spec) => {
+            let json = if ast { spec.to_ast_json() } else { spec.to_json() };
+            write_output("-", &json).is_ok()
+        }
+        Err(errors) => {
+            report_parse_errors(input_file, &errors, false);
+            false
+        }
+    }
+}
+
+/// Writes the Graphviz diagram of the lexer of a specification (`klex graph`).
+/// Returns false if the specification has errors or the file cannot be written.
+fn graph_spec(input_file: &str, output_file: &str, options: &GeneratorOptions) -> bool {
+    let spec = match read_spec(input_file, &[]) {
+        Ok(spec) => spec,
+        Err(errors) => {
+            report_parse_errors(input_file, &errors, false);
+            return false;
+        }
+    };
+    match write_output(output_file, &generate_graph(&spec, options)) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("Error writing graph file '{}': {}", output_file, e);
+            false
+        }
+    }
+}
+
+/// Writes the Markdown reference of the token kinds of a specification (`klex doc`),
+/// titled with its `%prefix` or file name. Returns false on errors.
+fn document_tokens(input_file: &str, output_file: &str) -> bool {
+    let spec = match read_spec(input_file, &[]) {
+        Ok(spec) => spec,
+        Err(errors) => {
+            report_parse_errors(input_file, &errors, false);
+            return false;
+        }
+    };
+    let title = spec.name_prefix.clone().unwrap_or_else(|| {
+        let stem = Path::new(input_file).file_stem().map(|stem| stem.to_string_lossy().into_owned());
+        stem.filter(|_| input_file != "-").unwrap_or_else(|| "the lexer".to_string())
+    });
+    match write_output(output_file, &generate_token_docs(&spec, &title)) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("Error writing token documentation '{}': {}", output_file, e);
+            false
+        }
+    }
+}
+
+/// Prints the statistics of a specification (`klex stats`). Returns false on errors.
+fn print_stats(input_file: &str, options: &GeneratorOptions) -> bool {
+    match read_spec(input_file, &[]) {
+        Ok(spec) => {
+            print!("{}", spec_stats(&spec, options));
+            true
+        }
+        Err(errors) => {
+            report_parse_errors(input_file, &errors, false);
+            false
+        }
+    }
+}
+
+/// Tokenizes a sample input (or standard input for `-`) with the rules of a
+/// specification and prints the tokens (`klex tokenize`). Returns false on errors.
+fn tokenize_sample(input_file: &str, sample_file: &str) -> bool {
+    let Some((spec, sample)) = read_spec_and_sample(input_file, sample_file) else {
+        return false;
+    };
+    let tokens = match spec.interpret(&sample) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("Error in specification '{}': {}", source_name(input_file), e);
+            return false;
+        }
+    };
+    for warning in spec.interpret_warnings() {
+        eprintln!("warning: {}", warning);
+    }
+
+    let kind_width = tokens.iter().map(|token| token.kind.len()).max().unwrap_or(0);
+    let mut stdout = io::stdout().lock();
+    for token in &tokens {
+        let mut line = format!("{:>4}:{:<4} {:>6}  {:<kind_width$}  {:?}", token.row, token.col, token.index, token.kind, token.text);
+        if let Some(value) = &token.value {
+            line.push_str(&format!(" = {}", value));
+        }
+        if let Some(channel) = &token.channel {
+            line.push_str(&format!(" ({})", channel));
+        }
+        if writeln!(stdout, "{}", line).is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Reads a specification and a sample input (either may be standard input for `-`),
+/// reporting errors. Returns None on errors.
+fn read_spec_and_sample(input_file: &str, sample_file: &str) -> Option<(LexerSpec, String)> {
+    if input_file == "-" && sample_file == "-" {
+        eprintln!("The specification and the sample cannot both be read from standard input");
+        return None;
+    }
+    let spec = match read_spec(input_file, &[]) {
+        Ok(spec) => spec,
+        Err(errors) => {
+            report_parse_errors(input_file, &errors, false);
+            return None;
+        }
+    };
+    let sample = if sample_file == "-" { io::read_to_string(io::stdin()) } else { fs::read_to_string(sample_file) };
+    match sample {
+        Ok(sample) => Some((spec, sample)),
+        Err(e) => {
+            eprintln!("Error reading sample input '{}': {}", source_name(sample_file), e);
+            None
         }
     }
 }
+
+/// Prints a sample input with the tokens the rules of a specification read from it
+/// colored by kind (`klex highlight`). Returns false on errors.
+fn highlight_sample(input_file: &str, sample_file: &str) -> bool {
+    let Some((spec, sample)) = read_spec_and_sample(input_file, sample_file) else {
+        return false;
+    };
+    match spec.highlight(&sample) {
+        Ok(highlighted) => io::stdout().lock().write_all(highlighted.as_bytes()).is_ok(),
+        Err(e) => {
+            eprintln!("Error in specification '{}': {}", source_name(input_file), e);
+            false
+        }
+    }
+}
+
+/// Runs an interactive session on a specification (`klex repl`). Returns false on errors.
+fn repl(input_file: &str) -> bool {
+    let spec = match read_spec(input_file, &[]) {
+        Ok(spec) => spec,
+        Err(errors) => {
+            report_parse_errors(input_file, &errors, false);
+            return false;
+        }
+    };
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    if prompt {
+        println!("{}: {} rules; type a sample line, or :help", source_name(input_file), spec.rules.len());
+    }
+    match run_repl(spec, stdin.lock(), &mut io::stdout().lock(), prompt) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            false
+        }
+    }
+}
+
+/// Prints a progress message: on standard output, or on standard error when the
+/// messages are JSON lines so standard output only carries those.
+fn progress(message: &str) {
+    if JSON_MESSAGES.load(Ordering::Relaxed) {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// Prints a diagnostic as a JSON line, on standard error when standard output carries
+/// a generated file.
+fn print_json_message(
+    input_file: &str,
+    line: Option<usize>,
+    column: Option<usize>,
+    severity: &str,
+    code: &str,
+    message: &str,
+    to_stderr: bool,
+) {
+    let number = |n: Option<usize>| n.map_or("null".to_string(), |n| n.to_string());
+    let json = format!(
+        "{{\"file\":{},\"line\":{},\"column\":{},\"severity\":\"{}\",\"code\":\"{}\",\"message\":{}}}",
+        json_string(source_name(input_file)),
+        number(line),
+        number(column),
+        severity,
+        code,
+        json_string(message)
+    );
+    if to_stderr {
+        eprintln!("{}", json);
+    } else {
+        println!("{}", json);
+    }
+}
+
+/// Prints the errors of a specification that could not be parsed.
+fn report_parse_errors(input_file: &str, errors: &[ParseError], to_stderr: bool) {
+    for e in errors {
+        if JSON_MESSAGES.load(Ordering::Relaxed) {
+            print_json_message(input_file, e.line, e.column, "error", "parse-error", &e.message, to_stderr);
+        } else {
+            eprintln!("Error parsing specification '{}': {}", source_name(input_file), e);
+        }
+    }
+}
+
+/// Prints the diagnostics of a specification, located like lints when `with_file` is set.
+///
+/// JSON lines locate the diagnostics at their rules; the column is read from the
+/// specification file, so it is null for standard input.
+fn report_diagnostics(input_file: &str, diagnostics: &[Diagnostic], to_stderr: bool, with_file: bool) {
+    if !with_file && !JSON_MESSAGES.load(Ordering::Relaxed) {
+        for diagnostic in diagnostics {
+            eprintln!("{}: {}", diagnostic.severity, diagnostic.message);
+        }
+        return;
+    }
+    let text = spec_text(input_file);
+    for diagnostic in diagnostics {
+        print_diagnostic(input_file, text.as_deref(), diagnostic, &diagnostic.severity.to_string(), to_stderr);
+    }
+}
+
+/// Prints a diagnostic as `file:line: severity[code]: message`, or as a JSON line.
+fn print_diagnostic(input_file: &str, text: Option<&str>, diagnostic: &Diagnostic, severity: &str, to_stderr: bool) {
+    if JSON_MESSAGES.load(Ordering::Relaxed) {
+        print_json_diagnostic(input_file, text, diagnostic, severity, to_stderr);
+        return;
+    }
+    let name = source_name(input_file);
+    match diagnostic.span {
+        Some(span) => eprintln!("{}:{}: {}[{}]: {}", name, span.line, severity, diagnostic.code, diagnostic.message),
+        None => eprintln!("{}: {}[{}]: {}", name, severity, diagnostic.code, diagnostic.message),
+    }
+}
+
+/// Returns the text of a specification file to locate diagnostics in, if it can be read.
+fn spec_text(input_file: &str) -> Option<String> {
+    if input_file == "-" {
+        None
+    } else {
+        fs::read_to_string(input_file).ok()
+    }
+}
+
+/// Prints a diagnostic as a JSON line, with the column of its span in `text`.
+fn print_json_diagnostic(input_file: &str, text: Option<&str>, diagnostic: &Diagnostic, severity: &str, to_stderr: bool) {
+    let line = diagnostic.span.map(|span| span.line);
+    let column = diagnostic.span.zip(text).and_then(|(span, text)| {
+        let before = text.get(..span.start)?;
+        Some(before.chars().rev().take_while(|&c| c != '\n').count() + 1)
+    });
+    print_json_message(input_file, line, column, severity, diagnostic.code, &diagnostic.message, to_stderr);
+}
+
+/// Lints specifications (`klex lint`), printing the lints with their severities, and as
+/// errors for the `deny` codes; `allow` codes are not reported unless they are errors. A
+/// code named in both lists is denied, and a named code takes precedence over `all`. Returns false if a
+/// specification has errors or a denied lint was found.
+fn lint_specs(input_files: &[String], options: &GeneratorOptions, deny: &[String], allow: &[String]) -> bool {
+    let named = |codes: &[String], code: &str| codes.iter().any(|c| c == code);
+    let mut errors = 0;
+    let mut warnings = 0;
+    for input_file in input_files {
+        let spec = match read_spec(input_file, &[]) {
+            Ok(spec) => spec,
+            Err(parse_errors) => {
+                report_parse_errors(input_file, &parse_errors, false);
+                errors += parse_errors.len();
+                continue;
+            }
+        };
+        let text = spec_text(input_file);
+        let mut reported = 0;
+        for lint in lint_spec(&spec, options) {
+            let denied = lint.severity == Severity::Error
+                || named(deny, lint.code)
+                || (named(deny, "all") && !named(allow, lint.code));
+            if !denied && (named(allow, lint.code) || named(allow, "all")) {
+                continue;
+            }
+            reported += 1;
+            let severity = if denied { "error" } else { "warning" };
+            if denied {
+                errors += 1;
+            } else {
+                warnings += 1;
+            }
+            print_diagnostic(input_file, text.as_deref(), &lint, severity, false);
+        }
+        if reported == 0 {
+            progress(&format!("{}: ok", source_name(input_file)));
+        }
+    }
+    if errors + warnings > 0 {
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        eprintln!("{} error{}, {} warning{}", errors, plural(errors), warnings, plural(warnings));
+    }
+    errors == 0
+}
+
+/// Removes `flag` and the value after it from the arguments and returns the value.
+fn take_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    if index + 1 >= args.len() {
+        eprintln!("Missing value for {}", flag);
+        process::exit(1);
+    }
+    args.remove(index);
+    Some(args.remove(index))
+}
+
+/// Like `take_value`, but the value must be a Rust identifier.
+fn take_name(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let name = take_value(args, flag)?;
+    let mut chars = name.chars();
+    let is_identifier = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+    if !is_identifier {
+        eprintln!("Invalid name for {}: '{}'", flag, name);
+        process::exit(1);
+    }
+    Some(name)
+}
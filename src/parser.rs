@@ -3,9 +3,13 @@
 //! This module handles parsing of lexer specification files and provides
 //! data structures to represent the parsed content.
 
+use crate::generator::pattern_to_regex;
+use crate::highlight::style_code;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 /// Represents different types of rule patterns.
 #[derive(Debug, Clone)]
@@ -22,6 +26,12 @@ pub enum RulePattern {
     CharRangeMatch1(char, char),
     /// Character range with zero or more matches: [0-9]*, [a-z]*
     CharRangeMatch0(char, char),
+    /// Character range with a bounded number of matches: [0-9]{4}, [a-z]{2,}, [a-f]{1,3}
+    CharRangeRepeat(char, char, usize, Option<usize>),
+    /// Negated character class with one or more matches: [^"\n]+
+    NegatedCharSet1(Vec<(char, char)>),
+    /// Negated character class with zero or more matches: [^"\n]*
+    NegatedCharSet0(Vec<(char, char)>),
     /// Choice between patterns: (pattern1 | pattern2)
     Choice(Vec<RulePattern>),
     /// Escaped special character: \+, \*, \n, etc.
@@ -30,19 +40,69 @@ pub enum RulePattern {
     AnyChar,
     /// One or more any characters: ?+
     AnyCharPlus,
+    /// Case-insensitive literal or regex: "select"i, 'x'i, /regex/i
+    CaseInsensitive(Box<RulePattern>),
+    /// End of input: <<EOF>>
+    Eof,
+    /// Nested delimited text: %balanced "/*" "*/"
+    Balanced(String, String),
+    /// Text up to a terminator captured by the opening regex: %heredoc /<<([A-Z]+)/
+    Heredoc(String),
+    /// Sub-patterns matched one after another: "0x" [0-9a-f]+
+    Sequence(Vec<RulePattern>),
+}
+
+/// A range of the text of a specification, such as a rule or a directive.
+///
+/// Rules and directives pulled in with `%include` have the span of the `%include` line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SourceSpan {
+    /// Byte offset of the start of the range
+    pub start: usize,
+    /// Byte offset of the end of the range (exclusive)
+    pub end: usize,
+    /// Line of the start of the range (1-based; 0 for rules not parsed from a specification)
+    pub line: usize,
+}
+
+/// One of the previous tokens a context rule depends on: `IDENT`, `(NUMBER|IDENT)` or `IDENT("r")`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextToken {
+    /// Token kinds accepted at this position (any one of them)
+    pub kinds: Vec<String>,
+    /// Text the token must have (only checked on the last token)
+    pub text: Option<String>,
+}
+
+/// A `%` directive of the rules section, such as `%option` or `%token`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecDirective {
+    /// The directive name with its `%`, e.g. `%option`
+    pub name: String,
+    /// Where the directive is in the specification
+    pub span: SourceSpan,
 }
 
 /// Represents a lexer rule with a pattern and token kind.
 ///
 /// Each rule defines how to match a specific token type using a pattern.
-/// Rules can optionally depend on a previous token context.
+/// Rules can optionally depend on the kinds of the previous tokens.
 #[derive(Debug, Clone)]
 pub struct LexerRule {
     pub pattern: RulePattern,
     pub kind: u32,
     pub name: String,
-    pub context_token: Option<String>, // Optional context dependency
+    pub context: Vec<ContextToken>,    // Previous tokens the rule depends on, oldest first (empty = any)
     pub action_code: Option<String>,   // Optional action code to execute when matched
+    pub states: Vec<String>,           // Start conditions this rule is limited to (empty = any)
+    pub skip: bool,                    // Consume the match without emitting a token (%skip)
+    pub trailing_context: Option<RulePattern>, // Lookahead that must follow the match (r/s)
+    pub channel: Option<String>,       // Channel the token is emitted on (None = default channel)
+    pub line_start: bool,              // Only match at the start of a line (^pattern)
+    pub cfg: Option<String>,           // cfg predicate the rule is compiled under (%cfg(...))
+    pub doc: Option<String>,           // Description of the token (-> NAME "..." or the comments above)
+    pub span: SourceSpan,              // Where the rule is in the specification
+    pub pattern_span: SourceSpan,      // Where the pattern of the rule is in the specification
 }
 
 impl LexerRule {
@@ -58,8 +118,17 @@ impl LexerRule {
             pattern,
             kind,
             name,
-            context_token: None,
+            context: Vec::new(),
             action_code: None,
+            states: Vec::new(),
+            skip: false,
+            trailing_context: None,
+            channel: None,
+            line_start: false,
+            cfg: None,
+            doc: None,
+            span: SourceSpan::default(),
+            pattern_span: SourceSpan::default(),
         }
     }
 
@@ -81,11 +150,55 @@ impl LexerRule {
             pattern,
             kind,
             name,
-            context_token: Some(context_token),
+            context: vec![ContextToken { kinds: vec![context_token], text: None }],
             action_code: None,
+            states: Vec::new(),
+            skip: false,
+            trailing_context: None,
+            channel: None,
+            line_start: false,
+            cfg: None,
+            doc: None,
+            span: SourceSpan::default(),
+            pattern_span: SourceSpan::default(),
         }
     }
 
+    /// Returns the kind of the token that must precede this rule, when its context is a
+    /// single token of one kind, like the `context_token` field rules had before contexts
+    /// of several tokens.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #![allow(deprecated)]
+    /// use klex::parse_spec;
+    ///
+    /// let spec = parse_spec("[a-z]+ -> IDENT\n[0-9]+ -> NUMBER\n%IDENT '(' -> CallStart\n%(NUMBER|IDENT) '[' -> Index\n").unwrap();
+    /// assert_eq!(spec.rules[0].context_token(), None);
+    /// assert_eq!(spec.rules[2].context_token(), Some("IDENT"));
+    /// assert_eq!(spec.rules[3].context_token(), None);
+    /// ```
+    #[deprecated(note = "use the `context` field, which lists every previous token the rule depends on")]
+    pub fn context_token(&self) -> Option<&str> {
+        match self.context.as_slice() {
+            [ContextToken { kinds, text: None }] if kinds.len() == 1 => Some(&kinds[0]),
+            _ => None,
+        }
+    }
+
+    /// Creates a new skip rule that consumes its match without emitting a token.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The pattern to match
+    /// * `kind` - The numeric token kind identifier
+    pub fn new_skip(pattern: RulePattern, kind: u32) -> Self {
+        let mut rule = LexerRule::new(pattern, kind, String::new());
+        rule.skip = true;
+        rule
+    }
+
     /// Creates a new lexer rule with action code.
     ///
     /// # Arguments
@@ -97,9 +210,173 @@ impl LexerRule {
             pattern,
             kind: 0,             // Action rules don't need a kind
             name: String::new(), // Action rules don't have a name
-            context_token: None,
+            context: Vec::new(),
             action_code: Some(action_code),
+            states: Vec::new(),
+            skip: false,
+            trailing_context: None,
+            channel: None,
+            line_start: false,
+            cfg: None,
+            doc: None,
+            span: SourceSpan::default(),
+            pattern_span: SourceSpan::default(),
+        }
+    }
+}
+
+/// Spec-wide settings declared with the `%option` directive.
+///
+/// Options are whitespace-separated names, e.g. `%option longest-match skip-whitespace`.
+/// Options with a value take it as the next word or after `=`: `%option tab-width 4`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LexerOptions {
+    /// Choose the rule with the longest match instead of the first matching rule
+    pub longest_match: bool,
+    /// Skip whitespace between tokens without emitting tokens
+    pub skip_whitespace: bool,
+    /// Return a final `TokenKind::Eof` token at the end of input
+    pub emit_eof: bool,
+    /// Emit `Indent`/`Dedent` tokens when the indentation of a line changes
+    pub indentation_tokens: bool,
+    /// Skip a `#!` line at the start of input
+    pub skip_shebang: bool,
+    /// Return a `#!` line at the start of input as a `Shebang` token
+    pub shebang_token: bool,
+    /// Remove a UTF-8 byte order mark at the start of input
+    pub strip_bom: bool,
+    /// Match regex rules with generated DFA tables instead of the regex crate
+    pub dfa: bool,
+    /// Match regex rules with a DFA serialized at generation time (needs `regex-automata`)
+    pub precompiled_dfa: bool,
+    /// Check all regex rules with one `RegexSet` per token before running their regexes
+    pub regex_set: bool,
+    /// Store the byte offsets of the text in tokens instead of a copy of the text
+    pub token_offsets: bool,
+    /// Generate code that only needs `core` and `alloc` (implies `dfa`)
+    pub no_std: bool,
+    /// Return a run of unmatched input as one token instead of one token per character
+    pub recover: bool,
+    /// Generate a lexer over `&[u8]` whose character classes match single bytes
+    pub bytes: bool,
+    /// Share the texts of tokens with the same text through an `Interner`
+    pub intern: bool,
+    /// Also generate `extern "C"` functions over the lexer for use from C
+    pub ffi: bool,
+    /// Also generate `wasm_bindgen` wrappers of the lexer for JavaScript
+    pub wasm: bool,
+    /// Also generate PyO3 wrappers of the lexer for Python
+    pub pyo3: bool,
+    /// Count `Token::length` in characters instead of bytes
+    pub length_chars: bool,
+    /// Store both `len_bytes` and `len_chars` in tokens
+    pub token_lengths: bool,
+    /// Generate `Lexer<'a>` borrowing its input instead of owning a copy
+    pub borrow_input: bool,
+    /// Implement `Iterator` for the lexer with unmatched input returned as `Err(LexError)`
+    pub result_errors: bool,
+    /// Attach the tokens on other channels to the tokens on the default channel as trivia
+    pub trivia: bool,
+    /// Count token columns in UTF-16 code units (for the Language Server Protocol)
+    pub utf16_columns: bool,
+    /// Report a rule producing a token that an earlier rule already produces as an error
+    pub unique_tokens: bool,
+    /// Merge the rules producing the same token into the first of them as a choice
+    pub merge_tokens: bool,
+    /// Columns between tab stops for token columns and indentation (None counts a tab as one column)
+    pub tab_width: Option<usize>,
+    /// Longest token in bytes before lexing stops with a `LimitError`
+    pub max_token_length: Option<usize>,
+    /// Most tokens read before lexing stops with a `LimitError`
+    pub max_tokens: Option<usize>,
+}
+
+impl LexerOptions {
+    /// Applies a single option name (e.g. `longest-match`) to the options.
+    ///
+    /// Underscores may be used in place of dashes. Returns an error for unknown options.
+    pub fn set(&mut self, option: &str) -> Result<(), ParseError> {
+        match option.replace('_', "-").as_str() {
+            "longest-match" => self.longest_match = true,
+            "first-match" => self.longest_match = false,
+            "skip-whitespace" => self.skip_whitespace = true,
+            "emit-eof" => self.emit_eof = true,
+            "indentation-tokens" => self.indentation_tokens = true,
+            "skip-shebang" => self.skip_shebang = true,
+            "shebang-token" => self.shebang_token = true,
+            "strip-bom" => self.strip_bom = true,
+            "dfa" => self.dfa = true,
+            "precompiled-dfa" => self.precompiled_dfa = true,
+            "regex-set" => self.regex_set = true,
+            "token-offsets" => self.token_offsets = true,
+            "no-std" => self.no_std = true,
+            "recover" => self.recover = true,
+            "bytes" => self.bytes = true,
+            "utf16-columns" => self.utf16_columns = true,
+            "trivia" => self.trivia = true,
+            "result-errors" => self.result_errors = true,
+            "borrow-input" => self.borrow_input = true,
+            "length-chars" => self.length_chars = true,
+            "length-bytes" => self.length_chars = false,
+            "token-lengths" => self.token_lengths = true,
+            "unique-tokens" => {
+                self.unique_tokens = true;
+                self.merge_tokens = false;
+            }
+            "merge-tokens" => {
+                self.merge_tokens = true;
+                self.unique_tokens = false;
+            }
+            "intern" => self.intern = true,
+            "ffi" => self.ffi = true,
+            "wasm" => self.wasm = true,
+            "pyo3" => self.pyo3 = true,
+            // Generated lexers operate on UTF-8 input unless `bytes` is set
+            "utf8" | "utf-8" => {}
+            _ => {
+                return Err(ParseError::new(format!("Unknown option: {}", option)));
+            }
         }
+        Ok(())
+    }
+
+    /// Returns true for options that take a value, such as `tab-width`.
+    pub fn takes_value(option: &str) -> bool {
+        matches!(
+            option.replace('_', "-").as_str(),
+            "tab-width" | "max-token-length" | "max-tokens"
+        )
+    }
+
+    /// Applies an option with a value (e.g. `tab-width` and `4`) to the options.
+    ///
+    /// Returns an error for unknown options and invalid values.
+    pub fn set_value(&mut self, option: &str, value: &str) -> Result<(), ParseError> {
+        match option.replace('_', "-").as_str() {
+            "tab-width" => match value.parse::<usize>() {
+                Ok(width) if width > 0 => self.tab_width = Some(width),
+                _ => {
+                    return Err(ParseError::new(format!(
+                        "tab-width expects a positive number: {}",
+                        value
+                    )));
+                }
+            },
+            name @ ("max-token-length" | "max-tokens") => {
+                let limit = value.parse::<usize>().map_err(|_| {
+                    ParseError::new(format!("{} expects a number: {}", name, value))
+                })?;
+                if name == "max-tokens" {
+                    self.max_tokens = Some(limit);
+                } else {
+                    self.max_token_length = Some(limit);
+                }
+            }
+            _ => {
+                return Err(ParseError::new(format!("Unknown option: {}", option)));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -110,12 +387,42 @@ impl LexerRule {
 /// - Lexer rules (pattern -> token mappings)
 /// - Suffix code (Rust code to include at the end)
 /// - Custom tokens (explicitly declared with %token directive)
+/// - Start conditions (declared with %state and %xstate directives)
+/// - Named sub-pattern definitions (`NAME = pattern`)
+/// - Spec-wide options (declared with the %option directive)
+/// - Error token for unmatched input (declared with the %error directive)
+/// - Token channels (used as `-> TOKEN @channel`)
+/// - Value types of tokens (declared with the %type directive)
+/// - Keywords recognized in identifier tokens (declared with the %keyword directive)
+/// - Name prefix of the generated items (declared with the %prefix directive)
+/// - Fields of the user state of the lexer (declared with the %state_struct directive)
+/// - Trivia token kinds skipped by `significant_tokens` (declared with the %trivia directive)
+/// - Terminal styles of token kinds for `klex highlight` (declared with the %style directive)
+/// - The directives of the rules section with their location in the specification
+/// - The kinds declared by directives, with their place among the rules, which numbers the kinds
 #[derive(Debug)]
 pub struct LexerSpec {
     pub prefix_code: String,
     pub rules: Vec<LexerRule>,
     pub suffix_code: String,
     pub custom_tokens: Vec<String>,
+    pub states: Vec<String>,
+    pub exclusive_states: Vec<String>,
+    pub definitions: Vec<(String, RulePattern)>,
+    pub options: LexerOptions,
+    pub error_token: Option<String>,
+    pub channels: Vec<String>,
+    pub token_types: Vec<(String, String)>,
+    pub keywords: Vec<(String, String)>,
+    pub name_prefix: Option<String>,
+    pub tests: Vec<(String, Vec<String>)>,
+    pub trivia_kinds: Vec<String>,
+    pub state_fields: Vec<(String, String)>,
+    pub styles: Vec<(String, String)>,
+    pub directives: Vec<SpecDirective>,
+    /// Kinds of the `%token`, `%keyword` and `%error` directives, each with the number of
+    /// rules before its directive
+    pub token_declarations: Vec<(usize, String)>,
 }
 
 impl LexerSpec {
@@ -126,6 +433,21 @@ impl LexerSpec {
             rules: Vec::new(),
             suffix_code: String::new(),
             custom_tokens: Vec::new(),
+            states: Vec::new(),
+            exclusive_states: Vec::new(),
+            definitions: Vec::new(),
+            options: LexerOptions::default(),
+            error_token: None,
+            channels: Vec::new(),
+            token_types: Vec::new(),
+            keywords: Vec::new(),
+            name_prefix: None,
+            tests: Vec::new(),
+            trivia_kinds: Vec::new(),
+            state_fields: Vec::new(),
+            styles: Vec::new(),
+            directives: Vec::new(),
+            token_declarations: Vec::new(),
         }
     }
 }
@@ -137,21 +459,48 @@ impl Default for LexerSpec {
 }
 
 /// Error type for parsing failures.
+///
+/// Errors found in the rules section carry the 1-based line and column of the
+/// offending rule in the specification, together with the text of that line.
 #[derive(Debug)]
 pub struct ParseError {
-    message: String,
+    /// The description of the error
+    pub message: String,
+    /// The line of the specification the error was found in (1-based)
+    pub line: Option<usize>,
+    /// The column of the first non-blank character of that line (1-based)
+    pub column: Option<usize>,
+    /// The text of that line
+    pub source_line: Option<String>,
 }
 
 impl ParseError {
     /// Creates a new parse error with the given message.
     pub fn new(message: String) -> Self {
-        ParseError { message }
+        ParseError { message, line: None, column: None, source_line: None }
+    }
+
+    /// Attaches the line of the specification the error was found in.
+    fn at(mut self, line: usize, source_line: &str) -> Self {
+        let column = source_line.chars().take_while(|c| c.is_whitespace()).count() + 1;
+        self.line = Some(line);
+        self.column = Some(column);
+        self.source_line = Some(source_line.to_string());
+        self
     }
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Parse error: {}", self.message)
+        write!(f, "Parse error: {}", self.message)?;
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "\n  --> line {}, column {}", line, column)?;
+            if let Some(source_line) = &self.source_line {
+                let indent: String = source_line.chars().take(column - 1).collect();
+                write!(f, "\n   | {}\n   | {}^", source_line, indent)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -168,10 +517,57 @@ impl Error for ParseError {}
 /// - ? for any single character
 /// - ?+ for one or more any characters
 /// - \+, \n, \t, etc. for escaped characters
+/// - "string"i, 'c'i, /regex/i for case-insensitive matching
+/// - %balanced "open" "close" for nested delimited text
+/// - %heredoc /opening/ for text up to a terminator captured by the opening
 /// - Any other pattern is treated as a regex for backward compatibility
 fn parse_pattern(input: &str) -> Result<RulePattern, ParseError> {
     let trimmed = input.trim();
 
+    // Balanced delimiters: %balanced "/*" "*/"
+    if let Some(delimiters) = trimmed.strip_prefix("%balanced") {
+        return parse_balanced(delimiters).ok_or_else(|| {
+            ParseError::new(format!("%balanced expects an opening and a closing literal: {}", trimmed))
+        });
+    }
+
+    // Dynamic terminator: %heredoc /<<([A-Z]+)\n/
+    if let Some(opening) = trimmed.strip_prefix("%heredoc") {
+        return match parse_pattern(opening)? {
+            RulePattern::Regex(regex) => Ok(RulePattern::Heredoc(regex)),
+            _ => Err(ParseError::new(format!("%heredoc expects an opening /regex/: {}", trimmed))),
+        };
+    }
+
+    // Choice: pattern1 | pattern2 (binds looser than sequences)
+    if let Some(alternatives) = split_alternatives(trimmed) {
+        let patterns = alternatives.into_iter().map(parse_pattern).collect::<Result<_, _>>()?;
+        return Ok(RulePattern::Choice(patterns));
+    }
+
+    // Sequence of sub-patterns separated by whitespace: "0x" [0-9a-f]+
+    let atoms = split_sequence(trimmed);
+    if atoms.len() > 1 {
+        let patterns = atoms.into_iter().map(parse_pattern).collect::<Result<_, _>>()?;
+        return Ok(RulePattern::Sequence(patterns));
+    }
+
+    // Case-insensitive flag: "string"i, 'c'i, /regex/i
+    if let Some(inner) = trimmed.strip_suffix('i') {
+        let is_flaggable = inner.len() >= 2
+            && ['"', '\'', '/']
+                .iter()
+                .any(|&q| inner.starts_with(q) && inner.ends_with(q));
+        if is_flaggable {
+            return Ok(RulePattern::CaseInsensitive(Box::new(parse_pattern(inner)?)));
+        }
+    }
+
+    // End of input: <<EOF>>
+    if trimmed == "<<EOF>>" {
+        return Ok(RulePattern::Eof);
+    }
+
     // Any character plus: ?+
     if trimmed == "?+" {
         return Ok(RulePattern::AnyCharPlus);
@@ -182,6 +578,15 @@ fn parse_pattern(input: &str) -> Result<RulePattern, ParseError> {
         return Ok(RulePattern::AnyChar);
     }
 
+    // Hex or Unicode escape: \x1b, \u{3042}
+    if trimmed.starts_with("\\x") || trimmed.starts_with("\\u{") {
+        let content = unescape_literal(trimmed)?;
+        let mut chars = content.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(RulePattern::EscapedChar(c));
+        }
+    }
+
     // Escaped character: \+, \n, etc.
     if trimmed.starts_with('\\') && trimmed.len() == 2 {
         let escape_char = trimmed.chars().nth(1).unwrap();
@@ -208,16 +613,20 @@ fn parse_pattern(input: &str) -> Result<RulePattern, ParseError> {
         return Ok(RulePattern::EscapedChar(actual_char));
     }
 
-    // Character literal: 'c'
-    if trimmed.starts_with('\'') && trimmed.ends_with('\'') && trimmed.len() == 3 {
-        let ch = trimmed.chars().nth(1).unwrap();
-        return Ok(RulePattern::CharLiteral(ch));
+    // Character literal: 'c', '\n', '\u{3042}'
+    if trimmed.starts_with('\'') && trimmed.ends_with('\'') && trimmed.len() >= 3 {
+        let content = unescape_literal(&trimmed[1..trimmed.len() - 1])?;
+        let mut chars = content.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(ch), None) => Ok(RulePattern::CharLiteral(ch)),
+            _ => Err(ParseError::new(format!("Invalid character literal: {}", trimmed))),
+        };
     }
 
-    // String literal: "string"
+    // String literal: "string", "a\tb"
     if trimmed.starts_with('"') && trimmed.ends_with('"') && trimmed.len() >= 2 {
-        let content = &trimmed[1..trimmed.len() - 1];
-        return Ok(RulePattern::StringLiteral(content.to_string()));
+        let content = unescape_literal(&trimmed[1..trimmed.len() - 1])?;
+        return Ok(RulePattern::StringLiteral(content));
     }
 
     // Regular expression: /pattern/
@@ -226,6 +635,13 @@ fn parse_pattern(input: &str) -> Result<RulePattern, ParseError> {
         return Ok(RulePattern::Regex(content.to_string()));
     }
 
+    // Negated character classes: [^"\n]+, [^a-z]*
+    if let Some(rest) = trimmed.strip_prefix("[^") {
+        if let Some(pattern) = parse_negated_char_set(rest) {
+            return Ok(pattern);
+        }
+    }
+
     // Character patterns: [0-9]+, [abc]+, [a-z]* etc.
     if trimmed.starts_with('[') && trimmed.contains(']') {
         // Parse bracket pattern
@@ -241,7 +657,7 @@ fn parse_pattern(input: &str) -> Result<RulePattern, ParseError> {
                     let hex_str = &s[3..s.len()-1];
                     u32::from_str_radix(hex_str, 16)
                         .ok()
-                        .and_then(|code| char::from_u32(code))
+                        .and_then(char::from_u32)
                 } else if s.starts_with("\\x") && s.len() == 4 {
                     // Parse hex escape: \x41
                     let hex_str = &s[2..];
@@ -264,7 +680,12 @@ fn parse_pattern(input: &str) -> Result<RulePattern, ParseError> {
                     match quantifier {
                         "+" => return Ok(RulePattern::CharRangeMatch1(start_char, end_char)),
                         "*" => return Ok(RulePattern::CharRangeMatch0(start_char, end_char)),
-                        _ => {} // Fall through to CharSet for other quantifiers
+                        _ => {
+                            if let Some((min, max)) = parse_repetition(quantifier)? {
+                                return Ok(RulePattern::CharRangeRepeat(start_char, end_char, min, max));
+                            }
+                            // Fall through to CharSet for other quantifiers
+                        }
                     }
                 }
             }
@@ -274,23 +695,1102 @@ fn parse_pattern(input: &str) -> Result<RulePattern, ParseError> {
         return Ok(RulePattern::CharSet(trimmed.to_string()));
     }
 
-    // Choice: (pattern1 | pattern2)
-    if trimmed.starts_with('(') && trimmed.ends_with(')') {
-        let content = &trimmed[1..trimmed.len() - 1];
-        let parts: Vec<&str> = content.split('|').collect();
-        if parts.len() > 1 {
-            let mut patterns = Vec::new();
-            for part in parts {
-                patterns.push(parse_pattern(part.trim())?);
-            }
-            return Ok(RulePattern::Choice(patterns));
-        }
+    // Group: ( pattern ), e.g. (("+" | "-") | [0-9]+)
+    if let Some(content) = group_content(trimmed) {
+        return parse_pattern(content);
     }
 
     // Default: treat as regex pattern for backward compatibility
     Ok(RulePattern::Regex(trimmed.to_string()))
 }
 
+/// Resolves escape sequences in the body of a quoted pattern.
+///
+/// Supports `\n`, `\t`, `\r`, `\0`, `\\`, `\'`, `\"`, `\xHH` and `\u{...}`;
+/// any other escaped character stands for itself.
+fn unescape_literal(content: &str) -> Result<String, ParseError> {
+    let mut result = String::with_capacity(content.len());
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|b| b.is_ascii())
+                    .map(char::from)
+                    .ok_or_else(|| ParseError::new(format!("Invalid escape \\x{} in literal", hex)))?
+            }
+            Some('u') => {
+                let rest = chars.as_str();
+                let code = rest
+                    .strip_prefix('{')
+                    .and_then(|r| r.split_once('}'))
+                    .map(|(hex, _)| hex)
+                    .ok_or_else(|| ParseError::new(format!("Invalid unicode escape in literal: {}", content)))?;
+                let ch = u32::from_str_radix(code, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| ParseError::new(format!("Invalid unicode escape \\u{{{}}} in literal", code)))?;
+                chars = rest[code.len() + 2..].chars();
+                ch
+            }
+            Some(other) => other,
+            None => return Err(ParseError::new(format!("Unterminated escape in literal: {}", content))),
+        };
+        result.push(escaped);
+    }
+    Ok(result)
+}
+
+/// Parses a bounded repetition quantifier: `{n}`, `{n,}` or `{n,m}`.
+///
+/// Returns `Ok(None)` if the input is not a bounded repetition.
+fn parse_repetition(quantifier: &str) -> Result<Option<(usize, Option<usize>)>, ParseError> {
+    let Some(inside) = quantifier.strip_prefix('{').and_then(|q| q.strip_suffix('}')) else {
+        return Ok(None);
+    };
+    let parse_count = |s: &str| s.trim().parse::<usize>().ok();
+    let (min, max) = match inside.split_once(',') {
+        None => match parse_count(inside) {
+            Some(n) => (n, Some(n)),
+            None => return Ok(None),
+        },
+        Some((min, max)) if max.trim().is_empty() => match parse_count(min) {
+            Some(n) => (n, None),
+            None => return Ok(None),
+        },
+        Some((min, max)) => match (parse_count(min), parse_count(max)) {
+            (Some(min), Some(max)) => (min, Some(max)),
+            _ => return Ok(None),
+        },
+    };
+    if max.is_some_and(|max| max < min) {
+        return Err(ParseError::new(format!("Invalid repetition {}: maximum is less than minimum", quantifier)));
+    }
+    Ok(Some((min, max)))
+}
+
+/// Returns true if the given string is a valid definition or token name.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => chars.all(|c| c.is_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+/// Finds the `->` that separates the pattern of a rule from its token name or action.
+///
+/// Arrows inside quoted literals, `/regex/`, `[...]` classes and `{...}` template
+/// references belong to the pattern, as do escaped characters, so `"->" -> ARROW`
+/// works. If a literal is unterminated, the first `->` is taken.
+fn find_arrow(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    // Returns the position of `close` after `start`, skipping escaped characters
+    let closing = |start: usize, close: u8| {
+        let mut j = start;
+        while j < bytes.len() {
+            match bytes[j] {
+                b'\\' => j += 2,
+                c if c == close => return Some(j),
+                _ => j += 1,
+            }
+        }
+        None
+    };
+    let mut i = 0;
+    while i < bytes.len() {
+        let at_token_start = i == 0 || bytes[i - 1].is_ascii_whitespace() || matches!(bytes[i - 1], b'(' | b'|' | b',' | b'>');
+        let close = match bytes[i] {
+            b'-' if bytes.get(i + 1) == Some(&b'>') => return Some(i),
+            b'\\' => {
+                i += 2;
+                continue;
+            }
+            b'"' => b'"',
+            b'[' => b']',
+            b'{' => b'}',
+            b'/' if at_token_start => b'/',
+            // A character literal: 'c' or an escape such as '\n'
+            b'\'' if bytes.get(i + 1) == Some(&b'\\') => b'\'',
+            b'\'' if line[i + 1..].chars().nth(1) == Some('\'') => b'\'',
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        match closing(i + 1, close) {
+            Some(end) => i = end + 1,
+            None => return line.find("->"),
+        }
+    }
+    None
+}
+
+/// Splits a named definition line (`NAME = pattern`) into its name and pattern.
+fn split_definition(line: &str) -> Option<(&str, &str)> {
+    if find_arrow(line).is_some() {
+        return None;
+    }
+    let eq_pos = line.find('=')?;
+    let name = line[..eq_pos].trim();
+    let pattern = line[eq_pos + 1..].trim();
+    if is_identifier(name) && !pattern.is_empty() {
+        Some((name, pattern))
+    } else {
+        None
+    }
+}
+
+/// Returns true if a pattern starts with a pattern directive (`%balanced`, `%heredoc`)
+/// rather than a context token.
+fn is_pattern_directive(pattern: &str) -> bool {
+    pattern.starts_with("%balanced") || pattern.starts_with("%heredoc")
+}
+
+/// Parses the delimiters of a `%balanced "open" "close"` pattern.
+fn parse_balanced(delimiters: &str) -> Option<RulePattern> {
+    let literal = |atom: &str| match parse_pattern(atom) {
+        Ok(RulePattern::StringLiteral(s)) if !s.is_empty() => Some(s),
+        Ok(RulePattern::CharLiteral(c)) => Some(c.to_string()),
+        _ => None,
+    };
+    match split_sequence(delimiters.trim())[..] {
+        [open, close] => Some(RulePattern::Balanced(literal(open)?, literal(close)?)),
+        _ => None,
+    }
+}
+
+/// Parses the body of a `%template NAME(PARAMS) = pattern` directive.
+fn parse_template(input: &str) -> Option<(String, Vec<String>, String)> {
+    let (head, body) = input.split_once('=')?;
+    let (name, params) = head.trim().strip_suffix(')')?.split_once('(')?;
+    let params: Vec<String> = params.split(',').map(|p| p.trim().to_string()).collect();
+    let body = body.trim();
+    if !is_identifier(name.trim()) || !params.iter().all(|p| is_identifier(p)) || body.is_empty() {
+        return None;
+    }
+    Some((name.trim().to_string(), params, body.to_string()))
+}
+
+/// Replaces `{NAME(args)}` references in the pattern part of a line with the
+/// body of the template, substituting each parameter with its argument.
+///
+/// Parameters are replaced where they appear as whole words (not after `\`).
+/// An argument consisting of a single punctuation character is escaped, so
+/// `{quoted(")}` works inside and outside character classes.
+fn expand_templates(line: &str, templates: &[(String, Vec<String>, String)]) -> Result<String, ParseError> {
+    let pattern_end = find_arrow(line).unwrap_or(line.len());
+    let mut pattern = line[..pattern_end].to_string();
+    for _ in 0..32 {
+        let reference = templates.iter().find_map(|(name, params, body)| {
+            let open = format!("{{{}(", name);
+            let start = pattern.find(&open)?;
+            let args_start = start + open.len();
+            let args_len = pattern[args_start..].find(")}")?;
+            Some((start, args_start, args_len, name, params, body))
+        });
+        let Some((start, args_start, args_len, name, params, body)) = reference else {
+            return Ok(pattern + &line[pattern_end..]);
+        };
+        let args_text = &pattern[args_start..args_start + args_len];
+        let args: Vec<&str> = if params.len() == 1 {
+            vec![args_text]
+        } else {
+            args_text.split(',').collect()
+        };
+        if args.len() != params.len() {
+            return Err(ParseError::new(format!(
+                "Template '{}' expects {} argument(s): {}",
+                name,
+                params.len(),
+                line
+            )));
+        }
+        let args: Vec<String> = args
+            .into_iter()
+            .map(|arg| {
+                let arg = if arg.trim().is_empty() { arg } else { arg.trim() };
+                let mut chars = arg.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if !c.is_alphanumeric() && c != '_' => format!("\\{}", c),
+                    _ => arg.to_string(),
+                }
+            })
+            .collect();
+        let instance = substitute_params(body, params, &args);
+        pattern.replace_range(start..args_start + args_len + 2, &format!("({})", instance));
+    }
+    Err(ParseError::new(format!("Template expansion is too deep: {}", line)))
+}
+
+/// Replaces whole-word occurrences of `params` in `body` with `args`.
+fn substitute_params(body: &str, params: &[String], args: &[String]) -> String {
+    let mut result = String::new();
+    let mut word = String::new();
+    let mut after_backslash = false;
+    let mut word_escaped = false;
+    let flush = |word: &mut String, result: &mut String, escaped: bool| {
+        match params.iter().position(|p| p == word.as_str()) {
+            Some(i) if !escaped => result.push_str(&args[i]),
+            _ => result.push_str(word),
+        }
+        word.clear();
+    };
+    for c in body.chars() {
+        if c.is_alphanumeric() || c == '_' {
+            if word.is_empty() {
+                word_escaped = after_backslash;
+            }
+            word.push(c);
+        } else {
+            flush(&mut word, &mut result, word_escaped);
+            result.push(c);
+        }
+        after_backslash = c == '\\' && !after_backslash;
+    }
+    flush(&mut word, &mut result, word_escaped);
+    result
+}
+
+/// Parses a rule pattern, expanding `{NAME}` references to named definitions.
+///
+/// A pattern consisting of a single reference reuses the definition as-is, so
+/// `{DIGIT}` keeps the direct matching of `[0-9]`. References embedded in a
+/// larger pattern are replaced with the regex of the definition as a group.
+fn parse_pattern_with_definitions(
+    input: &str,
+    definitions: &[(String, RulePattern)],
+) -> Result<RulePattern, ParseError> {
+    let trimmed = input.trim();
+    if is_pattern_directive(trimmed) {
+        return parse_pattern(trimmed);
+    }
+    if let Some(alternatives) = split_alternatives(trimmed) {
+        let patterns = alternatives
+            .into_iter()
+            .map(|alternative| parse_pattern_with_definitions(alternative, definitions))
+            .collect::<Result<_, _>>()?;
+        return Ok(RulePattern::Choice(patterns));
+    }
+    let atoms = split_sequence(trimmed);
+    if atoms.len() > 1 {
+        let patterns = atoms
+            .into_iter()
+            .map(|atom| parse_pattern_with_definitions(atom, definitions))
+            .collect::<Result<_, _>>()?;
+        return Ok(RulePattern::Sequence(patterns));
+    }
+    if let Some(content) = group_content(trimmed) {
+        return parse_pattern_with_definitions(content, definitions);
+    }
+    if trimmed.starts_with('\'') || trimmed.starts_with('"') || !trimmed.contains('{') {
+        return parse_pattern(trimmed);
+    }
+
+    let lookup = |name: &str| definitions.iter().find(|(n, _)| n == name).map(|(_, p)| p);
+
+    if let Some(name) = trimmed.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+        if let Some(pattern) = lookup(name) {
+            return Ok(pattern.clone());
+        }
+    }
+
+    let mut expanded = String::new();
+    let mut rest = trimmed;
+    while let Some(open) = rest.find('{') {
+        expanded.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        // Escapes such as \u{...} and \p{...} are not references
+        let is_escape = ["\\u", "\\x", "\\p", "\\P"]
+            .iter()
+            .any(|e| expanded.ends_with(e));
+        let name = after.find('}').map(|close| &after[..close]);
+        match name {
+            Some(name) if is_identifier(name) && !is_escape => {
+                let pattern = lookup(name).ok_or_else(|| {
+                    ParseError::new(format!("Undefined definition '{{{}}}' in pattern: {}", name, trimmed))
+                })?;
+                expanded.push_str(&format!("(?:{})", pattern_to_regex(pattern)));
+                rest = &after[name.len() + 1..];
+            }
+            _ => {
+                expanded.push('{');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+
+    parse_pattern(&expanded)
+}
+
+/// Splits a pattern with trailing context (`r/s`) into `r` and `s`.
+///
+/// The separator is a `/` outside of quotes, brackets and a leading `/regex/`.
+fn split_trailing_context(input: &str) -> Option<(&str, &str)> {
+    let mut quote: Option<char> = None;
+    let mut in_brackets = false;
+    let mut in_regex = false;
+    let mut escaped = false;
+    for (i, ch) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match ch {
+            '\\' => escaped = true,
+            _ if quote == Some(ch) => quote = None,
+            _ if quote.is_some() => {}
+            '\'' | '"' if !in_brackets && !in_regex => quote = Some(ch),
+            '[' => in_brackets = true,
+            ']' => in_brackets = false,
+            '/' if !in_brackets && i == 0 => in_regex = true,
+            '/' if !in_brackets && in_regex => in_regex = false,
+            '/' if !in_brackets => {
+                let (body, context) = (input[..i].trim(), input[i + 1..].trim());
+                if body.is_empty() || context.is_empty() {
+                    return None;
+                }
+                return Some((body, context));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits a pattern list at top-level occurrences of `separator`.
+///
+/// Separators inside strings, character literals, character classes,
+/// `/regex/` patterns, parentheses and braces are ignored.
+fn split_top_level(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut part_start = 0;
+    let mut quote: Option<char> = None;
+    let mut in_brackets = false;
+    let mut in_regex = false;
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, ch) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match ch {
+            '\\' => escaped = true,
+            _ if quote == Some(ch) => quote = None,
+            _ if quote.is_some() => {}
+            '/' if in_regex => in_regex = false,
+            _ if in_regex => {}
+            ']' => in_brackets = false,
+            _ if in_brackets => {}
+            '\'' | '"' => quote = Some(ch),
+            '[' => in_brackets = true,
+            '/' if input[part_start..i].trim().is_empty() => in_regex = true,
+            _ if ch == separator && depth == 0 => {
+                parts.push(&input[part_start..i]);
+                part_start = i + ch.len_utf8();
+            }
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    parts.push(&input[part_start..]);
+    parts
+}
+
+/// Splits a pattern into the alternatives of a top-level choice (`a | b`).
+///
+/// Returns None if the pattern has no top-level `|` or an alternative is empty.
+fn split_alternatives(input: &str) -> Option<Vec<&str>> {
+    let alternatives: Vec<&str> = split_top_level(input, '|').into_iter().map(str::trim).collect();
+    if alternatives.len() > 1 && alternatives.iter().all(|a| !a.is_empty()) {
+        Some(alternatives)
+    } else {
+        None
+    }
+}
+
+/// Returns the content of a pattern enclosed in one pair of parentheses: `( pattern )`.
+///
+/// Regex groups such as `(?:...)` and patterns like `(a)(b)` are not enclosed groups.
+fn group_content(input: &str) -> Option<&str> {
+    let content = input.strip_prefix('(')?.strip_suffix(')')?;
+    let is_enclosed = split_top_level(content, ')').len() == 1;
+    if is_enclosed && !content.trim().is_empty() && !content.starts_with('?') {
+        Some(content.trim())
+    } else {
+        None
+    }
+}
+
+/// Splits a pattern into the sub-patterns of a sequence at top-level whitespace.
+///
+/// A pattern is only a sequence if one of its parts is written in the pattern syntax
+/// (see `is_sequence_atom`); otherwise, like `hello world`, it stays a bare regex whose
+/// spaces match spaces. Returns the whole pattern as the only part then.
+fn split_sequence(input: &str) -> Vec<&str> {
+    let atoms: Vec<&str> = split_top_level(input, ' ')
+        .into_iter()
+        .map(str::trim)
+        .filter(|atom| !atom.is_empty())
+        .collect();
+    if atoms.iter().any(|atom| is_sequence_atom(atom)) {
+        atoms
+    } else {
+        vec![input]
+    }
+}
+
+/// Returns true for a part of a pattern that marks it as a sequence: a quoted literal,
+/// a bracket class or a `{NAME}` reference, possibly with a quantifier, or a group
+/// containing one.
+fn is_sequence_atom(atom: &str) -> bool {
+    let atom = atom.trim_end_matches(['+', '*', '?']);
+    let is_name = |name: &str| {
+        name.starts_with(|c: char| c.is_alphabetic() || c == '_') && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+    };
+    atom.starts_with(['"', '\'', '['])
+        || atom.strip_prefix('{').and_then(|rest| rest.strip_suffix('}')).is_some_and(is_name)
+        || group_content(atom).is_some_and(|content| split_top_level(content, ' ').into_iter().any(is_sequence_atom))
+}
+
+/// Parses the pattern part of a rule, including an optional trailing context (`r/s`).
+///
+/// A comma-separated list of patterns (`"<>", "!="`) becomes a choice between them.
+pub(crate) fn parse_rule_pattern(
+    input: &str,
+    definitions: &[(String, RulePattern)],
+) -> Result<(RulePattern, Option<RulePattern>), ParseError> {
+    if is_pattern_directive(input.trim()) {
+        return Ok((parse_pattern(input)?, None));
+    }
+    let aliases = split_top_level(input.trim(), ',');
+    if aliases.len() > 1 {
+        let mut patterns = Vec::new();
+        for alias in aliases {
+            if alias.trim().is_empty() {
+                return Err(ParseError::new(format!("Empty pattern in pattern list: {}", input.trim())));
+            }
+            patterns.push(parse_pattern_with_definitions(alias, definitions)?);
+        }
+        return Ok((RulePattern::Choice(patterns), None));
+    }
+    match split_trailing_context(input.trim()) {
+        Some((body, context)) => Ok((
+            parse_pattern_with_definitions(body, definitions)?,
+            Some(parse_pattern_with_definitions(context, definitions)?),
+        )),
+        None => Ok((parse_pattern_with_definitions(input, definitions)?, None)),
+    }
+}
+
+/// Splits a directive argument list such as `A B C` or `A, B, C` into names.
+fn split_names(input: &str) -> Vec<String> {
+    input
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Parses the body of a `%state_struct` directive, `{ name: Type, ... }`, into its fields.
+///
+/// Commas inside generic arguments and tuples don't separate fields, and `//`
+/// comments are ignored. Returns None if a field is not `name: Type`.
+fn parse_state_struct(input: &str) -> Option<Vec<(String, String)>> {
+    let body = input.trim().strip_prefix('{')?.strip_suffix('}')?;
+    let body: String = body
+        .lines()
+        .map(|line| line.split("//").next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut fields = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in body.char_indices().chain(std::iter::once((body.len(), ','))) {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' | ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                let field = body[start..i].trim();
+                start = i + 1;
+                if field.is_empty() {
+                    continue;
+                }
+                let (name, field_type) = field.split_once(':')?;
+                let (name, field_type) = (name.trim(), field_type.trim());
+                if !is_identifier(name) || field_type.is_empty() {
+                    return None;
+                }
+                fields.push((name.to_string(), field_type.to_string()));
+            }
+            _ => {}
+        }
+    }
+    Some(fields)
+}
+
+/// Splits the argument of a `%test` directive, `"input" => NAME NAME`, into the
+/// still escaped input and the expected token names.
+fn parse_test(input: &str) -> Option<(String, Vec<String>)> {
+    let rest = input.trim().strip_prefix('"')?;
+    let mut end = None;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => {
+                end = Some(i);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let end = end?;
+    let names = split_names(rest[end + 1..].trim_start().strip_prefix("=>")?);
+    if !names.iter().all(|name| is_identifier(name)) {
+        return None;
+    }
+    Some((rest[..end].to_string(), names))
+}
+
+/// Removes `/* ... */` block comments and `//` comments after rules from a rules section.
+///
+/// A comment only starts at the beginning of a line or after whitespace, and
+/// never inside a string, character literal, character class or `/regex/`,
+/// so patterns such as `"//"` or `/\/\/.*/` are left untouched.
+/// `//` comments on lines of their own are kept, as descriptions of the following rule.
+fn strip_comments(rules: &str) -> Result<String, ParseError> {
+    let chars: Vec<char> = rules.chars().collect();
+    let mut output = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        let at_token_start = i == 0 || chars[i - 1].is_whitespace();
+        match ch {
+            '/' if at_token_start && chars.get(i + 1) == Some(&'*') => {
+                // Block comment: blank it out, keeping the lines and byte offsets of the following rules
+                let mut j = i + 2;
+                loop {
+                    match chars.get(j) {
+                        None => {
+                            return Err(ParseError::new("Unterminated block comment".to_string()));
+                        }
+                        Some('*') if chars.get(j + 1) == Some(&'/') => break,
+                        _ => {}
+                    }
+                    j += 1;
+                }
+                for &c in &chars[i..j + 2] {
+                    if c == '\n' {
+                        output.push('\n');
+                    } else {
+                        output.extend(std::iter::repeat_n(' ', c.len_utf8()));
+                    }
+                }
+                i = j + 2;
+                continue;
+            }
+            '/' if at_token_start && chars.get(i + 1) == Some(&'/') => {
+                // Line comment; comments on lines of their own are kept to describe the next rule
+                let whole_line = output.rsplit('\n').next().is_some_and(|line| line.trim().is_empty());
+                while i < chars.len() && chars[i] != '\n' {
+                    if whole_line {
+                        output.push(chars[i]);
+                    }
+                    i += 1;
+                }
+                continue;
+            }
+            '"' | '[' | '/' => {
+                // Copy a string literal, character class or regex up to its closing delimiter
+                let close = match ch {
+                    '[' => ']',
+                    '/' if !at_token_start => {
+                        output.push(ch);
+                        i += 1;
+                        continue;
+                    }
+                    c => c,
+                };
+                output.push(ch);
+                i += 1;
+                while i < chars.len() && chars[i] != '\n' {
+                    output.push(chars[i]);
+                    i += 1;
+                    if chars[i - 1] == '\\' {
+                        if i < chars.len() && chars[i] != '\n' {
+                            output.push(chars[i]);
+                            i += 1;
+                        }
+                    } else if chars[i - 1] == close {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '\'' => {
+                // Copy character literals such as '"' and '\''
+                let len = match (chars.get(i + 1), chars.get(i + 2), chars.get(i + 3)) {
+                    (Some('\\'), Some(_), Some('\'')) => 4,
+                    (Some(c), Some('\''), _) if *c != '\n' => 3,
+                    _ => 1,
+                };
+                output.extend(&chars[i..i + len]);
+                i += len;
+                continue;
+            }
+            _ => {}
+        }
+        output.push(ch);
+        i += 1;
+    }
+    Ok(output)
+}
+
+/// Splits a spec into its prefix code, rules and suffix code sections.
+///
+/// The sections are separated by lines consisting of `%%` only, so `%%` inside
+/// code or patterns does not split the spec. The suffix code may be omitted,
+/// and a spec without a `%%` line consists of rules only.
+fn split_sections(input: &str) -> Result<(&str, &str, &str), ParseError> {
+    let mut parts = Vec::new();
+    let mut part_start = 0;
+    let mut line_start = 0;
+    for line in input.split_inclusive('\n') {
+        if line.trim() == "%%" {
+            parts.push(&input[part_start..line_start]);
+            part_start = line_start + line.len();
+        }
+        line_start += line.len();
+    }
+    parts.push(&input[part_start..]);
+    match parts[..] {
+        [rules] => Ok(("", rules, "")),
+        [prefix_code, rules] => Ok((prefix_code, rules, "")),
+        [prefix_code, rules, suffix_code] => Ok((prefix_code, rules, suffix_code)),
+        _ => Err(ParseError::new(
+            "Input must have at most 3 sections separated by %% lines".to_string(),
+        )),
+    }
+}
+
+/// Replaces `%include "file"` lines in a rules section with the rules of the included file.
+///
+/// If the included file has `%%` sections, only its rules section is used.
+/// `stack` holds the files currently being included, to detect include cycles.
+fn expand_includes(
+    rules: &str,
+    base_dir: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Vec<(usize, String)>, (ParseError, usize)> {
+    let rules = strip_comments(rules).map_err(|error| (error, 1))?;
+    let mut output = Vec::new();
+    for (line_number, line) in (1..).zip(rules.lines()) {
+        let Some(file_part) = line.trim().strip_prefix("%include") else {
+            output.push((line_number, line.to_string()));
+            continue;
+        };
+        let included = include_rules(file_part, line, base_dir, stack).map_err(|error| (error, line_number))?;
+        // The rules of the included file are reported at the %include line
+        output.push((line_number, line.to_string()));
+        output.extend(included.into_iter().map(|line| (line_number, line)));
+    }
+    Ok(output)
+}
+
+/// Reads the rules of the file named by an `%include` line, with its own includes expanded.
+fn include_rules(
+    file_part: &str,
+    line: &str,
+    base_dir: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Vec<String>, ParseError> {
+    let file_part = file_part.trim();
+    let file_name = file_part
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| {
+            ParseError::new(format!("Expected a quoted file name after %include: {}", line.trim()))
+        })?;
+    let path = base_dir.join(file_name);
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+    if stack.contains(&canonical) {
+        return Err(ParseError::new(format!("Recursive %include of {}", path.display())));
+    }
+    let content = fs::read_to_string(&path).map_err(|e| {
+        ParseError::new(format!("Cannot include {}: {}", path.display(), e))
+    })?;
+    let (_, included_rules, _) = split_sections(&content)?;
+    stack.push(canonical);
+    let expanded = expand_includes(
+        included_rules.trim(),
+        path.parent().unwrap_or(Path::new("")),
+        stack,
+    )
+    .map_err(|(error, _)| error)?;
+    stack.pop();
+    Ok(expanded.into_iter().map(|(_, line)| line).collect())
+}
+
+/// Parses the rest of a negated character class after `[^`, e.g. `"\n]+`.
+///
+/// Supports single characters, ranges (`a-z`) and the escapes `\n`, `\t`, `\r`,
+/// `\xHH` and `\u{HHHH}`. Returns None for classes that need the regex engine
+/// (e.g. `\d` or other quantifiers), so they fall back to `CharSet`.
+fn parse_negated_char_set(rest: &str) -> Option<RulePattern> {
+    let mut chars = Vec::new();
+    let mut iter = rest.chars();
+    let mut closed = false;
+    while let Some(ch) = iter.next() {
+        // An escaped '-' is a literal character, never a range separator
+        let escaped = ch == '\\';
+        let ch = match ch {
+            ']' => {
+                closed = true;
+                break;
+            }
+            '\\' => match iter.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'x' => {
+                    let hex: String = iter.by_ref().take(2).collect();
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                'u' => {
+                    if iter.next()? != '{' {
+                        return None;
+                    }
+                    let hex: String = iter.by_ref().take_while(|&c| c != '}').collect();
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                c if c.is_ascii_alphanumeric() => return None, // \d, \w, \s, ...
+                c => c,
+            },
+            '[' => return None,
+            c => c,
+        };
+        chars.push((ch, escaped));
+    }
+    if !closed || chars.is_empty() {
+        return None;
+    }
+    let quantifier = iter.as_str();
+
+    // Group characters into ranges: a-z
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if i + 2 < chars.len() && chars[i + 1] == ('-', false) {
+            let (start, end) = (chars[i].0, chars[i + 2].0);
+            if start > end {
+                return None;
+            }
+            ranges.push((start, end));
+            i += 3;
+        } else {
+            ranges.push((chars[i].0, chars[i].0));
+            i += 1;
+        }
+    }
+
+    match quantifier {
+        "+" => Some(RulePattern::NegatedCharSet1(ranges)),
+        "*" => Some(RulePattern::NegatedCharSet0(ranges)),
+        _ => None,
+    }
+}
+
+/// Splits a trailing description (`NUMBER "Integer literal"`) off the right part of a rule.
+fn split_description(right_part: &str) -> Result<(&str, Option<String>), ParseError> {
+    match right_part.find('"') {
+        Some(quote) if right_part.len() > quote + 1 && right_part.ends_with('"') => {
+            let description = unescape_literal(&right_part[quote + 1..right_part.len() - 1])?;
+            Ok((right_part[..quote].trim_end(), Some(description)))
+        }
+        Some(_) => Err(ParseError::new(format!("Unterminated token description: {}", right_part))),
+        None => Ok((right_part, None)),
+    }
+}
+
+/// Splits the left part of a context rule into its context and pattern.
+///
+/// The context is a token name (`IDENT`), a choice of names (`(NUMBER|IDENT)`) or a
+/// sequence of either, oldest first (`seq(LET IDENT)`).
+fn split_context(left_part: &str) -> Option<(Vec<ContextToken>, &str)> {
+    let mut context = Vec::new();
+    let rest = match left_part.strip_prefix("seq(") {
+        Some(sequence) => {
+            let elements = split_top_level(sequence, ')')[0];
+            let mut remaining = elements.trim_start();
+            while !remaining.is_empty() {
+                let (position, rest) = read_context_token(remaining)?;
+                if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+                    return None;
+                }
+                context.push(position);
+                remaining = rest.trim_start();
+            }
+            sequence.get(elements.len() + 1..)?
+        }
+        None => {
+            let (position, rest) = read_context_token(left_part)?;
+            context.push(position);
+            rest
+        }
+    };
+    let pattern = rest.trim();
+    (!context.is_empty() && !pattern.is_empty()).then_some((context, pattern))
+}
+
+/// Reads one position of a context from the start of `input`: a name or a choice of names,
+/// optionally followed by the text the token must have (`IDENT("r")`).
+fn read_context_token(input: &str) -> Option<(ContextToken, &str)> {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_';
+    let (names, rest) = match input.strip_prefix('(') {
+        Some(group) => group.split_once(')')?,
+        None => input.split_at(input.find(|c: char| !is_name_char(c)).unwrap_or(input.len())),
+    };
+    let kinds: Vec<String> = names.split('|').map(|name| name.trim().to_string()).collect();
+    if kinds.iter().any(|name| name.is_empty() || !name.chars().all(is_name_char)) {
+        return None;
+    }
+    let (text, rest) = match rest.strip_prefix('(') {
+        Some(value) => {
+            let literal = split_top_level(value, ')')[0];
+            let text = match parse_pattern(literal).ok()? {
+                RulePattern::StringLiteral(s) => s,
+                RulePattern::CharLiteral(c) => c.to_string(),
+                _ => return None,
+            };
+            (Some(text), value.get(literal.len() + 1..)?)
+        }
+        None => (None, rest),
+    };
+    Some((ContextToken { kinds, text }, rest))
+}
+
+/// Splits a token name with an optional channel (`COMMENT @hidden`) into its parts.
+fn split_channel(right_part: &str) -> Result<(String, Option<String>), ParseError> {
+    match right_part.split_once('@') {
+        Some((name, channel)) => {
+            let channel = channel.trim();
+            if !is_identifier(channel) {
+                return Err(ParseError::new(format!("Invalid channel name '@{}'", channel)));
+            }
+            Ok((name.trim().to_string(), Some(channel.to_string())))
+        }
+        None => Ok((right_part.trim().to_string(), None)),
+    }
+}
+
+/// Splits a `%cfg(predicate)` prefix off a rule line.
+///
+/// Returns the predicate (e.g. `feature = "extended"`) and the rest of the line.
+fn split_cfg_prefix(line: &str) -> Result<Option<(String, &str)>, ParseError> {
+    let Some(rest) = line.strip_prefix("%cfg(") else {
+        return Ok(None);
+    };
+    let mut depth = 1;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => {
+                depth -= 1;
+                if depth == 0 {
+                    let predicate = rest[..i].trim();
+                    let rule = rest[i + 1..].trim_start();
+                    if predicate.is_empty() || rule.is_empty() {
+                        break;
+                    }
+                    return Ok(Some((predicate.to_string(), rule)));
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(ParseError::new(format!("Invalid %cfg(...) prefix in rule: {}", line)))
+}
+
+/// Returns the newest spec format version (`major.minor`) this klex supports.
+///
+/// The spec version follows the major and minor version of the crate.
+pub fn supported_spec_version() -> (u64, u64) {
+    let major = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+    let minor = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0);
+    (major, minor)
+}
+
+/// Returns true if a spec declaring `%klex <version>` can be parsed by this klex.
+///
+/// # Examples
+///
+/// ```rust
+/// use klex::{parse_spec, supports_spec_version};
+///
+/// assert!(supports_spec_version("0.1"));
+/// assert!(!supports_spec_version("99.0"));
+/// assert!(parse_spec("%%\n%klex 99.0\n[0-9]+ -> NUMBER\n%%\n").is_err());
+/// ```
+pub fn supports_spec_version(version: &str) -> bool {
+    parse_spec_version(version).is_some_and(|required| required <= supported_spec_version())
+}
+
+/// Parses a `major.minor` (or `major.minor.patch`) spec version.
+fn parse_spec_version(version: &str) -> Option<(u64, u64)> {
+    let mut parts = version.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    match parts.next() {
+        Some(patch) if patch.parse::<u64>().is_err() || parts.next().is_some() => None,
+        _ => Some((major, minor)),
+    }
+}
+
+/// Returns true if the given name is the default token channel.
+pub fn is_default_channel(name: &str) -> bool {
+    name.eq_ignore_ascii_case("default")
+}
+
+/// Returns true if the given name is the implicit initial start condition.
+pub fn is_initial_state(name: &str) -> bool {
+    name == "INITIAL" || name == "Initial"
+}
+
+/// Splits a leading start condition list (`<STATE1,STATE2>`) from a rule line.
+///
+/// Returns the state names and the rest of the line, or `None` if the line
+/// does not start with a start condition list.
+fn split_state_prefix(line: &str) -> Option<(Vec<String>, &str)> {
+    if !line.starts_with('<') {
+        return None;
+    }
+    let close = line.find('>')?;
+    let inside = &line[1..close];
+    let is_name_list = !inside.trim().is_empty()
+        && inside
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == ',' || c == ' ');
+    if !is_name_list {
+        return None;
+    }
+    Some((split_names(inside), line[close + 1..].trim()))
+}
+
+/// Returns the brace nesting depth at the end of a piece of Rust code.
+///
+/// Braces inside string literals, character literals and line comments are ignored.
+fn brace_depth(code: &str) -> i32 {
+    let mut depth = 0;
+    let mut chars = code.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '"' => {
+                // Skip string literal
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '\'' => {
+                // Skip character literals such as '{' and '\''; lifetimes are left alone
+                let mut lookahead = chars.clone();
+                let literal_len = match lookahead.next() {
+                    Some('\\') => lookahead.position(|c| c == '\'').map(|n| n + 2),
+                    Some(_) if lookahead.next() == Some('\'') => Some(2),
+                    _ => None,
+                };
+                if let Some(len) = literal_len {
+                    for _ in 0..len {
+                        chars.next();
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                // Skip line comment
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// Joins rule lines whose `{ action }` block spans several lines into a single logical line.
+///
+/// Each logical line keeps the line number of its first line.
+fn join_action_lines(rules_lines: &[(usize, String)]) -> Vec<(usize, String)> {
+    let mut logical_lines = Vec::new();
+    let mut pending: Option<(usize, String)> = None;
+
+    for &(line_number, ref line) in rules_lines {
+        let line = line.as_str();
+        if let Some((start_line, mut current)) = pending.take() {
+            current.push('\n');
+            current.push_str(line);
+            let action_start = if current.trim_start().starts_with("%state_struct") {
+                0
+            } else {
+                find_arrow(&current).map_or(0, |pos| pos + 2)
+            };
+            if brace_depth(&current[action_start..]) > 0 {
+                pending = Some((start_line, current));
+            } else {
+                logical_lines.push((start_line, current));
+            }
+            continue;
+        }
+
+        let is_open_action = find_arrow(line).is_some_and(|pos| {
+            let right_part = line[pos + 2..].trim_start();
+            right_part.starts_with('{') && brace_depth(right_part) > 0
+        });
+        let is_directive = line.trim_start().starts_with("//") || line.trim_start().starts_with("%test");
+        let is_open_state_struct = line.trim_start().starts_with("%state_struct") && brace_depth(line) > 0;
+        if (is_open_action && !is_directive) || is_open_state_struct {
+            pending = Some((line_number, line.to_string()));
+        } else {
+            logical_lines.push((line_number, line.to_string()));
+        }
+    }
+
+    // An unterminated action block is reported by the rule parser
+    if let Some(current) = pending {
+        logical_lines.push(current);
+    }
+    logical_lines
+}
+
 /// Parses a lexer specification file.
 ///
 /// The input should be in the format:
@@ -302,7 +1802,54 @@ fn parse_pattern(input: &str) -> Result<RulePattern, ParseError> {
 /// (Rust code)
 /// ```
 ///
+/// Sections are separated by lines containing only `%%`. The second `%%` and
+/// the code after it may be omitted, and input without `%%` consists of rules only.
+///
 /// Rules should be in the format: `pattern -> TOKEN_NAME` or just `pattern`.
+/// A rule may be limited to start conditions declared with `%state` by
+/// prefixing it with `<STATE>` (or `<STATE1,STATE2>`). States declared with
+/// `%xstate` are exclusive: rules without a prefix are not active in them.
+///
+/// Patterns separated by whitespace are matched one after another
+/// (e.g. `"0x" [0-9a-f]+ -> HEX`).
+///
+/// A rule starting with `^` only matches at the start of a line.
+///
+/// A pattern followed by `/context` only matches when the context follows it,
+/// without consuming the context (e.g. `[0-9]+/".." -> INT`).
+///
+/// Several patterns can share one token with `("true" | "false") -> BOOL`
+/// or the list form `"<>", "!=" -> NOT_EQUAL`.
+///
+/// Use `pattern -> %skip` to consume matches without emitting tokens.
+/// `<<EOF>> -> TOKEN` or `<<EOF>> -> { code }` runs once at the end of input.
+/// `%error NAME` turns runs of unmatched input into `NAME` tokens.
+/// `pattern -> TOKEN @channel` emits the token on another channel (e.g. `@hidden`).
+/// `%keyword if else` turns identifier tokens (rules named `IDENTIFIER`,
+/// `Identifier`, `IDENT` or `Ident`) that are keywords into `If`/`Else` tokens.
+/// `%type TOKEN type` parses the text of `TOKEN` tokens into `type` (e.g. `i64`).
+/// `%cfg(predicate) rule` compiles the rule and its token only under `#[cfg(predicate)]`.
+/// `%balanced "/*" "*/" -> TOKEN` matches nested delimited text such as nested comments.
+/// `%heredoc /<<([A-Z]+)/ -> TOKEN` matches up to the line starting with the captured terminator.
+/// `%template quoted(q) = q [^q]* q` defines a rule template, instantiated as `{quoted(')}`.
+/// `%klex 0.1` rejects the spec if it needs a newer klex (see [`supports_spec_version`]).
+/// `%prefix Json` renames the generated items to `JsonToken`, `JsonLexer`, etc.
+/// `%test "1+2" => NUMBER PLUS NUMBER` generates a `#[test]` checking the token kinds of the input.
+/// `%state_struct { depth: usize }` adds fields that action code reads and writes as `self.user_state.depth`.
+/// `%trivia WS COMMENT` marks token kinds that `Lexer::significant_tokens` skips.
+/// `%style NUMBER bold yellow` sets the colors of `NUMBER` tokens in `klex highlight`.
+/// `/* ... */` block comments and trailing `//` comments are allowed in the
+/// rules section.
+/// Action blocks (`pattern -> { code }`) may span multiple lines.
+///
+/// Named sub-patterns can be defined with `NAME = pattern` and referenced
+/// from later patterns as `{NAME}`.
+///
+/// `%include "file.klex"` inserts the rules of another file. Paths are
+/// resolved relative to the current directory; use [`parse_spec_file`] to
+/// resolve them relative to the including file.
+///
+/// Spec-wide settings are given with `%option` (see [`LexerOptions`]).
 ///
 /// # Arguments
 ///
@@ -328,124 +1875,751 @@ fn parse_pattern(input: &str) -> Result<RulePattern, ParseError> {
 ///
 /// let spec = parse_spec(input).unwrap();
 /// assert_eq!(spec.rules.len(), 2);
+///
+/// // Rules only, or code and rules without a suffix section
+/// let spec = parse_spec("[0-9]+ -> NUMBER\n\"+\" -> PLUS").unwrap();
+/// assert_eq!(spec.rules.len(), 2);
+/// let spec = parse_spec("use std::fmt;\n%%\n[0-9]+ -> NUMBER").unwrap();
+/// assert_eq!(spec.prefix_code, "use std::fmt;");
+/// assert!(spec.suffix_code.is_empty());
+///
+/// // Only a line of its own separates sections
+/// let spec = parse_spec("const P: &str = \"%%\";\n%%\n\"%%\" -> PERCENTS\n%%\n").unwrap();
+/// assert_eq!(spec.prefix_code, "const P: &str = \"%%\";");
+/// assert_eq!(spec.rules.len(), 1);
+///
+/// // Expected token kinds for an input
+/// let spec = parse_spec("[0-9]+ -> NUMBER\n%test \"1\\n\" => NUMBER UNKNOWN").unwrap();
+/// assert_eq!(spec.tests, vec![("1\n".to_string(), vec!["NUMBER".to_string(), "UNKNOWN".to_string()])]);
+/// assert!(parse_spec("%test 1 => NUMBER").is_err());
+///
+/// // Token descriptions, after the name or in the comments right above the rule
+/// let spec = parse_spec("// Integer\n[0-9]+ -> NUMBER\n\"+\" -> PLUS \"Addition\"").unwrap();
+/// assert_eq!(spec.rules[0].doc.as_deref(), Some("Integer"));
+/// assert_eq!(spec.rules[1].doc.as_deref(), Some("Addition"));
+///
+/// // One rule per token with %option unique-tokens, or one merged rule with merge-tokens
+/// assert!(parse_spec("\"<>\" -> NE\n\"!=\" -> NE").is_ok());
+/// let error = parse_spec("%option unique-tokens\n\"<>\" -> NE\n\"!=\" -> NE").unwrap_err();
+/// assert!(error.to_string().contains("Token NE is already produced by the rule on line 2"));
+/// let spec = parse_spec("%option merge-tokens\n\"<>\" -> NE\n\"!=\" -> NE").unwrap();
+/// assert_eq!(spec.rules.len(), 1);
+///
+/// // Rules, their patterns and directives record where they are in the specification
+/// let input = "%%\n%token KEYWORD\n  [0-9]+ -> NUMBER\n";
+/// let spec = parse_spec(input).unwrap();
+/// let rule = &spec.rules[0];
+/// assert_eq!(&input[rule.span.start..rule.span.end], "[0-9]+ -> NUMBER");
+/// assert_eq!(&input[rule.pattern_span.start..rule.pattern_span.end], "[0-9]+");
+/// assert_eq!(rule.span.line, 3);
+/// assert_eq!(spec.directives[0].name, "%token");
+/// assert_eq!(spec.directives[0].span.line, 2);
+///
+/// // Errors point at the offending line of the specification
+/// let error = parse_spec("use std::fmt;\n%%\n[0-9]+ -> NUMBER\n  %option bogus").unwrap_err();
+/// let error = error.downcast_ref::<klex::ParseError>().unwrap();
+/// assert_eq!((error.line, error.column), (Some(4), Some(3)));
+/// assert!(error.to_string().ends_with("--> line 4, column 3\n   |   %option bogus\n   |   ^"));
 /// ```
 pub fn parse_spec(input: &str) -> Result<LexerSpec, Box<dyn Error>> {
+    parse_spec_all(input).map_err(|mut errors| errors.remove(0).into())
+}
+
+/// Parses a lexer specification, reporting every error of the rules section at once.
+///
+/// Parsing goes on after a rule with an error, so the returned errors cover all
+/// the rules in the specification, in the order they appear.
+///
+/// # Examples
+///
+/// ```rust
+/// use klex::parse_spec_all;
+///
+/// let errors = parse_spec_all("%option bogus\n[0-9]+ -> NUMBER\n<NOPE> \"x\" -> X").unwrap_err();
+/// assert_eq!(errors.len(), 2);
+/// assert_eq!(errors[0].line, Some(1));
+/// assert_eq!(errors[1].line, Some(3));
+/// ```
+pub fn parse_spec_all(input: &str) -> Result<LexerSpec, Vec<ParseError>> {
+    parse_spec_in(input, Path::new(""), &[])
+}
+
+/// Reads and parses a lexer specification file.
+///
+/// `%include` paths are resolved relative to the directory of `path`.
+/// Files with the `.json` extension are read with [`LexerSpec::from_json`].
+///
+/// # Examples
+///
+/// ```rust
+/// use klex::parse_spec_file;
+///
+/// let spec = parse_spec_file("tests/test_include.klex").unwrap();
+/// assert!(spec.rules.iter().any(|r| r.name == "Plus"));
+/// ```
+pub fn parse_spec_file<P: AsRef<Path>>(path: P) -> Result<LexerSpec, Box<dyn Error>> {
+    let path = path.as_ref();
+    let input = fs::read_to_string(path)?;
+    parse_spec_file_text(&input, path, &[]).map_err(|mut errors| errors.remove(0).into())
+}
+
+/// Reads and parses a lexer specification file, reporting every error of the rules section at once.
+///
+/// A file that cannot be read is reported as a single error.
+pub fn parse_spec_file_all<P: AsRef<Path>>(path: P) -> Result<LexerSpec, Vec<ParseError>> {
+    parse_spec_file_with_definitions(path, &[])
+}
+
+/// Reads and parses a lexer specification file with shared named definitions, such as
+/// the `[definitions]` of a `klex.toml`.
+///
+/// `definitions` holds `(NAME, pattern)` pairs defined before the rules of the file, in
+/// order, so rules can reference them as `{NAME}` and the file can redefine them. They
+/// are not used for JSON specs, whose patterns are already expanded.
+///
+/// # Examples
+///
+/// ```rust
+/// use klex::parse_spec_file_with_definitions;
+///
+/// let definitions = [("DIGIT".to_string(), "[0-9]".to_string())];
+/// let spec = parse_spec_file_with_definitions("tests/test_definitions.klex", &definitions).unwrap();
+/// assert!(spec.definitions.iter().any(|(name, _)| name == "DIGIT"));
+/// ```
+pub fn parse_spec_file_with_definitions<P: AsRef<Path>>(
+    path: P,
+    definitions: &[(String, String)],
+) -> Result<LexerSpec, Vec<ParseError>> {
+    let path = path.as_ref();
+    let input = fs::read_to_string(path)
+        .map_err(|e| vec![ParseError::new(format!("Cannot read {}: {}", path.display(), e))])?;
+    parse_spec_file_text(&input, path, definitions)
+}
+
+/// Parses the text of the specification file at `path`, in JSON if its extension is `.json`.
+fn parse_spec_file_text(
+    input: &str,
+    path: &Path,
+    definitions: &[(String, String)],
+) -> Result<LexerSpec, Vec<ParseError>> {
+    if path.extension().is_some_and(|extension| extension == "json") {
+        return LexerSpec::from_json(input).map_err(|error| vec![error]);
+    }
+    parse_spec_in(input, path.parent().unwrap_or(Path::new("")), definitions)
+}
+
+/// Returns the files a specification file pulls in with `%include`, directly or through
+/// other included files, in the order they are included.
+///
+/// Included files that cannot be read are listed but not followed, so tools watching the
+/// files (or build scripts printing `cargo:rerun-if-changed`) notice when they appear.
+///
+/// # Examples
+///
+/// ```rust
+/// use klex::spec_file_includes;
+/// use std::path::Path;
+///
+/// let includes = spec_file_includes("tests/test_include.klex");
+/// assert_eq!(includes, [Path::new("tests/include/common_tokens.klex")]);
+/// ```
+pub fn spec_file_includes<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
+    let mut includes = Vec::new();
+    collect_includes(path.as_ref(), &mut includes);
+    includes
+}
+
+/// Adds the files included by the specification file at `path` to `includes`, recursively.
+fn collect_includes(path: &Path, includes: &mut Vec<PathBuf>) {
+    if path.extension().is_some_and(|extension| extension == "json") {
+        return;
+    }
+    let Ok(content) = fs::read_to_string(path) else {
+        return;
+    };
+    let Ok(rules) = split_sections(&content).and_then(|(_, rules, _)| strip_comments(rules)) else {
+        return;
+    };
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    for line in rules.lines() {
+        let file_name = line
+            .trim()
+            .strip_prefix("%include")
+            .and_then(|file_part| file_part.trim().strip_prefix('"')?.strip_suffix('"'));
+        let Some(file_name) = file_name else {
+            continue;
+        };
+        let included = base_dir.join(file_name);
+        if !includes.contains(&included) {
+            includes.push(included.clone());
+            collect_includes(&included, includes);
+        }
+    }
+}
+
+/// Parses a lexer specification, resolving `%include` paths against `base_dir`.
+fn parse_spec_in(
+    input: &str,
+    base_dir: &Path,
+    definitions: &[(String, String)],
+) -> Result<LexerSpec, Vec<ParseError>> {
     let mut spec = LexerSpec::new();
-    let mut token_names: HashMap<String, u32> = HashMap::new();
+    for (name, pattern) in definitions {
+        let pattern = parse_pattern_with_definitions(pattern, &spec.definitions).map_err(|error| {
+            vec![ParseError::new(format!("Invalid shared definition `{}`: {}", name, error.message))]
+        })?;
+        spec.definitions.retain(|(n, _)| n != name);
+        spec.definitions.push((name.clone(), pattern));
+    }
 
-    // Split by %%
-    let parts: Vec<&str> = input.split("%%").collect();
+    let (prefix_code, rules, suffix_code) = split_sections(input).map_err(|error| vec![error])?;
+    spec.prefix_code = prefix_code.trim().to_string();
+    spec.suffix_code = suffix_code.trim().to_string();
 
-    if parts.len() != 3 {
-        return Err(Box::new(ParseError::new(
-            "Input must have exactly 3 sections separated by %%".to_string(),
-        )));
+    // Line numbers of the rules count from the first line of the rules section
+    let first_line = input[..rules.as_ptr() as usize - input.as_ptr() as usize].matches('\n').count() + 1;
+    let locate = |error: ParseError, line: usize| {
+        match (line > 0).then(|| input.lines().nth(first_line + line - 2)).flatten() {
+            Some(source_line) => error.at(first_line + line - 1, source_line),
+            None => error,
+        }
+    };
+    let rules_lines =
+        expand_includes(rules, base_dir, &mut Vec::new()).map_err(|(error, line)| vec![locate(error, line)])?;
+    let errors = parse_rules(&mut spec, &rules_lines, input, first_line);
+    if errors.is_empty() {
+        Ok(spec)
+    } else {
+        Err(errors.into_iter().map(|(line, error)| locate(error, line)).collect())
     }
+}
 
-    spec.prefix_code = parts[0].trim().to_string();
-    spec.suffix_code = parts[2].trim().to_string();
+/// Per-rule state carried from one line of the rules section to the next.
+#[derive(Default)]
+struct RuleContext {
+    token_names: HashMap<String, u32>,
+    kind_counter: u32,
+    templates: Vec<(String, Vec<String>, String)>,
+    comment_lines: Vec<String>,
+}
 
-    // Parse rules section
-    let rules_section = parts[1].trim();
-    let mut kind_counter = 0u32;
+/// Parses the lines of a rules section into `spec`, numbered from 1.
+///
+/// The rules section starts at line `first_line` of `input`, which locates the rules and directives.
+/// Parsing goes on after a line with an error; the errors are returned with their line numbers.
+fn parse_rules(
+    spec: &mut LexerSpec,
+    rules_lines: &[(usize, String)],
+    input: &str,
+    first_line: usize,
+) -> Vec<(usize, ParseError)> {
+    let line_starts: Vec<usize> = std::iter::once(0).chain(input.match_indices('\n').map(|(i, _)| i + 1)).collect();
+    let mut context = RuleContext::default();
+    let mut errors = Vec::new();
+    let mut rule_lines = Vec::new();
+    for (line_number, line) in join_action_lines(rules_lines) {
+        let text = line.trim();
+        let start = line_starts[first_line + line_number - 2];
+        let source_line = input[start..].lines().next().unwrap_or("");
+        // Rules of an included file are located at their %include line
+        let included = source_line.trim_start().starts_with("%include") && !text.starts_with("%include");
+        let (whole, part) = if included { (source_line, source_line.trim()) } else { (line.as_str(), text) };
+        let span = sub_span(
+            SourceSpan { start, end: start + whole.len(), line: first_line + line_number - 1 },
+            whole,
+            part,
+        );
+        let rule_count = spec.rules.len();
+        match parse_rule_line(spec, &mut context, text, span) {
+            Ok(()) if spec.rules.len() == rule_count && text.starts_with('%') => {
+                let name = text.split(|c: char| c.is_whitespace() || c == '(' || c == '{').next().unwrap_or(text);
+                spec.directives.push(SpecDirective { name: name.to_string(), span });
+            }
+            Ok(()) => {}
+            Err(error) => errors.push((line_number, error)),
+        }
+        if included {
+            for rule in &mut spec.rules[rule_count..] {
+                rule.pattern_span = span;
+            }
+        }
+        rule_lines.resize(spec.rules.len(), line_number);
+    }
 
-    for line in rules_section.lines() {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with("//") {
-            continue;
+    // Rules producing a token that an earlier rule produces
+    if spec.options.unique_tokens {
+        let mut first_rules: HashMap<&str, usize> = HashMap::new();
+        for (i, rule) in spec.rules.iter().enumerate() {
+            if rule.action_code.is_some() || rule.name.is_empty() {
+                continue;
+            }
+            match first_rules.get(rule.name.as_str()) {
+                Some(&first) => errors.push((
+                    rule_lines[i],
+                    ParseError::new(format!(
+                        "Token {} is already produced by the rule on line {} (%option unique-tokens)",
+                        rule.name, spec.rules[first].span.line
+                    )),
+                )),
+                None => {
+                    first_rules.insert(&rule.name, i);
+                }
+            }
         }
+    } else if spec.options.merge_tokens {
+        merge_token_rules(spec);
+    }
+    errors.sort_by_key(|(line_number, _)| *line_number);
+    errors
+}
 
-        // Check for %token directive
-        if line.starts_with("%token") {
-            // Extract custom token names: %token TOKEN1 TOKEN2 TOKEN3
-            // or %token TOKEN1, TOKEN2, TOKEN3
-            let tokens_part = line[6..].trim(); // Remove "%token"
-            
-            // Split by whitespace and/or commas
-            let token_names_list: Vec<String> = tokens_part
-                .split(|c: char| c.is_whitespace() || c == ',')
-                .filter(|s| !s.is_empty())
-                .map(|s| s.to_string())
-                .collect();
-            
-            spec.custom_tokens.extend(token_names_list);
+/// Merges the rules producing the same token into the first of them as a choice of
+/// their patterns (%option merge-tokens).
+///
+/// Only rules matching under the same conditions are merged.
+fn merge_token_rules(spec: &mut LexerSpec) {
+    let mergeable = |rule: &LexerRule| {
+        rule.action_code.is_none()
+            && !rule.skip
+            && !rule.name.is_empty()
+            && rule.trailing_context.is_none()
+            && !matches!(rule.pattern, RulePattern::Eof | RulePattern::Balanced(..) | RulePattern::Heredoc(_))
+    };
+    let mut rules: Vec<LexerRule> = Vec::new();
+    for rule in std::mem::take(&mut spec.rules) {
+        let earlier = rules.iter_mut().find(|earlier| {
+            mergeable(earlier)
+                && mergeable(&rule)
+                && earlier.name == rule.name
+                && earlier.states == rule.states
+                && earlier.context == rule.context
+                && earlier.channel == rule.channel
+                && earlier.line_start == rule.line_start
+                && earlier.cfg == rule.cfg
+        });
+        let Some(earlier) = earlier else {
+            rules.push(rule);
             continue;
+        };
+        let mut alternatives = match std::mem::replace(&mut earlier.pattern, RulePattern::AnyChar) {
+            RulePattern::Choice(alternatives) => alternatives,
+            pattern => vec![pattern],
+        };
+        match rule.pattern {
+            RulePattern::Choice(more) => alternatives.extend(more),
+            pattern => alternatives.push(pattern),
         }
+        earlier.pattern = RulePattern::Choice(alternatives);
+        if earlier.doc.is_none() {
+            earlier.doc = rule.doc;
+        }
+    }
+    spec.rules = rules;
+}
 
-        // Parse different rule formats
-        if line.starts_with('%') {
-            // Context-dependent rule: %<CONTEXT_TOKEN> <pattern> -> <TOKEN_NAME>
-            if let Some(arrow_pos) = line.find("->") {
-                let left_part = line[1..arrow_pos].trim(); // Remove '%' and get left part
-                let token_name = line[arrow_pos + 2..].trim().to_string();
-
-                // Split left part to get context token and pattern
-                let parts: Vec<&str> = left_part.splitn(2, ' ').collect();
-                if parts.len() == 2 {
-                    let context_token = parts[0].trim().to_string();
-                    if !token_names.contains_key(&context_token) {
-                        return Err(Box::new(ParseError::new(format!(
-                            "Unknown context token '{}' in rule: {}",
-                            context_token, line
-                        ))));
-                    }
-                    let pattern_str = parts[1].trim();
-                    let pattern = parse_pattern(pattern_str)?;
-                    spec.rules.push(LexerRule::new_with_context(
-                        pattern,
-                        kind_counter,
-                        token_name,
-                        context_token,
-                    ));
-                } else {
-                    return Err(Box::new(ParseError::new(format!(
-                        "Invalid context rule format: {}",
-                        line
-                    ))));
+/// Returns the span of `part`, a slice of `text` whose span is `span`.
+///
+/// `span` itself is returned if `part` is not a slice of `text`.
+fn sub_span(span: SourceSpan, text: &str, part: &str) -> SourceSpan {
+    let offset = (part.as_ptr() as usize).wrapping_sub(text.as_ptr() as usize);
+    if offset > text.len() || part.len() > text.len() - offset {
+        return span;
+    }
+    SourceSpan {
+        start: span.start + offset,
+        end: span.start + offset + part.len(),
+        line: span.line + text[..offset].matches('\n').count(),
+    }
+}
+
+/// Parses one logical line of the rules section, located at `span`, into `spec`.
+fn parse_rule_line(
+    spec: &mut LexerSpec,
+    context: &mut RuleContext,
+    line: &str,
+    span: SourceSpan,
+) -> Result<(), ParseError> {
+    let kind_counter = context.kind_counter;
+    let RuleContext { token_names, templates, comment_lines, .. } = context;
+    if let Some(comment) = line.strip_prefix("//") {
+        // Comment lines right above a rule describe its token
+        comment_lines.push(comment.trim_start_matches('/').trim().to_string());
+        return Ok(());
+    }
+    if line.is_empty() {
+        comment_lines.clear();
+        return Ok(());
+    }
+    let comment_doc = (!comment_lines.is_empty()).then(|| std::mem::take(comment_lines).join("\n"));
+
+    // Check for %token directive
+    if let Some(tokens_part) = line.strip_prefix("%token") {
+        // Extract custom token names: %token TOKEN1 TOKEN2 TOKEN3
+        // or %token TOKEN1, TOKEN2, TOKEN3
+        for name in split_names(tokens_part) {
+            spec.token_declarations.push((spec.rules.len(), name.clone()));
+            spec.custom_tokens.push(name);
+        }
+        return Ok(());
+    }
+
+    // Check for %trivia directive: %trivia WHITESPACE COMMENT NEWLINE
+    if let Some(trivia_part) = line.strip_prefix("%trivia") {
+        let names = split_names(trivia_part);
+        if names.is_empty() || !names.iter().all(|name| is_identifier(name)) {
+            return Err(ParseError::new(format!(
+                "%trivia expects token names: {}",
+                line
+            )));
+        }
+        for name in names {
+            if !spec.trivia_kinds.contains(&name) {
+                spec.trivia_kinds.push(name);
+            }
+        }
+        return Ok(());
+    }
+
+    // Check for %style directive: %style COMMENT dim green
+    if let Some(style_part) = line.strip_prefix("%style") {
+        let parts: Vec<&str> = style_part.split_whitespace().collect();
+        if parts.len() < 2 || !is_identifier(parts[0]) || !parts[1..].iter().all(|word| style_code(word).is_some()) {
+            return Err(ParseError::new(format!(
+                "%style expects a token name and styles such as bold or bright-blue: {}",
+                line
+            )));
+        }
+        spec.styles.retain(|(name, _)| name != parts[0]);
+        spec.styles.push((parts[0].to_string(), parts[1..].join(" ")));
+        return Ok(());
+    }
+
+    // Check for %error directive: %error LEX_ERROR
+    if let Some(error_part) = line.strip_prefix("%error") {
+        let names = split_names(error_part);
+        if names.len() != 1 || !is_identifier(&names[0]) {
+            return Err(ParseError::new(format!(
+                "%error expects a single token name: {}",
+                line
+            )));
+        }
+        spec.token_declarations.push((spec.rules.len(), names[0].clone()));
+        spec.error_token = names.into_iter().next();
+        return Ok(());
+    }
+
+    // Check for %klex directive: %klex 0.1
+    if let Some(version) = line.strip_prefix("%klex") {
+        let Some(required) = parse_spec_version(version) else {
+            return Err(ParseError::new(format!(
+                "%klex expects a version such as 0.1: {}",
+                line
+            )));
+        };
+        if !supports_spec_version(version) {
+            let (major, minor) = supported_spec_version();
+            return Err(ParseError::new(format!(
+                "This spec requires klex {}.{}, but this klex supports spec version {}.{}",
+                required.0, required.1, major, minor
+            )));
+        }
+        return Ok(());
+    }
+
+    // Check for %prefix directive: %prefix Json
+    if let Some(prefix_part) = line.strip_prefix("%prefix") {
+        let names = split_names(prefix_part);
+        if names.len() != 1 || !is_identifier(&names[0]) {
+            return Err(ParseError::new(format!(
+                "%prefix expects a single identifier: {}",
+                line
+            )));
+        }
+        spec.name_prefix = names.into_iter().next();
+        return Ok(());
+    }
+
+    // Check for %test directive: %test "1+2" => NUMBER PLUS NUMBER
+    if let Some(test_part) = line.strip_prefix("%test") {
+        let test = parse_test(test_part).ok_or_else(|| {
+            ParseError::new(format!("%test expects \"input\" => TOKEN_NAME...: {}", line))
+        })?;
+        spec.tests.push((unescape_literal(&test.0)?, test.1));
+        return Ok(());
+    }
+
+    // Check for %keyword directive: %keyword if else while self=SelfKeyword
+    if let Some(keywords_part) = line.strip_prefix("%keyword") {
+        for keyword in split_names(keywords_part) {
+            let (word, token_name) = match keyword.split_once('=') {
+                Some((word, token_name)) => (word.to_string(), token_name.to_string()),
+                None => {
+                    let mut chars = keyword.chars();
+                    let first = chars.next().map(|c| c.to_uppercase().collect::<String>());
+                    (keyword.clone(), first.unwrap_or_default() + chars.as_str())
                 }
+            };
+            if !is_identifier(&word) || !is_identifier(&token_name) || token_name == "Self" {
+                return Err(ParseError::new(format!(
+                    "Invalid keyword '{}' (use word=TOKEN_NAME to name its token): {}",
+                    keyword, line
+                )));
+            }
+            spec.keywords.retain(|(w, _)| *w != word);
+            spec.token_declarations.push((spec.rules.len(), token_name.clone()));
+            spec.keywords.push((word, token_name));
+        }
+        return Ok(());
+    }
+
+    // Check for %type directive: %type NUMBER i64
+    if let Some(type_part) = line.strip_prefix("%type") {
+        let parts: Vec<&str> = type_part.split_whitespace().collect();
+        let is_type_path = |t: &str| t.split("::").all(is_identifier);
+        if parts.len() != 2 || !is_identifier(parts[0]) || !is_type_path(parts[1]) {
+            return Err(ParseError::new(format!(
+                "%type expects a token name and a type: {}",
+                line
+            )));
+        }
+        spec.token_types.retain(|(name, _)| name != parts[0]);
+        spec.token_types.push((parts[0].to_string(), parts[1].to_string()));
+        return Ok(());
+    }
+
+    // Check for %option directive: %option longest-match skip-whitespace tab-width 4
+    if let Some(options_part) = line.strip_prefix("%option") {
+        let mut names = split_names(options_part).into_iter();
+        while let Some(option) = names.next() {
+            if let Some((option, value)) = option.split_once('=') {
+                spec.options.set_value(option, value)?;
+            } else if LexerOptions::takes_value(&option) {
+                let value = names.next().ok_or_else(|| {
+                    ParseError::new(format!("%option {} expects a value", option))
+                })?;
+                spec.options.set_value(&option, &value)?;
             } else {
-                return Err(Box::new(ParseError::new(format!(
-                    "Context rule must have -> operator: {}",
+                spec.options.set(&option)?;
+            }
+        }
+        return Ok(());
+    }
+
+    // Check for %state_struct directive: %state_struct { paren_depth: usize, in_fstring: bool }
+    if let Some(struct_part) = line.strip_prefix("%state_struct") {
+        let fields = parse_state_struct(struct_part).ok_or_else(|| {
+            ParseError::new(format!("%state_struct expects fields as {{ name: Type, ... }}: {}", line))
+        })?;
+        for (name, field_type) in fields {
+            spec.state_fields.retain(|(field, _)| *field != name);
+            spec.state_fields.push((name, field_type));
+        }
+        return Ok(());
+    }
+
+    // Check for %state / %xstate directives: %state STATE1 STATE2
+    let (states_part, exclusive) = if let Some(rest) = line.strip_prefix("%xstate") {
+        (Some(rest), true)
+    } else {
+        (line.strip_prefix("%state"), false)
+    };
+    if let Some(states_part) = states_part {
+        for state in split_names(states_part) {
+            if is_initial_state(&state) || spec.states.contains(&state) {
+                continue;
+            }
+            if exclusive {
+                spec.exclusive_states.push(state.clone());
+            }
+            spec.states.push(state);
+        }
+        return Ok(());
+    }
+
+    // %include lines are expanded by expand_includes
+    if line.starts_with("%include") {
+        return Ok(());
+    }
+
+    // Check for %template directive: %template quoted(q) = q [^q]* q
+    if let Some(template_part) = line.strip_prefix("%template") {
+        let template = parse_template(template_part.trim()).ok_or_else(|| {
+            ParseError::new(format!("%template expects NAME(PARAMS) = pattern: {}", line))
+        })?;
+        templates.retain(|(name, _, _)| *name != template.0);
+        templates.push(template);
+        return Ok(());
+    }
+
+    // Instantiate template references: {quoted(')}
+    let source = line;
+    let expanded = expand_templates(line, templates)?;
+    let line = expanded.as_str();
+
+    // Named definition: NAME = pattern
+    if let Some((name, pattern_str)) = split_definition(line) {
+        let pattern = parse_pattern_with_definitions(pattern_str, &spec.definitions)?;
+        spec.definitions.retain(|(n, _)| n != name);
+        spec.definitions.push((name.to_string(), pattern));
+        return Ok(());
+    }
+
+    // Conditional compilation: %cfg(feature = "extended") rule
+    let (rule_cfg, line) = match split_cfg_prefix(line)? {
+        Some((predicate, rest)) => (Some(predicate), rest),
+        None => (None, line),
+    };
+
+    // Start condition prefix: <STATE> rule
+    let (rule_states, line) = match split_state_prefix(line) {
+        Some((states, rest)) => {
+            for state in &states {
+                if !is_initial_state(state) && !spec.states.contains(state) {
+                    return Err(ParseError::new(format!(
+                        "Unknown state '{}' in rule: {}",
+                        state, line
+                    )));
+                }
+            }
+            (states, rest)
+        }
+        None => (Vec::new(), line),
+    };
+
+    // Line anchor: ^pattern only matches at the start of a line
+    let (line_start, line) = match line.strip_prefix('^') {
+        Some(rest) if !rest.is_empty() && !rest.trim_start().starts_with("->") => (true, rest),
+        _ => (false, line),
+    };
+
+    // Parse different rule formats
+    let trailing_context;
+    let pattern_text;
+    let mut rule_channel = None;
+    let mut rule_description = None;
+    if line.starts_with('%') && !is_pattern_directive(line) {
+        // Context-dependent rule: %<CONTEXT_TOKEN> <pattern> -> <TOKEN_NAME>
+        if let Some(arrow_pos) = find_arrow(line) {
+            let left_part = line[1..arrow_pos].trim(); // Remove '%' and get left part
+            let (right_part, description) = split_description(line[arrow_pos + 2..].trim())?;
+            rule_description = description;
+            let (token_name, channel) = split_channel(right_part)?;
+            rule_channel = channel;
+            if token_name == "%skip" {
+                return Err(ParseError::new(format!(
+                    "%skip is not supported in context rules: {}",
                     line
-                ))));
-            }
-        } else if let Some(arrow_pos) = line.find("->") {
-            // Regular rule: pattern -> name or pattern -> { action_code }
-            let pattern_str = line[..arrow_pos].trim();
-            let pattern = parse_pattern(pattern_str)?;
-            let right_part = line[arrow_pos + 2..].trim();
-
-            if right_part.starts_with('{') && right_part.ends_with('}') {
-                // Action rule: pattern -> { action_code }
-                let action_code = right_part[1..right_part.len() - 1].trim().to_string();
-                let mut rule = LexerRule::new_with_action(pattern, action_code);
-                rule.kind = kind_counter; // Set the kind for action rules too
+                )));
+            }
+
+            // Split left part to get the context tokens and pattern
+            if let Some((rule_context, pattern_str)) = split_context(left_part) {
+                let mut kinds = rule_context.iter().flat_map(|position| &position.kinds);
+                if let Some(unknown) = kinds.find(|kind| !token_names.contains_key(*kind)) {
+                    return Err(ParseError::new(format!(
+                        "Unknown context token '{}' in rule: {}",
+                        unknown, line
+                    )));
+                }
+                if rule_context.iter().rev().skip(1).any(|position| position.text.is_some()) {
+                    return Err(ParseError::new(format!(
+                        "Only the last token of a context can be checked for its text: {}",
+                        line
+                    )));
+                }
+                let (pattern, trailing) = parse_rule_pattern(pattern_str, &spec.definitions)?;
+                trailing_context = trailing;
+                pattern_text = pattern_str;
+                let mut rule = LexerRule::new(pattern, kind_counter, token_name);
+                rule.context = rule_context;
                 spec.rules.push(rule);
             } else {
-                // Token rule: pattern -> TOKEN_NAME
-                let mut name = right_part.to_string();
-                // Special case: _ is treated as Whitespace
-                if name == "_" {
-                    name = "Whitespace".to_string();
-                }
-                spec.rules.push(LexerRule::new(pattern, kind_counter, name));
+                return Err(ParseError::new(format!(
+                    "Invalid context rule format: {}",
+                    line
+                )));
             }
         } else {
-            // Use the pattern as the name
-            let pattern_str = line;
-            let pattern = parse_pattern(pattern_str)?;
-            let name = format!("TOKEN_{}", kind_counter);
-            spec.rules.push(LexerRule::new(pattern, kind_counter, name));
+            return Err(ParseError::new(format!(
+                "Context rule must have -> operator: {}",
+                line
+            )));
         }
+    } else if let Some(arrow_pos) = find_arrow(line) {
+        // Regular rule: pattern -> name or pattern -> { action_code }
+        let pattern_str = line[..arrow_pos].trim();
+        let (pattern, trailing) = parse_rule_pattern(pattern_str, &spec.definitions)?;
+        trailing_context = trailing;
+        pattern_text = pattern_str;
+        let right_part = line[arrow_pos + 2..].trim();
 
-        if let Some(rule) = spec.rules.last() {
-            if rule.action_code.is_none() && !rule.name.is_empty() {
-                token_names.insert(rule.name.clone(), rule.kind);
+        if right_part.starts_with('{') && brace_depth(right_part) != 0 {
+            return Err(ParseError::new(format!(
+                "Unterminated action block in rule: {}",
+                line
+            )));
+        } else if right_part.starts_with('{') && right_part.ends_with('}') {
+            // Action rule: pattern -> { action_code }
+            let action_code = right_part[1..right_part.len() - 1].trim().to_string();
+            let mut rule = LexerRule::new_with_action(pattern, action_code);
+            rule.kind = kind_counter; // Set the kind for action rules too
+            spec.rules.push(rule);
+        } else if right_part == "%skip" {
+            // Skip rule: pattern -> %skip
+            spec.rules.push(LexerRule::new_skip(pattern, kind_counter));
+        } else {
+            // Token rule: pattern -> TOKEN_NAME (optionally followed by @channel and "description")
+            let (right_part, description) = split_description(right_part)?;
+            rule_description = description;
+            let (mut name, channel) = split_channel(right_part)?;
+            rule_channel = channel;
+            // Special case: _ is treated as Whitespace
+            if name == "_" {
+                name = "Whitespace".to_string();
             }
+            spec.rules.push(LexerRule::new(pattern, kind_counter, name));
         }
+    } else {
+        // Use the pattern as the name
+        let pattern_str = line;
+        let (pattern, trailing) = parse_rule_pattern(pattern_str, &spec.definitions)?;
+        trailing_context = trailing;
+        pattern_text = pattern_str;
+        let name = format!("TOKEN_{}", kind_counter);
+        spec.rules.push(LexerRule::new(pattern, kind_counter, name));
+    }
 
-        kind_counter += 1;
+    if let Some(rule) = spec.rules.last_mut() {
+        rule.states = rule_states;
+        rule.trailing_context = trailing_context;
+        rule.line_start = line_start;
+        rule.cfg = rule_cfg;
+        rule.doc = rule_description.or(comment_doc);
+        rule.span = span;
+        // Template references change the text, so only the rule itself can be located then
+        rule.pattern_span = if expanded == source {
+            sub_span(span, &expanded, pattern_text)
+        } else {
+            span
+        };
+        if let Some(channel) = rule_channel.filter(|c| !is_default_channel(c)) {
+            if !spec.channels.contains(&channel) {
+                spec.channels.push(channel.clone());
+            }
+            rule.channel = Some(channel);
+        }
+        let is_eof_rule = matches!(rule.pattern, RulePattern::Eof);
+        if is_eof_rule && (rule.skip || !rule.context.is_empty() || rule.trailing_context.is_some()) {
+            return Err(ParseError::new(format!(
+                "<<EOF>> rules must be of the form <<EOF>> -> TOKEN or <<EOF>> -> {{ action }}: {}",
+                line
+            )));
+        }
+        if rule.action_code.is_none() && !rule.name.is_empty() {
+            token_names.insert(rule.name.clone(), rule.kind);
+        }
     }
 
-    Ok(spec)
+    context.kind_counter += 1;
+    Ok(())
 }
@@ -0,0 +1,182 @@
+//! Layout pass for generated code.
+//!
+//! The template, the generated snippets and the user's code sections are indented
+//! in different ways. This pass re-indents everything by bracket nesting with four
+//! spaces per level, the way rustfmt lays out blocks, so the emitted lexer reads
+//! like hand-written code. It only touches leading whitespace, trailing whitespace
+//! and runs of blank lines; lines that start inside a string literal or a block
+//! comment are kept as they are.
+
+/// Spaces per indentation level.
+const INDENT: &str = "    ";
+
+/// Where the scanner is at the end of a line.
+#[derive(Clone, Copy, PartialEq)]
+enum Context {
+    /// Ordinary code
+    Code,
+    /// Inside a `"..."` string literal
+    Str,
+    /// Inside a raw string literal closed by `"` and this many `#`
+    RawStr(usize),
+    /// Inside a block comment nested this deep
+    Comment(usize),
+}
+
+/// Re-indents generated code by bracket nesting.
+pub(crate) fn pretty_print(code: &str) -> String {
+    let mut output = String::with_capacity(code.len());
+    // Indentation level of the line that opened each unclosed bracket
+    let mut openers: Vec<usize> = Vec::new();
+    let mut context = Context::Code;
+    let mut blank_run = false;
+    // Whether the last line closed a bracket, after which a method chain continues unindented
+    let mut after_closer = false;
+
+    for line in code.lines() {
+        if context != Context::Code {
+            // The line continues a literal or comment, so its whitespace is content
+            output.push_str(line);
+            output.push('\n');
+            context = scan_line(line, context, &mut openers, 0);
+            blank_run = false;
+            continue;
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            // Collapse runs of blank lines and drop those right after an opening bracket
+            if !blank_run && !output.ends_with("{\n") && !output.is_empty() {
+                output.push('\n');
+            }
+            blank_run = true;
+            continue;
+        }
+        if blank_run && trimmed.starts_with('}') && output.ends_with("\n\n") {
+            // No blank line before a closing bracket either
+            output.pop();
+        }
+        blank_run = false;
+
+        let closers = trimmed.chars().take_while(|c| matches!(c, '}' | ')' | ']')).count();
+        let mut level = if closers > 0 {
+            openers
+                .get(openers.len().saturating_sub(closers))
+                .copied()
+                .unwrap_or(0)
+        } else {
+            openers.last().map_or(0, |level| level + 1)
+        };
+        if closers == 0 && is_continuation(trimmed) && !(after_closer && trimmed.starts_with('.')) {
+            level += 1;
+        }
+        for _ in 0..level {
+            output.push_str(INDENT);
+        }
+        let line = if context_ends_in_literal(trimmed) { line.trim_start() } else { trimmed };
+        output.push_str(line);
+        output.push('\n');
+        context = scan_line(trimmed, Context::Code, &mut openers, level);
+        after_closer = closers > 0;
+    }
+    while output.ends_with("\n\n") {
+        output.pop();
+    }
+    output
+}
+
+/// Lines that continue the expression of the previous line, such as method chains.
+fn is_continuation(line: &str) -> bool {
+    (line.starts_with('.') && !line.starts_with(".."))
+        || line.starts_with("&&")
+        || line.starts_with("||")
+        || line.starts_with('?')
+}
+
+/// Whether a line ends inside a string literal, whose trailing whitespace must stay.
+fn context_ends_in_literal(line: &str) -> bool {
+    let mut openers = Vec::new();
+    matches!(scan_line(line, Context::Code, &mut openers, 0), Context::Str | Context::RawStr(_))
+}
+
+/// Scans one line starting in `context`, pushing and popping brackets outside
+/// literals and comments, and returns the context at the end of the line.
+fn scan_line(line: &str, mut context: Context, openers: &mut Vec<usize>, level: usize) -> Context {
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match context {
+            Context::Str => {
+                if c == '\\' {
+                    i += 1;
+                } else if c == '"' {
+                    context = Context::Code;
+                }
+            }
+            Context::RawStr(hashes) => {
+                if c == '"' && chars[i + 1..].iter().take(hashes).filter(|&&h| h == '#').count() == hashes {
+                    i += hashes;
+                    context = Context::Code;
+                }
+            }
+            Context::Comment(depth) => {
+                if c == '*' && chars.get(i + 1) == Some(&'/') {
+                    i += 1;
+                    context = if depth == 1 { Context::Code } else { Context::Comment(depth - 1) };
+                } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+                    i += 1;
+                    context = Context::Comment(depth + 1);
+                }
+            }
+            Context::Code => match c {
+                '/' if chars.get(i + 1) == Some(&'/') => break,
+                '/' if chars.get(i + 1) == Some(&'*') => {
+                    i += 1;
+                    context = Context::Comment(1);
+                }
+                '"' => context = Context::Str,
+                'r' if !follows_identifier(&chars, i) => {
+                    let hashes = chars[i + 1..].iter().take_while(|&&h| h == '#').count();
+                    if chars.get(i + 1 + hashes) == Some(&'"') {
+                        i += 1 + hashes;
+                        context = Context::RawStr(hashes);
+                    }
+                }
+                '\'' => i = skip_char_literal(&chars, i),
+                '{' | '(' | '[' => openers.push(level),
+                '}' | ')' | ']' => {
+                    openers.pop();
+                }
+                _ => {}
+            },
+        }
+        i += 1;
+    }
+    context
+}
+
+/// Whether the character at `i` continues an identifier, so `r` is not a raw string prefix.
+fn follows_identifier(chars: &[char], i: usize) -> bool {
+    i > 0 && {
+        let prev = chars[i - 1];
+        // `br"..."` is still a raw string
+        (prev.is_alphanumeric() || prev == '_') && !(prev == 'b' && (i < 2 || !chars[i - 2].is_alphanumeric()))
+    }
+}
+
+/// Skips a character literal starting at the quote at `i` and returns the index
+/// of its closing quote; a lifetime leaves the index unchanged.
+fn skip_char_literal(chars: &[char], i: usize) -> usize {
+    if chars.get(i + 1) == Some(&'\\') {
+        // Escaped character such as '\n', '\'' or '\u{7f}'
+        let mut j = i + 3;
+        while j < chars.len() && chars[j] != '\'' {
+            j += 1;
+        }
+        return j;
+    }
+    if chars.get(i + 2) == Some(&'\'') {
+        return i + 2;
+    }
+    i
+}
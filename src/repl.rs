@@ -0,0 +1,218 @@
+//! Interactive exploration of a spec (`klex repl`).
+//!
+//! `run_repl` reads sample lines and prints the tokens the rules of a spec read from
+//! each of them with [`LexerSpec::interpret`]. Commands starting with `:` switch rules
+//! off and on, to see which rule produces a token, and try a candidate pattern on the
+//! following samples before it is added to the spec.
+
+use crate::generator::{describe_pattern, extract_action_kind, rule_to_regex};
+use crate::parser::{parse_rule_pattern, LexerRule, LexerSpec};
+use regex::Regex;
+use std::io::{self, BufRead, Write};
+
+/// Help printed by `:help`.
+const HELP: &str = "\
+Type a sample line to see its tokens. Commands:
+  :rules          list the rules with their numbers
+  :off N...       switch rules off (all for every rule)
+  :on N...        switch rules back on (all for every rule)
+  :try PATTERN    also show where PATTERN matches in the next samples
+  :try            stop trying the pattern
+  :help           show this help
+  :quit           leave (end of input works too)
+A sample starting with `:` is typed with `::`.";
+
+/// A pattern tried on the samples with `:try`.
+struct Candidate {
+    text: String,
+    regex: Regex,
+    /// True if the token is capture group 1 (trailing context)
+    has_context: bool,
+}
+
+/// State of a session: every rule of the spec and the rules switched on.
+struct Session {
+    spec: LexerSpec,
+    rules: Vec<LexerRule>,
+    enabled: Vec<bool>,
+    candidate: Option<Candidate>,
+}
+
+impl Session {
+    /// Describes a rule for `:rules`: `[0-9]+ -> NUMBER`, `/\* -> { COMMENT }` or `\s+ -> %skip`.
+    fn describe_rule(rule: &LexerRule) -> String {
+        let target = match &rule.action_code {
+            _ if rule.skip => "%skip".to_string(),
+            Some(action_code) => format!("{{ {} }}", extract_action_kind(action_code).as_deref().unwrap_or("...")),
+            None => rule.name.clone(),
+        };
+        let states = if rule.states.is_empty() { String::new() } else { format!("<{}> ", rule.states.join(",")) };
+        format!("{}{} -> {}", states, describe_pattern(&rule.pattern), target)
+    }
+
+    /// Switches the rules numbered in `args` (1-based, or `all`) on or off.
+    fn switch_rules(&mut self, args: &str, on: bool) -> Result<(), String> {
+        let mut numbers = Vec::new();
+        for arg in args.split_whitespace() {
+            if arg == "all" {
+                numbers.extend(1..=self.rules.len());
+                continue;
+            }
+            match arg.parse::<usize>() {
+                Ok(number) if (1..=self.rules.len()).contains(&number) => numbers.push(number),
+                _ => return Err(format!("no rule {}; :rules lists the rules 1 to {}", arg, self.rules.len())),
+            }
+        }
+        if numbers.is_empty() {
+            return Err("expected rule numbers or all".to_string());
+        }
+        for number in numbers {
+            self.enabled[number - 1] = on;
+        }
+        self.spec.rules = self
+            .rules
+            .iter()
+            .zip(&self.enabled)
+            .filter(|(_, enabled)| **enabled)
+            .map(|(rule, _)| rule.clone())
+            .collect();
+        Ok(())
+    }
+
+    /// Sets the candidate pattern of `:try`, or clears it for an empty pattern.
+    fn try_pattern(&mut self, pattern: &str) -> Result<(), String> {
+        if pattern.is_empty() {
+            self.candidate = None;
+            return Ok(());
+        }
+        let (pattern_rule, trailing_context) =
+            parse_rule_pattern(pattern, &self.spec.definitions).map_err(|e| e.message)?;
+        let mut rule = LexerRule::new(pattern_rule, 0, "Candidate".to_string());
+        rule.trailing_context = trailing_context;
+        let regex = Regex::new(&rule_to_regex(&rule)).map_err(|e| {
+            let error = e.to_string();
+            format!("invalid regex: {}", error.lines().last().unwrap_or_default().trim().trim_start_matches("error: "))
+        })?;
+        self.candidate = Some(Candidate { text: pattern.to_string(), regex, has_context: rule.trailing_context.is_some() });
+        Ok(())
+    }
+
+    /// Runs a command line (without its `:`) and writes its output.
+    /// Returns false for `:quit`.
+    fn command<W: Write>(&mut self, line: &str, output: &mut W) -> io::Result<bool> {
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let result = match name {
+            "q" | "quit" => return Ok(false),
+            "h" | "help" => {
+                writeln!(output, "{}", HELP)?;
+                Ok(())
+            }
+            "rules" => {
+                for (i, rule) in self.rules.iter().enumerate() {
+                    let state = if self.enabled[i] { "on " } else { "off" };
+                    writeln!(output, "{:>3} {}  {}", i + 1, state, Self::describe_rule(rule))?;
+                }
+                Ok(())
+            }
+            "on" | "off" => self.switch_rules(args, name == "on"),
+            "try" => self.try_pattern(args.trim()),
+            _ => Err(format!("unknown command :{}; :help lists the commands", name)),
+        };
+        if let Err(message) = result {
+            writeln!(output, "error: {}", message)?;
+        }
+        Ok(true)
+    }
+
+    /// Writes the tokens of a sample line, and where the candidate pattern matches in it.
+    fn sample<W: Write>(&self, line: &str, output: &mut W) -> io::Result<()> {
+        let tokens = match self.spec.interpret(line) {
+            Ok(tokens) => tokens,
+            Err(e) => return writeln!(output, "error: {}", e.message),
+        };
+        let kind_width = tokens.iter().map(|token| token.kind.len()).max().unwrap_or(0);
+        for token in &tokens {
+            write!(output, "{:>4}  {:<kind_width$}  {:?}", token.col, token.kind, token.text)?;
+            if let Some(value) = &token.value {
+                write!(output, " = {}", value)?;
+            }
+            if let Some(channel) = &token.channel {
+                write!(output, " ({})", channel)?;
+            }
+            writeln!(output)?;
+        }
+
+        if let Some(candidate) = &self.candidate {
+            let group = if candidate.has_context { 1 } else { 0 };
+            let matches: Vec<String> = candidate
+                .regex
+                .captures_iter(line)
+                .filter_map(|caps| caps.get(group))
+                .filter(|m| !m.is_empty())
+                .map(|m| format!("{:?} at {}", m.as_str(), line[..m.start()].chars().count() + 1))
+                .collect();
+            match matches.as_slice() {
+                [] => writeln!(output, "  try {}: no match", candidate.text)?,
+                _ => writeln!(output, "  try {}: {}", candidate.text, matches.join(", "))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs an interactive session on a spec, reading sample lines and commands from
+/// `input` and writing the tokens and answers to `output`.
+///
+/// Each sample line is tokenized with the rules switched on, one token per line with
+/// its column, kind and text. `:rules` lists the rules, `:off N` and `:on N` switch
+/// them, and `:try PATTERN` shows where a pattern in spec syntax (e.g. `[0-9]+\.[0-9]+`
+/// or `{DIGIT}+`) matches in the following samples. With `prompt`, a `klex> ` prompt is
+/// written before each line. The session ends with `:quit` or at the end of input.
+///
+/// # Examples
+///
+/// ```rust
+/// use klex::{parse_spec, run_repl};
+///
+/// let spec = parse_spec("[0-9]+ -> NUMBER\n[a-z]+ -> WORD\n[ ]+ -> %skip\n").unwrap();
+/// let input = "ab 12\n:off 1\n:try [0-9]+\nab 12\n";
+/// let mut output = Vec::new();
+/// run_repl(spec, input.as_bytes(), &mut output, false).unwrap();
+/// let output = String::from_utf8(output).unwrap();
+/// assert!(output.starts_with("   1  WORD    \"ab\"\n   4  NUMBER  \"12\"\n"));
+/// assert!(output.ends_with("   5  Unknown  \"2\"\n  try [0-9]+: \"12\" at 4\n"));
+/// ```
+pub fn run_repl<R: BufRead, W: Write>(spec: LexerSpec, input: R, output: &mut W, prompt: bool) -> io::Result<()> {
+    let rules = spec.rules.clone();
+    let enabled = vec![true; rules.len()];
+    let mut session = Session { spec, rules, enabled, candidate: None };
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(output, "klex> ")?;
+            output.flush()?;
+        }
+        let Some(line) = lines.next() else {
+            // End the prompt line at the end of input
+            if prompt {
+                writeln!(output)?;
+            }
+            break;
+        };
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.strip_prefix(':') {
+            Some(sample) if sample.starts_with(':') => session.sample(sample, output)?,
+            Some(command) => {
+                if !session.command(command.trim(), output)? {
+                    break;
+                }
+            }
+            None => session.sample(line, output)?,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! Statistics of lexer specifications.
+//!
+//! `spec_stats` sums up the performance shape of a spec: which kinds of patterns its
+//! rules use, how many of them the generated lexer matches with direct comparisons
+//! and how many fall back to a regex, and how large the automaton of the DFA
+//! backends gets.
+
+use crate::dfa::compile_tables;
+use crate::generator::{dfa_candidates, effective_backend, rule_literal, uses_regex, Backend, GeneratorOptions};
+use crate::parser::{LexerSpec, RulePattern};
+use std::fmt;
+
+/// Statistics of a spec, as printed by `klex stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecStats {
+    /// Number of rules
+    pub rules: usize,
+    /// Number of rules by pattern kind, such as `("string literal", 3)`, most used first
+    pub pattern_kinds: Vec<(&'static str, usize)>,
+    /// Rules matched with direct character and string comparisons
+    pub direct_rules: usize,
+    /// Rules that need a regular expression
+    pub regex_rules: usize,
+    /// Backend the lexer is generated with
+    pub backend: Backend,
+    /// Regex rules the DFA backends can compile into the automaton
+    pub dfa_rules: usize,
+    /// Number of states of the automaton, or None if no rule can be compiled
+    pub dfa_states: Option<usize>,
+    /// Number of byte classes of the automaton
+    pub dfa_classes: Option<usize>,
+    /// Longest fixed string a rule matches
+    pub longest_literal: Option<String>,
+    /// Number of start conditions besides `INITIAL`
+    pub start_conditions: usize,
+}
+
+/// Returns the kind of a pattern for the statistics.
+fn pattern_kind(pattern: &RulePattern) -> &'static str {
+    match pattern {
+        RulePattern::CharLiteral(_) | RulePattern::EscapedChar(_) => "char literal",
+        RulePattern::StringLiteral(_) => "string literal",
+        RulePattern::Regex(_) => "regex",
+        RulePattern::CharSet(_) => "char set",
+        RulePattern::CharRangeMatch1(..) | RulePattern::CharRangeMatch0(..) | RulePattern::CharRangeRepeat(..) => {
+            "char range"
+        }
+        RulePattern::NegatedCharSet1(_) | RulePattern::NegatedCharSet0(_) => "negated char set",
+        RulePattern::Choice(_) => "choice",
+        RulePattern::AnyChar | RulePattern::AnyCharPlus => "any char",
+        RulePattern::CaseInsensitive(_) => "case-insensitive",
+        RulePattern::Eof => "end of input",
+        RulePattern::Balanced(..) => "balanced",
+        RulePattern::Heredoc(_) => "heredoc",
+        RulePattern::Sequence(_) => "sequence",
+    }
+}
+
+/// Returns the name of a backend as written in `klex.toml`.
+fn backend_name(backend: Backend) -> &'static str {
+    match backend {
+        Backend::Regex => "regex",
+        Backend::Dfa => "dfa",
+        Backend::PrecompiledDfa => "precompiled-dfa",
+        Backend::RegexSet => "regex-set",
+    }
+}
+
+/// Collects the statistics of a spec.
+///
+/// The automaton is compiled whatever the backend, so its size can be estimated
+/// before switching to `--dfa`.
+///
+/// # Example
+///
+/// ```rust
+/// use klex::{parse_spec, spec_stats, GeneratorOptions};
+///
+/// let spec = parse_spec("\"if\" -> IF\n\"else\" -> ELSE\n[0-9]+ -> NUMBER\n/[a-z]\\w*/ -> IDENT\n").unwrap();
+/// let stats = spec_stats(&spec, &GeneratorOptions::default());
+/// assert_eq!(stats.pattern_kinds, [("string literal", 2), ("char range", 1), ("regex", 1)]);
+/// assert_eq!((stats.direct_rules, stats.regex_rules), (3, 1));
+/// assert_eq!(stats.longest_literal.as_deref(), Some("else"));
+/// ```
+pub fn spec_stats(spec: &LexerSpec, options: &GeneratorOptions) -> SpecStats {
+    let mut pattern_kinds: Vec<(&'static str, usize)> = Vec::new();
+    for rule in &spec.rules {
+        let kind = pattern_kind(&rule.pattern);
+        match pattern_kinds.iter_mut().find(|(name, _)| *name == kind) {
+            Some((_, count)) => *count += 1,
+            None => pattern_kinds.push((kind, 1)),
+        }
+    }
+    // Stable, so kinds used equally often stay in the order of the spec
+    pattern_kinds.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    let matched: Vec<_> = spec.rules.iter().filter(|rule| !matches!(rule.pattern, RulePattern::Eof)).collect();
+    let regex_rules = matched.iter().filter(|rule| uses_regex(rule)).count();
+    let candidates = dfa_candidates(spec);
+    let tables = compile_tables(&candidates);
+    let longest_literal = spec
+        .rules
+        .iter()
+        .filter_map(rule_literal)
+        .fold(None, |longest: Option<String>, literal| match longest {
+            Some(longest) if longest.chars().count() >= literal.chars().count() => Some(longest),
+            _ => Some(literal),
+        });
+
+    SpecStats {
+        rules: spec.rules.len(),
+        pattern_kinds,
+        direct_rules: matched.len() - regex_rules,
+        regex_rules,
+        backend: effective_backend(spec, options),
+        dfa_rules: candidates.len(),
+        dfa_states: tables.as_ref().map(|tables| tables.eoi.len()),
+        dfa_classes: tables.as_ref().map(|tables| tables.class_count),
+        longest_literal,
+        start_conditions: spec.states.len(),
+    }
+}
+
+/// Returns `count` followed by the singular or plural form of a noun.
+fn count_of(count: usize, singular: &str, plural: &str) -> String {
+    format!("{} {}", count, if count == 1 { singular } else { plural })
+}
+
+impl fmt::Display for SpecStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rules: {}", self.rules)?;
+        for (kind, count) in &self.pattern_kinds {
+            writeln!(f, "  {:<18} {}", kind, count)?;
+        }
+        writeln!(f, "matching: {} direct, {} regex", self.direct_rules, self.regex_rules)?;
+        writeln!(f, "backend: {}", backend_name(self.backend))?;
+        match (self.dfa_states, self.dfa_classes) {
+            (Some(states), Some(classes)) => writeln!(
+                f,
+                "automaton: {}, {}, {} for {}",
+                count_of(states, "state", "states"),
+                count_of(classes, "byte class", "byte classes"),
+                count_of(states * classes, "transition", "transitions"),
+                count_of(self.dfa_rules, "regex rule", "regex rules")
+            )?,
+            _ => writeln!(f, "automaton: none")?,
+        }
+        match &self.longest_literal {
+            Some(literal) => writeln!(f, "longest literal: {:?} ({})", literal, count_of(literal.chars().count(), "char", "chars"))?,
+            None => writeln!(f, "longest literal: none")?,
+        }
+        writeln!(f, "start conditions: {}", self.start_conditions)
+    }
+}
@@ -0,0 +1,300 @@
+//! Tests of the klex command line tool, run on the built binary.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A spec without problems.
+const CALC_SPEC: &str = "[0-9]+ -> NUMBER\n[a-z]+ -> WORD\n[ ]+ -> %skip\n";
+
+/// A spec with a lint warning (no whitespace rule) but no error.
+const WARN_SPEC: &str = "[0-9]+ -> NUMBER\n";
+
+/// A spec whose regex does not compile, which is an error of generation.
+const INVALID_REGEX_SPEC: &str = "[0-9]+ -> NUMBER\n/[a-z/ -> WORD\n[ ]+ -> %skip\n";
+
+/// A spec with a parse error on line 3.
+const BAD_SPEC: &str = "%%\n[0-9]+ -> NUMBER\n%bogus\n%%\n";
+
+/// Creates an empty scratch directory for a test, with the given files in it.
+fn scratch_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("klex-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (file, content) in files {
+        fs::write(dir.join(file), content).unwrap();
+    }
+    dir
+}
+
+/// Runs klex in `dir` with the arguments, writing `stdin` to its standard input.
+fn klex(dir: &Path, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_klex"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn test_generate_file() {
+    let dir = scratch_dir("generate", &[("calc.klex", CALC_SPEC)]);
+    let output = klex(&dir, &["calc.klex", "calc_lexer.rs"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read_to_string(dir.join("calc_lexer.rs")).unwrap().contains("NUMBER"));
+
+    let output = klex(&dir, &["missing.klex"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Cannot read missing.klex"));
+
+    // Errors of generation keep the lexer from being written
+    fs::write(dir.join("regex.klex"), INVALID_REGEX_SPEC).unwrap();
+    let output = klex(&dir, &["regex.klex", "regex_lexer.rs"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("error: rule `[a-z -> WORD` has an invalid regex"));
+    assert!(!dir.join("regex_lexer.rs").exists());
+}
+
+#[test]
+fn test_stdin_and_stdout() {
+    let dir = scratch_dir("stdin", &[]);
+    let output = klex(&dir, &["-", "-"], CALC_SPEC);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // Only the lexer goes to standard output
+    assert!(stdout(&output).contains("// Generated from: <stdin>"));
+    assert!(stdout(&output).contains("NUMBER"));
+    assert!(stderr(&output).contains("Lexer generated successfully: <stdout>"));
+
+    let output = klex(&dir, &["-", "-"], BAD_SPEC);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).is_empty());
+}
+
+#[test]
+fn test_check() {
+    let dir = scratch_dir("check", &[("calc.klex", CALC_SPEC), ("warn.klex", WARN_SPEC), ("bad.klex", BAD_SPEC)]);
+    let output = klex(&dir, &["--check", "calc.klex"], "");
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("calc.klex: ok"));
+    assert!(!dir.join("lexer.rs").exists());
+
+    // Warnings are printed without failing the check
+    let output = klex(&dir, &["--check", "warn.klex"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = klex(&dir, &["--check", "calc.klex", "bad.klex"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("line 3, column 1"));
+    assert!(stderr(&output).contains("1 error, 0 warnings"));
+
+    fs::write(dir.join("regex.klex"), INVALID_REGEX_SPEC).unwrap();
+    let output = klex(&dir, &["--check", "regex.klex"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("regex.klex:2: error[invalid-regex]: rule `[a-z -> WORD` has an invalid regex"));
+    assert!(stderr(&output).contains("1 error, 0 warnings"));
+
+    // The lints are checked too
+    fs::write(dir.join("shadow.klex"), "[a-z]+ -> IDENT\n\"if\" -> IF\n[ ]+ -> %skip\n").unwrap();
+    let output = klex(&dir, &["--check", "shadow.klex"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("shadow.klex:2: warning[shadowed-keyword]"));
+}
+
+#[test]
+fn test_message_format_json() {
+    let dir = scratch_dir("json-messages", &[("bad.klex", BAD_SPEC)]);
+    let output = klex(&dir, &["--check", "--message-format=json", "bad.klex"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stdout(&output).lines().next(),
+        Some(
+            r#"{"file":"bad.klex","line":3,"column":1,"severity":"error","code":"parse-error","message":"Context rule must have -> operator: %bogus"}"#
+        )
+    );
+}
+
+#[test]
+fn test_out_dir() {
+    let dir = scratch_dir("out-dir", &[("calc.klex", CALC_SPEC), ("warn.klex", WARN_SPEC), ("bad.klex", BAD_SPEC)]);
+    let output = klex(&dir, &["--out-dir", "out", "*n.klex", "calc.klex"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("2 of 2 lexers generated"));
+    assert!(dir.join("out/calc_lexer.rs").exists());
+    assert!(dir.join("out/warn_lexer.rs").exists());
+
+    let output = klex(&dir, &["--out-dir", "out", "calc.klex", "bad.klex"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!dir.join("out/bad_lexer.rs").exists());
+}
+
+#[test]
+fn test_emit() {
+    let dir = scratch_dir("emit", &[("calc.klex", CALC_SPEC)]);
+    let output = klex(&dir, &["--emit", "json", "calc.klex"], "");
+    assert!(output.status.success());
+    assert!(stdout(&output).contains(r#""name": "NUMBER""#));
+    assert!(!stdout(&output).contains(r#""span""#));
+
+    // The JSON form generates the same lexer
+    fs::write(dir.join("calc.json"), stdout(&output)).unwrap();
+    klex(&dir, &["calc.klex", "from_klex.rs"], "");
+    klex(&dir, &["calc.json", "from_json.rs"], "");
+    let from_klex = fs::read_to_string(dir.join("from_klex.rs")).unwrap();
+    let from_json = fs::read_to_string(dir.join("from_json.rs")).unwrap();
+    assert_eq!(from_klex.replace("calc.klex", "calc.json"), from_json);
+
+    let output = klex(&dir, &["--emit", "ast", "calc.klex"], "");
+    assert!(output.status.success());
+    assert!(stdout(&output).contains(r#""span""#));
+}
+
+#[test]
+fn test_watch() {
+    let dir = scratch_dir("watch", &[("calc.klex", CALC_SPEC)]);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_klex"))
+        .args(["--watch", "calc.klex", "-o", "calc_lexer.rs"])
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let wait_for = |text: &str| {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while Instant::now() < deadline {
+            if fs::read_to_string(dir.join("calc_lexer.rs")).is_ok_and(|code| code.contains(text)) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    };
+    let generated = wait_for("NUMBER");
+    fs::write(dir.join("calc.klex"), format!("{}'+' -> PLUS\n", CALC_SPEC)).unwrap();
+    let regenerated = generated && wait_for("PLUS");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(generated && regenerated);
+}
+
+#[test]
+fn test_build() {
+    let config = "[options]\nvisibility = \"pub(crate)\"\n\n[[lexer]]\nspec = \"calc.klex\"\n";
+    let dir = scratch_dir("build", &[("calc.klex", CALC_SPEC), ("klex.toml", config)]);
+    let output = klex(&dir, &["build"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("1 of 1 lexer generated"));
+    assert!(fs::read_to_string(dir.join("calc_lexer.rs")).unwrap().contains("pub(crate) enum TokenKind"));
+
+    // Errors of the configuration are reported at their line
+    fs::write(dir.join("other.toml"), "[options]\nbackend = \"dfa\"\nbogus = \"x\"\n").unwrap();
+    let output = klex(&dir, &["build", "other.toml"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Unknown option `bogus`\n  --> line 3, column 1"));
+}
+
+#[test]
+fn test_tokenize() {
+    let dir = scratch_dir("tokenize", &[("calc.klex", CALC_SPEC), ("sample.txt", "ab 12")]);
+    let output = klex(&dir, &["tokenize", "calc.klex", "sample.txt"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "   1:1         0  WORD    \"ab\"\n   1:4         3  NUMBER  \"12\"\n");
+
+    let output = klex(&dir, &["tokenize", "calc.klex"], "");
+    assert_eq!(output.status.code(), Some(1));
+
+    // Start conditions follow the action code, and the rest of it is reported as not run
+    let spec = "%xstate STR\n'\"' -> { self.begin(LexerState::STR); None }\n<STR>[^\"]+ -> TEXT\n<STR>'\"' -> { self.begin(LexerState::Initial); None }\n[a-z]+ -> WORD\n";
+    fs::write(dir.join("str.klex"), spec).unwrap();
+    fs::write(dir.join("str.txt"), "a\"b c\"").unwrap();
+    let output = klex(&dir, &["tokenize", "str.klex", "str.txt"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "   1:1         0  WORD  \"a\"\n   1:3         2  TEXT  \"b c\"\n");
+    assert!(stderr(&output).contains("warning: action code is not run"));
+}
+
+#[test]
+fn test_highlight() {
+    let spec = format!("%style NUMBER bold yellow\n{}", CALC_SPEC);
+    let dir = scratch_dir("highlight", &[("calc.klex", &spec), ("sample.txt", "ab 12")]);
+    let output = klex(&dir, &["highlight", "calc.klex", "sample.txt"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "\x1b[36mab\x1b[0m \x1b[1;33m12\x1b[0m");
+}
+
+#[test]
+fn test_graph() {
+    let dir = scratch_dir("graph", &[("calc.klex", CALC_SPEC)]);
+    let output = klex(&dir, &["graph", "--format", "dot", "calc.klex"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("digraph lexer {"));
+
+    let output = klex(&dir, &["graph", "--dfa", "-o", "calc.dot", "calc.klex"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read_to_string(dir.join("calc.dot")).unwrap().starts_with("digraph lexer {"));
+}
+
+#[test]
+fn test_doc() {
+    let dir = scratch_dir("doc", &[("calc.klex", CALC_SPEC)]);
+    let output = klex(&dir, &["doc", "calc.klex", "-o", "TOKENS.md"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let docs = fs::read_to_string(dir.join("TOKENS.md")).unwrap();
+    assert!(docs.starts_with("# Tokens of calc\n"));
+    assert!(docs.contains("| `NUMBER` | `[0-9]+` |  | `0` |"));
+}
+
+#[test]
+fn test_stats() {
+    let dir = scratch_dir("stats", &[("calc.klex", CALC_SPEC)]);
+    let output = klex(&dir, &["stats", "calc.klex"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("rules: 3\n"));
+    assert!(stdout(&output).contains("matching: 2 direct, 1 regex\n"));
+}
+
+#[test]
+fn test_lint() {
+    let dir = scratch_dir("lint", &[("calc.klex", CALC_SPEC), ("warn.klex", WARN_SPEC)]);
+    let output = klex(&dir, &["lint", "calc.klex", "warn.klex"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("warn.klex: warning[missing-whitespace-rule]"));
+    assert!(stderr(&output).contains("0 errors, 1 warning"));
+
+    // --deny makes a lint fail the command, a later --allow silences it again
+    let output = klex(&dir, &["lint", "--deny", "all", "warn.klex"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("warn.klex: error[missing-whitespace-rule]"));
+    let output = klex(&dir, &["lint", "--deny", "all", "--allow", "missing-whitespace-rule", "warn.klex"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = klex(&dir, &["lint", "--deny", "no-such-lint", "warn.klex"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Unknown lint 'no-such-lint'"));
+}
+
+#[test]
+fn test_repl() {
+    let dir = scratch_dir("repl", &[("calc.klex", CALC_SPEC)]);
+    let output = klex(&dir, &["repl", "calc.klex"], "ab 1\n:rules\n:quit\nignored\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "   1  WORD    \"ab\"\n   4  NUMBER  \"1\"\n  1 on   [0-9]+ -> NUMBER\n  2 on   [a-z]+ -> WORD\n  3 on   [ ]+ -> %skip\n"
+    );
+}